@@ -0,0 +1,73 @@
+# Copyright 2025 Bruce Merry
+#
+# This program is free software: you can redistribute it and/or modify it
+# under the terms of the GNU General Public License as published by the Free
+# Software Foundation, either version 3 of the License, or (at your option)
+# any later version.
+#
+# This program is distributed in the hope that it will be useful, but WITHOUT
+# ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+# FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+# more details.
+#
+# You should have received a copy of the GNU General Public License along
+# with this program. If not, see <https://www.gnu.org/licenses/>.
+
+"""Access the socit planner from Python via its C ABI.
+
+Build the shared library with ``cargo build --release``, then e.g.::
+
+    planner = Planner("target/release/libsocit.so")
+    planner.compute_targets(
+        events=[],
+        panels=[],
+        battery={"capacity": 5000, "min_soc": 20},
+        load={"min_discharge_power": 100, "max_discharge_power": 500},
+        now=datetime.datetime.now(datetime.timezone.utc),
+    )
+"""
+
+import ctypes
+import datetime
+import json
+
+
+class Planner:
+    def __init__(self, path):
+        self._lib = ctypes.CDLL(path)
+        self._lib.socit_compute_targets.argtypes = [ctypes.c_char_p]
+        self._lib.socit_compute_targets.restype = ctypes.c_void_p
+        self._lib.socit_panels_power.argtypes = [ctypes.c_char_p, ctypes.c_double]
+        self._lib.socit_panels_power.restype = ctypes.c_double
+        self._lib.socit_free_string.argtypes = [ctypes.c_void_p]
+        self._lib.socit_free_string.restype = None
+
+    def compute_targets(self, *, events, panels, battery, load, now):
+        """Compute target states of charge.
+
+        `events` is a list of dicts with keys `start`, `end` (as datetimes)
+        and `note`. The other dicts correspond to the structures in
+        ``socit::planner``.
+        """
+        request = {
+            "events": [
+                dict(event, start=event["start"].isoformat(), end=event["end"].isoformat())
+                for event in events
+            ],
+            "panels": panels,
+            "battery": battery,
+            "load": load,
+            "now": now.isoformat(),
+        }
+        raw = self._lib.socit_compute_targets(json.dumps(request).encode())
+        try:
+            response = json.loads(ctypes.string_at(raw).decode())
+        finally:
+            self._lib.socit_free_string(raw)
+        if "error" in response:
+            raise ValueError(response["error"])
+        return response
+
+    def panels_power(self, panels, time: datetime.datetime):
+        """Predicted clear-sky power (W) from a list of panels."""
+        return self._lib.socit_panels_power(json.dumps(panels).encode(), time.timestamp())
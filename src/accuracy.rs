@@ -0,0 +1,312 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Tracking how well the load and PV predictions match reality
+//!
+//! Each cycle, the change in stored energy predicted from the expected PV
+//! power and load is compared with the change in the measured SoC. Only
+//! periods when the battery is free to charge and discharge are used: at
+//! the minimum SoC the grid takes over the load, and when full the PV is
+//! curtailed. The comparisons are summed over windows (an hour by default)
+//! so that the whole-percentage SoC readings average out.
+//!
+//! From the recent windows, the bias (mean error) and mean absolute error
+//! are reported, and scale factors for the load and PV predictions are
+//! estimated by least squares.
+
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+use std::collections::VecDeque;
+
+use crate::config::AccuracyConfig;
+use crate::planner::duration_hours;
+
+/// Predicted power flows (W) at the time of a sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Prediction {
+    pub pv: f64,
+    pub load: f64,
+}
+
+/// Factors by which the predictions should be scaled to match reality
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scales {
+    pub load: f64,
+    pub pv: f64,
+}
+
+impl Default for Scales {
+    fn default() -> Self {
+        Self { load: 1.0, pv: 1.0 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccuracyStats {
+    /// Mean of actual minus predicted battery power (W, positive for charging)
+    pub bias: f64,
+    /// Mean absolute difference between actual and predicted battery power (W)
+    pub error: f64,
+    /// Estimated scales, once there are enough windows
+    pub scales: Option<Scales>,
+}
+
+struct Sample {
+    time: DateTime<Utc>,
+    soc: f64,
+    /// Minimum SoC set on the inverter
+    min_soc: f64,
+    prediction: Prediction,
+}
+
+/// Energies (Wh) summed over a window
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Window {
+    duration: Duration,
+    /// Predicted change in stored energy
+    predicted: f64,
+    /// Actual change in stored energy
+    actual: f64,
+    /// Contributions of PV and load to `predicted`, after efficiency losses
+    pv: f64,
+    load: f64,
+}
+
+impl Window {
+    /// Difference between the actual and predicted change, as a mean power (W)
+    fn error(&self) -> f64 {
+        (self.actual - self.predicted) / duration_hours(self.duration)
+    }
+}
+
+pub struct AccuracyTracker<'a> {
+    config: &'a AccuracyConfig,
+    charge_efficiency: f64,
+    discharge_efficiency: f64,
+    last: Option<Sample>,
+    current: Window,
+    /// Completed windows, oldest first
+    windows: VecDeque<Window>,
+}
+
+impl<'a> AccuracyTracker<'a> {
+    /// Samples further apart than this break the window
+    const MAX_GAP: Duration = Duration::minutes(10);
+    /// SoC (%) within this distance of the minimum or of full is not free-running
+    const MARGIN: f64 = 1.0;
+    /// Limits on the estimated scales
+    const MIN_SCALE: f64 = 0.5;
+    const MAX_SCALE: f64 = 2.0;
+
+    pub fn new(
+        config: &'a AccuracyConfig,
+        charge_efficiency: f64,
+        discharge_efficiency: f64,
+    ) -> Self {
+        Self {
+            config,
+            charge_efficiency,
+            discharge_efficiency,
+            last: None,
+            current: Window::default(),
+            windows: VecDeque::new(),
+        }
+    }
+
+    fn free_running(soc: f64, min_soc: f64) -> bool {
+        soc > min_soc + Self::MARGIN && soc < 100.0 - Self::MARGIN
+    }
+
+    /// Add a sample of the SoC (%), with the minimum SoC (%) that is being
+    /// set on the inverter and the predictions for the coming cycle.
+    pub fn add(
+        &mut self,
+        time: DateTime<Utc>,
+        soc: f64,
+        min_soc: f64,
+        capacity: f64,
+        prediction: Prediction,
+    ) {
+        if let Some(last) = &self.last {
+            let elapsed = time - last.time;
+            if elapsed > Duration::zero()
+                && elapsed <= Self::MAX_GAP
+                && Self::free_running(last.soc, last.min_soc)
+                && Self::free_running(soc, last.min_soc)
+            {
+                let hours = duration_hours(elapsed);
+                let net = last.prediction.pv - last.prediction.load;
+                let factor = if net >= 0.0 {
+                    self.charge_efficiency
+                } else {
+                    1.0 / self.discharge_efficiency
+                };
+                let window = &mut self.current;
+                window.duration += elapsed;
+                window.pv += factor * last.prediction.pv * hours;
+                window.load += factor * last.prediction.load * hours;
+                window.predicted += factor * net * hours;
+                window.actual += (soc - last.soc) * 0.01 * capacity;
+                if window.duration.to_std().unwrap_or_default() >= self.config.window {
+                    info!(
+                        "Battery energy changed by {:.0} Wh over {:.1} h, predicted {:.0} Wh",
+                        window.actual,
+                        duration_hours(window.duration),
+                        window.predicted
+                    );
+                    self.windows.push_back(*window);
+                    while self.windows.len() > self.config.windows {
+                        self.windows.pop_front();
+                    }
+                    self.current = Window::default();
+                }
+            } else {
+                // Partial windows are discarded so that every window is complete
+                self.current = Window::default();
+            }
+        }
+        self.last = Some(Sample {
+            time,
+            soc,
+            min_soc,
+            prediction,
+        });
+    }
+
+    /// Least-squares fit of actual = pv_scale * pv - load_scale * load
+    fn fit(&self) -> Option<Scales> {
+        if self.windows.len() < self.config.min_windows.max(1) {
+            return None;
+        }
+        let (mut spp, mut spl, mut sll, mut spa, mut sla) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for window in &self.windows {
+            spp += window.pv * window.pv;
+            spl += window.pv * window.load;
+            sll += window.load * window.load;
+            spa += window.pv * window.actual;
+            sla += window.load * window.actual;
+        }
+        if sll <= 0.0 {
+            return None;
+        }
+        let det = spp * sll - spl * spl;
+        let scales = if det > 1e-6 * spp * sll {
+            Scales {
+                pv: (spa * sll - sla * spl) / det,
+                load: (spa * spl - sla * spp) / det,
+            }
+        } else {
+            // Not enough variation in the PV to separate it from the load,
+            // so only estimate the load scale
+            Scales {
+                pv: 1.0,
+                load: (spl - sla) / sll,
+            }
+        };
+        let clamp = |x: f64| x.clamp(Self::MIN_SCALE, Self::MAX_SCALE);
+        Some(Scales {
+            load: clamp(scales.load),
+            pv: clamp(scales.pv),
+        })
+    }
+
+    /// Statistics over the recent windows, if there are any
+    pub fn stats(&self) -> Option<AccuracyStats> {
+        if self.windows.is_empty() {
+            return None;
+        }
+        let n = self.windows.len() as f64;
+        Some(AccuracyStats {
+            bias: self.windows.iter().map(Window::error).sum::<f64>() / n,
+            error: self.windows.iter().map(|w| w.error().abs()).sum::<f64>() / n,
+            scales: self.fit(),
+        })
+    }
+
+    /// Scales to apply to the predictions (1 unless `auto_scale` is enabled)
+    pub fn scales(&self) -> Scales {
+        if self.config.auto_scale {
+            self.fit().unwrap_or_default()
+        } else {
+            Scales::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn config() -> AccuracyConfig {
+        AccuracyConfig {
+            window: std::time::Duration::from_secs(3600),
+            windows: 10,
+            min_windows: 2,
+            auto_scale: true,
+        }
+    }
+
+    /// Simulate a battery whose real load and PV differ from the predictions
+    fn simulate(tracker: &mut AccuracyTracker, hours: i64, pv: f64, load: f64) {
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let offset = tracker.last.as_ref().map_or(start, |last| last.time);
+        // 10 kWh battery, so 1% is 100 Wh
+        let mut soc = tracker.last.as_ref().map_or(50.0, |last| last.soc);
+        for minute in 0..=hours * 60 {
+            let time = offset + Duration::minutes(minute);
+            if minute > 0 {
+                soc += (1.2 * pv - 0.8 * load) / 60.0 / 100.0;
+            }
+            let prediction = Prediction { pv, load };
+            tracker.add(time, soc.round(), 20.0, 10000.0, prediction);
+        }
+    }
+
+    #[test]
+    fn test_accuracy() {
+        let config = config();
+        let mut tracker = AccuracyTracker::new(&config, 1.0, 1.0);
+        assert_eq!(tracker.stats(), None);
+        // At night, the load is over-predicted
+        simulate(&mut tracker, 3, 0.0, 500.0);
+        let stats = tracker.stats().unwrap();
+        assert!((stats.bias - 100.0).abs() < 50.0, "{stats:?}");
+        // During the day, the PV is under-predicted
+        simulate(&mut tracker, 3, 1500.0, 500.0);
+        let scales = tracker.scales();
+        assert!((scales.load - 0.8).abs() < 0.1, "{scales:?}");
+        assert!((scales.pv - 1.2).abs() < 0.1, "{scales:?}");
+    }
+
+    #[test]
+    fn test_not_free_running() {
+        let config = config();
+        let mut tracker = AccuracyTracker::new(&config, 1.0, 1.0);
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let prediction = Prediction {
+            pv: 0.0,
+            load: 500.0,
+        };
+        // Held at the minimum SoC, so the grid carries the load
+        for minute in 0..=120 {
+            let time = start + Duration::minutes(minute);
+            tracker.add(time, 20.0, 20.0, 10000.0, prediction);
+        }
+        assert_eq!(tracker.stats(), None);
+        assert_eq!(tracker.scales(), Scales::default());
+    }
+}
@@ -0,0 +1,146 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Notifications when alarm conditions start or stop
+//!
+//! Each kind of alert is either raised or clear, and a notification is only
+//! sent when it changes state.
+
+use log::{info, warn};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::{AlertSinkConfig, AlertsConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// Current SoC is below the alarm SoC
+    LowSoc,
+    /// No load-shedding information for longer than the timeout
+    StaleEsp,
+    /// Communication with the inverter has failed repeatedly
+    InverterFailure,
+    /// Inverter clock disagrees with the system clock
+    ClockSkew,
+    /// Programs written to the inverter did not read back correctly
+    WriteFailure,
+    /// Grid is down outside the load-shedding schedule
+    GridOutage,
+}
+
+impl fmt::Display for AlertKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AlertKind::LowSoc => "Low SoC",
+            AlertKind::StaleEsp => "Stale load-shedding data",
+            AlertKind::InverterFailure => "Inverter communication failure",
+            AlertKind::ClockSkew => "Inverter clock skew",
+            AlertKind::WriteFailure => "Inverter write failure",
+            AlertKind::GridOutage => "Unscheduled grid outage",
+        };
+        f.write_str(name)
+    }
+}
+
+pub struct Alerter {
+    client: Client,
+    sinks: Vec<AlertSinkConfig>,
+    raised: Mutex<HashSet<AlertKind>>,
+}
+
+impl Alerter {
+    pub fn new(config: Option<&AlertsConfig>) -> reqwest::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            sinks: config.map_or_else(Vec::new, |config| config.sinks.clone()),
+            raised: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Set whether an alert is raised, notifying if it changes.
+    pub async fn set(&self, kind: AlertKind, raised: bool, message: &str) {
+        let changed = {
+            let mut lock = self.raised.lock().unwrap();
+            if raised {
+                lock.insert(kind)
+            } else {
+                lock.remove(&kind)
+            }
+        };
+        if !changed {
+            return;
+        }
+        let text = if raised {
+            format!("{kind}: {message}")
+        } else {
+            format!("Resolved: {kind}: {message}")
+        };
+        if raised {
+            warn!("Alert raised: {text}");
+        } else {
+            info!("Alert cleared: {text}");
+        }
+        for sink in self.sinks.iter() {
+            if let Err(err) = self.send(sink, kind, raised, &text).await {
+                warn!("Failed to send alert: {err}");
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        sink: &AlertSinkConfig,
+        kind: AlertKind,
+        raised: bool,
+        text: &str,
+    ) -> reqwest::Result<()> {
+        let request = match sink {
+            AlertSinkConfig::Webhook { url } => self.client.post(url).json(&json!({
+                "alert": kind,
+                "raised": raised,
+                "message": text,
+            })),
+            AlertSinkConfig::Telegram { bot_token, chat_id } => self
+                .client
+                .post(format!(
+                    "https://api.telegram.org/bot{bot_token}/sendMessage"
+                ))
+                .json(&json!({
+                    "chat_id": chat_id,
+                    "text": text,
+                })),
+            AlertSinkConfig::Pushover { token, user } => self
+                .client
+                .post("https://api.pushover.net/1/messages.json")
+                .json(&json!({
+                    "token": token,
+                    "user": user,
+                    "title": "socit",
+                    "message": text,
+                })),
+        };
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Reading the battery state directly from a battery management system
+
+use log::warn;
+use std::io::{self, ErrorKind};
+use tokio::time::Instant;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::{Client, Reader};
+use tokio_modbus::slave::Slave;
+
+use crate::config::{BmsConfig, BmsRegister};
+use crate::inverter::Result;
+use crate::modbus::{Bus, Connection};
+
+/// Battery state reported by the BMS
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BmsReading {
+    /// State of charge (%)
+    pub soc: f64,
+    /// Usable capacity (Wh), adjusted for the state of health if known
+    pub capacity: Option<f64>,
+    /// Cell temperatures (°C)
+    pub temperatures: Vec<f64>,
+}
+
+impl BmsReading {
+    pub fn min_temperature(&self) -> Option<f64> {
+        self.temperatures.iter().copied().reduce(f64::min)
+    }
+
+    pub fn max_temperature(&self) -> Option<f64> {
+        self.temperatures.iter().copied().reduce(f64::max)
+    }
+}
+
+/// A BMS that reports its state over Modbus
+pub struct Bms {
+    bus: Bus,
+    slave: Slave,
+    config: BmsConfig,
+}
+
+/// Convert a raw register value to physical units
+fn decode(register: &BmsRegister, raw: u16) -> f64 {
+    let raw = if register.signed {
+        raw as i16 as f64
+    } else {
+        raw as f64
+    };
+    raw * register.scale + register.offset
+}
+
+async fn read_raw(ctx: &mut Context, register: &BmsRegister) -> Result<f64> {
+    let words = if register.input {
+        ctx.read_input_registers(register.address, 1).await??
+    } else {
+        ctx.read_holding_registers(register.address, 1).await??
+    };
+    Ok(decode(register, words[0]))
+}
+
+impl Bms {
+    pub fn new(config: &BmsConfig, bus: Bus) -> Self {
+        Self {
+            bus,
+            slave: Slave(config.id),
+            config: config.clone(),
+        }
+    }
+
+    /// Read a single register, applying the configured delay and timeout
+    async fn read_register(
+        &self,
+        connection: &mut Connection,
+        register: &BmsRegister,
+    ) -> Result<f64> {
+        if let Some(last) = connection.last_request {
+            tokio::time::sleep_until(last + self.config.request_delay).await;
+        }
+        let result =
+            tokio::time::timeout(self.config.timeout, read_raw(&mut connection.ctx, register))
+                .await;
+        connection.last_request = Some(Instant::now());
+        match result {
+            Ok(result) => result,
+            Err(_) => {
+                // Start afresh so that a late response is not mistaken for the next one
+                if let Err(err) = connection.ctx.disconnect().await {
+                    warn!("Failed to disconnect from the BMS: {err}");
+                }
+                Err(io::Error::new(ErrorKind::TimedOut, "BMS request timed out").into())
+            }
+        }
+    }
+
+    /// Read the battery state
+    pub async fn read(&self) -> Result<BmsReading> {
+        let mut connection = self.bus.lock(self.slave).await;
+        let config = &self.config;
+        let soc = self.read_register(&mut connection, &config.soc).await?;
+        let mut capacity = None;
+        if let Some(register) = &config.capacity {
+            let mut value = self.read_register(&mut connection, register).await?;
+            if let Some(register) = &config.soh {
+                value *= self.read_register(&mut connection, register).await? * 0.01;
+            }
+            capacity = Some(value);
+        }
+        let mut temperatures = Vec::new();
+        for register in config.temperatures.iter() {
+            temperatures.push(self.read_register(&mut connection, register).await?);
+        }
+        Ok(BmsReading {
+            soc,
+            capacity,
+            temperatures,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let register = BmsRegister {
+            address: 0,
+            scale: 0.1,
+            offset: -40.0,
+            signed: false,
+            input: false,
+        };
+        assert!((decode(&register, 654) - 25.4).abs() < 1e-9);
+        let register = BmsRegister {
+            signed: true,
+            offset: 0.0,
+            ..register
+        };
+        assert!((decode(&register, 0xfff6) + 1.0).abs() < 1e-9);
+    }
+}
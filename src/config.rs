@@ -14,10 +14,17 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use serde::Deserialize;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::time::Duration;
 
-#[derive(Deserialize)]
+use crate::holidays::Holidays;
+use crate::planner::local_time;
+use crate::timezone::Timezone;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct PanelConfig {
     pub latitude: f64,
@@ -25,6 +32,159 @@ pub struct PanelConfig {
     pub tilt: f64,
     pub azimuth: f64,
     pub power: f64,
+    /// MPPT string (numbered from 1) to which the panels are connected
+    #[serde(default)]
+    pub mppt: Option<usize>,
+    /// Where to get the forecast of production
+    #[serde(default)]
+    pub forecast: ForecastSource,
+    /// Local horizon, as (azimuth, elevation) pairs in degrees
+    #[serde(default)]
+    pub horizon: Vec<[f64; 2]>,
+    /// Fraction of the output still produced when the sun is behind the horizon
+    #[serde(default)]
+    pub diffuse: f64,
+    /// Change in output (% per °C) as the cell temperature rises above 25°C
+    #[serde(default)]
+    pub temperature_coefficient: Option<f64>,
+    /// Nominal operating cell temperature (°C)
+    #[serde(default = "noct_default")]
+    pub noct: f64,
+    /// Maximum power (W) that the MPPT can draw from these panels
+    #[serde(default)]
+    pub max_power: Option<f64>,
+    /// Factor applied to the predicted output (e.g. for soiling or degradation)
+    #[serde(default = "derate_default")]
+    pub derate: f64,
+    /// Additional factor for each calendar month, starting from January
+    #[serde(default)]
+    pub monthly: Option<[f64; 12]>,
+    /// Additional orientations sharing `max_power` (e.g. the other half of an
+    /// east/west array, or the rear of bifacial panels)
+    #[serde(default)]
+    pub faces: Vec<PanelFaceConfig>,
+}
+
+impl PanelConfig {
+    /// All the orientations of the panels, starting with the primary one
+    pub fn all_faces(&self) -> impl Iterator<Item = PanelFaceConfig> + '_ {
+        let primary = PanelFaceConfig {
+            tilt: self.tilt,
+            azimuth: self.azimuth,
+            power: self.power,
+        };
+        std::iter::once(primary).chain(self.faces.iter().cloned())
+    }
+
+    /// Total rated power (W) of all the orientations
+    pub fn rated_power(&self) -> f64 {
+        self.all_faces().map(|face| face.power).sum()
+    }
+}
+
+/// Orientation of part of a set of panels
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PanelFaceConfig {
+    pub tilt: f64,
+    pub azimuth: f64,
+    pub power: f64,
+}
+
+fn noct_default() -> f64 {
+    45.0
+}
+
+fn derate_default() -> f64 {
+    1.0
+}
+
+/// Source of the PV production forecast for a set of panels
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForecastSource {
+    /// Clear-sky model computed from the position of the sun
+    #[default]
+    ClearSky,
+    /// https://forecast.solar, falling back to clear-sky if unavailable
+    ForecastSolar,
+}
+
+/// How to round a state of charge to a whole percentage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rounding {
+    #[default]
+    Nearest,
+    Up,
+    Down,
+}
+
+/// Fallback SoC (%), either fixed or for each month (January first)
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum FallbackSoc {
+    Fixed(f64),
+    Monthly([f64; 12]),
+}
+
+impl FallbackSoc {
+    /// Fallback SoC on a (local) date
+    pub fn on(&self, date: NaiveDate) -> f64 {
+        match self {
+            FallbackSoc::Fixed(soc) => *soc,
+            FallbackSoc::Monthly(socs) => socs[date.month0() as usize],
+        }
+    }
+}
+
+/// Register layout of the inverter firmware
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegisterLayout {
+    SinglePhase,
+    ThreePhase,
+}
+
+impl std::fmt::Display for RegisterLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterLayout::SinglePhase => write!(f, "single-phase"),
+            RegisterLayout::ThreePhase => write!(f, "three-phase"),
+        }
+    }
+}
+
+/// System work mode of the inverter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkMode {
+    /// Export surplus power to the grid
+    SellingFirst,
+    /// Only power the essential loads
+    LimitedToLoad,
+    /// Power all household loads (measured by the CT coil) without exporting
+    LimitedToHome,
+}
+
+/// Time of day (local time) during which the smart load output is expected to be on
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmartLoadConfig {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Power drawn by the smart load (W)
+    pub power: f64,
+}
+
+/// Time of day (local time) during which the SoC should not fall below a floor
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocFloorConfig {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Minimum SoC (%) during the window
+    pub soc: f64,
 }
 
 #[derive(Deserialize)]
@@ -35,21 +195,119 @@ pub struct InverterConfig {
     pub id: u8,
     // TODO: validation of range
     pub min_soc: f64,
-    pub fallback_soc: f64,
+    pub fallback_soc: FallbackSoc,
     pub min_discharge_power: f64,
     pub max_discharge_power: f64,
     #[serde(default)]
     pub charge_power: Option<f64>,
+    /// Usable battery capacity (Wh), overriding the value derived from the inverter settings
+    #[serde(default)]
+    pub capacity_wh: Option<f64>,
+    /// Fraction of energy stored when charging the battery
+    #[serde(default = "efficiency_default")]
+    pub charge_efficiency: f64,
+    /// Fraction of energy delivered when discharging the battery
+    #[serde(default = "efficiency_default")]
+    pub discharge_efficiency: f64,
+    /// Power (W) consumed by the inverter itself
+    #[serde(default)]
+    pub self_consumption: f64,
     #[serde(default = "dry_run_default")]
     pub dry_run: bool,
     #[serde(default)]
+    pub timezone: Option<Timezone>,
+    #[serde(default)]
+    pub target_rounding: Rounding,
+    #[serde(default)]
+    pub fallback_rounding: Rounding,
+    #[serde(default)]
+    pub batch_writes: bool,
+    /// Register layout to use instead of detecting it from the device type
+    #[serde(default)]
+    pub register_layout: Option<RegisterLayout>,
+    /// Read the programs back after writing them, and retry if they differ
+    #[serde(default = "verify_writes_default")]
+    pub verify_writes: bool,
+    /// Difference between the inverter clock and system time that is reported as an error
+    #[serde(default = "max_clock_skew_default", with = "humantime_serde")]
+    pub max_clock_skew: Duration,
+    /// Consecutive failed transactions after which the inverter is considered unreachable
+    #[serde(default = "unreachable_failures_default")]
+    pub unreachable_failures: u64,
+    /// Time to wait for the response to a Modbus request
+    #[serde(default = "modbus_timeout_default", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Number of times to retry a Modbus request that failed or timed out
+    #[serde(default = "retries_default")]
+    pub retries: u32,
+    /// Minimum time between the end of one Modbus request and the start of the next
+    #[serde(default, with = "humantime_serde")]
+    pub request_delay: Duration,
+    /// Restore the programs found at startup on shutdown, instead of writing fallback_soc
+    #[serde(default)]
+    pub restore_programs: bool,
+    /// Skip rewriting the programs if the target changed by less than this (%)
+    #[serde(default)]
+    pub deadband: f64,
+    /// Maximum total PV input power (W) accepted by the inverter
+    #[serde(default)]
+    pub max_pv_power: Option<f64>,
+    /// Enable (or disable) grid charging in every program; unchanged if not given
+    #[serde(default)]
+    pub grid_charge: Option<bool>,
+    /// Power (W) to set in every program; unchanged if not given
+    #[serde(default)]
+    pub program_power: Option<f64>,
+    /// Whether the trickle register accepts negative (export) values
+    #[serde(default)]
+    pub signed_trickle: bool,
+    #[serde(default)]
     pub panels: Vec<PanelConfig>,
+    /// Predicted PV power (W) that defines the PV window (default: 10% of rated power)
+    #[serde(default)]
+    pub pv_window_threshold: Option<f64>,
+    #[serde(default)]
+    pub smart_load: Vec<SmartLoadConfig>,
+    #[serde(default)]
+    pub soc_floor: Vec<SocFloorConfig>,
+}
+
+impl InverterConfig {
+    /// Fallback SoC (%) in effect at a time, using the inverter time zone
+    pub fn fallback_soc_at(&self, time: DateTime<Utc>) -> f64 {
+        self.fallback_soc
+            .on(local_time(self.timezone.as_ref(), time).date())
+    }
 }
 
 fn id_default() -> u8 {
     1
 }
 
+fn efficiency_default() -> f64 {
+    1.0
+}
+
+fn max_clock_skew_default() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn verify_writes_default() -> bool {
+    true
+}
+
+fn unreachable_failures_default() -> u64 {
+    3
+}
+
+fn modbus_timeout_default() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn retries_default() -> u32 {
+    1
+}
+
 fn dry_run_default() -> bool {
     false
 }
@@ -65,6 +323,67 @@ pub struct EspConfig {
     pub timeout: Duration,
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForecastSolarConfig {
+    #[serde(default = "forecast_solar_url_default")]
+    pub url: String,
+    /// Optional API key for a paid plan
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Time between refreshes of the forecast for each set of panels
+    #[serde(default = "forecast_solar_interval_default", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for ForecastSolarConfig {
+    fn default() -> Self {
+        Self {
+            url: forecast_solar_url_default(),
+            key: None,
+            interval: forecast_solar_interval_default(),
+        }
+    }
+}
+
+fn forecast_solar_url_default() -> String {
+    "https://api.forecast.solar".to_string()
+}
+
+fn forecast_solar_interval_default() -> Duration {
+    // The free tier allows 12 requests per hour, and the forecast is only
+    // updated hourly.
+    Duration::from_secs(60 * 60)
+}
+
+/// Access to the Open-Meteo forecast API, for ambient temperatures
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenMeteoConfig {
+    #[serde(default = "open_meteo_url_default")]
+    pub url: String,
+    /// Time between refreshes of the forecast
+    #[serde(default = "open_meteo_interval_default", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for OpenMeteoConfig {
+    fn default() -> Self {
+        Self {
+            url: open_meteo_url_default(),
+            interval: open_meteo_interval_default(),
+        }
+    }
+}
+
+fn open_meteo_url_default() -> String {
+    "https://api.open-meteo.com/v1/forecast".to_string()
+}
+
+fn open_meteo_interval_default() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
 fn interval_default() -> Duration {
     // Default to 40 minutes
     Duration::from_secs(40 * 60)
@@ -83,24 +402,1043 @@ pub struct Influxdb2Config {
     pub org: String,
     pub token: String,
     pub bucket: String,
+    /// Tags added to every point (e.g. to distinguish sites)
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
 }
 
 fn default_host() -> String {
     "http://localhost:8086".to_string()
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Influxdb1Config {
+    #[serde(default = "default_host")]
+    pub host: String,
+    pub database: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Tags added to every point (e.g. to distinguish sites)
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonitoringConfig {
+    /// Maximum number of failed updates to queue for each backend
+    #[serde(default = "buffer_size_default")]
+    pub buffer_size: usize,
+    #[serde(default = "max_backoff_default", with = "humantime_serde")]
+    pub max_backoff: Duration,
+    /// Directory in which to persist queued updates
+    #[serde(default)]
+    pub spool_directory: Option<PathBuf>,
+    /// Number of recent updates and errors to keep in memory
+    #[serde(default = "cycle_log_size_default")]
+    pub cycle_log_size: usize,
+}
+
+fn buffer_size_default() -> usize {
+    1000
+}
+
+fn cycle_log_size_default() -> usize {
+    100
+}
+
+fn max_backoff_default() -> Duration {
+    // Default to 5 minutes
+    Duration::from_secs(5 * 60)
+}
+
+impl Default for MonitoringConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: buffer_size_default(),
+            max_backoff: max_backoff_default(),
+            spool_directory: None,
+            cycle_log_size: cycle_log_size_default(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PostgresConfig {
+    #[serde(default = "postgres_host_default")]
+    pub host: String,
+    #[serde(default = "postgres_port_default")]
+    pub port: u16,
+    pub user: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub database: String,
+    /// Tables are named <prefix>_soc, <prefix>_coil and <prefix>_pv
+    #[serde(default = "postgres_table_prefix_default")]
+    pub table_prefix: String,
+    /// Convert the tables to TimescaleDB hypertables
+    #[serde(default)]
+    pub timescaledb: bool,
+    #[serde(default)]
+    pub ssl_mode: PostgresSslMode,
+    /// PEM file with the certificates to trust, instead of the public roots
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Time allowed for connecting and for each write
+    #[serde(default = "postgres_timeout_default", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+/// Whether to connect to PostgreSQL over TLS
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PostgresSslMode {
+    #[default]
+    Disable,
+    /// Use TLS if the server supports it
+    Prefer,
+    Require,
+}
+
+fn postgres_host_default() -> String {
+    "localhost".to_string()
+}
+
+fn postgres_port_default() -> u16 {
+    5432
+}
+
+fn postgres_table_prefix_default() -> String {
+    "socit".to_string()
+}
+
+fn postgres_timeout_default() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    #[default]
+    Csv,
+    Jsonl,
+    /// Collected during the day and converted once the day is over
+    Parquet,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub format: FileFormat,
+    /// Number of daily files of each kind to keep (None to keep all)
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "mqtt_port_default")]
+    pub port: u16,
+    #[serde(default = "mqtt_client_id_default")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "mqtt_topic_prefix_default")]
+    pub topic_prefix: String,
+    /// Prefix for Home Assistant discovery messages (empty to disable)
+    #[serde(default = "mqtt_discovery_prefix_default")]
+    pub discovery_prefix: String,
+    /// Connect to the broker with TLS
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM file with the certificates to trust, instead of the public roots
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+}
+
+fn mqtt_port_default() -> u16 {
+    1883
+}
+
+fn mqtt_client_id_default() -> String {
+    "socit".to_string()
+}
+
+fn mqtt_topic_prefix_default() -> String {
+    "socit".to_string()
+}
+
+fn mqtt_discovery_prefix_default() -> String {
+    "homeassistant".to_string()
+}
+
+/// A register holding one measurement from the BMS
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BmsRegister {
+    pub address: u16,
+    /// Multiplier converting the raw value to physical units
+    #[serde(default = "scale_default")]
+    pub scale: f64,
+    /// Added after scaling
+    #[serde(default)]
+    pub offset: f64,
+    /// Whether the raw value is a signed 16-bit integer
+    #[serde(default)]
+    pub signed: bool,
+    /// Read with function 0x04 (input registers) rather than 0x03 (holding registers)
+    #[serde(default)]
+    pub input: bool,
+}
+
+fn scale_default() -> f64 {
+    1.0
+}
+
+/// Battery management system that reports its state over Modbus
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BmsConfig {
+    /// Serial device or socket address; may be the same as the inverter's
+    pub device: String,
+    #[serde(default = "id_default")]
+    pub id: u8,
+    #[serde(default = "modbus_timeout_default", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Minimum time between the end of one Modbus request and the start of the next
+    #[serde(default, with = "humantime_serde")]
+    pub request_delay: Duration,
+    /// State of charge (%)
+    pub soc: BmsRegister,
+    /// Full capacity (Wh)
+    #[serde(default)]
+    pub capacity: Option<BmsRegister>,
+    /// State of health (%), by which the capacity is scaled
+    #[serde(default)]
+    pub soh: Option<BmsRegister>,
+    /// Cell (or module) temperatures (°C)
+    #[serde(default)]
+    pub temperatures: Vec<BmsRegister>,
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct CoilConfig {
     pub power_threshold: f64,
     pub trickle: f64,
+    #[serde(default)]
+    pub non_essential: NonEssentialSource,
+    /// Number of samples in the moving mean
+    #[serde(default = "coil_history_default")]
+    pub history: usize,
+    /// Minimum change (W) before the trickle setting is updated
+    #[serde(default = "coil_hysteresis_default")]
+    pub hysteresis: f64,
+    /// Discard samples further than this (W) from the median of recent samples
+    pub outlier: Option<f64>,
+    /// Discard readings larger than this (W) in magnitude, which are corrupt
+    #[serde(default = "coil_max_power_default")]
+    pub max_power: f64,
+    /// Track the ideal setting with a PI controller instead of a moving mean
+    pub pid: Option<CoilPidConfig>,
+}
+
+fn coil_history_default() -> usize {
+    11
+}
+
+fn coil_hysteresis_default() -> f64 {
+    10.0
+}
+
+fn coil_max_power_default() -> f64 {
+    30000.0
+}
+
+/// Gains and limits for PI control of the trickle setting
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoilPidConfig {
+    /// Fraction of the error applied immediately (0 to 1)
+    pub kp: f64,
+    /// Rate at which the error is integrated (per second)
+    pub ki: f64,
+    /// Minimum trickle setting (W)
+    #[serde(default = "coil_pid_min_default")]
+    pub min: f64,
+    /// Maximum trickle setting (W)
+    #[serde(default = "coil_pid_max_default")]
+    pub max: f64,
+}
+
+fn coil_pid_min_default() -> f64 {
+    -500.0
+}
+
+fn coil_pid_max_default() -> f64 {
+    500.0
+}
+
+/// How the coil controller determines the power drawn by non-essential loads
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NonEssentialSource {
+    /// Difference between the CT coil and inverter readings
+    #[default]
+    Difference,
+    /// Reported directly by the inverter
+    Measured,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DischargeLimitConfig {
+    /// Limit discharge when SoC is within this many percent of the alarm SoC
+    pub margin: f64,
+    /// Reduced maximum discharge current (A)
+    pub current: f64,
+}
+
+/// Expected household load (W) by hour of the day (local time)
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadConfig {
+    /// Every day, unless overridden by `weekday` or `weekend`
+    #[serde(default)]
+    pub hourly: Option<[f64; 24]>,
+    /// Monday to Friday
+    #[serde(default)]
+    pub weekday: Option<[f64; 24]>,
+    /// Saturday and Sunday
+    #[serde(default)]
+    pub weekend: Option<[f64; 24]>,
+}
+
+/// Public holidays, on which the weekend load profile and tariff apply
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HolidaysConfig {
+    #[serde(default)]
+    pub dates: Vec<NaiveDate>,
+    /// iCalendar file listing holidays
+    #[serde(default)]
+    pub ics: Option<PathBuf>,
+}
+
+/// Be more cautious during higher stages of load-shedding, when extra slots
+/// are likely to be added at short notice
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StageConfig {
+    /// Apply the adjustments if any upcoming event is at this stage or higher
+    #[serde(default = "stage_threshold_default")]
+    pub threshold: u32,
+    /// Factor applied to the predicted PV power
+    #[serde(default = "stage_pv_scale_default")]
+    pub pv_scale: f64,
+    /// Extra SoC (%) added to the targets
+    #[serde(default)]
+    pub margin: f64,
+}
+
+fn stage_threshold_default() -> u32 {
+    4
+}
+
+fn stage_pv_scale_default() -> f64 {
+    0.5
+}
+
+/// Parameters of the simulation used to compute the targets
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulationConfig {
+    /// How far ahead to look for load-shedding. Beyond `far_future`, this
+    /// is limited to the period covered by the load-shedding schedule.
+    #[serde(default = "horizon_default", with = "humantime_serde")]
+    pub horizon: Duration,
+    #[serde(default = "step_default", with = "humantime_serde")]
+    pub step: Duration,
+    /// Load-shedding starting further ahead than this is weighted by
+    /// `far_future_weight`
+    #[serde(default = "far_future_default", with = "humantime_serde")]
+    pub far_future: Duration,
+    #[serde(default = "far_future_weight_default")]
+    pub far_future_weight: f64,
+    pub ensemble: Option<EnsembleConfig>,
+}
+
+/// Ensemble of simulations with perturbed inputs
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnsembleConfig {
+    #[serde(default = "members_default")]
+    pub members: usize,
+    /// Percentile (0-100) of the ensemble targets to use
+    #[serde(default = "percentile_default")]
+    pub percentile: f64,
+    /// Maximum time by which events may start early
+    #[serde(default = "perturbation_default", with = "humantime_serde")]
+    pub early: Duration,
+    /// Maximum time by which events may be extended
+    #[serde(default = "perturbation_default", with = "humantime_serde")]
+    pub extend: Duration,
+    /// Maximum fractional reduction in PV output
+    #[serde(default = "pv_spread_default")]
+    pub pv_spread: f64,
+}
+
+fn members_default() -> usize {
+    20
+}
+
+fn percentile_default() -> f64 {
+    90.0
+}
+
+fn perturbation_default() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+fn pv_spread_default() -> f64 {
+    0.3
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            horizon: horizon_default(),
+            step: step_default(),
+            far_future: far_future_default(),
+            far_future_weight: far_future_weight_default(),
+            ensemble: None,
+        }
+    }
+}
+
+fn horizon_default() -> Duration {
+    Duration::from_secs(48 * 60 * 60)
+}
+
+fn far_future_default() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn far_future_weight_default() -> f64 {
+    1.0
+}
+
+fn step_default() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// A time-of-use tariff period (local time)
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TariffPeriodConfig {
+    /// Description (e.g. "off-peak") for documentation purposes
+    #[serde(default)]
+    pub name: Option<String>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Price per kWh (in any currency)
+    pub price: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TariffConfig {
+    pub periods: Vec<TariffPeriodConfig>,
+    /// Periods on Saturdays, Sundays and holidays (default: same as `periods`)
+    #[serde(default)]
+    pub weekend: Option<Vec<TariffPeriodConfig>>,
+}
+
+impl TariffConfig {
+    /// The periods that apply on weekdays or on weekends
+    pub fn periods(&self, weekend: bool) -> &[TariffPeriodConfig] {
+        match &self.weekend {
+            Some(periods) if weekend => periods,
+            _ => &self.periods,
+        }
+    }
+
+    /// Price at a local time, if covered by any period
+    pub fn price(&self, time: NaiveDateTime, holidays: &Holidays) -> Option<f64> {
+        self.periods(holidays.is_weekend(time.date()))
+            .iter()
+            .find(|period| crate::planner::in_window(period.start, period.end, time.time()))
+            .map(|period| period.price)
+    }
+
+    /// The periods with the lowest price on weekdays or on weekends
+    pub fn cheapest(&self, weekend: bool) -> impl Iterator<Item = &TariffPeriodConfig> {
+        let periods = self.periods(weekend);
+        let min_price = periods
+            .iter()
+            .map(|period| period.price)
+            .fold(f64::INFINITY, f64::min);
+        periods
+            .iter()
+            .filter(move |period| period.price == min_price)
+    }
+}
+
+/// Cost of wear on the battery, to weigh against the grid tariff
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WearConfig {
+    /// Cost per kWh discharged from the battery (same currency as the tariff)
+    pub cost: f64,
+    /// Minimum saving per kWh over the grid price for cycling to be worthwhile
+    #[serde(default)]
+    pub margin: f64,
+}
+
+/// Whether a controller runs and whether it may change inverter settings
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControllerMode {
+    #[default]
+    Enabled,
+    /// Compute and report as usual, but do not change inverter settings
+    ObserveOnly,
+    Disabled,
+}
+
+/// Settings for a single controller
+///
+/// In the configuration file this may be given either as just a mode, or as
+/// a table with a mode and other settings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "ControllerSettingsRepr")]
+pub struct ControllerSettings {
+    pub mode: ControllerMode,
+    /// Time between updates, if overriding the controller's default
+    pub interval: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ControllerSettingsTable {
+    #[serde(default)]
+    mode: ControllerMode,
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ControllerSettingsRepr {
+    Mode(ControllerMode),
+    Table(ControllerSettingsTable),
+}
+
+impl From<ControllerSettingsRepr> for ControllerSettings {
+    fn from(repr: ControllerSettingsRepr) -> Self {
+        match repr {
+            ControllerSettingsRepr::Mode(mode) => Self {
+                mode,
+                interval: None,
+            },
+            ControllerSettingsRepr::Table(table) => Self {
+                mode: table.mode,
+                interval: table.interval,
+            },
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControllersConfig {
+    #[serde(default)]
+    pub soc: ControllerSettings,
+    #[serde(default)]
+    pub coil: ControllerSettings,
+    #[serde(default)]
+    pub pv: ControllerSettings,
+    #[serde(default)]
+    pub work_mode: ControllerSettings,
+    #[serde(default)]
+    pub clock_sync: ControllerSettings,
+    #[serde(default)]
+    pub peak_shaving: ControllerSettings,
+    #[serde(default)]
+    pub generator: ControllerSettings,
+}
+
+/// Start a generator through a relay when the battery runs low during an outage
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeneratorConfig {
+    pub relay: RelayConfig,
+    /// Start the generator when the SoC falls below this during an outage
+    pub start_soc: f64,
+    /// Stop the generator when the SoC reaches this (or the grid returns)
+    pub stop_soc: f64,
+    /// Minimum time for which the generator runs once started
+    #[serde(default = "generator_min_runtime_default", with = "humantime_serde")]
+    pub min_runtime: Duration,
+    /// Minimum time between stopping the generator and starting it again
+    #[serde(default = "generator_cooldown_default", with = "humantime_serde")]
+    pub cooldown: Duration,
+}
+
+fn generator_min_runtime_default() -> Duration {
+    Duration::from_secs(1800)
+}
+
+fn generator_cooldown_default() -> Duration {
+    Duration::from_secs(900)
+}
+
+/// Raise the battery discharge limit while grid import is high
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeakShavingConfig {
+    /// Grid import (W) above which the discharge limit is raised
+    pub threshold: f64,
+    /// Discharge current (A) to allow while shaving
+    pub current: f64,
+    /// Time that import must stay below the threshold before restoring the limit
+    #[serde(default = "peak_shaving_hold_default", with = "humantime_serde")]
+    pub hold: Duration,
+}
+
+fn peak_shaving_hold_default() -> Duration {
+    Duration::from_secs(300)
+}
+
+/// Keep the inverter clock in sync with the system clock
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClockSyncConfig {
+    /// Drift beyond which the clock is corrected
+    #[serde(default = "clock_sync_max_drift_default", with = "humantime_serde")]
+    pub max_drift: Duration,
+}
+
+fn clock_sync_max_drift_default() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Limit on how quickly the minimum SoC rises
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RampConfig {
+    /// Maximum increase in the target (percent per minute)
+    pub rate: f64,
+}
+
+/// Response when the SoC falls below the alarm SoC
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmergencyConfig {
+    /// Minimum SoC to set until the battery recovers
+    #[serde(default = "emergency_target_soc_default")]
+    pub target_soc: f64,
+    /// Work mode to switch to until the battery recovers
+    #[serde(default)]
+    pub work_mode: Option<WorkMode>,
+}
+
+fn emergency_target_soc_default() -> f64 {
+    100.0
+}
+
+/// Switch the work mode during load-shedding
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkModeConfig {
+    /// Work mode during load-shedding (the previous mode is restored afterwards)
+    #[serde(default = "work_mode_loadshedding_default")]
+    pub loadshedding: WorkMode,
+}
+
+fn work_mode_loadshedding_default() -> WorkMode {
+    WorkMode::LimitedToHome
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManualConfig {
+    /// File containing the manual override (auto, hold, charge or charge <SoC>)
+    pub path: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadProfileConfig {
+    /// File in which to persist the learned profile
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Number of samples in an hour slot before it is used
+    #[serde(default = "load_profile_min_samples_default")]
+    pub min_samples: u64,
+    /// Number of samples after which older samples are gradually forgotten
+    #[serde(default = "load_profile_max_samples_default")]
+    pub max_samples: u64,
+}
+
+fn load_profile_min_samples_default() -> u64 {
+    60
+}
+
+fn load_profile_max_samples_default() -> u64 {
+    600
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    /// Directory in which controller state is saved across restarts
+    pub directory: PathBuf,
+    /// Saved CT coil state older than this is discarded on startup
+    #[serde(default = "state_max_age_default", with = "humantime_serde")]
+    pub max_age: Duration,
+}
+
+fn state_max_age_default() -> Duration {
+    Duration::from_secs(600)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocFilterConfig {
+    #[serde(default = "soc_filter_enabled_default")]
+    pub enabled: bool,
+    /// Maximum plausible change in SoC (% per minute)
+    #[serde(default = "soc_filter_max_rate_default")]
+    pub max_rate: f64,
+    /// Number of consecutive readings to reject before accepting a large change
+    #[serde(default = "soc_filter_max_rejections_default")]
+    pub max_rejections: u32,
+    /// Maximum plausible relative change in battery capacity between readings
+    #[serde(default = "soc_filter_max_capacity_change_default")]
+    pub max_capacity_change: f64,
+}
+
+fn soc_filter_enabled_default() -> bool {
+    true
+}
+
+fn soc_filter_max_rate_default() -> f64 {
+    10.0
+}
+
+fn soc_filter_max_rejections_default() -> u32 {
+    5
+}
+
+fn soc_filter_max_capacity_change_default() -> f64 {
+    0.2
+}
+
+impl Default for SocFilterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: soc_filter_enabled_default(),
+            max_rate: soc_filter_max_rate_default(),
+            max_rejections: soc_filter_max_rejections_default(),
+            max_capacity_change: soc_filter_max_capacity_change_default(),
+        }
+    }
+}
+
+/// Comparison of the predicted change in SoC with the actual change
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccuracyConfig {
+    /// Period over which each comparison is made
+    #[serde(default = "accuracy_window_default", with = "humantime_serde")]
+    pub window: Duration,
+    /// Number of recent comparisons included in the statistics
+    #[serde(default = "accuracy_windows_default")]
+    pub windows: usize,
+    /// Number of comparisons needed before the load and PV scales are estimated
+    #[serde(default = "accuracy_min_windows_default")]
+    pub min_windows: usize,
+    /// Apply the estimated scales to the load and PV predictions
+    #[serde(default)]
+    pub auto_scale: bool,
+}
+
+fn accuracy_window_default() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn accuracy_windows_default() -> usize {
+    48
+}
+
+fn accuracy_min_windows_default() -> usize {
+    12
+}
+
+impl Default for AccuracyConfig {
+    fn default() -> Self {
+        Self {
+            window: accuracy_window_default(),
+            windows: accuracy_windows_default(),
+            min_windows: accuracy_min_windows_default(),
+            auto_scale: false,
+        }
+    }
+}
+
+/// Response to grid outages that are not in the load-shedding schedule
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutageConfig {
+    /// How long an unscheduled outage is assumed to continue, for planning
+    #[serde(default = "outage_assumed_duration_default", with = "humantime_serde")]
+    pub assumed_duration: Duration,
+}
+
+fn outage_assumed_duration_default() -> Duration {
+    Duration::from_secs(2 * 3600)
+}
+
+impl Default for OutageConfig {
+    fn default() -> Self {
+        Self {
+            assumed_duration: outage_assumed_duration_default(),
+        }
+    }
+}
+
+/// Smart relay controlling a load
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum RelayConfig {
+    /// Shelly relay, using the Gen1 HTTP API
+    Shelly {
+        url: String,
+        #[serde(default)]
+        channel: u32,
+    },
+    /// Relay running Tasmota firmware
+    Tasmota {
+        url: String,
+        #[serde(default = "tasmota_channel_default")]
+        channel: u32,
+    },
+}
+
+fn tasmota_channel_default() -> u32 {
+    1
+}
+
+/// Load (such as a geyser or pool pump) switched on when there is PV surplus
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlledLoadConfig {
+    pub name: String,
+    /// Power drawn while on (W)
+    pub power: f64,
+    pub relay: RelayConfig,
+    /// Keep the load off for this long before load-shedding
+    #[serde(
+        default = "controlled_load_block_before_default",
+        with = "humantime_serde"
+    )]
+    pub block_before: Duration,
+    /// Time for which the load is assumed to run when deciding whether to switch it on
+    #[serde(default = "controlled_load_run_for_default", with = "humantime_serde")]
+    pub run_for: Duration,
+    /// Minimum time between switching the load on and off
+    #[serde(
+        default = "controlled_load_min_switch_default",
+        with = "humantime_serde"
+    )]
+    pub min_switch: Duration,
+}
+
+fn controlled_load_block_before_default() -> Duration {
+    Duration::from_secs(7200)
+}
+
+fn controlled_load_run_for_default() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn controlled_load_min_switch_default() -> Duration {
+    Duration::from_secs(900)
+}
+
+/// How to talk to an EV charger
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum EvChargerKind {
+    /// OpenEVSE, using the RAPI HTTP interface
+    Openevse { url: String },
+    /// go-e Charger, using the HTTP API v2
+    GoE { url: String },
+}
+
+/// Throttle EV charging while the battery needs the grid to reach its target
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvChargerConfig {
+    pub charger: EvChargerKind,
+    /// Charge current (A) when not throttled
+    pub max_current: u32,
+    /// Charge current (A) while throttled (0 to pause)
+    #[serde(default)]
+    pub throttle_current: u32,
+    /// Predicted PV surplus (W) above which charging is never throttled
+    #[serde(default = "ev_charger_release_surplus_default")]
+    pub release_surplus: f64,
+    /// Minimum time between changes to the charge current
+    #[serde(
+        default = "controlled_load_min_switch_default",
+        with = "humantime_serde"
+    )]
+    pub min_switch: Duration,
+}
+
+fn ev_charger_release_surplus_default() -> f64 {
+    1500.0
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum AlertSinkConfig {
+    Webhook { url: String },
+    Telegram { bot_token: String, chat_id: String },
+    Pushover { token: String, user: String },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertsConfig {
+    /// Number of consecutive failed cycles before alerting about the inverter
+    #[serde(default = "inverter_failures_default")]
+    pub inverter_failures: u32,
+    #[serde(default)]
+    pub sinks: Vec<AlertSinkConfig>,
+}
+
+fn inverter_failures_default() -> u32 {
+    5
+}
+
+/// Limits on register writes to the inverter, across all controllers
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WriteBudgetConfig {
+    /// Maximum writes in any hour, for all writes
+    #[serde(default)]
+    pub max_writes_per_hour: Option<u64>,
+    /// Maximum writes in any 24 hours, beyond which non-urgent writes are deferred
+    #[serde(default)]
+    pub max_writes_per_day: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusConfig {
+    /// Path of the Unix domain socket on which to serve the status
+    pub socket: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address and port on which to listen
+    #[serde(default = "http_listen_default")]
+    pub listen: String,
+}
+
+fn http_listen_default() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// File to log to instead of standard error
+    pub file: PathBuf,
+    /// Rotate the file when it would grow beyond this size (bytes)
+    #[serde(default = "logging_max_size_default")]
+    pub max_size: u64,
+    /// Rotate the file when it is older than this
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+    /// Number of rotated files to keep
+    #[serde(default = "logging_keep_default")]
+    pub keep: usize,
+}
+
+fn logging_max_size_default() -> u64 {
+    10_000_000
+}
+
+fn logging_keep_default() -> usize {
+    5
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub inverter: InverterConfig,
+    pub bms: Option<BmsConfig>,
+    pub write_budget: Option<WriteBudgetConfig>,
     pub coil: Option<CoilConfig>,
-    pub esp: EspConfig,
+    #[serde(default)]
+    pub controllers: ControllersConfig,
+    pub discharge_limit: Option<DischargeLimitConfig>,
+    pub work_mode: Option<WorkModeConfig>,
+    pub clock_sync: Option<ClockSyncConfig>,
+    pub peak_shaving: Option<PeakShavingConfig>,
+    pub generator: Option<GeneratorConfig>,
+    pub emergency: Option<EmergencyConfig>,
+    pub ramp: Option<RampConfig>,
+    pub manual: Option<ManualConfig>,
+    #[serde(default)]
+    pub soc_filter: SocFilterConfig,
+    #[serde(default)]
+    pub accuracy: AccuracyConfig,
+    #[serde(default)]
+    pub outage: OutageConfig,
+    pub tariff: Option<TariffConfig>,
+    pub wear: Option<WearConfig>,
+    #[serde(default)]
+    pub simulation: SimulationConfig,
+    pub stage: Option<StageConfig>,
+    #[serde(default)]
+    pub forecast_solar: ForecastSolarConfig,
+    #[serde(default)]
+    pub open_meteo: OpenMeteoConfig,
+    pub load_profile: Option<LoadProfileConfig>,
+    pub load: Option<LoadConfig>,
+    pub holidays: Option<HolidaysConfig>,
+    pub state: Option<StateConfig>,
+    #[serde(default)]
+    pub controlled_load: Vec<ControlledLoadConfig>,
+    pub ev_charger: Option<EvChargerConfig>,
+    pub alerts: Option<AlertsConfig>,
+    pub status: Option<StatusConfig>,
+    pub http: Option<HttpConfig>,
+    pub logging: Option<LoggingConfig>,
+    /// Load-shedding schedule (if absent, only self-consumption is optimised)
+    pub esp: Option<EspConfig>,
+    #[serde(default)]
+    pub monitoring: MonitoringConfig,
+    pub influxdb1: Option<Influxdb1Config>,
     pub influxdb2: Option<Influxdb2Config>,
+    pub mqtt: Option<MqttConfig>,
+    pub file: Option<FileConfig>,
+    pub postgres: Option<PostgresConfig>,
 }
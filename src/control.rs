@@ -15,36 +15,195 @@
  */
 
 use async_trait::async_trait;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
 use futures::StreamExt;
 use log::{error, info, warn};
 use radians::Deg64;
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
-use std::collections::VecDeque;
-use std::sync::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::time::MissedTickBehavior;
 use tokio_stream::StreamMap;
 use tokio_util::sync::CancellationToken;
 
-use crate::config::{CoilConfig, Config, InverterConfig, PanelConfig};
-use crate::esp_api::{AreaResponse, API};
-use crate::inverter::{Info, Inverter, Result};
-use crate::monitoring::{CoilUpdate, Monitor, SocUpdate};
-use crate::sun::solar_fraction;
+use crate::accuracy::{AccuracyTracker, Prediction, Scales};
+use crate::alert::{AlertKind, Alerter};
+use crate::bms::Bms;
+use crate::config::{
+    ClockSyncConfig, CoilConfig, CoilPidConfig, Config, ControlledLoadConfig, ControllerMode,
+    ControllerSettings, ControllersConfig, DischargeLimitConfig, EmergencyConfig, EvChargerConfig,
+    ForecastSource, GeneratorConfig, InverterConfig, LoadConfig, LoadProfileConfig,
+    NonEssentialSource, OutageConfig, PanelConfig, PeakShavingConfig, RampConfig, SmartLoadConfig,
+    SocFilterConfig, StageConfig, StateConfig, TariffConfig, WearConfig, WorkMode, WorkModeConfig,
+};
+use crate::esp_api::{AreaResponse, Event, API};
+use crate::ev_charger::EvCharger;
+use crate::forecast_solar::ForecastSolar;
+use crate::holidays::Holidays;
+use crate::inverter::{
+    CurrentLimits, DryrunInverter, Info, Inverter, IoStats, Result, VerifyError,
+};
+use crate::load_profile::LoadLearner;
+use crate::manual::Override;
+use crate::monitoring::{
+    CoilUpdate, CycleEvent, CycleLog, HealthUpdate, Monitor, PvString, PvUpdate, SocUpdate,
+};
+use crate::open_meteo::OpenMeteo;
+use crate::persist::StateStore;
+use crate::planner::{
+    backup_runtime, calibrate_forecast, clip_power, derated_forecast, duration_hours,
+    expected_load, forecast_power, local_time, normalize_events, panels_power, pv_surplus,
+    pv_window, utc_time, Battery, Ensemble, LoadModel, LoadProfile, Plan, PvForecast, Simulation,
+    Simulator, Targets, Window,
+};
+use crate::relay::Relay;
+use crate::sun::{next_sunrise, next_sunset};
+use crate::systemd::Supervisor;
+use crate::timezone::Timezone;
 
 pub struct State {
     pub response: AreaResponse,
     pub time: DateTime<Utc>,
 }
 
+/// Outcomes of polling EskomSePush, for monitoring
+#[derive(Clone, Debug, Default)]
+pub struct EspStats {
+    pub successes: u64,
+    pub failures: u64,
+    pub last_success: Option<DateTime<Utc>>,
+    pub latency: Option<std::time::Duration>,
+    pub quota_remaining: Option<i64>,
+}
+
+/// Information shared between [poll_esp] and the controllers
+#[derive(Default)]
+pub struct EspStatus {
+    /// Latest successful response
+    pub state: Mutex<Option<State>>,
+    pub stats: Mutex<EspStats>,
+}
+
+/// Fetch the area information from EskomSePush once, returning whether it
+/// succeeded.
+pub async fn refresh_esp(api: &API, area_id: &str, esp: &EspStatus) -> bool {
+    let start = Instant::now();
+    let result = api.area(area_id).await;
+    let latency = start.elapsed();
+    let success = match result {
+        Ok(response) => {
+            let now = Utc::now();
+            let mut lock = esp.state.lock().unwrap();
+            *lock = Some(State {
+                response,
+                time: now,
+            });
+            drop(lock);
+            let mut lock = esp.stats.lock().unwrap();
+            lock.successes += 1;
+            lock.last_success = Some(now);
+            lock.latency = Some(latency);
+            drop(lock);
+            info!("Successfully updated area info from EskomSePush");
+            true
+        }
+        Err(err) => {
+            let mut lock = esp.stats.lock().unwrap();
+            lock.failures += 1;
+            lock.latency = Some(latency);
+            drop(lock);
+            warn!("Failed to update from EskomSePush: {err}");
+            false
+        }
+    };
+    match api.allowance().await {
+        Ok(response) => {
+            let allowance = response.allowance;
+            esp.stats.lock().unwrap().quota_remaining = Some(allowance.limit - allowance.count);
+        }
+        Err(err) => {
+            warn!("Failed to check EskomSePush API allowance: {err}");
+        }
+    }
+    success
+}
+
 pub async fn poll_esp(
     api: &API,
     area_id: &str,
     interval: std::time::Duration,
-    state: &Mutex<Option<State>>,
+    esp: &EspStatus,
+    token: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = token.cancelled() => { break; }
+        }
+        refresh_esp(api, area_id, esp).await;
+    }
+}
+
+/// Refresh the forecasts for panels that use forecast.solar once.
+///
+/// `forecasts` is indexed like `panels`. If a refresh fails, the previous
+/// forecast is kept.
+pub async fn refresh_forecasts(
+    api: &ForecastSolar,
+    panels: &[PanelConfig],
+    forecasts: &Mutex<Vec<Option<PvForecast>>>,
+) {
+    for (i, panel) in panels.iter().enumerate() {
+        if panel.forecast != ForecastSource::ForecastSolar {
+            continue;
+        }
+        let estimate = async {
+            let mut estimates = Vec::new();
+            for face in panel.all_faces() {
+                estimates.push(api.estimate(panel, &face).await?.forecast());
+            }
+            Ok::<_, reqwest::Error>(PvForecast::sum(&estimates))
+        };
+        match estimate.await {
+            Ok(forecast) => {
+                let mut lock = forecasts.lock().unwrap();
+                if lock.len() < panels.len() {
+                    lock.resize(panels.len(), None);
+                }
+                lock[i] = Some(calibrate_forecast(panel, forecast));
+                drop(lock);
+                info!("Successfully updated PV forecast {i} from forecast.solar");
+            }
+            Err(err) => {
+                warn!("Failed to update PV forecast {i} from forecast.solar: {err}");
+            }
+        }
+    }
+}
+
+/// Periodically refresh the forecasts for panels that use forecast.solar.
+///
+/// `forecasts` is indexed like `panels`. If a refresh fails, the previous
+/// forecast is kept until it runs out.
+pub async fn poll_forecasts(
+    api: &ForecastSolar,
+    panels: &[PanelConfig],
+    interval: std::time::Duration,
+    forecasts: &Mutex<Vec<Option<PvForecast>>>,
     token: CancellationToken,
 ) {
+    if !panels
+        .iter()
+        .any(|panel| panel.forecast == ForecastSource::ForecastSolar)
+    {
+        return;
+    }
     let mut interval = tokio::time::interval(interval);
     interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
@@ -52,179 +211,763 @@ pub async fn poll_esp(
             _ = interval.tick() => {},
             _ = token.cancelled() => { break; }
         }
-        match api.area(area_id).await {
+        refresh_forecasts(api, panels, forecasts).await;
+    }
+}
+
+/// Whether the clear-sky forecast of a panel is derated for temperature
+fn temperature_derated(panel: &PanelConfig) -> bool {
+    panel.forecast == ForecastSource::ClearSky && panel.temperature_coefficient.is_some()
+}
+
+/// Fetch ambient temperature forecasts from Open-Meteo once, and use them to
+/// derate the clear-sky forecast of panels with a temperature coefficient.
+/// `forecasts` is indexed like `panels`.
+pub async fn refresh_temperatures(
+    api: &OpenMeteo,
+    panels: &[PanelConfig],
+    forecasts: &Mutex<Vec<Option<PvForecast>>>,
+) {
+    for (i, panel) in panels.iter().enumerate() {
+        if !temperature_derated(panel) {
+            continue;
+        }
+        match api.forecast(panel.latitude, panel.longitude).await {
             Ok(response) => {
-                let mut lock = state.lock().unwrap();
-                *lock = Some(State {
-                    response,
-                    time: Utc::now(),
-                });
+                let forecast = derated_forecast(panel, &response.temperatures());
+                let mut lock = forecasts.lock().unwrap();
+                if lock.len() < panels.len() {
+                    lock.resize(panels.len(), None);
+                }
+                lock[i] = Some(forecast);
                 drop(lock);
-                info!("Successfully updated area info from EskomSePush");
+                info!("Successfully updated temperature forecast {i} from Open-Meteo");
             }
             Err(err) => {
-                warn!("Failed to update from EskomSePush: {err}");
+                warn!("Failed to update temperature forecast {i} from Open-Meteo: {err}");
             }
         }
     }
 }
 
+/// Periodically fetch ambient temperature forecasts from Open-Meteo (see
+/// [refresh_temperatures]).
+pub async fn poll_temperatures(
+    api: &OpenMeteo,
+    panels: &[PanelConfig],
+    interval: std::time::Duration,
+    forecasts: &Mutex<Vec<Option<PvForecast>>>,
+    token: CancellationToken,
+) {
+    if !panels.iter().any(temperature_derated) {
+        return;
+    }
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = token.cancelled() => { break; }
+        }
+        refresh_temperatures(api, panels, forecasts).await;
+    }
+}
+
 fn filter_state(state: &Option<State>, min_time: DateTime<Utc>) -> Option<&State> {
     state.as_ref().filter(|state| state.time >= min_time)
 }
 
-/// Number of (non-integer) hours in a duration
-fn duration_hours(duration: Duration) -> f64 {
-    (duration.num_milliseconds() as f64) / 3600000.0
+/// Inputs to the planner that are learnt or fetched at runtime
+struct PlanInputs {
+    profile: Option<LoadProfile>,
+    /// Days that follow the weekend load profile and tariff
+    holidays: Holidays,
+    /// Factors applied to the predicted load and PV
+    scales: Scales,
+    /// PV forecasts, indexed like the panels
+    forecasts: Vec<Option<PvForecast>>,
+    /// Manual override of the computed targets
+    manual: Override,
+    /// Grid outage in progress that is not in the load-shedding schedule
+    outage: Option<Event>,
 }
 
-fn panels_power(panels: &[PanelConfig], time: DateTime<Utc>) -> f64 {
-    let mut power = 0.0;
-    for panels in panels.iter() {
-        power += panels.power
-            * solar_fraction(
-                Deg64::new(panels.latitude),
-                Deg64::new(panels.longitude),
-                Deg64::new(90.0 - panels.tilt),
-                Deg64::new(panels.azimuth),
-                &time,
-            );
-    }
-    power
+/// Everything needed to run the planner
+struct PlanModel<'a> {
+    events: Vec<Event>,
+    simulator: Simulator,
+    stage: Option<&'a StageConfig>,
 }
 
-/// What to simulate when no load-shedding and not enough solar
-enum SimMode {
-    /// Power drains from battery
-    Drain,
-    /// Battery level held steady
-    Hold,
-    /// Charge battery as fast as possible
-    Charge,
+/// Set up the planner, or return `None` if the load-shedding state is unknown
+fn plan_model<'a>(
+    config: &'a Config,
+    state: Option<&State>,
+    info: &Info,
+    inputs: &PlanInputs,
+    extra_loads: &[SmartLoadConfig],
+    now: DateTime<Utc>,
+) -> Option<PlanModel<'a>> {
+    // Without EskomSePush, plan as if there is never any load-shedding
+    let state = match &config.esp {
+        Some(_) => state.map(Some),
+        None => Some(None),
+    };
+    match state {
+        None => None,
+        Some(state) => {
+            let mut raw_events = state.map_or_else(Vec::new, |state| state.response.events.clone());
+            raw_events.extend(inputs.outage.clone());
+            let events = normalize_events(&raw_events, now);
+            if events.len() != raw_events.len() {
+                info!(
+                    "Normalized {} load-shedding events to {} (dropping past or empty events and merging overlaps)",
+                    raw_events.len(),
+                    events.len()
+                );
+            }
+            for event in events.iter() {
+                info!(
+                    event_start = event.start.to_rfc3339().as_str(),
+                    event_end = event.end.to_rfc3339().as_str(),
+                    event_note = event.note.as_str();
+                    "Load-shedding from {} to {}",
+                    event.start,
+                    event.end
+                );
+            }
+            let mut simulation = simulation(config, state, now);
+            let max_stage = events.iter().filter_map(|event| event.stage()).max();
+            let stage_config = config
+                .stage
+                .as_ref()
+                .filter(|stage| max_stage.is_some_and(|x| x >= stage.threshold));
+            if let Some(stage) = stage_config {
+                info!(
+                    "Stage {} load-shedding: scaling PV by {} and adding {}% margin",
+                    max_stage.unwrap(),
+                    stage.pv_scale,
+                    stage.margin
+                );
+                simulation.pv_scale = stage.pv_scale;
+            }
+            simulation.pv_scale *= inputs.scales.pv;
+            let mut load = load_model(&config.inverter, info, inputs);
+            load.smart_load.extend_from_slice(extra_loads);
+            Some(PlanModel {
+                events,
+                simulator: Simulator {
+                    panels: config.inverter.panels.clone(),
+                    forecasts: inputs.forecasts.clone(),
+                    battery: battery(config, info),
+                    load,
+                    simulation,
+                },
+                stage: stage_config,
+            })
+        }
+    }
 }
 
-fn target_soc_helper(
-    config: &InverterConfig,
-    state: &State,
+fn target_socs(
+    config: &Config,
+    state: Option<&State>,
     info: &Info,
+    inputs: &PlanInputs,
+    extra_loads: &[SmartLoadConfig],
     now: DateTime<Utc>,
-    mode: SimMode,
-) -> (f64, DateTime<Utc>) {
-    let step = Duration::seconds(60);
-    let step_h = duration_hours(step);
-    let depth = info.capacity - config.min_soc * 0.01 * info.capacity;
-
-    let mut base_wh = 0.0;
-    let mut worst = 0.0_f64;
-    let mut floor = -depth;
-    let mut worst_time = now;
-    /* Project battery level forward for 24 hours, using optimistic
-     * assumptions about solar PV and consumption. Whenever the
-     * current point falls into load-shedding, check that there will
-     * be enough to get to the end with pessimistic assumptions.
-     */
-    let goal = now + Duration::seconds(86400);
-    let mut t = now;
-    let mut observe = |wh, t| {
-        if wh < worst {
-            worst = wh;
-            worst_time = t;
+) -> Targets {
+    let model = plan_model(config, state, info, inputs, extra_loads, now);
+    model_targets(config, model.as_ref(), now)
+}
+
+/// Targets from a planner set up by [plan_model], or the fallback if there is none
+fn model_targets(config: &Config, model: Option<&PlanModel>, now: DateTime<Utc>) -> Targets {
+    let Some(model) = model else {
+        let fallback_soc = config.inverter.fallback_soc_at(now);
+        return Targets {
+            target_soc_low: fallback_soc,
+            target_soc_high: fallback_soc,
+            alarm_soc: config.inverter.min_soc,
+        };
+    };
+    let mut targets = model.simulator.targets(&model.events, now);
+    if let Some(stage) = model.stage {
+        targets.target_soc_low = (targets.target_soc_low + stage.margin).min(100.0);
+        targets.target_soc_high = targets.target_soc_high.max(targets.target_soc_low);
+    }
+    targets
+}
+
+/// Compute the targets for a load-shedding response without talking to the
+/// inverter, using the configured load profile and the given PV forecasts
+/// (indexed like the panels, with clear-sky predictions for any missing).
+///
+/// The response is ignored if there is no `[esp]` section, and `None` is
+/// returned if there is one but no response is given.
+pub fn plan_offline(
+    config: &Config,
+    response: Option<AreaResponse>,
+    forecasts: Vec<Option<PvForecast>>,
+    capacity: f64,
+    now: DateTime<Utc>,
+) -> Option<Plan> {
+    let state = response.map(|response| State {
+        response,
+        time: now,
+    });
+    let info = Info {
+        capacity,
+        charge_power: config.inverter.charge_power.unwrap_or(0.0),
+        smart_load: !config.inverter.smart_load.is_empty(),
+    };
+    let inputs = PlanInputs {
+        profile: config.load.as_ref().map(configured_profile),
+        holidays: config
+            .holidays
+            .as_ref()
+            .map(Holidays::load)
+            .unwrap_or_default(),
+        scales: Scales::default(),
+        forecasts,
+        manual: Override::Auto,
+        outage: None,
+    };
+    let model = plan_model(config, state.as_ref(), &info, &inputs, &[], now)?;
+    let targets = target_socs(config, state.as_ref(), &info, &inputs, &[], now);
+    Some(Plan {
+        targets,
+        projection: model.simulator.project(&model.events, now),
+    })
+}
+
+/// Simulation parameters, with the horizon beyond the far-future threshold
+/// limited to the period covered by the load-shedding schedule and events.
+fn simulation(config: &Config, state: Option<&State>, now: DateTime<Utc>) -> Simulation {
+    let mut horizon = config.simulation.horizon.as_secs() as i64;
+    let far_future = config.simulation.far_future.as_secs() as i64;
+    let events_end =
+        state.and_then(|state| state.response.events.iter().map(|event| event.end).max());
+    let schedule_end = state
+        .and_then(|state| {
+            state
+                .response
+                .schedule
+                .days
+                .iter()
+                .filter_map(|day| day.date.succ_opt())
+                .max()
+        })
+        .and_then(|date| {
+            utc_time(
+                config.inverter.timezone.as_ref(),
+                date.and_hms_opt(0, 0, 0).unwrap(),
+            )
+        });
+    if let Some(end) = schedule_end.max(events_end) {
+        let available = (end - now).num_seconds().max(far_future);
+        if available < horizon {
+            info!(
+                "Limiting simulation horizon to {:.1} hours covered by the schedule",
+                available as f64 / 3600.0
+            );
+            horizon = available;
         }
+    }
+    Simulation {
+        horizon,
+        step: config.simulation.step.as_secs().max(1) as i64,
+        far_future,
+        far_future_weight: config.simulation.far_future_weight,
+        pv_scale: 1.0,
+        max_pv_power: config.inverter.max_pv_power,
+        ensemble: config
+            .simulation
+            .ensemble
+            .as_ref()
+            .map(|ensemble| Ensemble {
+                members: ensemble.members,
+                percentile: ensemble.percentile,
+                early: ensemble.early.as_secs() as i64,
+                extend: ensemble.extend.as_secs() as i64,
+                pv_spread: ensemble.pv_spread,
+            }),
+    }
+}
+
+/// Seconds until the next sunrise, and seconds of daylight remaining (zero at
+/// night), at the location of the first set of panels.
+fn sun_times(panels: &[PanelConfig], now: DateTime<Utc>) -> (Option<f64>, Option<f64>) {
+    let Some(panel) = panels.first() else {
+        return (None, None);
     };
-    while t < goal {
-        let mut have_grid = true;
-        for event in state.response.events.iter() {
-            if t >= event.start && t < event.end {
-                have_grid = false;
-                let end_wh = base_wh - config.max_discharge_power * duration_hours(event.end - t);
-                observe(end_wh.max(floor), t);
-            }
-        }
-        let mut power = panels_power(&config.panels, t + step / 2);
-        if let Some(charge_power) = config.charge_power {
-            power = power.min(charge_power);
-        }
-        power -= config.min_discharge_power;
-        if have_grid {
-            power = match mode {
-                SimMode::Drain => power,
-                SimMode::Hold => power.max(0.0),
-                SimMode::Charge => config.charge_power.unwrap_or(power),
-            };
+    let lat = Deg64::new(panel.latitude);
+    let lon = Deg64::new(panel.longitude);
+    let sunrise = next_sunrise(lat, lon, now);
+    let sunset = next_sunset(lat, lon, now);
+    let seconds = |t: DateTime<Utc>| (t - now).num_milliseconds() as f64 * 1e-3;
+    let daylight = match (sunrise, sunset) {
+        (Some(sunrise), Some(sunset)) if sunset > sunrise => Some(0.0),
+        (_, Some(sunset)) => Some(seconds(sunset)),
+        (Some(_), None) => Some(0.0),
+        (None, None) => None,
+    };
+    (sunrise.map(seconds), daylight)
+}
+
+fn battery(config: &Config, info: &Info) -> Battery {
+    let cheap_windows = |tariff: &TariffConfig, weekend| {
+        tariff
+            .cheapest(weekend)
+            .map(|period| Window {
+                start: period.start,
+                end: period.end,
+            })
+            .collect()
+    };
+    Battery {
+        capacity: info.capacity,
+        min_soc: config.inverter.min_soc,
+        charge_power: config.inverter.charge_power,
+        cheap_windows: config
+            .tariff
+            .as_ref()
+            .map_or_else(Vec::new, |tariff| cheap_windows(tariff, false)),
+        weekend_cheap_windows: config
+            .tariff
+            .as_ref()
+            .filter(|tariff| tariff.weekend.is_some())
+            .map(|tariff| cheap_windows(tariff, true)),
+        charge_efficiency: config.inverter.charge_efficiency,
+        discharge_efficiency: config.inverter.discharge_efficiency,
+        soc_floors: config.inverter.soc_floor.clone(),
+    }
+}
+
+fn load_model(config: &InverterConfig, info: &Info, inputs: &PlanInputs) -> LoadModel {
+    LoadModel {
+        min_discharge_power: config.min_discharge_power,
+        max_discharge_power: config.max_discharge_power,
+        // The smart load windows only apply if the port is in smart load mode
+        smart_load: if info.smart_load {
+            config.smart_load.clone()
+        } else {
+            Vec::new()
+        },
+        timezone: config.timezone.clone(),
+        profile: inputs.profile.clone(),
+        self_consumption: config.self_consumption,
+        holidays: inputs.holidays.clone(),
+        scale: inputs.scales.load,
+    }
+}
+
+/// Load profile from the configuration
+fn configured_profile(config: &LoadConfig) -> LoadProfile {
+    let hours = |day: Option<[f64; 24]>| {
+        day.or(config.hourly)
+            .map_or_else(|| vec![None; 24], |hours| hours.map(Some).to_vec())
+    };
+    LoadProfile {
+        weekday: hours(config.weekday),
+        weekend: hours(config.weekend),
+    }
+}
+
+/// Learns the load profile from inverter readings
+struct LoadLearning<'a> {
+    config: &'a LoadProfileConfig,
+    /// File in which the profile is persisted, if any
+    path: Option<PathBuf>,
+    learner: LoadLearner,
+    /// Number of samples since the profile was last saved
+    unsaved: u32,
+}
+
+impl<'a> LoadLearning<'a> {
+    /// Number of samples between saves
+    const SAVE_INTERVAL: u32 = 15;
+
+    fn new(config: &'a LoadProfileConfig, state: Option<&StateStore>) -> Self {
+        // Keep the profile with the rest of the state unless a path is given
+        let path = config
+            .path
+            .clone()
+            .or_else(|| state.map(|state| state.path("load_profile")));
+        let learner = match &path {
+            Some(path) => LoadLearner::load(path),
+            None => LoadLearner::default(),
+        };
+        Self {
+            config,
+            path,
+            learner,
+            unsaved: 0,
         }
-        base_wh += power * step_h;
-        t += step;
+    }
 
-        floor = floor.max(base_wh - depth);
-        observe(base_wh.max(floor), t);
+    fn add_sample(&mut self, time: NaiveDateTime, power: f64, holidays: &Holidays) {
+        self.learner
+            .add_sample(time, power, self.config.max_samples, holidays);
+        self.unsaved += 1;
+        if self.unsaved >= Self::SAVE_INTERVAL {
+            self.save();
+        }
     }
 
-    let extra = -worst / info.capacity * 100.0;
-    let target = config.min_soc + extra;
-    let target = target.clamp(0.0, 100.0);
-    (target, worst_time)
+    fn save(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(err) = self.learner.save(path) {
+                warn!("Failed to save load profile to {}: {err}", path.display());
+            }
+        }
+        self.unsaved = 0;
+    }
 }
 
-fn target_socs(
-    config: &InverterConfig,
-    state: Option<&State>,
-    info: &Info,
-    now: DateTime<Utc>,
-) -> (f64, f64, f64) {
-    match state {
-        None => (config.fallback_soc, config.fallback_soc, config.min_soc),
-        Some(state) => {
-            for event in state.response.events.iter() {
-                info!("Load-shedding from {} to {}", event.start, event.end);
+/// Rejects implausible SoC and capacity readings, substituting the last good one
+struct SocFilter<'a> {
+    config: &'a SocFilterConfig,
+    last_good: Option<(DateTime<Utc>, f64)>,
+    /// Number of consecutive rejected readings
+    rejections: u32,
+    /// Total number of rejected SoC readings
+    filtered: u64,
+    last_capacity: Option<f64>,
+    /// Number of consecutive rejected capacity readings
+    capacity_rejections: u32,
+}
+
+impl<'a> SocFilter<'a> {
+    fn new(config: &'a SocFilterConfig) -> Self {
+        Self {
+            config,
+            last_good: None,
+            rejections: 0,
+            filtered: 0,
+            last_capacity: None,
+            capacity_rejections: 0,
+        }
+    }
+
+    /// Returns the filtered capacity, or None if there is no good reading yet
+    fn filter_capacity(&mut self, capacity: f64) -> Option<f64> {
+        if !self.config.enabled {
+            return Some(capacity);
+        }
+        let valid = capacity.is_finite() && capacity > 0.0;
+        let plausible = valid
+            && self.last_capacity.is_none_or(|good| {
+                (capacity - good).abs() <= self.config.max_capacity_change * good
+            });
+        // The capacity can be reconfigured, so accept a persistent change
+        if plausible || (valid && self.capacity_rejections >= self.config.max_rejections) {
+            self.last_capacity = Some(capacity);
+            self.capacity_rejections = 0;
+            Some(capacity)
+        } else {
+            self.capacity_rejections += 1;
+            match self.last_capacity {
+                Some(good) => {
+                    warn!(
+                        "Ignoring implausible battery capacity reading {capacity} (using {good})"
+                    );
+                    Some(good)
+                }
+                None => {
+                    warn!("Ignoring implausible battery capacity reading {capacity}");
+                    None
+                }
+            }
+        }
+    }
+
+    /// Returns the filtered SoC, or None if there is no good reading yet
+    fn filter(&mut self, now: DateTime<Utc>, soc: f64) -> Option<f64> {
+        if !self.config.enabled {
+            return Some(soc);
+        }
+        let in_range = (0.0..=100.0).contains(&soc);
+        let plausible = in_range
+            && self.last_good.is_none_or(|(time, good)| {
+                // Always allow at least a minute's worth of change
+                let minutes = (duration_hours(now - time) * 60.0).max(1.0);
+                (soc - good).abs() <= self.config.max_rate * minutes
+            });
+        // If the reading persists, assume that it is real
+        if plausible || (in_range && self.rejections >= self.config.max_rejections) {
+            self.last_good = Some((now, soc));
+            self.rejections = 0;
+            Some(soc)
+        } else {
+            self.rejections += 1;
+            self.filtered += 1;
+            match self.last_good {
+                Some((_, good)) => {
+                    warn!("Ignoring implausible SoC reading {soc} (using {good})");
+                    Some(good)
+                }
+                None => {
+                    warn!("Ignoring implausible SoC reading {soc}");
+                    None
+                }
             }
-            let (target_high, _) = target_soc_helper(config, state, info, now, SimMode::Drain);
-            let (target_low, _) = target_soc_helper(config, state, info, now, SimMode::Hold);
-            let (alarm, _) = target_soc_helper(config, state, info, now, SimMode::Charge);
-            (target_low, target_high, alarm)
         }
     }
 }
 
 async fn update_soc(
     inverter: &mut dyn Inverter,
-    config: &InverterConfig,
-    monitor: &mut dyn Monitor,
-    state: &Mutex<Option<State>>,
-    esp_timeout: Duration,
-) -> Result<()> {
+    controller: &mut SocController<'_>,
+    inputs: &mut PlanInputs,
+) -> Result<SocUpdate> {
+    let config = controller.config;
+    let esp = controller.esp;
+    let esp_timeout = controller.esp_timeout;
+    let soc_filter = &mut controller.soc_filter;
+    let accuracy = &mut controller.accuracy;
+    let outage = &mut controller.outage;
+    let mut emergency = controller.emergency.as_mut();
+    let ramp = controller.ramp.as_mut();
+    let loads = &mut controller.loads;
+    let ev_charger = &mut controller.ev_charger;
     let now = Utc::now();
+    let timezone = config.inverter.timezone.as_ref();
+    // Controlled loads that are on take power that would otherwise charge the battery
+    let running_loads: Vec<SmartLoadConfig> = loads
+        .iter()
+        .filter(|load| load.on == Some(true))
+        .map(|load| load.window(timezone, now))
+        .collect();
     let info = inverter.get_info().await?;
-    let current_soc = inverter.get_soc().await?;
-    let target;
-    let update;
+    // The BMS is optional, so fall back to the inverter if it can't be read
+    let bms = match controller.bms {
+        Some(bms) => bms.read().await.map_or_else(
+            |err| {
+                warn!("Failed to read the BMS (using the inverter's readings): {err}");
+                None
+            },
+            Some,
+        ),
+        None => None,
+    };
+    let capacity = bms
+        .as_ref()
+        .and_then(|bms| bms.capacity)
+        .unwrap_or(info.capacity);
+    let info = Info {
+        capacity: soc_filter
+            .filter_capacity(capacity)
+            .ok_or_else(|| format!("battery capacity reading {capacity} is invalid"))?,
+        ..info
+    };
+    let raw_soc = match &bms {
+        Some(bms) => bms.soc,
+        None => inverter.get_soc().await?,
+    };
+    let current_soc = soc_filter
+        .filter(now, raw_soc)
+        .ok_or_else(|| format!("SoC reading {raw_soc} is out of range"))?;
+    let aux_power = inverter.get_aux_power().await?;
+    // Telemetry is only informational, so don't fail the cycle over it
+    let telemetry = inverter.get_telemetry().await.unwrap_or_else(|err| {
+        warn!("Failed to read inverter telemetry: {err}");
+        None
+    });
+    let telemetry = telemetry.unwrap_or_default();
+    if let Some(faults) = &telemetry.faults {
+        if controller.faults.as_ref() != Some(faults) {
+            if !faults.is_empty() {
+                warn!("Inverter reports faults: {}", faults.join(", "));
+            } else if controller.faults.is_some() {
+                info!("Inverter faults cleared");
+            }
+            controller.faults = Some(faults.clone());
+        }
+    }
+    let clock_skew = match inverter.get_clock().await {
+        Ok(clock) => clock.map(|clock| {
+            let skew = clock - local_time(config.inverter.timezone.as_ref(), Utc::now());
+            duration_hours(skew) * 3600.0
+        }),
+        Err(err) => {
+            warn!("Failed to read inverter clock: {err}");
+            None
+        }
+    };
+    if let Some(skew) = clock_skew {
+        if skew.abs() > config.inverter.max_clock_skew.as_secs_f64() {
+            error!("Inverter clock differs from system time by {skew:.0} s");
+        }
+    }
+    let stats = esp.stats.lock().unwrap().clone();
+    let pv_window_threshold = config
+        .inverter
+        .pv_window_threshold
+        .unwrap_or_else(|| 0.1 * config.inverter.panels.iter().map(|p| p.power).sum::<f64>());
+    let pv_window = pv_window(
+        &config.inverter.panels,
+        config.inverter.timezone.as_ref(),
+        now,
+        pv_window_threshold,
+    );
+    let (time_to_sunrise, daylight_remaining) = sun_times(&config.inverter.panels, now);
+    let mut target;
+    let mut update;
+    let mut load_decisions = Vec::with_capacity(loads.len());
+    let ev_throttle;
 
     {
-        let guard = &state.lock().unwrap();
+        let guard = &esp.state.lock().unwrap();
         let state = filter_state(guard, now - esp_timeout);
+        let scheduled = state.is_some_and(|state| {
+            state
+                .response
+                .events
+                .iter()
+                .any(|event| now >= event.start && now < event.end)
+        });
+        inputs.outage = outage.update(now, telemetry.grid_connected, scheduled);
         let est_start = Instant::now();
-        let (target_soc_low, target_soc_high, alarm_soc) = target_socs(config, state, &info, now);
+        let mut model = plan_model(config, state, &info, inputs, &running_loads, now);
+        let Targets {
+            target_soc_low,
+            target_soc_high,
+            alarm_soc,
+        } = model_targets(config, model.as_ref(), now);
+        let energy = |soc: f64| soc * 0.01 * info.capacity;
         info!(
+            soc = current_soc,
+            target_soc_low,
+            target_soc_high,
+            alarm_soc;
             "Target SoC range is {:.2} - {:.2} (alarm at {:.2}), computed in {:.3} s",
             target_soc_low,
             target_soc_high,
             alarm_soc,
             est_start.elapsed().as_secs_f64()
         );
-        target = current_soc.min(target_soc_high).max(target_soc_low);
+        info!(
+            "Target energy range is {:.2} - {:.2} kWh (alarm at {:.2} kWh), currently {:.2} kWh",
+            energy(target_soc_low) * 1e-3,
+            energy(target_soc_high) * 1e-3,
+            energy(alarm_soc) * 1e-3,
+            energy(current_soc) * 1e-3,
+        );
+        // Without a load reading, assume the pessimistic load-shedding load
+        let drain = telemetry
+            .load_power
+            .unwrap_or(config.inverter.max_discharge_power)
+            + config.inverter.self_consumption;
+        let runtime = backup_runtime(&battery(config, &info), current_soc, drain);
+        if let Some(runtime) = runtime {
+            info!("Estimated backup runtime is {runtime:.1} h at {drain:.0} W");
+        }
+        if cycling_marginal(config, &inputs.holidays, now) {
+            // Let the grid carry the load rather than cycling the battery
+            info!("Holding battery at current SoC, since cycling is not worth the wear");
+            target = current_soc.max(target_soc_low);
+        } else {
+            target = current_soc.min(target_soc_high).max(target_soc_low);
+        }
+        if let Some(ramp) = ramp {
+            let limited = ramp.limit(now, current_soc, target);
+            if limited < target {
+                info!("Ramping minimum SoC towards {target:.2}: {limited:.2} for now");
+            }
+            target = limited;
+        }
+        if let Some(emergency) = emergency.as_deref_mut() {
+            target = emergency.target(current_soc, target_soc_low, alarm_soc, target);
+        }
+        let manual_soc = inputs.manual.target(current_soc);
+        if let Some(soc) = manual_soc {
+            info!(
+                "Manual override ({}): setting minimum SoC to {soc:.0}",
+                inputs.manual
+            );
+            target = soc;
+        }
 
         let mut is_loadshedding = false;
         let mut next_change = None;
+        let mut next_outage = None;
         if let Some(state) = state {
-            for event in state.response.events.iter() {
+            for event in normalize_events(&state.response.events, now).iter() {
                 if now >= event.start && now < event.end {
                     is_loadshedding = true;
                     next_change = Some(event.end);
+                    next_outage = Some(event.start);
                     break;
                 } else if now < event.start {
                     next_change = Some(next_change.map_or(event.start, |t| min(t, event.start)));
+                    next_outage = next_change;
+                }
+            }
+        }
+
+        let load = load_model(&config.inverter, &info, inputs);
+        let predicted_pv = clip_power(
+            forecast_power(&config.inverter.panels, &inputs.forecasts, now),
+            config.inverter.max_pv_power,
+        );
+        let mut conditions = LoadConditions {
+            now,
+            current_soc,
+            next_outage,
+            surplus: pv_surplus(predicted_pv * inputs.scales.pv, &load, now),
+        };
+        // Compare with the unscaled predictions, including any controlled
+        // loads that are running
+        let mut unscaled = LoadModel { scale: 1.0, ..load };
+        unscaled.smart_load.extend_from_slice(&running_loads);
+        accuracy.add(
+            now,
+            current_soc,
+            target,
+            info.capacity,
+            Prediction {
+                pv: predicted_pv,
+                load: expected_load(&unscaled, now),
+            },
+        );
+        let accuracy = accuracy.stats();
+        if let Some(stats) = &accuracy {
+            info!(
+                "Actual battery power differs from predicted by {:.0} W on average \
+                 (mean absolute difference {:.0} W)",
+                stats.bias, stats.error
+            );
+        }
+        // Reuse the planner for the loads, leaving out those already running
+        // (since they are decided afresh) and adding each load that is to
+        // run. Loads earlier in the list have first claim on the surplus.
+        if let Some(model) = &mut model {
+            let smart_load = &mut model.simulator.load.smart_load;
+            smart_load.truncate(smart_load.len() - running_loads.len());
+        }
+        for load in loads.iter() {
+            let window = load.window(timezone, now);
+            let decision = load.decide(&conditions, &window, |window| {
+                let Some(model) = &mut model else {
+                    return model_targets(config, None, now);
+                };
+                model.simulator.load.smart_load.push(window.clone());
+                let targets = model_targets(config, Some(model), now);
+                model.simulator.load.smart_load.pop();
+                targets
+            });
+            if decision == LoadDecision::Run {
+                conditions.surplus -= load.config.power;
+                if let Some(model) = &mut model {
+                    model.simulator.load.smart_load.push(window);
                 }
             }
+            load_decisions.push(decision);
         }
+        ev_throttle = ev_charger
+            .as_ref()
+            .map(|ev_charger| ev_charger.should_throttle(&conditions, target_soc_low));
 
         update = SocUpdate {
             time: now,
@@ -232,196 +975,2687 @@ async fn update_soc(
             target_soc_high,
             alarm_soc,
             current_soc,
-            predicted_pv: panels_power(&config.panels, now),
+            capacity: info.capacity,
+            target_energy_low: energy(target_soc_low),
+            target_energy_high: energy(target_soc_high),
+            alarm_energy: energy(alarm_soc),
+            current_energy: energy(current_soc),
+            energy_deficit: energy((target_soc_low - current_soc).max(0.0)),
+            backup_runtime: runtime,
+            predicted_pv,
+            pv_window_start: pv_window.map(|(start, _)| start),
+            pv_window_end: pv_window.map(|(_, end)| end),
+            time_to_sunrise,
+            daylight_remaining,
             is_loadshedding,
             next_change,
+            smart_load: info.smart_load,
+            aux_power,
+            battery_power: telemetry.battery_power,
+            battery_voltage: telemetry.battery_voltage,
+            battery_current: telemetry.battery_current,
+            battery_temperature: telemetry.battery_temperature,
+            grid_connected: telemetry.grid_connected,
+            load_power: telemetry.load_power,
+            soc_filtered: soc_filter.filtered,
+            esp_successes: stats.successes,
+            esp_failures: stats.failures,
+            esp_age: stats
+                .last_success
+                .map(|t| (now - t).num_milliseconds() as f64 * 1e-3),
+            esp_latency: stats.latency.map(|x| x.as_secs_f64()),
+            esp_quota_remaining: stats.quota_remaining,
+            wear_cost: None,
+            grid_cost: None,
+            emergency: emergency.is_some_and(|emergency| emergency.active),
+            unscheduled_outage: inputs.outage.is_some(),
+            manual_soc,
+            inverter_writes: None,
+            write_budget_used: None,
+            clock_skew,
+            active_faults: telemetry.faults.clone(),
+            cell_temperature_min: bms.as_ref().and_then(|bms| bms.min_temperature()),
+            cell_temperature_max: bms.as_ref().and_then(|bms| bms.max_temperature()),
+            dry_run_writes: None,
+            prediction_bias: accuracy.map(|stats| stats.bias),
+            prediction_error: accuracy.map(|stats| stats.error),
+            load_scale: accuracy
+                .and_then(|stats| stats.scales)
+                .map(|scales| scales.load),
+            pv_scale: accuracy
+                .and_then(|stats| stats.scales)
+                .map(|scales| scales.pv),
         };
     }
 
-    inverter.set_min_soc(target, config.fallback_soc).await?;
-    if let Err(err) = monitor.soc_update(update).await {
-        warn!("Failed to update monitoring: {err}");
+    let saved = SavedTarget {
+        target,
+        fallback: config.inverter.fallback_soc_at(now),
+    };
+    // Programs hold whole percentages, so smaller changes need no rewrite
+    let deadband = config.inverter.deadband.max(0.5);
+    controller.restored = controller.restored.filter(|(time, restored)| {
+        now - *time < SocController::PROGRAM_HOLD
+            && restored.fallback == saved.fallback
+            && (restored.target - saved.target).abs() < deadband
+    });
+    if let Some((_, restored)) = &controller.restored {
+        info!(
+            "Keeping programs with minimum SoC {} written before the restart",
+            restored.target
+        );
+    } else {
+        inverter.set_min_soc(target, saved.fallback).await?;
+    }
+    update.inverter_writes = inverter.write_count();
+    update.write_budget_used = inverter.budget_used();
+    update.dry_run_writes = inverter.take_intended_writes();
+    // Only record targets that were really written
+    if let (Some(state), None, None) = (
+        &controller.state,
+        &controller.restored,
+        &update.dry_run_writes,
+    ) {
+        state.save(SocController::STATE_NAME, now, saved);
+    }
+    let mut intended = Vec::new();
+    for (load, decision) in loads.iter_mut().zip(load_decisions) {
+        intended.extend(load.update(now, decision).await);
+    }
+    if let (Some(ev_charger), Some(throttle)) = (ev_charger, ev_throttle) {
+        intended.extend(ev_charger.update(now, throttle).await);
+    }
+    if !intended.is_empty() {
+        update
+            .dry_run_writes
+            .get_or_insert_with(Vec::new)
+            .extend(intended);
     }
 
-    Ok(())
+    Ok(update)
 }
 
-#[async_trait]
-trait Controller: Send + Unpin {
-    fn interval(&self) -> std::time::Duration;
-    async fn update(&mut self, inverter: &mut dyn Inverter, monitor: &mut dyn Monitor);
-    async fn shutdown(&mut self, inverter: &mut dyn Inverter);
+/// Whether the grid price is too low to be worth the wear of discharging
+fn cycling_marginal(config: &Config, holidays: &Holidays, now: DateTime<Utc>) -> bool {
+    let (Some(wear), Some(tariff)) = (&config.wear, &config.tariff) else {
+        return false;
+    };
+    let local = local_time(config.inverter.timezone.as_ref(), now);
+    tariff
+        .price(local, holidays)
+        .is_some_and(|price| price - wear.cost < wear.margin)
 }
 
-struct SocController<'a> {
-    config: &'a InverterConfig,
-    state: &'a Mutex<Option<State>>,
-    esp_timeout: Duration,
+/// Estimates how much was spent today on battery wear and on grid imports
+struct CostTracker<'a> {
+    wear: &'a WearConfig,
+    tariff: Option<&'a TariffConfig>,
+    timezone: Option<&'a Timezone>,
+    holidays: Holidays,
+    day: Option<NaiveDate>,
+    /// Time, battery discharge power (W) and grid import power (W) of last sample
+    last: Option<(DateTime<Utc>, f64, Option<f64>)>,
+    wear_cost: f64,
+    grid_cost: f64,
 }
 
-impl<'a> SocController<'a> {
-    fn new(
-        config: &'a InverterConfig,
-        state: &'a Mutex<Option<State>>,
-        esp_timeout: Duration,
-    ) -> Self {
+impl<'a> CostTracker<'a> {
+    /// Samples further apart than this are not interpolated between
+    const MAX_GAP: Duration = Duration::minutes(10);
+
+    fn new(config: &'a Config, wear: &'a WearConfig, holidays: Holidays) -> Self {
         Self {
-            config,
-            state,
-            esp_timeout,
+            wear,
+            tariff: config.tariff.as_ref(),
+            timezone: config.inverter.timezone.as_ref(),
+            holidays,
+            day: None,
+            last: None,
+            wear_cost: 0.0,
+            grid_cost: 0.0,
+        }
+    }
+
+    /// Add a sample, returning the wear and grid costs so far today
+    fn add(
+        &mut self,
+        time: DateTime<Utc>,
+        battery_power: f64,
+        grid_power: Option<f64>,
+    ) -> (f64, Option<f64>) {
+        let day = local_time(self.timezone, time).date();
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.wear_cost = 0.0;
+            self.grid_cost = 0.0;
+        }
+        if let Some((last_time, last_battery, last_grid)) = self.last {
+            if time > last_time && time - last_time <= Self::MAX_GAP {
+                let hours = duration_hours(time - last_time);
+                // Only discharging counts, so that each kWh cycled is charged once
+                self.wear_cost += last_battery.max(0.0) * 1e-3 * hours * self.wear.cost;
+                let local = local_time(self.timezone, last_time);
+                if let (Some(grid), Some(price)) = (
+                    last_grid,
+                    self.tariff.and_then(|t| t.price(local, &self.holidays)),
+                ) {
+                    self.grid_cost += grid.max(0.0) * 1e-3 * hours * price;
+                }
+            }
         }
+        self.last = Some((time, battery_power, grid_power));
+        (self.wear_cost, self.tariff.map(|_| self.grid_cost))
     }
 }
 
+/// Controllers that temporarily override an inverter setting.
+///
+/// When several controllers override the same setting, the one that comes
+/// later in this list takes precedence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum OverrideOwner {
+    PeakShaving,
+    LoadShedding,
+    DischargeLimit,
+    Emergency,
+}
+
+/// An inverter setting that controllers can override
 #[async_trait]
-impl Controller for SocController<'_> {
-    fn interval(&self) -> std::time::Duration {
-        std::time::Duration::from_secs(60)
-    }
+trait Setting: Copy + PartialEq + Debug + Send + Sync {
+    /// Description used in logs
+    const NAME: &'static str;
 
-    async fn update(&mut self, inverter: &mut dyn Inverter, monitor: &mut dyn Monitor) {
-        if let Err(err) =
-            update_soc(inverter, self.config, monitor, self.state, self.esp_timeout).await
-        {
-            warn!("Failed to update inverter: {err}");
-        }
+    async fn read(inverter: &mut dyn Inverter) -> Result<Self>;
+    async fn write(&self, inverter: &mut dyn Inverter) -> Result<()>;
+}
+
+#[async_trait]
+impl Setting for WorkMode {
+    const NAME: &'static str = "work mode";
+
+    async fn read(inverter: &mut dyn Inverter) -> Result<Self> {
+        Ok(inverter
+            .get_work_mode()
+            .await?
+            .ok_or("current work mode is not recognised")?)
     }
 
-    async fn shutdown(&mut self, inverter: &mut dyn Inverter) {
-        info!(
-            "Shutting down, setting minimum SoC to {}",
-            self.config.fallback_soc
-        );
-        match inverter
-            .set_min_soc(self.config.fallback_soc, self.config.fallback_soc)
-            .await
-        {
-            Ok(_) => {}
-            Err(err) => {
-                error!("Failed to set minimum SoC: {err}");
-            }
-        }
+    async fn write(&self, inverter: &mut dyn Inverter) -> Result<()> {
+        inverter.set_work_mode(*self).await
     }
 }
 
-struct CoilController<'a> {
-    history: VecDeque<Option<f64>>,
-    config: &'a CoilConfig,
-    last_setting: Option<f64>,
+#[async_trait]
+impl Setting for CurrentLimits {
+    const NAME: &'static str = "battery current limits";
+
+    async fn read(inverter: &mut dyn Inverter) -> Result<Self> {
+        inverter.get_current_limits().await
+    }
+
+    async fn write(&self, inverter: &mut dyn Inverter) -> Result<()> {
+        inverter.set_current_limits(self).await
+    }
 }
 
-impl<'a> CoilController<'a> {
-    const CAPACITY: usize = 11;
+/// Overrides of one inverter setting, so that the setting has a single owner.
+///
+/// The value found before the first override is saved, and restored once the
+/// last override is released. In between, the override with the highest
+/// [OverrideOwner] is applied.
+struct SettingOverrides<T> {
+    /// Value in effect before the first override
+    original: Option<T>,
+    /// Value last written (None if unknown, for example after a failed write)
+    applied: Option<T>,
+    active: BTreeMap<OverrideOwner, T>,
+}
 
-    fn new(config: &'a CoilConfig) -> Self {
+impl<T> Default for SettingOverrides<T> {
+    fn default() -> Self {
         Self {
-            history: VecDeque::with_capacity(Self::CAPACITY),
-            config,
-            last_setting: None,
+            original: None,
+            applied: None,
+            active: BTreeMap::new(),
         }
     }
+}
 
-    async fn update_fallible(
+impl<T: Setting> SettingOverrides<T> {
+    fn is_active(&self, owner: OverrideOwner) -> bool {
+        self.active.contains_key(&owner)
+    }
+
+    /// Override the setting on behalf of `owner`, unless it already does.
+    ///
+    /// The overriding value is computed from the original value.
+    async fn acquire(
         &mut self,
         inverter: &mut dyn Inverter,
-        monitor: &mut dyn Monitor,
+        owner: OverrideOwner,
+        value: impl FnOnce(T) -> T + Send,
     ) -> Result<()> {
-        let info = inverter.get_coil().await?;
-        let mut target = None;
+        if !self.is_active(owner) {
+            let original = match self.original {
+                Some(original) => original,
+                None => {
+                    let original = T::read(inverter).await?;
+                    self.original = Some(original);
+                    self.applied = Some(original);
+                    original
+                }
+            };
+            self.active.insert(owner, value(original));
+        }
+        self.apply(inverter).await
+    }
+
+    /// Remove the override by `owner`, if any
+    async fn release(&mut self, inverter: &mut dyn Inverter, owner: OverrideOwner) -> Result<()> {
+        if self.active.remove(&owner).is_some() {
+            match (self.active.last_key_value(), self.original) {
+                (Some((next, value)), _) => {
+                    info!("Setting {} to {value:?} for {next:?}", T::NAME);
+                }
+                (None, Some(original)) => {
+                    info!("Restoring {} {original:?}", T::NAME);
+                }
+                (None, None) => {}
+            }
+        }
+        self.apply(inverter).await
+    }
+
+    /// Write the winning override, or the original value if there are none
+    async fn apply(&mut self, inverter: &mut dyn Inverter) -> Result<()> {
+        let Some(original) = self.original else {
+            return Ok(());
+        };
+        let target = self
+            .active
+            .last_key_value()
+            .map_or(original, |(_, &value)| value);
+        if self.applied != Some(target) {
+            self.applied = None;
+            target.write(inverter).await?;
+            self.applied = Some(target);
+        }
+        if self.active.is_empty() {
+            self.original = None;
+            self.applied = None;
+        }
+        Ok(())
+    }
+}
+
+type SharedOverrides<T> = Arc<tokio::sync::Mutex<SettingOverrides<T>>>;
+
+/// Inverter settings that more than one controller may override
+#[derive(Clone, Default)]
+struct Overrides {
+    work_mode: SharedOverrides<WorkMode>,
+    current_limits: SharedOverrides<CurrentLimits>,
+}
+
+/// Lowers the battery discharge current when the SoC approaches the alarm SoC
+struct DischargeLimiter<'a> {
+    config: &'a DischargeLimitConfig,
+    limits: SharedOverrides<CurrentLimits>,
+}
+
+impl<'a> DischargeLimiter<'a> {
+    /// Amount (%) by which SoC must recover before limiting is removed
+    const HYSTERESIS: f64 = 2.0;
+
+    fn new(config: &'a DischargeLimitConfig, limits: SharedOverrides<CurrentLimits>) -> Self {
+        Self { config, limits }
+    }
+
+    async fn update(&mut self, inverter: &mut dyn Inverter, update: &SocUpdate) -> Result<()> {
+        let threshold = update.alarm_soc + self.config.margin;
+        let mut limits = self.limits.lock().await;
+        let owner = OverrideOwner::DischargeLimit;
+        if limits.is_active(owner) || update.current_soc < threshold {
+            if update.current_soc >= threshold + Self::HYSTERESIS {
+                return limits.release(inverter, owner).await;
+            }
+            limits
+                .acquire(inverter, owner, |original| {
+                    let limited = CurrentLimits {
+                        discharge: original.discharge.min(self.config.current),
+                        ..original
+                    };
+                    warn!(
+                        "SoC {:.0} is close to alarm SoC {:.2}, limiting discharge current to {} A",
+                        update.current_soc, update.alarm_soc, limited.discharge
+                    );
+                    limited
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn restore(&mut self, inverter: &mut dyn Inverter) -> Result<()> {
+        let mut limits = self.limits.lock().await;
+        limits
+            .release(inverter, OverrideOwner::DischargeLimit)
+            .await
+    }
+}
+
+/// Limits how quickly the minimum SoC rises, to avoid sudden grid charging
+struct TargetRamp<'a> {
+    config: &'a RampConfig,
+    /// Previous (limited) target and when it was computed
+    last: Option<(DateTime<Utc>, f64)>,
+}
+
+impl<'a> TargetRamp<'a> {
+    fn new(config: &'a RampConfig) -> Self {
+        Self { config, last: None }
+    }
+
+    /// Limit the target based on the previous one (or the current SoC)
+    fn limit(&mut self, now: DateTime<Utc>, current_soc: f64, target: f64) -> f64 {
+        let (time, last) = self.last.unwrap_or((now, current_soc));
+        let minutes = duration_hours(now - time).max(0.0) * 60.0;
+        let limited = target.min(last.max(current_soc) + self.config.rate * minutes);
+        self.last = Some((now, limited));
+        limited
+    }
+}
+
+/// Tracks grid outages reported by the inverter, so that outages missing
+/// from the load-shedding schedule can be planned for
+struct OutageDetector<'a> {
+    config: &'a OutageConfig,
+    /// Start of the outage in progress, if any
+    start: Option<DateTime<Utc>>,
+    /// Whether the outage in progress is outside the schedule
+    unscheduled: bool,
+}
+
+impl<'a> OutageDetector<'a> {
+    fn new(config: &'a OutageConfig) -> Self {
+        Self {
+            config,
+            start: None,
+            unscheduled: false,
+        }
+    }
+
+    /// Update with the inverter's view of the grid (`None` if unknown) and
+    /// whether load-shedding is scheduled now. If an unscheduled outage is
+    /// in progress, returns an event covering it up to the assumed end.
+    fn update(
+        &mut self,
+        now: DateTime<Utc>,
+        grid_connected: Option<bool>,
+        scheduled: bool,
+    ) -> Option<Event> {
+        match (grid_connected, self.start) {
+            (Some(false), None) => {
+                if scheduled {
+                    info!("Grid is down, as scheduled");
+                } else {
+                    warn!("Grid is down outside the load-shedding schedule");
+                }
+                self.start = Some(now);
+                self.unscheduled = !scheduled;
+            }
+            (Some(false), Some(_)) if !scheduled && !self.unscheduled => {
+                warn!("Grid is still down after scheduled load-shedding ended");
+                self.unscheduled = true;
+            }
+            (Some(true), Some(start)) => {
+                info!(
+                    "Grid restored after {:.2} h ({})",
+                    duration_hours(now - start),
+                    if self.unscheduled {
+                        "unscheduled"
+                    } else {
+                        "scheduled"
+                    }
+                );
+                self.start = None;
+                self.unscheduled = false;
+            }
+            _ => {}
+        }
+        let start = self.start.filter(|_| self.unscheduled && !scheduled)?;
+        let assumed = Duration::seconds(self.config.assumed_duration.as_secs() as i64);
+        Some(Event {
+            start,
+            end: now + assumed,
+            note: "Unscheduled outage".to_string(),
+        })
+    }
+}
+
+/// Tries to rescue the battery once the SoC falls below the alarm SoC
+struct EmergencyResponder<'a> {
+    config: &'a EmergencyConfig,
+    active: bool,
+    work_mode: SharedOverrides<WorkMode>,
+}
+
+impl<'a> EmergencyResponder<'a> {
+    /// Amount (%) above the alarm SoC that the SoC must recover to
+    const HYSTERESIS: f64 = 2.0;
+
+    fn new(config: &'a EmergencyConfig, work_mode: SharedOverrides<WorkMode>) -> Self {
+        Self {
+            config,
+            active: false,
+            work_mode,
+        }
+    }
+
+    /// Update whether the emergency is in progress, and adjust the target SoC
+    fn target(
+        &mut self,
+        current_soc: f64,
+        target_soc_low: f64,
+        alarm_soc: f64,
+        target: f64,
+    ) -> f64 {
+        if !self.active && current_soc < alarm_soc {
+            warn!(
+                "SoC {:.0} is below alarm SoC {:.2}, setting minimum SoC to {}",
+                current_soc, alarm_soc, self.config.target_soc
+            );
+            self.active = true;
+        } else if self.active && current_soc >= target_soc_low.max(alarm_soc + Self::HYSTERESIS) {
+            info!("SoC has recovered to {current_soc:.0}, ending emergency response");
+            self.active = false;
+        }
+        if self.active {
+            target.max(self.config.target_soc)
+        } else {
+            target
+        }
+    }
+
+    async fn update_work_mode(&mut self, inverter: &mut dyn Inverter) -> Result<()> {
+        let Some(mode) = self.config.work_mode else {
+            return Ok(());
+        };
+        if !self.active {
+            return self.restore(inverter).await;
+        }
+        let mut work_mode = self.work_mode.lock().await;
+        work_mode
+            .acquire(inverter, OverrideOwner::Emergency, |original| {
+                info!("Switching work mode from {original:?} to {mode:?} until the SoC recovers");
+                mode
+            })
+            .await
+    }
+
+    async fn restore(&mut self, inverter: &mut dyn Inverter) -> Result<()> {
+        let mut work_mode = self.work_mode.lock().await;
+        work_mode.release(inverter, OverrideOwner::Emergency).await
+    }
+}
+
+#[async_trait]
+trait Controller: Send + Unpin {
+    /// Description used in logs
+    fn name(&self) -> &'static str;
+    fn interval(&self) -> std::time::Duration;
+    async fn update(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        monitor: &mut dyn Monitor,
+    ) -> Result<()>;
+    async fn shutdown(&mut self, inverter: &mut dyn Inverter);
+}
+
+/// Whether a controlled load should run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LoadDecision {
+    Run,
+    Idle,
+    /// Off because load-shedding is imminent, regardless of the minimum switch time
+    Blocked,
+}
+
+/// Conditions used to decide whether to run controlled loads and EV charging
+struct LoadConditions {
+    now: DateTime<Utc>,
+    current_soc: f64,
+    /// Start of the next (or current) load-shedding event
+    next_outage: Option<DateTime<Utc>>,
+    /// Predicted PV power (W) in excess of the household load
+    surplus: f64,
+}
+
+/// A load switched through a smart relay when there is PV to spare
+struct ControlledLoad<'a> {
+    config: &'a ControlledLoadConfig,
+    relay: Relay,
+    /// State most recently set (`None` if not yet set)
+    on: Option<bool>,
+    last_switch: Option<DateTime<Utc>>,
+    /// Only report when the relay would be switched
+    dry_run: bool,
+}
+
+impl<'a> ControlledLoad<'a> {
+    fn new(config: &'a ControlledLoadConfig, dry_run: bool) -> reqwest::Result<Self> {
+        Ok(Self {
+            config,
+            relay: Relay::new(&config.relay)?,
+            on: None,
+            last_switch: None,
+            dry_run,
+        })
+    }
+
+    /// Window during which the load is assumed to run if switched on now
+    fn window(&self, timezone: Option<&Timezone>, now: DateTime<Utc>) -> SmartLoadConfig {
+        // Windows are times of day, so they cannot span a whole day
+        let max = Duration::hours(23);
+        let run_for = Duration::from_std(self.config.run_for).map_or(max, |x| x.min(max));
+        SmartLoadConfig {
+            start: local_time(timezone, now).time(),
+            end: local_time(timezone, now + run_for).time(),
+            power: self.config.power,
+        }
+    }
+
+    /// Decide whether to run the load. `plan` computes the targets if the
+    /// load runs in the given window.
+    fn decide(
+        &self,
+        conditions: &LoadConditions,
+        window: &SmartLoadConfig,
+        plan: impl FnOnce(&SmartLoadConfig) -> Targets,
+    ) -> LoadDecision {
+        if let Some(outage) = conditions.next_outage {
+            if (outage - conditions.now).to_std().unwrap_or_default() < self.config.block_before {
+                return LoadDecision::Blocked;
+            }
+        }
+        if conditions.surplus < self.config.power {
+            return LoadDecision::Idle;
+        }
+        // Only run if the battery will still reach its target
+        if plan(window).target_soc_low > conditions.current_soc {
+            return LoadDecision::Idle;
+        }
+        LoadDecision::Run
+    }
+
+    /// Switch the load according to `decision`. In dry-run mode, the relay is
+    /// left alone and the intended switch is returned instead.
+    async fn update(&mut self, now: DateTime<Utc>, decision: LoadDecision) -> Option<String> {
+        let on = decision == LoadDecision::Run;
+        if self.on == Some(on) {
+            return None;
+        }
+        if decision != LoadDecision::Blocked {
+            if let Some(last_switch) = self.last_switch {
+                if (now - last_switch).to_std().unwrap_or_default() < self.config.min_switch {
+                    return None;
+                }
+            }
+        }
+        let description = format!("{} {}", self.config.name, if on { "on" } else { "off" });
+        if self.dry_run {
+            info!("Dry run: not switching {description} ({decision:?})");
+            self.on = Some(on);
+            self.last_switch = Some(now);
+            return Some(format!("switch {description}"));
+        }
+        info!("Switching {description} ({decision:?})");
+        match self.relay.set(on).await {
+            Ok(()) => {
+                self.on = Some(on);
+                self.last_switch = Some(now);
+            }
+            Err(err) => {
+                warn!("Failed to switch {}: {err}", self.config.name);
+            }
+        }
+        None
+    }
+}
+
+/// Throttles an EV charger while the battery needs the grid to reach its target
+struct EvThrottle<'a> {
+    config: &'a EvChargerConfig,
+    charger: EvCharger,
+    /// Charge current most recently set (`None` if not yet set)
+    current: Option<u32>,
+    last_switch: Option<DateTime<Utc>>,
+    /// Only report when the charge current would be changed
+    dry_run: bool,
+}
+
+impl<'a> EvThrottle<'a> {
+    fn new(config: &'a EvChargerConfig, dry_run: bool) -> reqwest::Result<Self> {
+        Ok(Self {
+            config,
+            charger: EvCharger::new(&config.charger)?,
+            current: None,
+            last_switch: None,
+            dry_run,
+        })
+    }
+
+    /// Whether charging should be throttled
+    fn should_throttle(&self, conditions: &LoadConditions, target_soc_low: f64) -> bool {
+        conditions.next_outage.is_some()
+            && conditions.current_soc < target_soc_low
+            && conditions.surplus < self.config.release_surplus
+    }
+
+    /// Set the charge current. In dry-run mode, the charger is left alone and
+    /// the intended change is returned instead.
+    async fn update(&mut self, now: DateTime<Utc>, throttle: bool) -> Option<String> {
+        let current = if throttle {
+            self.config.throttle_current
+        } else {
+            self.config.max_current
+        };
+        if self.current == Some(current) {
+            return None;
+        }
+        // Throttling is urgent, but releasing can wait
+        if !throttle {
+            if let Some(last_switch) = self.last_switch {
+                if (now - last_switch).to_std().unwrap_or_default() < self.config.min_switch {
+                    return None;
+                }
+            }
+        }
+        if self.dry_run {
+            info!("Dry run: not setting EV charge current to {current} A");
+            self.current = Some(current);
+            self.last_switch = Some(now);
+            return Some(format!("EV charge current to {current} A"));
+        }
+        if throttle {
+            info!("Battery is short of its target, throttling EV charging to {current} A");
+        } else {
+            info!("Releasing EV charging at {current} A");
+        }
+        match self.charger.set_current(current).await {
+            Ok(()) => {
+                self.current = Some(current);
+                self.last_switch = Some(now);
+            }
+            Err(err) => {
+                warn!("Failed to set EV charge current: {err}");
+            }
+        }
+        None
+    }
+}
+
+/// Resources shared by the controllers
+pub struct Context<'a> {
+    pub config: &'a Config,
+    pub esp: &'a EspStatus,
+    /// Load-shedding information older than this is ignored
+    pub esp_timeout: Duration,
+    pub alerter: &'a Alerter,
+    pub log: &'a CycleLog,
+    /// PV forecasts, indexed like the panels
+    pub forecasts: &'a Mutex<Vec<Option<PvForecast>>>,
+    /// Battery management system that takes precedence over the inverter's readings
+    pub bms: Option<&'a Bms>,
+}
+
+/// Minimum SoC last written by the SoC controller, saved across restarts
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct SavedTarget {
+    target: f64,
+    fallback: f64,
+}
+
+struct SocController<'a> {
+    config: &'a Config,
+    esp: &'a EspStatus,
+    esp_timeout: Duration,
+    forecasts: &'a Mutex<Vec<Option<PvForecast>>>,
+    limiter: Option<DischargeLimiter<'a>>,
+    emergency: Option<EmergencyResponder<'a>>,
+    ramp: Option<TargetRamp<'a>>,
+    soc_filter: SocFilter<'a>,
+    accuracy: AccuracyTracker<'a>,
+    outage: OutageDetector<'a>,
+    load_learning: Option<LoadLearning<'a>>,
+    costs: Option<CostTracker<'a>>,
+    loads: Vec<ControlledLoad<'a>>,
+    ev_charger: Option<EvThrottle<'a>>,
+    alerter: &'a Alerter,
+    /// Number of consecutive failed cycles before raising an alert
+    max_failures: u32,
+    failures: u32,
+    /// Latest statistics on communication with the inverter
+    io_stats: Option<IoStats>,
+    /// Faults reported by the inverter in the previous cycle
+    faults: Option<Vec<String>>,
+    bms: Option<&'a Bms>,
+    start: DateTime<Utc>,
+    state: Option<StateStore>,
+    /// Target written before a restart, until it is superseded
+    restored: Option<(DateTime<Utc>, SavedTarget)>,
+    holidays: Holidays,
+}
+
+impl<'a> SocController<'a> {
+    /// Name under which the last written target is saved
+    const STATE_NAME: &'static str = "soc";
+    /// Programs written by the inverter backend remain valid for at least this long
+    const PROGRAM_HOLD: Duration = Duration::minutes(2);
+
+    fn new(ctx: &Context<'a>, overrides: &Overrides) -> Self {
+        let config = ctx.config;
+        let state = config.state.as_ref().map(StateStore::new);
+        let restored = state
+            .as_ref()
+            .and_then(|state| state.load(Self::STATE_NAME));
+        let holidays = config
+            .holidays
+            .as_ref()
+            .map(Holidays::load)
+            .unwrap_or_default();
+        let dry_run = is_dry_run(config, &config.controllers.soc);
+        Self {
+            config,
+            esp: ctx.esp,
+            esp_timeout: ctx.esp_timeout,
+            forecasts: ctx.forecasts,
+            limiter: config
+                .discharge_limit
+                .as_ref()
+                .map(|limit| DischargeLimiter::new(limit, overrides.current_limits.clone())),
+            emergency: config
+                .emergency
+                .as_ref()
+                .map(|emergency| EmergencyResponder::new(emergency, overrides.work_mode.clone())),
+            ramp: config.ramp.as_ref().map(TargetRamp::new),
+            soc_filter: SocFilter::new(&config.soc_filter),
+            accuracy: AccuracyTracker::new(
+                &config.accuracy,
+                config.inverter.charge_efficiency,
+                config.inverter.discharge_efficiency,
+            ),
+            outage: OutageDetector::new(&config.outage),
+            load_learning: config
+                .load_profile
+                .as_ref()
+                .map(|load_profile| LoadLearning::new(load_profile, state.as_ref())),
+            costs: config
+                .wear
+                .as_ref()
+                .map(|wear| CostTracker::new(config, wear, holidays.clone())),
+            loads: config
+                .controlled_load
+                .iter()
+                .filter_map(|load| match ControlledLoad::new(load, dry_run) {
+                    Ok(load) => Some(load),
+                    Err(err) => {
+                        error!("Failed to set up {}: {err}", load.name);
+                        None
+                    }
+                })
+                .collect(),
+            ev_charger: config.ev_charger.as_ref().and_then(|ev_charger| {
+                match EvThrottle::new(ev_charger, dry_run) {
+                    Ok(ev_charger) => Some(ev_charger),
+                    Err(err) => {
+                        error!("Failed to set up EV charger: {err}");
+                        None
+                    }
+                }
+            }),
+            alerter: ctx.alerter,
+            max_failures: config
+                .alerts
+                .as_ref()
+                .map_or(u32::MAX, |alerts| alerts.inverter_failures),
+            failures: 0,
+            io_stats: None,
+            faults: None,
+            bms: ctx.bms,
+            start: Utc::now(),
+            state,
+            restored,
+            holidays,
+        }
+    }
+
+    fn reachable(&self, stats: &IoStats) -> bool {
+        stats.consecutive_failures < self.config.inverter.unreachable_failures
+    }
+
+    /// Report the health of the connection after a cycle, logging changes
+    async fn update_health(&mut self, stats: Option<IoStats>, monitor: &mut dyn Monitor) {
+        let Some(stats) = stats else {
+            return;
+        };
+        let reachable = self.reachable(&stats);
+        let was_reachable = self.io_stats.is_none_or(|old| self.reachable(&old));
+        if was_reachable && !reachable {
+            error!(
+                "Inverter is unreachable: {} consecutive communication failures",
+                stats.consecutive_failures
+            );
+        } else if !was_reachable && reachable {
+            info!("Inverter is reachable again");
+        }
+        self.io_stats = Some(stats);
+        let update = HealthUpdate {
+            time: Utc::now(),
+            inverter_reachable: reachable,
+            inverter_successes: stats.successes,
+            inverter_failures: stats.failures,
+            inverter_consecutive_failures: stats.consecutive_failures,
+        };
+        if let Err(err) = monitor.health_update(update).await {
+            warn!("Failed to update monitoring: {err}");
+        }
+    }
+
+    async fn check_alerts(&mut self, update: Option<&SocUpdate>) {
+        let now = Utc::now();
+        // Allow time for the first poll before considering the data stale
+        if self.config.esp.is_some() && now - self.start >= self.esp_timeout {
+            let stale =
+                filter_state(&self.esp.state.lock().unwrap(), now - self.esp_timeout).is_none();
+            self.alerter
+                .set(
+                    AlertKind::StaleEsp,
+                    stale,
+                    "no load-shedding information from EskomSePush",
+                )
+                .await;
+        }
+        if let Some(update) = update {
+            let message = format!(
+                "SoC is {:.0}% (alarm at {:.2}%)",
+                update.current_soc, update.alarm_soc
+            );
+            self.alerter
+                .set(
+                    AlertKind::LowSoc,
+                    update.current_soc < update.alarm_soc,
+                    &message,
+                )
+                .await;
+            if let Some(skew) = update.clock_skew {
+                let message = format!("inverter clock differs from system time by {skew:.0} s");
+                self.alerter
+                    .set(
+                        AlertKind::ClockSkew,
+                        skew.abs() > self.config.inverter.max_clock_skew.as_secs_f64(),
+                        &message,
+                    )
+                    .await;
+            }
+            self.alerter
+                .set(
+                    AlertKind::GridOutage,
+                    update.unscheduled_outage,
+                    "grid is down outside the load-shedding schedule",
+                )
+                .await;
+        }
+        let unreachable = self
+            .io_stats
+            .filter(|stats| !self.reachable(stats))
+            .map(|stats| stats.consecutive_failures);
+        let message = match unreachable {
+            Some(io_failures) => format!("unreachable after {io_failures} failed transactions"),
+            None => format!("{} consecutive failures", self.failures),
+        };
+        self.alerter
+            .set(
+                AlertKind::InverterFailure,
+                // Only alert on unreachability if alerts are configured
+                self.failures >= self.max_failures
+                    || (unreachable.is_some() && self.config.alerts.is_some()),
+                &message,
+            )
+            .await;
+    }
+}
+
+#[async_trait]
+impl Controller for SocController<'_> {
+    fn name(&self) -> &'static str {
+        "SoC"
+    }
+
+    fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(60)
+    }
+
+    async fn update(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        let learned = self
+            .load_learning
+            .as_ref()
+            .map(|learning| learning.learner.profile(learning.config));
+        let configured = self.config.load.as_ref().map(configured_profile);
+        let mut inputs = PlanInputs {
+            profile: match (learned, configured) {
+                (Some(learned), Some(configured)) => Some(learned.or(&configured)),
+                (learned, configured) => learned.or(configured),
+            },
+            holidays: self.holidays.clone(),
+            scales: self.accuracy.scales(),
+            forecasts: self.forecasts.lock().unwrap().clone(),
+            manual: self
+                .config
+                .manual
+                .as_ref()
+                .map_or(Override::Auto, |manual| {
+                    Override::load(&manual.path).unwrap_or_else(|err| {
+                        warn!("Ignoring manual override: {err}");
+                        Override::Auto
+                    })
+                }),
+            outage: None,
+        };
+        let result = update_soc(inverter, self, &mut inputs).await;
+        self.update_health(inverter.io_stats(), monitor).await;
+        match &result {
+            Ok(_) => {
+                self.alerter
+                    .set(
+                        AlertKind::WriteFailure,
+                        false,
+                        "programs read back correctly",
+                    )
+                    .await;
+            }
+            Err(err) => {
+                if let Some(err) = err.downcast_ref::<VerifyError>() {
+                    self.alerter
+                        .set(AlertKind::WriteFailure, true, &err.to_string())
+                        .await;
+                }
+            }
+        }
+        match result {
+            Ok(mut update) => {
+                self.failures = 0;
+                if let (Some(costs), Some(battery_power)) = (&mut self.costs, update.battery_power)
+                {
+                    let pv_power = match inverter.get_pv().await {
+                        Ok(pv) => pv.map(|strings| strings.iter().sum::<f64>()),
+                        Err(err) => {
+                            warn!("Failed to read PV power: {err}");
+                            None
+                        }
+                    };
+                    // Whatever the battery and panels don't supply comes from the grid
+                    let grid_power = update
+                        .load_power
+                        .zip(pv_power)
+                        .map(|(load, pv)| load - battery_power - pv);
+                    let (wear_cost, grid_cost) = costs.add(update.time, battery_power, grid_power);
+                    update.wear_cost = Some(wear_cost);
+                    update.grid_cost = grid_cost;
+                }
+                if let Err(err) = monitor.soc_update(update.clone()).await {
+                    warn!("Failed to update monitoring: {err}");
+                }
+                if let (Some(learning), Some(load_power)) =
+                    (&mut self.load_learning, update.load_power)
+                {
+                    let time = local_time(self.config.inverter.timezone.as_ref(), update.time);
+                    learning.add_sample(time, load_power, &self.holidays);
+                }
+                if let Some(limiter) = &mut self.limiter {
+                    if let Err(err) = limiter.update(inverter, &update).await {
+                        warn!("Failed to update discharge current limit: {err}");
+                    }
+                }
+                if let Some(emergency) = &mut self.emergency {
+                    if let Err(err) = emergency.update_work_mode(inverter).await {
+                        warn!("Failed to update emergency work mode: {err}");
+                    }
+                }
+                self.check_alerts(Some(&update)).await;
+                Ok(())
+            }
+            Err(err) => {
+                self.failures = self.failures.saturating_add(1);
+                self.check_alerts(None).await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn shutdown(&mut self, inverter: &mut dyn Inverter) {
+        if let Some(learning) = &mut self.load_learning {
+            learning.save();
+        }
+        // The programs are about to be replaced
+        if let Some(state) = &self.state {
+            state.remove(Self::STATE_NAME);
+        }
+        if let Some(limiter) = &mut self.limiter {
+            if let Err(err) = limiter.restore(inverter).await {
+                error!("Failed to restore discharge current limit: {err}");
+            }
+        }
+        if let Some(emergency) = &mut self.emergency {
+            if let Err(err) = emergency.restore(inverter).await {
+                error!("Failed to restore work mode: {err}");
+            }
+        }
+        if self.config.inverter.restore_programs {
+            info!("Shutting down, restoring the programs found at startup");
+            match inverter.restore_programs().await {
+                Ok(()) => return,
+                Err(err) => {
+                    error!("Failed to restore programs: {err}");
+                }
+            }
+        }
+        let fallback_soc = self.config.inverter.fallback_soc_at(Utc::now());
+        info!("Shutting down, setting minimum SoC to {fallback_soc}");
+        match inverter.set_min_soc(fallback_soc, fallback_soc).await {
+            Ok(_) => {}
+            Err(err) => {
+                error!("Failed to set minimum SoC: {err}");
+            }
+        }
+    }
+}
+
+/// PI controller tracking the ideal trickle setting
+///
+/// The integral term follows the (noisy) ideal setting, and the proportional
+/// term adds a fraction of the remaining error so that steps are followed
+/// quickly.
+struct TricklePid<'a> {
+    config: &'a CoilPidConfig,
+    integral: Option<f64>,
+    last_time: Option<DateTime<Utc>>,
+}
+
+impl<'a> TricklePid<'a> {
+    fn new(config: &'a CoilPidConfig) -> Self {
+        Self {
+            config,
+            integral: None,
+            last_time: None,
+        }
+    }
+
+    /// Update with the latest ideal setting and return the new output.
+    fn update(&mut self, now: DateTime<Utc>, target: f64) -> f64 {
+        let config = self.config;
+        let integral = match (self.integral, self.last_time) {
+            (Some(integral), Some(last_time)) => {
+                let dt = (now - last_time).num_milliseconds().max(0) as f64 * 1e-3;
+                // Never step past the target, which would make it unstable
+                let gain = (config.ki * dt).min(1.0);
+                integral + gain * (target - integral)
+            }
+            _ => target,
+        }
+        .clamp(config.min, config.max);
+        self.integral = Some(integral);
+        self.last_time = Some(now);
+        (integral + config.kp * (target - integral)).clamp(config.min, config.max)
+    }
+}
+
+/// State of the CT coil controller, saved across restarts
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct SavedCoil {
+    history: Vec<Option<f64>>,
+    last_setting: Option<f64>,
+    integral: Option<f64>,
+}
+
+struct CoilController<'a> {
+    history: VecDeque<Option<f64>>,
+    config: &'a CoilConfig,
+    capacity: usize,
+    pid: Option<TricklePid<'a>>,
+    last_setting: Option<f64>,
+    /// Whether the coil was active on the previous sample
+    last_active: Option<bool>,
+    state: Option<StateStore>,
+}
+
+/// Median of the values, or `None` if there are none.
+fn median(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let mut values: Vec<f64> = values.collect();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        Some((values[mid - 1] + values[mid]) / 2.0)
+    } else {
+        Some(values[mid])
+    }
+}
+
+impl<'a> CoilController<'a> {
+    /// Name under which the state is saved
+    const STATE_NAME: &'static str = "coil";
+
+    fn new(config: &'a CoilConfig, state: Option<&StateConfig>) -> Self {
+        let capacity = config.history.max(1);
+        let mut controller = Self {
+            history: VecDeque::with_capacity(capacity),
+            config,
+            capacity,
+            pid: config.pid.as_ref().map(TricklePid::new),
+            last_setting: None,
+            last_active: None,
+            state: state.map(StateStore::new),
+        };
+        if let Some(state) = state {
+            controller.restore(Utc::now(), state.max_age);
+        }
+        controller
+    }
+
+    /// Restore the state saved before a restart, unless it is too old
+    fn restore(&mut self, now: DateTime<Utc>, max_age: std::time::Duration) {
+        let Some((time, saved)) = self
+            .state
+            .as_ref()
+            .and_then(|state| state.load::<SavedCoil>(Self::STATE_NAME))
+        else {
+            return;
+        };
+        if (now - time).to_std().is_ok_and(|age| age > max_age) {
+            info!("Ignoring CT coil state saved at {time}");
+            return;
+        }
+        let skip = saved.history.len().saturating_sub(self.capacity);
+        self.history = saved.history.into_iter().skip(skip).collect();
+        self.last_setting = saved.last_setting;
+        if let Some(pid) = &mut self.pid {
+            pid.integral = saved.integral;
+            pid.last_time = saved.integral.map(|_| time);
+        }
+    }
+
+    fn save(&self) {
+        if let Some(state) = &self.state {
+            let saved = SavedCoil {
+                history: self.history.iter().cloned().collect(),
+                last_setting: self.last_setting,
+                integral: self.pid.as_ref().and_then(|pid| pid.integral),
+            };
+            state.save(Self::STATE_NAME, Utc::now(), saved);
+        }
+    }
+
+    /// Whether a sample is too far from the median of the recent samples
+    fn is_outlier(&self, target: f64) -> bool {
+        let Some(outlier) = self.config.outlier else {
+            return false;
+        };
+        // Too few samples to judge
+        if self.history.iter().flatten().count() < 3 {
+            return false;
+        }
+        median(self.history.iter().flatten().cloned())
+            .is_some_and(|median| (target - median).abs() > outlier)
+    }
+
+    async fn update_fallible(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        let info = inverter.get_coil().await?;
         if let Some(value) = &info {
-            let ne = value.coil - value.inverter;
+            let max = self.config.max_power;
+            if value.coil.abs() > max || value.inverter.abs() > max {
+                warn!(
+                    "Discarding implausible coil sample (coil {} W, inverter {} W)",
+                    value.coil, value.inverter
+                );
+                return Ok(());
+            }
+        }
+        let mut target = None;
+        if let Some(value) = &info {
+            let ne = match self.config.non_essential {
+                NonEssentialSource::Difference => value.coil - value.inverter,
+                NonEssentialSource::Measured => inverter
+                    .get_non_essential_power()
+                    .await?
+                    .ok_or("inverter does not report non-essential power")?,
+            };
             if ne <= self.config.power_threshold {
                 // It's fake power from misreading coil
                 target = Some(ne + self.config.trickle);
             }
         }
-        if self.history.len() == Self::CAPACITY {
-            self.history.pop_front();
+        let coil_active = info.as_ref().is_some_and(|x| x.coil_active);
+        let last_active = self.last_active.replace(coil_active);
+        if info.is_some() && last_active.is_some_and(|x| x != coil_active) {
+            info!("Discarding coil sample during a mode transition");
+            return Ok(());
+        }
+        if let Some(value) = target {
+            if self.is_outlier(value) {
+                info!("Discarding outlying coil sample of {value}");
+                return Ok(());
+            }
+        }
+        // The PI controller does not use the history, but it is still used for
+        // outlier rejection.
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(target);
+        let mean = if let Some(pid) = &mut self.pid {
+            // Hold the output while non-essential loads are running
+            let Some(target) = target else {
+                return Ok(());
+            };
+            pid.update(Utc::now(), target)
+        } else {
+            if self.history.len() != self.capacity {
+                return Ok(());
+            }
+            // Compute the sum if all elements are not None
+            let Some(sum) = self.history.iter().cloned().sum::<Option<f64>>() else {
+                return Ok(());
+            };
+            sum / (self.history.len() as f64)
+        };
+        if coil_active {
+            if self
+                .last_setting
+                .is_none_or(|x| (x - mean).abs() >= self.config.hysteresis)
+            {
+                info!("Setting trickle to {mean}.");
+                inverter.set_trickle(mean).await?;
+                self.last_setting = Some(mean);
+                self.save();
+            } else {
+                info!("Ideal trickle setting is {mean}, but not setting due to hysteresis");
+            }
+        } else {
+            info!("Ideal trickle setting is {mean}, but coil is not active.");
+        }
+        let update = CoilUpdate {
+            time: Utc::now(),
+            active: coil_active,
+            target: mean,
+            setting: self.last_setting,
+        };
+        if let Err(err) = monitor.coil_update(update).await {
+            warn!("Failed to update monitoring: {err}");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Controller for CoilController<'_> {
+    fn name(&self) -> &'static str {
+        "CT coil"
+    }
+
+    fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(10)
+    }
+
+    async fn update(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        self.update_fallible(inverter, monitor).await
+    }
+
+    async fn shutdown(&mut self, _inverter: &mut dyn Inverter) {
+        self.save();
+    }
+}
+
+/// Compares measured production of each MPPT string to the prediction
+struct PvController<'a> {
+    panels: &'a [PanelConfig],
+}
+
+impl<'a> PvController<'a> {
+    /// Below this fraction of rated power, the prediction is not used to compute a ratio
+    const MIN_FRACTION: f64 = 0.05;
+    /// Warn about strings whose ratio is less than this fraction of the best string
+    const WARN_FRACTION: f64 = 0.5;
+
+    fn new(panels: &'a [PanelConfig]) -> Self {
+        Self { panels }
+    }
+
+    async fn update_fallible(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        let Some(measured) = inverter.get_pv().await? else {
+            return Ok(());
+        };
+        let now = Utc::now();
+        let mut strings = Vec::new();
+        for (i, &measured) in measured.iter().enumerate() {
+            let mppt = i + 1;
+            let panels = self
+                .panels
+                .iter()
+                .filter(|panels| panels.mppt == Some(mppt));
+            let rated: f64 = panels.clone().map(PanelConfig::rated_power).sum();
+            let predicted = panels_power(panels, now);
+            let ratio = if rated > 0.0 && predicted >= Self::MIN_FRACTION * rated {
+                Some(measured / predicted)
+            } else {
+                None
+            };
+            strings.push(PvString {
+                mppt,
+                predicted,
+                measured,
+                ratio,
+            });
+        }
+        let best = strings.iter().filter_map(|s| s.ratio).fold(0.0, f64::max);
+        for string in strings.iter() {
+            if let Some(ratio) = string.ratio {
+                if ratio < Self::WARN_FRACTION * best {
+                    warn!(
+                        "MPPT {} is producing {:.0} W ({:.0}% of predicted), compared to {:.0}% for the best string",
+                        string.mppt,
+                        string.measured,
+                        ratio * 100.0,
+                        best * 100.0
+                    );
+                }
+            }
+        }
+        let update = PvUpdate { time: now, strings };
+        if let Err(err) = monitor.pv_update(update).await {
+            warn!("Failed to update monitoring: {err}");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Controller for PvController<'_> {
+    fn name(&self) -> &'static str {
+        "PV strings"
+    }
+
+    fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(60)
+    }
+
+    async fn update(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        self.update_fallible(inverter, monitor).await
+    }
+
+    async fn shutdown(&mut self, _inverter: &mut dyn Inverter) {}
+}
+
+/// Switches the work mode during load-shedding, restoring it afterwards
+struct WorkModeController<'a> {
+    config: &'a WorkModeConfig,
+    esp: &'a EspStatus,
+    esp_timeout: Duration,
+    work_mode: SharedOverrides<WorkMode>,
+}
+
+impl<'a> WorkModeController<'a> {
+    fn new(
+        ctx: &Context<'a>,
+        config: &'a WorkModeConfig,
+        work_mode: SharedOverrides<WorkMode>,
+    ) -> Self {
+        Self {
+            config,
+            esp: ctx.esp,
+            esp_timeout: ctx.esp_timeout,
+            work_mode,
+        }
+    }
+
+    fn is_loadshedding(&self, now: DateTime<Utc>) -> bool {
+        let guard = self.esp.state.lock().unwrap();
+        filter_state(&guard, now - self.esp_timeout).is_some_and(|state| {
+            normalize_events(&state.response.events, now)
+                .iter()
+                .any(|event| now >= event.start && now < event.end)
+        })
+    }
+
+    async fn restore(&mut self, inverter: &mut dyn Inverter) -> Result<()> {
+        let mut work_mode = self.work_mode.lock().await;
+        work_mode
+            .release(inverter, OverrideOwner::LoadShedding)
+            .await
+    }
+}
+
+#[async_trait]
+impl Controller for WorkModeController<'_> {
+    fn name(&self) -> &'static str {
+        "Work mode"
+    }
+
+    fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(60)
+    }
+
+    async fn update(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        _monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        if !self.is_loadshedding(Utc::now()) {
+            return self.restore(inverter).await;
+        }
+        let target = self.config.loadshedding;
+        let mut work_mode = self.work_mode.lock().await;
+        work_mode
+            .acquire(inverter, OverrideOwner::LoadShedding, |original| {
+                if original != target {
+                    info!("Load-shedding: switching work mode from {original:?} to {target:?}");
+                }
+                target
+            })
+            .await
+    }
+
+    async fn shutdown(&mut self, inverter: &mut dyn Inverter) {
+        if let Err(err) = self.restore(inverter).await {
+            error!("Failed to restore work mode: {err}");
+        }
+    }
+}
+
+/// Corrects drift in the inverter clock, which would shift the program windows
+struct ClockSyncController<'a> {
+    config: &'a ClockSyncConfig,
+    timezone: Option<&'a Timezone>,
+}
+
+impl<'a> ClockSyncController<'a> {
+    fn new(ctx: &Context<'a>, config: &'a ClockSyncConfig) -> Self {
+        Self {
+            config,
+            timezone: ctx.config.inverter.timezone.as_ref(),
+        }
+    }
+}
+
+#[async_trait]
+impl Controller for ClockSyncController<'_> {
+    fn name(&self) -> &'static str {
+        "Clock sync"
+    }
+
+    fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(3600)
+    }
+
+    async fn update(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        _monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        let Some(clock) = inverter.get_clock().await? else {
+            return Ok(());
+        };
+        let drift = clock - local_time(self.timezone, Utc::now());
+        let max_drift = Duration::from_std(self.config.max_drift)?;
+        if drift.abs() > max_drift {
+            let corrected = local_time(self.timezone, Utc::now());
+            info!(
+                "Inverter clock is off by {:.1} s, setting it to {corrected}",
+                duration_hours(drift) * 3600.0
+            );
+            inverter.set_clock(corrected).await?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self, _inverter: &mut dyn Inverter) {}
+}
+
+/// Raises the discharge current limit while grid import is above a threshold
+struct PeakShavingController<'a> {
+    config: &'a PeakShavingConfig,
+    limits: SharedOverrides<CurrentLimits>,
+    /// Last time at which import was above the threshold
+    last_peak: Option<DateTime<Utc>>,
+}
+
+impl<'a> PeakShavingController<'a> {
+    fn new(config: &'a PeakShavingConfig, limits: SharedOverrides<CurrentLimits>) -> Self {
+        Self {
+            config,
+            limits,
+            last_peak: None,
         }
-        self.history.push_back(target);
-        if self.history.len() != Self::CAPACITY {
-            return Ok(());
+    }
+
+    /// Whether the limit should be raised, given the current import.
+    fn should_shave(&mut self, now: DateTime<Utc>, import: f64) -> bool {
+        if import > self.config.threshold {
+            self.last_peak = Some(now);
         }
-        // Compute the sum if all elements are not None
-        let Some(sum) = self.history.iter().cloned().sum::<Option<f64>>() else {
+        let Some(last_peak) = self.last_peak else {
+            return false;
+        };
+        // A negative elapsed time (clock stepped backwards) counts as recent
+        (now - last_peak)
+            .to_std()
+            .map_or(true, |elapsed| elapsed < self.config.hold)
+    }
+
+    async fn restore(&mut self, inverter: &mut dyn Inverter) -> Result<()> {
+        let mut limits = self.limits.lock().await;
+        limits.release(inverter, OverrideOwner::PeakShaving).await
+    }
+}
+
+#[async_trait]
+impl Controller for PeakShavingController<'_> {
+    fn name(&self) -> &'static str {
+        "Peak shaving"
+    }
+
+    fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(10)
+    }
+
+    async fn update(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        _monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        let Some(info) = inverter.get_coil().await? else {
             return Ok(());
         };
-        let mean = sum / (self.history.len() as f64);
-        let coil_active = info.map_or(false, |x| x.coil_active);
-        if coil_active {
-            if self.last_setting.map_or(true, |x| (x - mean).abs() >= 10.0) {
-                info!("Setting trickle to {mean}.");
-                inverter.set_trickle(mean).await?;
-                self.last_setting = Some(mean);
-            } else {
-                info!("Ideal trickle setting is {mean}, but not setting due to hysteresis");
+        if self.should_shave(Utc::now(), info.coil) {
+            let mut limits = self.limits.lock().await;
+            limits
+                .acquire(inverter, OverrideOwner::PeakShaving, |original| {
+                    let raised = CurrentLimits {
+                        discharge: original.discharge.max(self.config.current),
+                        ..original
+                    };
+                    warn!(
+                        "Grid import of {:.0} W exceeds {:.0} W, raising discharge current limit to {} A",
+                        info.coil, self.config.threshold, raised.discharge
+                    );
+                    raised
+                })
+                .await
+        } else {
+            self.restore(inverter).await
+        }
+    }
+
+    async fn shutdown(&mut self, inverter: &mut dyn Inverter) {
+        if let Err(err) = self.restore(inverter).await {
+            error!("Failed to restore discharge current limit: {err}");
+        }
+    }
+}
+
+/// Decides when to run a generator, subject to the runtime constraints
+struct GeneratorSchedule<'a> {
+    config: &'a GeneratorConfig,
+    /// Time at which the generator was started, if running
+    started: Option<DateTime<Utc>>,
+    /// Time at which the generator was last stopped
+    stopped: Option<DateTime<Utc>>,
+}
+
+impl<'a> GeneratorSchedule<'a> {
+    fn new(config: &'a GeneratorConfig) -> Self {
+        Self {
+            config,
+            started: None,
+            stopped: None,
+        }
+    }
+
+    /// Whether the generator should run, given the SoC and grid state.
+    fn should_run(&self, now: DateTime<Utc>, soc: f64, grid_off: bool) -> bool {
+        let elapsed = |since: DateTime<Utc>| (now - since).to_std().unwrap_or_default();
+        match self.started {
+            Some(started) => {
+                elapsed(started) < self.config.min_runtime
+                    || (grid_off && soc < self.config.stop_soc)
+            }
+            None => {
+                grid_off
+                    && soc < self.config.start_soc
+                    && self
+                        .stopped
+                        .is_none_or(|stopped| elapsed(stopped) >= self.config.cooldown)
             }
+        }
+    }
+
+    fn set_running(&mut self, now: DateTime<Utc>, running: bool) {
+        if running {
+            self.started = Some(now);
         } else {
-            info!("Ideal trickle setting is {mean}, but coil is not active.");
+            self.started = None;
+            self.stopped = Some(now);
         }
-        let update = CoilUpdate {
-            time: Utc::now(),
-            active: coil_active,
-            target: mean,
-            setting: self.last_setting,
-        };
-        if let Err(err) = monitor.coil_update(update).await {
-            warn!("Failed to update monitoring: {err}");
+    }
+}
+
+/// Starts and stops a generator when the battery runs low during an outage
+struct GeneratorController<'a> {
+    schedule: GeneratorSchedule<'a>,
+    relay: Relay,
+    esp: &'a EspStatus,
+    esp_timeout: Duration,
+    /// Only log when the generator would be switched
+    dry_run: bool,
+}
+
+impl<'a> GeneratorController<'a> {
+    fn new(ctx: &Context<'a>, config: &'a GeneratorConfig, dry_run: bool) -> reqwest::Result<Self> {
+        Ok(Self {
+            schedule: GeneratorSchedule::new(config),
+            relay: Relay::new(&config.relay)?,
+            esp: ctx.esp,
+            esp_timeout: ctx.esp_timeout,
+            dry_run,
+        })
+    }
+
+    async fn switch(&self, run: bool) -> reqwest::Result<()> {
+        if self.dry_run {
+            info!(
+                "Dry run: not {} the generator",
+                if run { "starting" } else { "stopping" }
+            );
+            Ok(())
+        } else {
+            self.relay.set(run).await
         }
-        Ok(())
+    }
+
+    /// Whether load-shedding is in progress according to EskomSePush
+    fn is_loadshedding(&self, now: DateTime<Utc>) -> bool {
+        let guard = self.esp.state.lock().unwrap();
+        filter_state(&guard, now - self.esp_timeout).is_some_and(|state| {
+            normalize_events(&state.response.events, now)
+                .iter()
+                .any(|event| now >= event.start && now < event.end)
+        })
     }
 }
 
 #[async_trait]
-impl Controller for CoilController<'_> {
+impl Controller for GeneratorController<'_> {
+    fn name(&self) -> &'static str {
+        "Generator"
+    }
+
     fn interval(&self) -> std::time::Duration {
-        std::time::Duration::from_secs(10)
+        std::time::Duration::from_secs(60)
     }
 
-    async fn update(&mut self, inverter: &mut dyn Inverter, monitor: &mut dyn Monitor) {
-        match self.update_fallible(inverter, monitor).await {
-            Ok(_) => {}
+    async fn update(
+        &mut self,
+        inverter: &mut dyn Inverter,
+        _monitor: &mut dyn Monitor,
+    ) -> Result<()> {
+        let now = Utc::now();
+        let soc = inverter.get_soc().await?;
+        if !(0.0..=100.0).contains(&soc) {
+            return Err(format!("SoC reading {soc} is out of range").into());
+        }
+        // Prefer the inverter's view of the grid, falling back to the schedule
+        let grid_connected = match inverter.get_telemetry().await {
+            Ok(telemetry) => telemetry.and_then(|telemetry| telemetry.grid_connected),
             Err(err) => {
-                error!("Failed to update CT coil: {err}");
+                warn!("Failed to read grid status, using the load-shedding schedule: {err}");
+                None
             }
+        };
+        let grid_off = match grid_connected {
+            Some(connected) => !connected,
+            None => self.is_loadshedding(now),
+        };
+        let run = self.schedule.should_run(now, soc, grid_off);
+        if run == self.schedule.started.is_some() {
+            return Ok(());
         }
+        if run {
+            info!("SoC is {soc:.0}% during an outage, starting the generator");
+        } else {
+            info!("Stopping the generator (SoC is {soc:.0}%)");
+        }
+        self.switch(run).await?;
+        self.schedule.set_running(now, run);
+        Ok(())
     }
 
-    async fn shutdown(&mut self, _inverter: &mut dyn Inverter) {}
+    async fn shutdown(&mut self, _inverter: &mut dyn Inverter) {
+        if self.schedule.started.is_some() {
+            info!("Stopping the generator on shutdown");
+            match self.switch(false).await {
+                Ok(()) => self.schedule.set_running(Utc::now(), false),
+                Err(err) => error!("Failed to stop the generator: {err}"),
+            }
+        }
+    }
+}
+
+/// Entry in the table of known controllers
+struct ControllerEntry {
+    /// Settings for the controller in [ControllersConfig]
+    settings: fn(&ControllersConfig) -> &ControllerSettings,
+    /// Bounds on a configured update interval
+    min_interval: std::time::Duration,
+    max_interval: std::time::Duration,
+    /// Create the controller, or return `None` if it is not configured
+    create: for<'a> fn(&Context<'a>, &Overrides) -> Option<Box<dyn Controller + 'a>>,
+}
+
+/// Whether a controller must only report what it would change, either
+/// because it is observe-only or because the inverter is in dry-run mode
+fn is_dry_run(config: &Config, settings: &ControllerSettings) -> bool {
+    config.inverter.dry_run || settings.mode != ControllerMode::Enabled
+}
+
+const fn secs(secs: u64) -> std::time::Duration {
+    std::time::Duration::from_secs(secs)
+}
+
+/// All the known controllers
+const CONTROLLERS: &[ControllerEntry] = &[
+    ControllerEntry {
+        settings: |modes| &modes.soc,
+        min_interval: secs(10),
+        max_interval: secs(600),
+        create: create_soc,
+    },
+    ControllerEntry {
+        settings: |modes| &modes.coil,
+        min_interval: secs(1),
+        max_interval: secs(300),
+        create: create_coil,
+    },
+    ControllerEntry {
+        settings: |modes| &modes.pv,
+        min_interval: secs(10),
+        max_interval: secs(3600),
+        create: create_pv,
+    },
+    ControllerEntry {
+        settings: |modes| &modes.clock_sync,
+        min_interval: secs(60),
+        max_interval: secs(86400),
+        create: create_clock_sync,
+    },
+    ControllerEntry {
+        settings: |modes| &modes.work_mode,
+        min_interval: secs(10),
+        max_interval: secs(3600),
+        create: create_work_mode,
+    },
+    ControllerEntry {
+        settings: |modes| &modes.peak_shaving,
+        min_interval: secs(1),
+        max_interval: secs(300),
+        create: create_peak_shaving,
+    },
+    ControllerEntry {
+        settings: |modes| &modes.generator,
+        min_interval: secs(10),
+        max_interval: secs(600),
+        create: create_generator,
+    },
+];
+
+fn create_soc<'a>(ctx: &Context<'a>, overrides: &Overrides) -> Option<Box<dyn Controller + 'a>> {
+    Some(Box::new(SocController::new(ctx, overrides)))
+}
+
+fn create_coil<'a>(ctx: &Context<'a>, _overrides: &Overrides) -> Option<Box<dyn Controller + 'a>> {
+    let config = ctx.config.coil.as_ref()?;
+    Some(Box::new(CoilController::new(
+        config,
+        ctx.config.state.as_ref(),
+    )))
+}
+
+fn create_pv<'a>(ctx: &Context<'a>, _overrides: &Overrides) -> Option<Box<dyn Controller + 'a>> {
+    let panels = &ctx.config.inverter.panels;
+    if panels.iter().any(|panels| panels.mppt.is_some()) {
+        Some(Box::new(PvController::new(panels)))
+    } else {
+        None
+    }
+}
+
+fn create_clock_sync<'a>(
+    ctx: &Context<'a>,
+    _overrides: &Overrides,
+) -> Option<Box<dyn Controller + 'a>> {
+    let config = ctx.config.clock_sync.as_ref()?;
+    Some(Box::new(ClockSyncController::new(ctx, config)))
+}
+
+fn create_work_mode<'a>(
+    ctx: &Context<'a>,
+    overrides: &Overrides,
+) -> Option<Box<dyn Controller + 'a>> {
+    let config = ctx.config.work_mode.as_ref()?;
+    Some(Box::new(WorkModeController::new(
+        ctx,
+        config,
+        overrides.work_mode.clone(),
+    )))
+}
+
+fn create_peak_shaving<'a>(
+    ctx: &Context<'a>,
+    overrides: &Overrides,
+) -> Option<Box<dyn Controller + 'a>> {
+    let config = ctx.config.peak_shaving.as_ref()?;
+    Some(Box::new(PeakShavingController::new(
+        config,
+        overrides.current_limits.clone(),
+    )))
+}
+
+fn create_generator<'a>(
+    ctx: &Context<'a>,
+    _overrides: &Overrides,
+) -> Option<Box<dyn Controller + 'a>> {
+    let config = ctx.config.generator.as_ref()?;
+    let dry_run = is_dry_run(ctx.config, &ctx.config.controllers.generator);
+    match GeneratorController::new(ctx, config, dry_run) {
+        Ok(controller) => Some(Box::new(controller)),
+        Err(err) => {
+            error!("Failed to set up generator control: {err}");
+            None
+        }
+    }
+}
+
+/// Create the configured, enabled controllers, with their modes and intervals.
+fn create_controllers<'a>(
+    ctx: &Context<'a>,
+) -> Vec<(
+    Box<dyn Controller + 'a>,
+    ControllerMode,
+    std::time::Duration,
+)> {
+    let overrides = Overrides::default();
+    let mut controllers = Vec::new();
+    for entry in CONTROLLERS {
+        let settings = (entry.settings)(&ctx.config.controllers);
+        let Some(controller) = (entry.create)(ctx, &overrides) else {
+            continue;
+        };
+        match settings.mode {
+            ControllerMode::Enabled => {}
+            ControllerMode::ObserveOnly => {
+                info!("{} controller is in observe-only mode", controller.name());
+            }
+            ControllerMode::Disabled => {
+                info!("{} controller is disabled", controller.name());
+                continue;
+            }
+        }
+        let interval = match settings.interval {
+            Some(interval) => {
+                let bounded = interval.clamp(entry.min_interval, entry.max_interval);
+                if bounded != interval {
+                    warn!(
+                        "{} interval of {interval:?} is out of range; using {bounded:?}",
+                        controller.name()
+                    );
+                }
+                bounded
+            }
+            None => controller.interval(),
+        };
+        controllers.push((controller, settings.mode, interval));
+    }
+    controllers
+}
+
+/// Run a single update of the SoC controller, for driving socit from a timer.
+///
+/// Unlike [control_inverter], nothing is restored afterwards, since the point
+/// is to leave the programs in place until the next run.
+pub async fn control_once(
+    inverter: &mut dyn Inverter,
+    monitor: &mut dyn Monitor,
+    ctx: &Context<'_>,
+) -> Result<()> {
+    let mut controller = SocController::new(ctx, &Overrides::default());
+    match ctx.config.controllers.soc.mode {
+        ControllerMode::Enabled => controller.update(inverter, monitor).await,
+        ControllerMode::ObserveOnly => {
+            let mut dryrun = DryrunInverter::new(Box::new(&mut *inverter));
+            controller.update(&mut dryrun, monitor).await
+        }
+        ControllerMode::Disabled => Err("the SoC controller is disabled".into()),
+    }
 }
 
+/// Run the controllers until cancelled, reporting progress to `supervisor`
+/// as loop number `index`
 pub async fn control_inverter(
     inverter: &mut dyn Inverter,
-    config: &Config,
     monitor: &mut dyn Monitor,
-    state: &Mutex<Option<State>>,
-    esp_timeout: Duration,
+    ctx: &Context<'_>,
+    supervisor: &Supervisor,
+    index: usize,
     token: CancellationToken,
 ) {
-    let mut controllers: Vec<Box<dyn Controller>> = Vec::new();
-    controllers.push(Box::new(SocController::new(
-        &config.inverter,
-        state,
-        esp_timeout,
-    )));
-    if let Some(coil_config) = &config.coil {
-        controllers.push(Box::new(CoilController::new(coil_config)));
-    }
+    let mut controllers = create_controllers(ctx);
     let mut stream = StreamMap::new();
-    for (i, controller) in controllers.iter().enumerate() {
-        let mut interval = tokio::time::interval(controller.interval());
+    for (i, (_, _, period)) in controllers.iter().enumerate() {
+        let mut interval = tokio::time::interval(*period);
         interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
         stream.insert(i, tokio_stream::wrappers::IntervalStream::new(interval));
     }
+    /* Liveness is reported from the same loop that runs the updates, so
+     * that an update that hangs (for example, on a dead Modbus connection)
+     * stops the watchdog being petted.
+     */
+    let mut alive = supervisor.alive_interval().map(|period| {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
 
     loop {
         tokio::select! {
-            Some((idx, _)) = stream.next() => { controllers[idx].update(inverter, monitor).await; }
+            _ = async { alive.as_mut().unwrap().tick().await }, if alive.is_some() => {
+                supervisor.set_alive(index);
+            }
+            Some((idx, _)) = stream.next() => {
+                let (controller, mode, _) = &mut controllers[idx];
+                let result = match mode {
+                    ControllerMode::ObserveOnly => {
+                        let mut dryrun = DryrunInverter::new(Box::new(&mut *inverter));
+                        controller.update(&mut dryrun, monitor).await
+                    }
+                    _ => controller.update(inverter, monitor).await,
+                };
+                match result {
+                    Ok(()) => {
+                        // Only report readiness once the inverter has been read
+                        supervisor.set_ready(index);
+                    }
+                    Err(err) => {
+                        error!(controller = controller.name(); "Failed to update {}: {err}", controller.name());
+                        ctx.log.push(CycleEvent::Error {
+                            controller: controller.name().to_string(),
+                            message: err.to_string(),
+                        });
+                    }
+                }
+            }
             _ = token.cancelled() => { break; }
         }
     }
 
-    for controller in controllers.iter_mut() {
-        controller.shutdown(inverter).await;
+    for (controller, mode, _) in controllers.iter_mut() {
+        match mode {
+            ControllerMode::ObserveOnly => {
+                controller
+                    .shutdown(&mut DryrunInverter::new(Box::new(&mut *inverter)))
+                    .await;
+            }
+            _ => controller.shutdown(inverter).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{EvChargerKind, RelayConfig, TariffPeriodConfig};
+    use crate::fake_sunsynk::FakeSunsynk;
+    use crate::monitoring::NullMonitor;
+    use crate::sunsynk::SunsynkInverter;
+    use chrono::{FixedOffset, NaiveTime, TimeZone};
+
+    #[test]
+    fn test_soc_filter() {
+        let config = SocFilterConfig::default();
+        let mut filter = SocFilter::new(&config);
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let t = |minutes| start + Duration::minutes(minutes);
+        assert_eq!(filter.filter(t(0), 255.0), None);
+        assert_eq!(filter.filter(t(1), 50.0), Some(50.0));
+        assert_eq!(filter.filter(t(2), 0.0), Some(50.0));
+        assert_eq!(filter.filter(t(3), 255.0), Some(50.0));
+        assert_eq!(filter.filter(t(13), 60.0), Some(60.0));
+        assert_eq!(filter.filtered, 3);
+    }
+
+    #[test]
+    fn test_capacity_filter() {
+        let config = SocFilterConfig {
+            max_rejections: 2,
+            ..Default::default()
+        };
+        let mut filter = SocFilter::new(&config);
+        assert_eq!(filter.filter_capacity(0.0), None);
+        assert_eq!(filter.filter_capacity(10000.0), Some(10000.0));
+        assert_eq!(filter.filter_capacity(11000.0), Some(11000.0));
+        assert_eq!(filter.filter_capacity(0.0), Some(11000.0));
+        assert_eq!(filter.filter_capacity(f64::NAN), Some(11000.0));
+        // A persistent change is eventually accepted
+        assert_eq!(filter.filter_capacity(20000.0), Some(20000.0));
+    }
+
+    #[test]
+    fn test_ramp() {
+        let config = RampConfig { rate: 2.0 };
+        let mut ramp = TargetRamp::new(&config);
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let t = |minutes| start + Duration::minutes(minutes);
+        assert_eq!(ramp.limit(t(0), 30.0, 80.0), 30.0);
+        assert_eq!(ramp.limit(t(1), 30.0, 80.0), 32.0);
+        assert_eq!(ramp.limit(t(3), 31.0, 80.0), 36.0);
+        // Decreases are not limited
+        assert_eq!(ramp.limit(t(4), 31.0, 20.0), 20.0);
+        // Never limited below the current SoC
+        assert_eq!(ramp.limit(t(5), 50.0, 80.0), 52.0);
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median([].into_iter()), None);
+        assert_eq!(median([3.0, 1.0, 2.0].into_iter()), Some(2.0));
+        assert_eq!(median([4.0, 1.0, 3.0, 2.0].into_iter()), Some(2.5));
+    }
+
+    #[test]
+    fn test_coil_state() {
+        let dir = std::env::temp_dir().join(format!("socit-coil-{}", std::process::id()));
+        let state = StateConfig {
+            directory: dir.clone(),
+            max_age: std::time::Duration::from_secs(600),
+        };
+        let config = CoilConfig {
+            power_threshold: 800.0,
+            trickle: 10.0,
+            non_essential: NonEssentialSource::Difference,
+            history: 3,
+            hysteresis: 10.0,
+            outlier: None,
+            max_power: 30000.0,
+            pid: None,
+        };
+        let mut controller = CoilController::new(&config, Some(&state));
+        controller.history.extend([Some(1.0), None, Some(3.0)]);
+        controller.last_setting = Some(20.0);
+        controller.save();
+
+        let restored = CoilController::new(&config, Some(&state));
+        assert_eq!(restored.history, controller.history);
+        assert_eq!(restored.last_setting, Some(20.0));
+        // Stale state is ignored
+        let mut stale = CoilController::new(&config, None);
+        stale.state = Some(StateStore::new(&state));
+        stale.restore(Utc::now() + Duration::hours(1), state.max_age);
+        assert!(stale.history.is_empty());
+        assert_eq!(stale.last_setting, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_trickle_pid() {
+        let config = CoilPidConfig {
+            kp: 0.5,
+            ki: 0.05,
+            min: -100.0,
+            max: 100.0,
+        };
+        let mut pid = TricklePid::new(&config);
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let t = |seconds| start + Duration::seconds(seconds);
+        assert_eq!(pid.update(t(0), 20.0), 20.0);
+        // Step: integral moves half-way, and proportional half the remainder
+        assert_eq!(pid.update(t(10), 60.0), 50.0);
+        // Converges on a constant target
+        let mut output = 0.0;
+        for i in 2..100 {
+            output = pid.update(t(i * 10), 60.0);
+        }
+        assert!((output - 60.0).abs() < 1e-6);
+        // Output is clamped
+        assert_eq!(pid.update(t(1000), 500.0), 100.0);
+    }
+
+    fn fake_inverter(fake: &FakeSunsynk) -> SunsynkInverter {
+        let config: InverterConfig = toml::from_str(&format!(
+            r#"
+            device = "{}"
+            min_soc = 20
+            fallback_soc = 50
+            min_discharge_power = 500
+            max_discharge_power = 5000
+            timeout = "5s"
+            "#,
+            fake.address()
+        ))
+        .unwrap();
+        SunsynkInverter::new(&config)
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_work_mode() {
+        let fake = FakeSunsynk::start().await.unwrap();
+        let mut inverter = fake_inverter(&fake);
+        inverter
+            .set_work_mode(WorkMode::SellingFirst)
+            .await
+            .unwrap();
+        let mut overrides = SettingOverrides::default();
+        let mode = |mode| move |_| mode;
+
+        // Emergency starts during load-shedding and ends after it
+        overrides
+            .acquire(
+                &mut inverter,
+                OverrideOwner::LoadShedding,
+                mode(WorkMode::LimitedToHome),
+            )
+            .await
+            .unwrap();
+        overrides
+            .acquire(
+                &mut inverter,
+                OverrideOwner::Emergency,
+                mode(WorkMode::LimitedToLoad),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            inverter.get_work_mode().await.unwrap(),
+            Some(WorkMode::LimitedToLoad)
+        );
+        overrides
+            .release(&mut inverter, OverrideOwner::LoadShedding)
+            .await
+            .unwrap();
+        assert_eq!(
+            inverter.get_work_mode().await.unwrap(),
+            Some(WorkMode::LimitedToLoad)
+        );
+        overrides
+            .release(&mut inverter, OverrideOwner::Emergency)
+            .await
+            .unwrap();
+        assert_eq!(
+            inverter.get_work_mode().await.unwrap(),
+            Some(WorkMode::SellingFirst)
+        );
+
+        // Load-shedding starts during the emergency and ends after it
+        overrides
+            .acquire(
+                &mut inverter,
+                OverrideOwner::Emergency,
+                mode(WorkMode::LimitedToLoad),
+            )
+            .await
+            .unwrap();
+        overrides
+            .acquire(
+                &mut inverter,
+                OverrideOwner::LoadShedding,
+                mode(WorkMode::LimitedToHome),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            inverter.get_work_mode().await.unwrap(),
+            Some(WorkMode::LimitedToLoad)
+        );
+        overrides
+            .release(&mut inverter, OverrideOwner::Emergency)
+            .await
+            .unwrap();
+        assert_eq!(
+            inverter.get_work_mode().await.unwrap(),
+            Some(WorkMode::LimitedToHome)
+        );
+        overrides
+            .release(&mut inverter, OverrideOwner::LoadShedding)
+            .await
+            .unwrap();
+        assert_eq!(
+            inverter.get_work_mode().await.unwrap(),
+            Some(WorkMode::SellingFirst)
+        );
+        assert_eq!(overrides.original, None);
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_current_limits() {
+        let fake = FakeSunsynk::start().await.unwrap();
+        let mut inverter = fake_inverter(&fake);
+        let original = CurrentLimits {
+            charge: 50.0,
+            discharge: 50.0,
+        };
+        inverter.set_current_limits(&original).await.unwrap();
+        let mut overrides = SettingOverrides::default();
+        let discharge = |discharge| {
+            move |original| CurrentLimits {
+                discharge,
+                ..original
+            }
+        };
+
+        // The discharge limiter wins over peak shaving
+        overrides
+            .acquire(&mut inverter, OverrideOwner::PeakShaving, discharge(100.0))
+            .await
+            .unwrap();
+        overrides
+            .acquire(
+                &mut inverter,
+                OverrideOwner::DischargeLimit,
+                discharge(10.0),
+            )
+            .await
+            .unwrap();
+        let limits = inverter.get_current_limits().await.unwrap();
+        assert_eq!(limits.discharge, 10.0);
+        // Peak shaving is still active after the SoC recovers
+        overrides
+            .release(&mut inverter, OverrideOwner::DischargeLimit)
+            .await
+            .unwrap();
+        let limits = inverter.get_current_limits().await.unwrap();
+        assert_eq!(limits.discharge, 100.0);
+        overrides
+            .release(&mut inverter, OverrideOwner::PeakShaving)
+            .await
+            .unwrap();
+        assert_eq!(inverter.get_current_limits().await.unwrap(), original);
+
+        // Limiting starts before the peak and ends during it
+        overrides
+            .acquire(
+                &mut inverter,
+                OverrideOwner::DischargeLimit,
+                discharge(10.0),
+            )
+            .await
+            .unwrap();
+        overrides
+            .acquire(&mut inverter, OverrideOwner::PeakShaving, discharge(100.0))
+            .await
+            .unwrap();
+        let limits = inverter.get_current_limits().await.unwrap();
+        assert_eq!(limits.discharge, 10.0);
+        overrides
+            .release(&mut inverter, OverrideOwner::DischargeLimit)
+            .await
+            .unwrap();
+        overrides
+            .release(&mut inverter, OverrideOwner::PeakShaving)
+            .await
+            .unwrap();
+        assert_eq!(inverter.get_current_limits().await.unwrap(), original);
+    }
+
+    #[test]
+    fn test_peak_shaving() {
+        let config = PeakShavingConfig {
+            threshold: 5000.0,
+            current: 100.0,
+            hold: std::time::Duration::from_secs(300),
+        };
+        let mut controller = PeakShavingController::new(&config, Arc::default());
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let t = |minutes| start + Duration::minutes(minutes);
+        assert!(!controller.should_shave(t(0), 3000.0));
+        assert!(controller.should_shave(t(1), 6000.0));
+        // Held after the peak passes
+        assert!(controller.should_shave(t(3), 3000.0));
+        assert!(controller.should_shave(t(5), 3000.0));
+        assert!(!controller.should_shave(t(6), 3000.0));
+    }
+
+    #[test]
+    fn test_controlled_load() {
+        let config = ControlledLoadConfig {
+            name: "geyser".to_string(),
+            power: 2000.0,
+            relay: RelayConfig::Shelly {
+                url: "http://192.168.1.50".to_string(),
+                channel: 0,
+            },
+            block_before: std::time::Duration::from_secs(7200),
+            run_for: std::time::Duration::from_secs(3600),
+            min_switch: std::time::Duration::from_secs(900),
+        };
+        let load = ControlledLoad::new(&config, false).unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let timezone = Timezone::from_offset(FixedOffset::east_opt(7200).unwrap());
+        let window = load.window(Some(&timezone), now);
+        assert_eq!(window.start, NaiveTime::from_hms_opt(14, 0, 0).unwrap());
+        assert_eq!(window.end, NaiveTime::from_hms_opt(15, 0, 0).unwrap());
+        let targets = |low| {
+            move |_: &SmartLoadConfig| Targets {
+                target_soc_low: low,
+                target_soc_high: low,
+                alarm_soc: 20.0,
+            }
+        };
+        let mut conditions = LoadConditions {
+            now,
+            current_soc: 80.0,
+            next_outage: None,
+            surplus: 3000.0,
+        };
+        assert_eq!(
+            load.decide(&conditions, &window, targets(50.0)),
+            LoadDecision::Run
+        );
+        // Running it would leave the battery short
+        assert_eq!(
+            load.decide(&conditions, &window, targets(90.0)),
+            LoadDecision::Idle
+        );
+        conditions.next_outage = Some(now + Duration::hours(1));
+        assert_eq!(
+            load.decide(&conditions, &window, targets(50.0)),
+            LoadDecision::Blocked
+        );
+        conditions.next_outage = Some(now + Duration::hours(3));
+        conditions.surplus = 1000.0;
+        assert_eq!(
+            load.decide(&conditions, &window, targets(50.0)),
+            LoadDecision::Idle
+        );
+    }
+
+    #[tokio::test]
+    async fn test_controlled_load_dry_run() {
+        let config = ControlledLoadConfig {
+            name: "geyser".to_string(),
+            power: 2000.0,
+            // Nothing listens here, so switching the relay would fail
+            relay: RelayConfig::Shelly {
+                url: "http://127.0.0.1:1".to_string(),
+                channel: 0,
+            },
+            block_before: std::time::Duration::from_secs(7200),
+            run_for: std::time::Duration::from_secs(3600),
+            min_switch: std::time::Duration::from_secs(0),
+        };
+        let mut load = ControlledLoad::new(&config, true).unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        assert_eq!(
+            load.update(now, LoadDecision::Run).await.as_deref(),
+            Some("switch geyser on")
+        );
+        assert_eq!(load.update(now, LoadDecision::Run).await, None);
+        assert_eq!(
+            load.update(now, LoadDecision::Blocked).await.as_deref(),
+            Some("switch geyser off")
+        );
+    }
+
+    #[test]
+    fn test_generator() {
+        let config = GeneratorConfig {
+            relay: RelayConfig::Tasmota {
+                url: "http://generator.local".to_string(),
+                channel: 1,
+            },
+            start_soc: 25.0,
+            stop_soc: 60.0,
+            min_runtime: std::time::Duration::from_secs(1800),
+            cooldown: std::time::Duration::from_secs(900),
+        };
+        let mut generator = GeneratorSchedule::new(&config);
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let t = |minutes| start + Duration::minutes(minutes);
+        assert!(!generator.should_run(t(0), 20.0, false));
+        assert!(!generator.should_run(t(0), 30.0, true));
+        assert!(generator.should_run(t(0), 20.0, true));
+        generator.set_running(t(0), true);
+        // Runs for the minimum time even if the grid returns
+        assert!(generator.should_run(t(10), 30.0, false));
+        assert!(!generator.should_run(t(40), 30.0, false));
+        assert!(!generator.should_run(t(40), 70.0, true));
+        assert!(generator.should_run(t(40), 50.0, true));
+        generator.set_running(t(40), false);
+        // Cooldown
+        assert!(!generator.should_run(t(50), 20.0, true));
+        assert!(generator.should_run(t(60), 20.0, true));
+    }
+
+    #[tokio::test]
+    async fn test_generator_observe_only() {
+        let fake = FakeSunsynk::start().await.unwrap();
+        // The grid is disconnected, since the register is zero
+        fake.set_soc(20);
+        let mut inverter = fake_inverter(&fake);
+        let config = GeneratorConfig {
+            // Nothing listens here, so switching the relay would fail
+            relay: RelayConfig::Tasmota {
+                url: "http://127.0.0.1:1".to_string(),
+                channel: 1,
+            },
+            start_soc: 25.0,
+            stop_soc: 60.0,
+            min_runtime: std::time::Duration::from_secs(0),
+            cooldown: std::time::Duration::from_secs(0),
+        };
+        let esp = EspStatus::default();
+        let mut generator = GeneratorController {
+            schedule: GeneratorSchedule::new(&config),
+            relay: Relay::new(&config.relay).unwrap(),
+            esp: &esp,
+            esp_timeout: Duration::hours(1),
+            dry_run: true,
+        };
+        generator
+            .update(&mut inverter, &mut NullMonitor)
+            .await
+            .unwrap();
+        assert!(generator.schedule.started.is_some());
+        fake.set_soc(70);
+        generator
+            .update(&mut inverter, &mut NullMonitor)
+            .await
+            .unwrap();
+        assert!(generator.schedule.started.is_none());
+        fake.set_soc(20);
+        generator
+            .update(&mut inverter, &mut NullMonitor)
+            .await
+            .unwrap();
+        generator.shutdown(&mut inverter).await;
+        assert!(generator.schedule.started.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ev_throttle_dry_run() {
+        let config = EvChargerConfig {
+            // Nothing listens here, so setting the current would fail
+            charger: EvChargerKind::Openevse {
+                url: "http://127.0.0.1:1".to_string(),
+            },
+            max_current: 32,
+            throttle_current: 6,
+            release_surplus: 1500.0,
+            min_switch: std::time::Duration::from_secs(0),
+        };
+        let mut ev_charger = EvThrottle::new(&config, true).unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        assert_eq!(
+            ev_charger.update(now, true).await.as_deref(),
+            Some("EV charge current to 6 A")
+        );
+        assert_eq!(ev_charger.update(now, true).await, None);
+        assert_eq!(
+            ev_charger.update(now, false).await.as_deref(),
+            Some("EV charge current to 32 A")
+        );
+    }
+
+    #[test]
+    fn test_emergency_target() {
+        let config = EmergencyConfig {
+            target_soc: 100.0,
+            work_mode: None,
+        };
+        let mut emergency = EmergencyResponder::new(&config, Arc::default());
+        assert_eq!(emergency.target(30.0, 40.0, 25.0, 40.0), 40.0);
+        assert_eq!(emergency.target(24.0, 40.0, 25.0, 40.0), 100.0);
+        assert!(emergency.active);
+        // Stays active until the SoC reaches the low target
+        assert_eq!(emergency.target(35.0, 40.0, 25.0, 40.0), 100.0);
+        assert_eq!(emergency.target(40.0, 40.0, 25.0, 40.0), 40.0);
+        assert!(!emergency.active);
+    }
+
+    #[test]
+    fn test_outage_detector() {
+        let config = OutageConfig {
+            assumed_duration: std::time::Duration::from_secs(3600),
+        };
+        let mut outage = OutageDetector::new(&config);
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let t = |minutes| start + Duration::minutes(minutes);
+        assert!(outage.update(t(0), Some(true), false).is_none());
+        // Scheduled outages are already planned for
+        assert!(outage.update(t(1), Some(false), true).is_none());
+        // Still down once the scheduled period ends
+        let event = outage.update(t(120), Some(false), false).unwrap();
+        assert_eq!(event.start, t(1));
+        assert_eq!(event.end, t(180));
+        assert!(outage.update(t(121), Some(true), false).is_none());
+        assert!(outage.start.is_none());
+        // Unscheduled from the start, and unknown readings don't end it
+        assert!(outage.update(t(200), Some(false), false).is_some());
+        let event = outage.update(t(210), None, false).unwrap();
+        assert_eq!(event.start, t(200));
+        assert_eq!(event.end, t(270));
+        assert!(outage.update(t(220), Some(true), false).is_none());
+    }
+
+    #[test]
+    fn test_cost_tracker() {
+        let wear = WearConfig {
+            cost: 0.5,
+            margin: 0.0,
+        };
+        let tariff = TariffConfig {
+            periods: vec![TariffPeriodConfig {
+                name: None,
+                start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+                price: 2.0,
+            }],
+            weekend: None,
+        };
+        let mut tracker = CostTracker {
+            wear: &wear,
+            tariff: Some(&tariff),
+            timezone: Some(&Timezone::from_offset(FixedOffset::east_opt(0).unwrap())),
+            holidays: Holidays::default(),
+            day: None,
+            last: None,
+            wear_cost: 0.0,
+            grid_cost: 0.0,
+        };
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 11, 0, 0).unwrap();
+        let t = |minutes| start + Duration::minutes(minutes);
+        assert_eq!(tracker.add(t(0), 1200.0, Some(600.0)), (0.0, Some(0.0)));
+        let (wear_cost, grid_cost) = tracker.add(t(5), -1000.0, Some(3000.0));
+        assert!((wear_cost - 0.05).abs() < 1e-9);
+        assert!((grid_cost.unwrap() - 0.1).abs() < 1e-9);
+        // Charging does not add wear, and there is no price after noon
+        tracker.add(t(65), 0.0, Some(3000.0));
+        let (wear_cost, grid_cost) = tracker.add(t(70), 0.0, Some(3000.0));
+        assert!((wear_cost - 0.05).abs() < 1e-9);
+        assert!((grid_cost.unwrap() - 0.1).abs() < 1e-9);
+        // Totals reset at midnight
+        assert_eq!(tracker.add(t(780), 0.0, None), (0.0, Some(0.0)));
+    }
+
+    #[test]
+    fn test_fallback_soc_monthly() {
+        let config: InverterConfig = toml::from_str(
+            r#"
+            device = "/dev/ttyUSB0"
+            min_soc = 20
+            fallback_soc = [40, 40, 45, 50, 55, 60, 60, 60, 55, 50, 45, 40]
+            min_discharge_power = 500
+            max_discharge_power = 5000
+            timezone = "UTC+2"
+            "#,
+        )
+        .unwrap();
+        let june = Utc.with_ymd_and_hms(2024, 6, 15, 12, 0, 0).unwrap();
+        assert_eq!(config.fallback_soc_at(june), 60.0);
+        // Already January in the inverter's time zone
+        let new_year = Utc.with_ymd_and_hms(2024, 12, 31, 23, 0, 0).unwrap();
+        assert_eq!(config.fallback_soc_at(new_year), 40.0);
+    }
+
+    #[test]
+    fn test_tariff_holidays() {
+        let period = |price| TariffPeriodConfig {
+            name: None,
+            start: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+            price,
+        };
+        let tariff = TariffConfig {
+            periods: vec![period(2.0)],
+            weekend: Some(vec![period(1.0)]),
+        };
+        // A Monday
+        let monday = NaiveDate::from_ymd_opt(2024, 6, 3)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        assert_eq!(tariff.price(monday, &Holidays::default()), Some(2.0));
+        let holidays = Holidays::new([monday.date()]);
+        assert_eq!(tariff.price(monday, &holidays), Some(1.0));
+        assert_eq!(tariff.cheapest(true).next(), Some(&period(1.0)));
     }
 }
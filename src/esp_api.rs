@@ -16,17 +16,34 @@
 
 use chrono::naive::NaiveDate;
 use chrono::{DateTime, Utc};
+#[cfg(feature = "daemon")]
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "daemon")]
 use std::time::Duration;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Event {
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
     pub note: String,
 }
 
+impl Event {
+    /// Load-shedding stage, parsed from the note (e.g. "Stage 4"). If the
+    /// note names several stages, the highest is returned.
+    pub fn stage(&self) -> Option<u32> {
+        self.note
+            .split("Stage ")
+            .skip(1)
+            .filter_map(|rest| {
+                let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+                digits.parse().ok()
+            })
+            .max()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Info {
     pub name: String,
@@ -53,11 +70,26 @@ pub struct AreaResponse {
     pub schedule: Schedule,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct Allowance {
+    pub count: i64,
+    pub limit: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AllowanceResponse {
+    pub allowance: Allowance,
+}
+
+#[cfg(feature = "daemon")]
 pub struct API {
     key: String,
     client: Client,
 }
 
+#[cfg(feature = "daemon")]
 impl API {
     pub fn new(key: impl Into<String>) -> reqwest::Result<Self> {
         Ok(Self {
@@ -79,4 +111,34 @@ impl API {
             .json()
             .await
     }
+
+    /// Check the API quota (this does not count against the quota)
+    pub async fn allowance(&self) -> reqwest::Result<AllowanceResponse> {
+        self.client
+            .get("https://developer.sepush.co.za/business/2.0/api_allowance")
+            .header("Token", &self.key)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stage() {
+        let event = |note: &str| Event {
+            start: Utc::now(),
+            end: Utc::now(),
+            note: note.to_string(),
+        };
+        assert_eq!(event("Stage 4").stage(), Some(4));
+        assert_eq!(event("Stage 2 / Stage 6").stage(), Some(6));
+        assert_eq!(event("Stage 10").stage(), Some(10));
+        assert_eq!(event("Unknown").stage(), None);
+    }
 }
@@ -0,0 +1,99 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Control of EV chargers over HTTP
+
+use reqwest::Client;
+use std::time::Duration;
+
+use crate::config::EvChargerKind;
+
+pub struct EvCharger {
+    client: Client,
+    kind: EvChargerKind,
+}
+
+impl EvCharger {
+    pub fn new(kind: &EvChargerKind) -> reqwest::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            kind: kind.clone(),
+        })
+    }
+
+    /// URLs to request (in order) to set the charge current, where 0 pauses charging
+    fn urls(&self, current: u32) -> Vec<String> {
+        match &self.kind {
+            EvChargerKind::Openevse { url } => {
+                let url = url.trim_end_matches('/');
+                let rapi = |command: &str| format!("{url}/r?json=1&rapi=%24{command}");
+                if current == 0 {
+                    vec![rapi("FS")]
+                } else {
+                    vec![rapi(&format!("SC+{current}")), rapi("FE")]
+                }
+            }
+            EvChargerKind::GoE { url } => {
+                let url = url.trim_end_matches('/');
+                if current == 0 {
+                    vec![format!("{url}/api/set?frc=1")]
+                } else {
+                    vec![format!("{url}/api/set?amp={current}&frc=0")]
+                }
+            }
+        }
+    }
+
+    /// Set the charge current (A), pausing charging if it is 0.
+    pub async fn set_current(&self, current: u32) -> reqwest::Result<()> {
+        for url in self.urls(current) {
+            self.client.get(url).send().await?.error_for_status()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_urls() {
+        let openevse = EvCharger::new(&EvChargerKind::Openevse {
+            url: "http://openevse.local/".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            openevse.urls(16),
+            [
+                "http://openevse.local/r?json=1&rapi=%24SC+16",
+                "http://openevse.local/r?json=1&rapi=%24FE"
+            ]
+        );
+        assert_eq!(
+            openevse.urls(0),
+            ["http://openevse.local/r?json=1&rapi=%24FS"]
+        );
+        let go_e = EvCharger::new(&EvChargerKind::GoE {
+            url: "http://192.168.1.60".to_string(),
+        })
+        .unwrap();
+        assert_eq!(go_e.urls(0), ["http://192.168.1.60/api/set?frc=1"]);
+        assert_eq!(go_e.urls(10), ["http://192.168.1.60/api/set?amp=10&frc=0"]);
+    }
+}
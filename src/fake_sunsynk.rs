@@ -0,0 +1,394 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Fake Sunsynk inverter for testing
+//!
+//! [FakeSunsynk] runs a Modbus TCP server on the loopback interface that
+//! holds the inverter's registers, so that [SunsynkInverter] can be tested
+//! end to end by pointing its `device` at [FakeSunsynk::address]. The
+//! register encodings here are written out independently of the inverter
+//! code so that they catch mistakes in it.
+//!
+//! This module is only available with the `test-support` feature.
+//!
+//! [SunsynkInverter]: crate::sunsynk::SunsynkInverter
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use std::future::{ready, Ready};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
+use tokio_modbus::server::Service;
+use tokio_modbus::{ExceptionCode, Request, Response};
+
+use crate::inverter::Program;
+
+const NUM_REGISTERS: usize = 1 << 16;
+const NUM_PROGRAMS: usize = 6;
+const REG_DEVICE_TYPE: u16 = 0;
+const REG_SERIAL: u16 = 3;
+const REG_CLOCK: u16 = 22;
+const REG_BATTERY_CAPACITY_AH: u16 = 204;
+const REG_BATTERY_RESTART_VOLTAGE: u16 = 221;
+const REG_GRID_CHARGE_CURRENT: u16 = 230;
+
+#[derive(Default)]
+struct State {
+    registers: Vec<u16>,
+    /// Address and values of each write request
+    writes: Vec<(u16, Vec<u16>)>,
+}
+
+impl State {
+    fn read(&self, addr: u16, cnt: u16) -> Result<Vec<u16>, ExceptionCode> {
+        let start = addr as usize;
+        let end = start + cnt as usize;
+        if end > NUM_REGISTERS {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        Ok(self.registers[start..end].to_vec())
+    }
+
+    fn write(&mut self, addr: u16, words: &[u16]) -> Result<(), ExceptionCode> {
+        let start = addr as usize;
+        let end = start + words.len();
+        if end > NUM_REGISTERS {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        self.registers[start..end].copy_from_slice(words);
+        self.writes.push((addr, words.to_vec()));
+        Ok(())
+    }
+}
+
+struct FakeService {
+    state: Arc<Mutex<State>>,
+}
+
+impl Service for FakeService {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = Ready<Result<Response, ExceptionCode>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let mut state = self.state.lock().unwrap();
+        ready(match req {
+            Request::ReadHoldingRegisters(addr, cnt) => {
+                state.read(addr, cnt).map(Response::ReadHoldingRegisters)
+            }
+            Request::WriteSingleRegister(addr, word) => state
+                .write(addr, &[word])
+                .map(|()| Response::WriteSingleRegister(addr, word)),
+            Request::WriteMultipleRegisters(addr, words) => state
+                .write(addr, &words)
+                .map(|()| Response::WriteMultipleRegisters(addr, words.len() as u16)),
+            _ => Err(ExceptionCode::IllegalFunction),
+        })
+    }
+}
+
+/// A fake single-phase or three-phase Sunsynk inverter.
+///
+/// The server stops when this is dropped.
+pub struct FakeSunsynk {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    server: JoinHandle<()>,
+}
+
+impl FakeSunsynk {
+    /// Start a server on an unused port.
+    ///
+    /// The inverter starts out as a single-phase model with a 100 Ah, 50 V
+    /// battery at 50% and the clock set to 2024-01-01 12:00:00. Every other
+    /// register is zero except for the program times, which are spread
+    /// through the day as on a new inverter.
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            registers: vec![0; NUM_REGISTERS],
+            writes: Vec::new(),
+        }));
+        let server_state = state.clone();
+        let server = tokio::spawn(async move {
+            let on_connected = |stream, addr| {
+                let state = server_state.clone();
+                async move {
+                    accept_tcp_connection(stream, addr, |_| {
+                        Ok(Some(FakeService {
+                            state: state.clone(),
+                        }))
+                    })
+                }
+            };
+            let _ = Server::new(listener).serve(&on_connected, |_| {}).await;
+        });
+        let fake = Self {
+            address,
+            state,
+            server,
+        };
+        fake.set(REG_DEVICE_TYPE, &[3]);
+        fake.set(REG_SERIAL, &serial_registers("FAKE000001"));
+        fake.set(REG_BATTERY_CAPACITY_AH, &[100]);
+        fake.set(REG_BATTERY_RESTART_VOLTAGE, &[5000]);
+        fake.set(REG_GRID_CHARGE_CURRENT, &[40]);
+        fake.set_soc(50);
+        let times: Vec<u16> = (0..NUM_PROGRAMS as u16).map(|i| i * 400).collect();
+        fake.set(fake.program_base(), &times);
+        fake.set_clock(
+            NaiveDate::from_ymd_opt(2024, 1, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+        );
+        Ok(fake)
+    }
+
+    /// Address to use as the inverter `device`
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Set registers starting at `addr`. This is not recorded as a write.
+    pub fn set(&self, addr: u16, words: &[u16]) {
+        let start = addr as usize;
+        self.state.lock().unwrap().registers[start..start + words.len()].copy_from_slice(words);
+    }
+
+    /// Get `cnt` registers starting at `addr`
+    pub fn get(&self, addr: u16, cnt: u16) -> Vec<u16> {
+        let start = addr as usize;
+        self.state.lock().unwrap().registers[start..start + cnt as usize].to_vec()
+    }
+
+    /// Take the writes made by clients so far, as the address and values of
+    /// each request
+    pub fn take_writes(&self) -> Vec<(u16, Vec<u16>)> {
+        std::mem::take(&mut self.state.lock().unwrap().writes)
+    }
+
+    fn three_phase(&self) -> bool {
+        matches!(self.get(REG_DEVICE_TYPE, 1)[0], 5 | 6)
+    }
+
+    /// First register of the programs, which depends on the model
+    fn program_base(&self) -> u16 {
+        if self.three_phase() {
+            148
+        } else {
+            250
+        }
+    }
+
+    /// Set the device type, which selects the register layout
+    pub fn set_device_type(&self, device_type: u16) {
+        // Keep the programs when the layout changes
+        let programs = self.get(self.program_base(), 5 * NUM_PROGRAMS as u16);
+        self.set(REG_DEVICE_TYPE, &[device_type]);
+        self.set(self.program_base(), &programs);
+    }
+
+    /// Set the battery state of charge (%)
+    pub fn set_soc(&self, soc: u16) {
+        let addr = if self.three_phase() { 588 } else { 184 };
+        self.set(addr, &[soc]);
+    }
+
+    /// Set the inverter's clock. The clock does not advance by itself.
+    pub fn set_clock(&self, time: NaiveDateTime) {
+        self.set(
+            REG_CLOCK,
+            &[
+                ((time.year() as u16 - 2000) << 8) | time.month() as u16,
+                ((time.day() as u16) << 8) | time.hour() as u16,
+                ((time.minute() as u16) << 8) | time.second() as u16,
+            ],
+        );
+    }
+
+    /// The time-of-use programs currently in the registers
+    pub fn programs(&self) -> Vec<Program> {
+        let n = NUM_PROGRAMS;
+        let block = self.get(self.program_base(), 5 * n as u16);
+        (0..n)
+            .map(|i| Program {
+                time: NaiveTime::from_hms_opt((block[i] / 100) as u32, (block[i] % 100) as u32, 0)
+                    .unwrap_or_default(),
+                power: block[n + i],
+                soc: block[3 * n + i],
+                grid_charge: block[4 * n + i] & 1 != 0,
+            })
+            .collect()
+    }
+}
+
+impl Drop for FakeSunsynk {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Pack an ASCII serial number two characters per register
+fn serial_registers(serial: &str) -> Vec<u16> {
+    serial
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::InverterConfig;
+    use crate::inverter::Inverter;
+    use crate::sunsynk::SunsynkInverter;
+
+    /// Create an inverter connected to `fake`, with extra configuration
+    fn inverter_with(fake: &FakeSunsynk, extra: &str) -> SunsynkInverter {
+        let config: InverterConfig = toml::from_str(&format!(
+            r#"
+            device = "{}"
+            min_soc = 20
+            fallback_soc = 50
+            min_discharge_power = 500
+            max_discharge_power = 5000
+            timeout = "5s"
+            {extra}
+            "#,
+            fake.address()
+        ))
+        .unwrap();
+        SunsynkInverter::new(&config)
+    }
+
+    fn inverter(fake: &FakeSunsynk) -> SunsynkInverter {
+        inverter_with(fake, "")
+    }
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_info() {
+        let fake = FakeSunsynk::start().await.unwrap();
+        let mut inverter = inverter(&fake);
+        let info = inverter.get_info().await.unwrap();
+        assert_eq!(info.capacity, 5000.0);
+        assert_eq!(info.charge_power, 2000.0);
+        assert!(!info.smart_load);
+        assert_eq!(inverter.get_soc().await.unwrap(), 50.0);
+        assert_eq!(fake.take_writes(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_set_min_soc_wrap() {
+        let fake = FakeSunsynk::start().await.unwrap();
+        let clock = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(23, 58, 0)
+            .unwrap();
+        fake.set_clock(clock);
+        let mut inverter = inverter(&fake);
+        inverter.set_min_soc(60.4, 20.0).await.unwrap();
+        let programs = fake.programs();
+        let times: Vec<NaiveTime> = programs.iter().map(|p| p.time).collect();
+        let socs: Vec<u16> = programs.iter().map(|p| p.soc).collect();
+        assert_eq!(
+            times,
+            vec![
+                time(0, 10),
+                time(0, 15),
+                time(0, 20),
+                time(0, 25),
+                time(0, 30),
+                time(23, 50)
+            ]
+        );
+        assert_eq!(socs, vec![20, 20, 20, 20, 20, 60]);
+        // Only the changed times and SoCs are written
+        assert!(fake
+            .take_writes()
+            .iter()
+            .all(|(addr, _)| (250..256).contains(addr) || (268..274).contains(addr)));
+    }
+
+    #[tokio::test]
+    async fn test_batch_keeps_power_and_charge() {
+        let fake = FakeSunsynk::start().await.unwrap();
+        // Program power and charge flags set by the user
+        fake.set(256, &[1000, 2000, 3000, 4000, 5000, 6000]);
+        fake.set(274, &[1, 0, 1, 2, 3, 0]);
+        let mut inverter = inverter_with(&fake, "batch_writes = true");
+        inverter.set_min_soc(60.0, 20.0).await.unwrap();
+        assert_eq!(fake.take_writes().len(), 1);
+        assert_eq!(fake.programs()[1].soc, 20);
+        assert_eq!(fake.get(256, 6), vec![1000, 2000, 3000, 4000, 5000, 6000]);
+        assert_eq!(fake.get(274, 6), vec![1, 0, 1, 2, 3, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_three_phase() {
+        let fake = FakeSunsynk::start().await.unwrap();
+        fake.set_device_type(5);
+        fake.set_soc(77);
+        // The clock register is not known, so the time zone must be given
+        let mut inverter = inverter(&fake);
+        assert!(inverter.set_min_soc(30.0, 40.0).await.is_err());
+        let mut inverter = inverter_with(&fake, r#"timezone = "+02:00""#);
+        assert_eq!(inverter.get_soc().await.unwrap(), 77.0);
+        inverter.set_min_soc(30.0, 40.0).await.unwrap();
+        assert_eq!(fake.programs()[0].soc, 30);
+        assert!(fake.take_writes().iter().all(|(addr, _)| *addr < 250));
+        // Registers whose three-phase addresses are unknown are not used
+        let telemetry = inverter.get_telemetry().await.unwrap().unwrap();
+        assert_eq!(telemetry.grid_connected, None);
+        assert_eq!(telemetry.faults, None);
+        assert_eq!(inverter.get_aux_power().await.unwrap(), None);
+        assert_eq!(inverter.get_clock().await.unwrap(), None);
+        assert!(inverter.set_trickle(100.0).await.is_err());
+        assert!(inverter.get_current_limits().await.is_err());
+        assert!(inverter.get_info().await.is_err());
+        assert_eq!(fake.take_writes(), vec![]);
+        let mut inverter = inverter_with(&fake, "capacity_wh = 10000\ncharge_power = 3000");
+        let info = inverter.get_info().await.unwrap();
+        assert_eq!(info.capacity, 10000.0);
+        assert_eq!(info.charge_power, 3000.0);
+    }
+
+    #[tokio::test]
+    async fn test_clock() {
+        let fake = FakeSunsynk::start().await.unwrap();
+        let mut inverter = inverter(&fake);
+        let clock = NaiveDate::from_ymd_opt(2025, 3, 4)
+            .unwrap()
+            .and_hms_opt(5, 6, 7)
+            .unwrap();
+        inverter.set_clock(clock).await.unwrap();
+        assert_eq!(
+            fake.get(REG_CLOCK, 3),
+            vec![(25 << 8) | 3, (4 << 8) | 5, (6 << 8) | 7]
+        );
+        assert_eq!(inverter.get_clock().await.unwrap(), Some(clock));
+    }
+}
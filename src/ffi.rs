@@ -0,0 +1,165 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! C ABI for the planner and sun model
+//!
+//! Structured inputs and outputs are passed as JSON strings, using the serde
+//! representations of the types in [crate::planner]. Strings returned by
+//! these functions must be freed with [socit_free_string]. See
+//! `python/socit_planner.py` for a wrapper using ctypes.
+//!
+//! The same functions are exported when building for WebAssembly (with
+//! `--no-default-features`). Since JavaScript cannot allocate memory inside
+//! the module, [socit_alloc_string] provides buffers for input strings. See
+//! `web/socit_planner.js` for a wrapper.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::ffi::{c_char, CStr, CString};
+
+use crate::config::PanelConfig;
+use crate::esp_api::Event;
+use crate::planner::{compute_targets, panels_power, Battery, LoadModel, Targets};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetsRequest {
+    #[serde(default)]
+    events: Vec<Event>,
+    #[serde(default)]
+    panels: Vec<PanelConfig>,
+    battery: Battery,
+    load: LoadModel,
+    now: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Response {
+    Targets(Targets),
+    Error { error: String },
+}
+
+fn targets_json(request: &str) -> String {
+    let response = match serde_json::from_str::<TargetsRequest>(request) {
+        Ok(r) => Response::Targets(compute_targets(
+            &r.events, &r.panels, &r.battery, &r.load, r.now,
+        )),
+        Err(err) => Response::Error {
+            error: err.to_string(),
+        },
+    };
+    serde_json::to_string(&response).unwrap()
+}
+
+fn to_c_string(s: String) -> *mut c_char {
+    // JSON output never contains NUL bytes
+    CString::new(s).unwrap().into_raw()
+}
+
+/// Compute target states of charge.
+///
+/// The request is a JSON object with keys `events`, `panels`, `battery`,
+/// `load` and `now`. The result is a JSON object with the targets, or with a
+/// single `error` key if the request could not be parsed.
+///
+/// # Safety
+///
+/// `request` must point to a NUL-terminated string. The return value must be
+/// freed with [socit_free_string].
+#[no_mangle]
+pub unsafe extern "C" fn socit_compute_targets(request: *const c_char) -> *mut c_char {
+    let request = CStr::from_ptr(request).to_string_lossy();
+    to_c_string(targets_json(&request))
+}
+
+/// Predicted clear-sky power (W) from a JSON list of panels at a time given
+/// in seconds since the UNIX epoch. Returns NaN if the input is invalid.
+///
+/// # Safety
+///
+/// `panels` must point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn socit_panels_power(panels: *const c_char, time: f64) -> f64 {
+    let panels = CStr::from_ptr(panels).to_string_lossy();
+    let Ok(panels) = serde_json::from_str::<Vec<PanelConfig>>(&panels) else {
+        return f64::NAN;
+    };
+    match DateTime::from_timestamp_millis((time * 1000.0) as i64) {
+        Some(time) => panels_power(&panels, time),
+        None => f64::NAN,
+    }
+}
+
+/// Allocate a string of `len` bytes (plus a NUL terminator) to be filled in
+/// by the caller, for passing to the other functions.
+///
+/// The caller must not store any NUL bytes in it, and must free it with
+/// [socit_free_string].
+#[no_mangle]
+pub extern "C" fn socit_alloc_string(len: usize) -> *mut c_char {
+    to_c_string(" ".repeat(len))
+}
+
+/// Free a string returned by one of the other functions.
+///
+/// # Safety
+///
+/// `s` must have been returned by this library and not already freed, or be
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn socit_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compute_targets() {
+        let request = CString::new(
+            r#"{
+                "events": [{
+                    "start": "2024-06-01T16:00:00Z",
+                    "end": "2024-06-01T18:00:00Z",
+                    "note": "Stage 2"
+                }],
+                "battery": {"capacity": 5000, "min_soc": 20, "charge_power": 2000},
+                "load": {"min_discharge_power": 100, "max_discharge_power": 500},
+                "now": "2024-06-01T12:00:00Z"
+            }"#,
+        )
+        .unwrap();
+        let response = unsafe {
+            let raw = socit_compute_targets(request.as_ptr());
+            let response = CStr::from_ptr(raw).to_str().unwrap().to_string();
+            socit_free_string(raw);
+            response
+        };
+        let targets: serde_json::Value = serde_json::from_str(&response).unwrap();
+        let low = targets["target_soc_low"].as_f64().unwrap();
+        assert!((low - 40.0).abs() < 1e-6, "{response}");
+    }
+
+    #[test]
+    fn test_error() {
+        let response: serde_json::Value = serde_json::from_str(&targets_json("{}")).unwrap();
+        assert!(response["error"].is_string());
+    }
+}
@@ -0,0 +1,221 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Append monitoring data to local files
+//!
+//! A new file is started for each kind of update every day (UTC), and
+//! optionally old files are deleted. Parquet files cannot be appended to, so
+//! for that format the updates are staged in a JSON lines file, which is
+//! converted once the day is over.
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::config::{FileConfig, FileFormat};
+use crate::monitoring::{CoilUpdate, HealthUpdate, Monitor, PvUpdate, SocUpdate};
+use crate::parquet;
+
+const STAGING_SUFFIX: &str = ".jsonl.partial";
+
+/// Row in the PV file (there is one row per string)
+#[derive(Serialize)]
+struct PvRecord {
+    time: DateTime<Utc>,
+    mppt: usize,
+    predicted: f64,
+    measured: f64,
+    ratio: Option<f64>,
+}
+
+pub struct FileMonitor {
+    directory: PathBuf,
+    format: FileFormat,
+    max_files: Option<usize>,
+}
+
+impl FileMonitor {
+    pub fn new(config: &FileConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.directory)?;
+        info!(
+            "Writing monitoring data to {}",
+            config.directory.to_string_lossy()
+        );
+        Ok(Self {
+            directory: config.directory.clone(),
+            format: config.format,
+            max_files: config.max_files,
+        })
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            FileFormat::Csv => "csv",
+            FileFormat::Jsonl => "jsonl",
+            FileFormat::Parquet => "parquet",
+        }
+    }
+
+    fn staging_path(&self, kind: &str, date: NaiveDate) -> PathBuf {
+        self.directory
+            .join(format!("socit-{kind}-{date}{STAGING_SUFFIX}"))
+    }
+
+    /// Convert staged files from days before `today` to Parquet
+    fn convert_staged(&self, kind: &str, today: NaiveDate) -> Result<(), Box<dyn Error>> {
+        let prefix = format!("socit-{kind}-");
+        for entry in std::fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(date) = name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(STAGING_SUFFIX))
+                .and_then(|date| date.parse::<NaiveDate>().ok())
+            else {
+                continue;
+            };
+            if date >= today {
+                continue;
+            }
+            let staged = entry.path();
+            let mut rows = Vec::new();
+            for line in std::fs::read_to_string(&staged)?.lines() {
+                match serde_json::from_str(line) {
+                    Ok(row) => rows.push(row),
+                    // A partial line may be left if the process was killed
+                    Err(err) => warn!("Skipping bad line in {}: {err}", staged.display()),
+                }
+            }
+            let path = self.path(kind, date);
+            let tmp_path = path.with_extension("parquet.tmp");
+            parquet::write(std::io::BufWriter::new(File::create(&tmp_path)?), &rows)?;
+            std::fs::rename(&tmp_path, &path)?;
+            std::fs::remove_file(&staged)?;
+            info!("Converted {} to {}", staged.display(), path.display());
+        }
+        Ok(())
+    }
+
+    fn path(&self, kind: &str, date: NaiveDate) -> PathBuf {
+        self.directory
+            .join(format!("socit-{kind}-{date}.{}", self.extension()))
+    }
+
+    /// Delete all but the newest `max_files` files of a given kind
+    fn remove_old(&self, kind: &str) -> std::io::Result<()> {
+        let Some(max_files) = self.max_files else {
+            return Ok(());
+        };
+        let prefix = format!("socit-{kind}-");
+        let suffix = format!(".{}", self.extension());
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(&prefix) && name.ends_with(&suffix) {
+                paths.push(entry.path());
+            }
+        }
+        // The date format sorts lexicographically
+        paths.sort();
+        let excess = paths.len().saturating_sub(max_files);
+        for path in &paths[..excess] {
+            info!("Removing old monitoring file {}", path.to_string_lossy());
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn append<T: Serialize>(
+        &self,
+        kind: &str,
+        time: DateTime<Utc>,
+        records: &[T],
+    ) -> Result<(), Box<dyn Error>> {
+        let date = time.date_naive();
+        let path = match self.format {
+            FileFormat::Parquet => self.staging_path(kind, date),
+            _ => self.path(kind, date),
+        };
+        let is_new = !path.exists();
+        let file: File = OpenOptions::new().create(true).append(true).open(&path)?;
+        match self.format {
+            FileFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(is_new)
+                    .from_writer(file);
+                for record in records {
+                    writer.serialize(record)?;
+                }
+                writer.flush()?;
+            }
+            FileFormat::Jsonl | FileFormat::Parquet => {
+                let mut writer = std::io::BufWriter::new(file);
+                for record in records {
+                    serde_json::to_writer(&mut writer, record)?;
+                    writer.write_all(b"\n")?;
+                }
+                writer.flush()?;
+            }
+        }
+        if is_new && self.format == FileFormat::Parquet {
+            if let Err(err) = self.convert_staged(kind, date) {
+                warn!("Failed to convert monitoring files to Parquet: {err}");
+            }
+        }
+        if is_new {
+            if let Err(err) = self.remove_old(kind) {
+                warn!("Failed to remove old monitoring files: {err}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Monitor for FileMonitor {
+    async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
+        self.append("soc", update.time, &[&update])
+    }
+
+    async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>> {
+        self.append("coil", update.time, &[&update])
+    }
+
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>> {
+        let records: Vec<_> = update
+            .strings
+            .iter()
+            .map(|string| PvRecord {
+                time: update.time,
+                mppt: string.mppt,
+                predicted: string.predicted,
+                measured: string.measured,
+                ratio: string.ratio,
+            })
+            .collect();
+        self.append("pv", update.time, &records)
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        self.append("health", update.time, &[&update])
+    }
+}
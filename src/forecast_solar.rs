@@ -0,0 +1,132 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Client for the [forecast.solar](https://forecast.solar) PV forecast API
+
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::{ForecastSolarConfig, PanelConfig, PanelFaceConfig};
+use crate::planner::PvForecast;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EstimateResponse {
+    /// Forecast power (W), keyed by time
+    pub result: HashMap<String, f64>,
+}
+
+impl EstimateResponse {
+    /// Convert to a forecast, ignoring any times that cannot be parsed
+    pub fn forecast(&self) -> PvForecast {
+        PvForecast::new(
+            self.result
+                .iter()
+                .filter_map(|(time, power)| {
+                    let time = DateTime::parse_from_rfc3339(time).ok()?;
+                    Some((time.with_timezone(&Utc), *power))
+                })
+                .collect(),
+        )
+    }
+}
+
+pub struct ForecastSolar {
+    client: Client,
+    url: String,
+}
+
+/// Convert a compass azimuth (0 = North, 90 = East) to the convention used
+/// by forecast.solar (-180 = North, -90 = East, 0 = South, 90 = West).
+fn azimuth(compass: f64) -> f64 {
+    (compass - 180.0 + 540.0).rem_euclid(360.0) - 180.0
+}
+
+impl ForecastSolar {
+    pub fn new(config: &ForecastSolarConfig) -> reqwest::Result<Self> {
+        let base = config.url.trim_end_matches('/');
+        let url = match &config.key {
+            Some(key) => format!("{base}/{key}"),
+            None => base.to_string(),
+        };
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            url,
+        })
+    }
+
+    /// Fetch the estimate for one orientation of a set of panels
+    pub async fn estimate(
+        &self,
+        panels: &PanelConfig,
+        face: &PanelFaceConfig,
+    ) -> reqwest::Result<EstimateResponse> {
+        self.client
+            .get(format!(
+                "{}/estimate/watts/{}/{}/{}/{}/{}",
+                self.url,
+                panels.latitude,
+                panels.longitude,
+                face.tilt,
+                azimuth(face.azimuth),
+                face.power * 1e-3
+            ))
+            .query(&[("time", "utc")])
+            .header("Accept", "application/json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_azimuth() {
+        assert_eq!(azimuth(0.0), -180.0);
+        assert_eq!(azimuth(90.0), -90.0);
+        assert_eq!(azimuth(180.0), 0.0);
+        assert_eq!(azimuth(270.0), 90.0);
+    }
+
+    #[test]
+    fn test_forecast() {
+        let json = r#"{
+            "result": {
+                "2024-06-01T06:00:00+00:00": 0,
+                "2024-06-01T07:00:00+00:00": 250,
+                "bad": 1000
+            },
+            "message": {"code": 0, "type": "success", "text": ""}
+        }"#;
+        let response: EstimateResponse = serde_json::from_str(json).unwrap();
+        let forecast = response.forecast();
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 6, 0, 0).unwrap();
+        assert_eq!(
+            forecast.points,
+            vec![(start, 0.0), (start + chrono::Duration::hours(1), 250.0)]
+        );
+    }
+}
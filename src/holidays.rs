@@ -0,0 +1,186 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Public holidays, which follow the weekend load profile and tariff
+//!
+//! Holidays are given as a list of dates and/or an iCalendar (ICS) file,
+//! such as those published for public holidays. Each event in the file
+//! marks the days it covers as holidays. Recurrence rules are not expanded,
+//! so the file needs to list each year's holidays.
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::config::HolidaysConfig;
+
+/// Longest event (in days) accepted from an ICS file
+const MAX_EVENT_DAYS: i64 = 366;
+
+/// Set of holiday dates (local time)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Holidays {
+    dates: BTreeSet<NaiveDate>,
+}
+
+impl Holidays {
+    pub fn new(dates: impl IntoIterator<Item = NaiveDate>) -> Self {
+        Self {
+            dates: dates.into_iter().collect(),
+        }
+    }
+
+    /// Load the configured dates and ICS file. If the file can't be read or
+    /// parsed, a warning is logged and only the listed dates are used.
+    pub fn load(config: &HolidaysConfig) -> Self {
+        let mut holidays = Self::new(config.dates.iter().copied());
+        if let Some(path) = &config.ics {
+            match std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| parse_ics(&text))
+            {
+                Ok(dates) => {
+                    info!("Loaded {} holidays from {}", dates.len(), path.display());
+                    holidays.dates.extend(dates);
+                }
+                Err(err) => warn!("Ignoring holidays in {}: {err}", path.display()),
+            }
+        }
+        holidays
+    }
+
+    pub fn is_holiday(&self, date: NaiveDate) -> bool {
+        self.dates.contains(&date)
+    }
+
+    /// Whether a day follows the weekend pattern: Saturday, Sunday or a holiday
+    pub fn is_weekend(&self, date: NaiveDate) -> bool {
+        matches!(date.weekday(), Weekday::Sat | Weekday::Sun) || self.is_holiday(date)
+    }
+}
+
+/// Dates of an ICS event being parsed
+#[derive(Default)]
+struct Event {
+    /// Start date and whether it is a whole date
+    start: Option<(NaiveDate, bool)>,
+    end: Option<NaiveDate>,
+}
+
+/// Parse the date from an ICS `DTSTART` or `DTEND` value, returning whether
+/// it is a whole date (rather than a date and time).
+fn parse_ics_date(value: &str) -> Result<(NaiveDate, bool), String> {
+    value
+        .get(..8)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok())
+        .map(|date| (date, value.len() == 8))
+        .ok_or_else(|| format!("invalid date {value:?}"))
+}
+
+/// Dates covered by the events in an iCalendar file.
+///
+/// An all-day event ends the day before its `DTEND`, as in the standard.
+/// Events with a start time only cover the day on which they start.
+pub fn parse_ics(text: &str) -> Result<Vec<NaiveDate>, String> {
+    // Undo line folding, where continuation lines start with whitespace
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let mut dates = Vec::new();
+    let mut event: Option<Event> = None;
+    for line in &lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        // Drop parameters such as ;VALUE=DATE
+        let name = name.split(';').next().unwrap_or_default();
+        match (name, &mut event) {
+            ("BEGIN", None) if value == "VEVENT" => event = Some(Event::default()),
+            ("DTSTART", Some(event)) => event.start = Some(parse_ics_date(value)?),
+            ("DTEND", Some(event)) => event.end = Some(parse_ics_date(value)?.0),
+            ("END", Some(Event { start, end })) if value == "VEVENT" => {
+                let Some((start, whole_days)) = *start else {
+                    return Err("event without DTSTART".into());
+                };
+                let days = match end {
+                    Some(end) if whole_days => (*end - start).num_days(),
+                    _ => 1,
+                };
+                if days > MAX_EVENT_DAYS {
+                    return Err(format!("event starting {start} is too long"));
+                }
+                dates.extend(start.iter_days().take(days.max(1) as usize));
+                event = None;
+            }
+            _ => {}
+        }
+    }
+    Ok(dates)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn test_is_weekend() {
+        let holidays = Holidays::new([date(2024, 12, 16)]);
+        assert!(holidays.is_weekend(date(2024, 12, 16))); // Monday holiday
+        assert!(!holidays.is_weekend(date(2024, 12, 17)));
+        assert!(holidays.is_weekend(date(2024, 12, 21))); // Saturday
+    }
+
+    #[test]
+    fn test_parse_ics() {
+        let text = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART;VALUE=DATE:20241225\r\n\
+            DTEND;VALUE=DATE:20241227\r\n\
+            SUMMARY:Christmas Day and\r\n \
+            Day of Goodwill\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART;VALUE=DATE:20250101\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART:20250321T000000\r\n\
+            DTEND:20250322T000000\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        assert_eq!(
+            parse_ics(text).unwrap(),
+            vec![
+                date(2024, 12, 25),
+                date(2024, 12, 26),
+                date(2025, 1, 1),
+                date(2025, 3, 21)
+            ]
+        );
+        assert!(parse_ics("BEGIN:VEVENT\nDTSTART:2024\nEND:VEVENT\n").is_err());
+        assert!(parse_ics("BEGIN:VEVENT\nEND:VEVENT\n").is_err());
+    }
+}
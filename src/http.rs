@@ -0,0 +1,422 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! HTTP API for the status, forecasts and manual overrides
+//!
+//! This contains a minimal HTTP/1.1 server that handles one request per
+//! connection. The endpoints are:
+//!
+//! - `GET /`: a dashboard showing the SoC, targets, load-shedding and the
+//!   projected SoC
+//! - `GET /status`: the same status as served on the status socket
+//! - `GET /forecast`: predicted PV power for the next day
+//! - `GET /events`: upcoming load-shedding events
+//! - `GET /projection`: projected SoC, starting from the lower target
+//! - `GET /override` and `POST /override`: the manual override (the body of
+//!   a POST is in the same format as the override file)
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::Config;
+use crate::control::{plan_offline, EspStatus};
+use crate::manual::Override;
+use crate::planner::{clip_power, forecast_power, normalize_events, PvForecast};
+use crate::status::StatusBoard;
+
+/// Maximum size of the request headers and body
+const MAX_REQUEST: usize = 65536;
+/// Time allowed for a client to send its request
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Path, without any query string
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(value: &impl Serialize) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(err) => Self::error(500, &err.to_string()),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: json!({ "error": message }).to_string().into_bytes(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Parse a request from the start of `data`. Returns `None` if more data is
+/// needed.
+fn parse_request(data: &[u8]) -> Result<Option<Request>, Response> {
+    let Some(header_end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let bad = || Response::error(400, "malformed request");
+    let header = std::str::from_utf8(&data[..header_end]).map_err(|_| bad())?;
+    let mut lines = header.split("\r\n");
+    let mut request_line = lines.next().ok_or_else(bad)?.split(' ');
+    let method = request_line.next().ok_or_else(bad)?.to_string();
+    let target = request_line.next().ok_or_else(bad)?;
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let mut length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| bad())?;
+            }
+        }
+    }
+    let body_start = header_end + 4;
+    if body_start + length > MAX_REQUEST {
+        return Err(Response::error(413, "request too large"));
+    }
+    if data.len() < body_start + length {
+        return Ok(None);
+    }
+    Ok(Some(Request {
+        method,
+        path,
+        body: data[body_start..body_start + length].to_vec(),
+    }))
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request, Response> {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        if let Some(request) = parse_request(&data)? {
+            return Ok(request);
+        }
+        if data.len() >= MAX_REQUEST {
+            return Err(Response::error(413, "request too large"));
+        }
+        let n = stream
+            .read(&mut buffer)
+            .await
+            .map_err(|err| Response::error(400, &err.to_string()))?;
+        if n == 0 {
+            return Err(Response::error(400, "incomplete request"));
+        }
+        data.extend_from_slice(&buffer[..n]);
+    }
+}
+
+#[derive(Serialize)]
+struct ForecastPoint {
+    time: DateTime<Utc>,
+    power: f64,
+}
+
+#[derive(Serialize)]
+struct ProjectionPoint {
+    time: DateTime<Utc>,
+    soc: f64,
+}
+
+#[derive(Serialize)]
+struct ProjectionResponse {
+    target_soc: f64,
+    worst_time: DateTime<Utc>,
+    soc: Vec<ProjectionPoint>,
+}
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// State needed to answer requests
+pub struct Api<'a> {
+    pub config: &'a Config,
+    pub board: &'a StatusBoard,
+    pub esp: &'a EspStatus,
+    pub forecasts: &'a Mutex<Vec<Option<PvForecast>>>,
+}
+
+impl Api<'_> {
+    pub fn handle(&self, request: &Request, now: DateTime<Utc>) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/") => Response {
+                status: 200,
+                content_type: "text/html; charset=utf-8",
+                body: DASHBOARD.as_bytes().to_vec(),
+            },
+            ("GET", "/status") => Response::json(&self.board.status(self.esp, now)),
+            ("GET", "/projection") => self.projection(now),
+            ("GET", "/forecast") => Response::json(&self.forecast(now)),
+            ("GET", "/events") => {
+                let events = self
+                    .esp
+                    .state
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map_or_else(Vec::new, |state| {
+                        normalize_events(&state.response.events, now)
+                    });
+                Response::json(&events)
+            }
+            ("GET", "/override") => self.get_override(),
+            ("POST", "/override") => self.set_override(&request.body),
+            (_, "/" | "/status" | "/forecast" | "/events" | "/projection" | "/override") => {
+                Response::error(405, "method not allowed")
+            }
+            _ => Response::error(404, "not found"),
+        }
+    }
+
+    /// Predicted PV power every 15 minutes for the next day
+    fn forecast(&self, now: DateTime<Utc>) -> Vec<ForecastPoint> {
+        let forecasts = self.forecasts.lock().unwrap().clone();
+        let step = Duration::minutes(15);
+        let start = now.duration_trunc(step).unwrap_or(now);
+        (0..96)
+            .map(|i| {
+                let time = start + step * i;
+                let power = forecast_power(&self.config.inverter.panels, &forecasts, time);
+                ForecastPoint {
+                    time,
+                    power: clip_power(power, self.config.inverter.max_pv_power),
+                }
+            })
+            .collect()
+    }
+
+    /// Projected SoC every 15 minutes, using the capacity from the latest
+    /// update (since the inverter can't be queried from here)
+    fn projection(&self, now: DateTime<Utc>) -> Response {
+        let status = self.board.status(self.esp, now);
+        let Some(capacity) = status.soc.map(|soc| soc.capacity) else {
+            return Response::error(503, "no SoC update yet");
+        };
+        let response = self
+            .esp
+            .state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|state| state.response.clone());
+        let forecasts = self.forecasts.lock().unwrap().clone();
+        let Some(plan) = plan_offline(self.config, response, forecasts, capacity, now) else {
+            return Response::error(503, "no load-shedding information");
+        };
+        let step = Duration::minutes(15);
+        let mut next = now;
+        let mut soc = Vec::new();
+        for &(time, value) in plan.projection.soc.iter() {
+            if time >= next {
+                soc.push(ProjectionPoint { time, soc: value });
+                next = time + step;
+            }
+        }
+        Response::json(&ProjectionResponse {
+            target_soc: plan.projection.target_soc,
+            worst_time: plan.projection.worst_time,
+            soc,
+        })
+    }
+
+    fn get_override(&self) -> Response {
+        let Some(path) = self.config.manual.as_ref().map(|manual| &manual.path) else {
+            return Response::error(404, "no [manual] section is configured");
+        };
+        match Override::load(path) {
+            Ok(value) => Response::json(&json!({ "override": value.to_string() })),
+            Err(err) => Response::error(500, &err.to_string()),
+        }
+    }
+
+    fn set_override(&self, body: &[u8]) -> Response {
+        let Some(path) = self.config.manual.as_ref().map(|manual| &manual.path) else {
+            return Response::error(404, "no [manual] section is configured");
+        };
+        let value = match std::str::from_utf8(body)
+            .map_err(|err| err.to_string())
+            .and_then(|body| body.parse::<Override>().map_err(|err| err.to_string()))
+        {
+            Ok(value) => value,
+            Err(err) => return Response::error(400, &err),
+        };
+        if let Err(err) = std::fs::write(path, format!("{value}\n")) {
+            return Response::error(500, &err.to_string());
+        }
+        info!("Manual override set to {value} over HTTP");
+        Response::json(&json!({ "override": value.to_string() }))
+    }
+}
+
+async fn handle_connection(api: &Api<'_>, mut stream: TcpStream) -> std::io::Result<()> {
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => api.handle(&request, Utc::now()),
+        Ok(Err(response)) => response,
+        Err(_) => Response::error(400, "timed out reading request"),
+    };
+    let header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+/// Serve the API until cancelled
+pub async fn serve(listen: &str, api: Api<'_>, token: CancellationToken) {
+    let listener = match TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("Cannot listen on {listen}: {err}");
+            return;
+        }
+    };
+    info!("Serving HTTP API on {listen}");
+    loop {
+        let stream = tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Failed to accept HTTP connection: {err}");
+                    continue;
+                }
+            },
+            _ = token.cancelled() => { break; }
+        };
+        if let Err(err) = handle_connection(&api, stream).await {
+            warn!("Failed to send HTTP response: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request(b"GET /status HTTP/1.1\r\nHost: x\r\n"),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_request(b"GET /status?x=1 HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Ok(Some(Request {
+                method: "GET".to_string(),
+                path: "/status".to_string(),
+                body: Vec::new(),
+            }))
+        );
+        let post = b"POST /override HTTP/1.1\r\nContent-Length: 9\r\n\r\ncharge 80";
+        assert_eq!(parse_request(&post[..post.len() - 1]), Ok(None));
+        assert_eq!(
+            parse_request(post).unwrap().unwrap().body,
+            b"charge 80".to_vec()
+        );
+        assert_eq!(
+            parse_request(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n")
+                .unwrap_err()
+                .status,
+            400
+        );
+    }
+
+    #[test]
+    fn test_override() {
+        let path = std::env::temp_dir().join(format!("socit-override-{}", std::process::id()));
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [inverter]
+            device = "/dev/null"
+            min_soc = 20
+            fallback_soc = 50
+            min_discharge_power = 200
+            max_discharge_power = 1000
+
+            [manual]
+            path = {path:?}
+            "#
+        ))
+        .unwrap();
+        let board = StatusBoard::default();
+        let esp = EspStatus::default();
+        let forecasts = Mutex::new(Vec::new());
+        let api = Api {
+            config: &config,
+            board: &board,
+            esp: &esp,
+            forecasts: &forecasts,
+        };
+        let request = |method: &str, path: &str, body: &str| Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.as_bytes().to_vec(),
+        };
+        let now = Utc::now();
+        let response = api.handle(&request("POST", "/override", "charge 80"), now);
+        assert_eq!(response.status, 200);
+        assert_eq!(Override::load(&path).unwrap(), Override::Charge(80.0));
+        let response = api.handle(&request("POST", "/override", "bogus"), now);
+        assert_eq!(response.status, 400);
+        let response = api.handle(&request("GET", "/override", ""), now);
+        assert_eq!(response.body, br#"{"override":"charge 80"}"#.to_vec());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(api.handle(&request("GET", "/events", ""), now).body, b"[]");
+        assert_eq!(api.handle(&request("PUT", "/status", ""), now).status, 405);
+        assert_eq!(api.handle(&request("GET", "/nope", ""), now).status, 404);
+        assert_eq!(
+            api.handle(&request("GET", "/projection", ""), now).status,
+            503
+        );
+        assert_eq!(api.handle(&request("GET", "/", ""), now).status, 200);
+    }
+}
@@ -0,0 +1,278 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use async_trait::async_trait;
+use log::{info, warn};
+use reqwest::Client;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::config::Influxdb1Config;
+use crate::monitoring::{CoilUpdate, HealthUpdate, Monitor, PvUpdate, SocUpdate};
+
+/// A field value in InfluxDB line protocol
+enum Value {
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+/// Escape a tag key or value for line protocol
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Format a single point in line protocol, with a timestamp in seconds
+fn format_line(measurement: &str, fields: &[(&str, Value)], timestamp: i64) -> String {
+    let mut line = measurement.to_string();
+    for (i, (key, value)) in fields.iter().enumerate() {
+        line.push(if i == 0 { ' ' } else { ',' });
+        // Writing to a String cannot fail
+        match value {
+            Value::Float(x) => write!(line, "{key}={x:?}").unwrap(),
+            Value::Bool(x) => write!(line, "{key}={x}").unwrap(),
+        }
+    }
+    write!(line, " {timestamp}").unwrap();
+    line
+}
+
+pub struct Influxdb1Monitor {
+    client: Client,
+    url: String,
+    database: String,
+    username: Option<String>,
+    password: Option<String>,
+    tags: BTreeMap<String, String>,
+}
+
+impl Influxdb1Monitor {
+    pub async fn new(config: &Influxdb1Config) -> reqwest::Result<Self> {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        match client.get(format!("{}/ping", config.host)).send().await {
+            Ok(response) if response.status().is_success() => {
+                info!(
+                    "Successfully connected to Influxdb server at {}",
+                    &config.host
+                );
+            }
+            Ok(response) => {
+                warn!("Influxdb server is unhealthy: {}", response.status());
+            }
+            Err(err) => {
+                warn!("Could not connect to Influxdb server: {}", err);
+            }
+        }
+        Ok(Self {
+            client,
+            url: format!("{}/write", config.host),
+            database: config.database.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            tags: config.tags.clone(),
+        })
+    }
+
+    /// Measurement name followed by the configured tags
+    fn measurement(&self, name: &str) -> String {
+        let mut measurement = name.to_string();
+        for (key, value) in self.tags.iter() {
+            write!(measurement, ",{}={}", escape_tag(key), escape_tag(value)).unwrap();
+        }
+        measurement
+    }
+
+    async fn write(&self, line: String) -> Result<(), Box<dyn Error>> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .query(&[("db", self.database.as_str()), ("precision", "s")]);
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        request.body(line).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Monitor for Influxdb1Monitor {
+    async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
+        let mut fields = vec![
+            ("target_soc_low", update.target_soc_low.into()),
+            ("target_soc_high", update.target_soc_high.into()),
+            ("alarm_soc", update.alarm_soc.into()),
+            ("current_soc", update.current_soc.into()),
+            ("capacity", update.capacity.into()),
+            ("target_energy_low", update.target_energy_low.into()),
+            ("target_energy_high", update.target_energy_high.into()),
+            ("alarm_energy", update.alarm_energy.into()),
+            ("current_energy", update.current_energy.into()),
+            ("energy_deficit", update.energy_deficit.into()),
+            ("predicted_pv", update.predicted_pv.into()),
+            ("is_loadshedding", update.is_loadshedding.into()),
+            ("emergency", update.emergency.into()),
+            ("unscheduled_outage", update.unscheduled_outage.into()),
+            ("smart_load", update.smart_load.into()),
+            ("soc_filtered", (update.soc_filtered as f64).into()),
+            ("esp_successes", (update.esp_successes as f64).into()),
+            ("esp_failures", (update.esp_failures as f64).into()),
+        ];
+        if let Some(next_change) = update.next_change {
+            fields.push((
+                "next_change_seconds",
+                ((next_change - update.time).num_milliseconds() as f64 * 1e-3).into(),
+            ));
+        }
+        if let Some(aux_power) = update.aux_power {
+            fields.push(("aux_power", aux_power.into()));
+        }
+        // Times are stored as UNIX timestamps in seconds
+        if let Some(start) = update.pv_window_start {
+            fields.push(("pv_window_start", (start.timestamp() as f64).into()));
+        }
+        if let Some(end) = update.pv_window_end {
+            fields.push(("pv_window_end", (end.timestamp() as f64).into()));
+        }
+        let telemetry = [
+            ("battery_power", update.battery_power),
+            ("battery_voltage", update.battery_voltage),
+            ("battery_current", update.battery_current),
+            ("battery_temperature", update.battery_temperature),
+            ("cell_temperature_min", update.cell_temperature_min),
+            ("cell_temperature_max", update.cell_temperature_max),
+            ("load_power", update.load_power),
+            ("backup_runtime", update.backup_runtime),
+            ("time_to_sunrise", update.time_to_sunrise),
+            ("daylight_remaining", update.daylight_remaining),
+            ("manual_soc", update.manual_soc),
+            ("clock_skew", update.clock_skew),
+            ("wear_cost", update.wear_cost),
+            ("grid_cost", update.grid_cost),
+            ("prediction_bias", update.prediction_bias),
+            ("prediction_error", update.prediction_error),
+            ("load_scale", update.load_scale),
+            ("pv_scale", update.pv_scale),
+        ];
+        for (name, value) in telemetry {
+            if let Some(value) = value {
+                fields.push((name, value.into()));
+            }
+        }
+        if let Some(grid_connected) = update.grid_connected {
+            fields.push(("grid_connected", grid_connected.into()));
+        }
+        if let Some(esp_age) = update.esp_age {
+            fields.push(("esp_age", esp_age.into()));
+        }
+        if let Some(esp_latency) = update.esp_latency {
+            fields.push(("esp_latency", esp_latency.into()));
+        }
+        if let Some(quota) = update.esp_quota_remaining {
+            fields.push(("esp_quota_remaining", (quota as f64).into()));
+        }
+        if let Some(writes) = update.inverter_writes {
+            fields.push(("inverter_writes", (writes as f64).into()));
+        }
+        if let Some(used) = update.write_budget_used {
+            fields.push(("write_budget_used", (used as f64).into()));
+        }
+        if let Some(faults) = &update.active_faults {
+            fields.push(("fault_count", (faults.len() as f64).into()));
+        }
+        self.write(format_line(
+            &self.measurement("socit"),
+            &fields,
+            update.time.timestamp(),
+        ))
+        .await
+    }
+
+    async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>> {
+        let mut fields = vec![
+            ("active", update.active.into()),
+            ("target", update.target.into()),
+        ];
+        if let Some(setting) = update.setting {
+            fields.push(("setting", setting.into()));
+        }
+        self.write(format_line(
+            &self.measurement("socit-coil"),
+            &fields,
+            update.time.timestamp(),
+        ))
+        .await
+    }
+
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>> {
+        let mut lines = Vec::new();
+        for string in update.strings.iter() {
+            let mut fields = vec![
+                ("predicted", string.predicted.into()),
+                ("measured", string.measured.into()),
+            ];
+            if let Some(ratio) = string.ratio {
+                fields.push(("ratio", ratio.into()));
+            }
+            let measurement = format!("{},mppt={}", self.measurement("socit-pv"), string.mppt);
+            lines.push(format_line(&measurement, &fields, update.time.timestamp()));
+        }
+        self.write(lines.join("\n")).await
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        let fields = vec![
+            ("inverter_reachable", update.inverter_reachable.into()),
+            (
+                "inverter_successes",
+                (update.inverter_successes as f64).into(),
+            ),
+            (
+                "inverter_failures",
+                (update.inverter_failures as f64).into(),
+            ),
+            (
+                "inverter_consecutive_failures",
+                (update.inverter_consecutive_failures as f64).into(),
+            ),
+        ];
+        self.write(format_line(
+            &self.measurement("socit-health"),
+            &fields,
+            update.time.timestamp(),
+        ))
+        .await
+    }
+}
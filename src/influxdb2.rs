@@ -16,18 +16,21 @@
 
 use async_trait::async_trait;
 use influxdb2::api::write::TimestampPrecision;
+use influxdb2::models::data_point::DataPointBuilder;
 use influxdb2::models::health::Status;
 use influxdb2::models::DataPoint;
 use influxdb2::Client;
 use log::{info, warn};
+use std::collections::BTreeMap;
 use std::error::Error;
 
 use crate::config::Influxdb2Config;
-use crate::monitoring::{CoilUpdate, Monitor, SocUpdate};
+use crate::monitoring::{CoilUpdate, HealthUpdate, Monitor, PvUpdate, SocUpdate};
 
 pub struct Influxdb2Monitor {
     client: Client,
     bucket: String,
+    tags: BTreeMap<String, String>,
 }
 
 impl Influxdb2Monitor {
@@ -58,27 +61,106 @@ impl Influxdb2Monitor {
         Self {
             client,
             bucket: config.bucket.to_owned(),
+            tags: config.tags.clone(),
         }
     }
+
+    /// Start a point with the configured tags
+    fn builder(&self, measurement: &str) -> DataPointBuilder {
+        self.tags
+            .iter()
+            .fold(DataPoint::builder(measurement), |builder, (key, value)| {
+                builder.tag(key, value)
+            })
+    }
 }
 
 #[async_trait]
 impl Monitor for Influxdb2Monitor {
     async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
-        let mut builder = DataPoint::builder("socit")
+        let mut builder = self
+            .builder("socit")
             .timestamp(update.time.timestamp())
             .field("target_soc_low", update.target_soc_low)
             .field("target_soc_high", update.target_soc_high)
             .field("alarm_soc", update.alarm_soc)
             .field("current_soc", update.current_soc)
+            .field("capacity", update.capacity)
+            .field("target_energy_low", update.target_energy_low)
+            .field("target_energy_high", update.target_energy_high)
+            .field("alarm_energy", update.alarm_energy)
+            .field("current_energy", update.current_energy)
+            .field("energy_deficit", update.energy_deficit)
             .field("predicted_pv", update.predicted_pv)
-            .field("is_loadshedding", update.is_loadshedding);
+            .field("is_loadshedding", update.is_loadshedding)
+            .field("emergency", update.emergency)
+            .field("unscheduled_outage", update.unscheduled_outage)
+            .field("smart_load", update.smart_load)
+            .field("soc_filtered", update.soc_filtered as i64)
+            .field("esp_successes", update.esp_successes as i64)
+            .field("esp_failures", update.esp_failures as i64);
         if let Some(next_change) = update.next_change {
             builder = builder.field(
                 "next_change_seconds",
                 (next_change - update.time).num_milliseconds() as f64 * 1e-3,
             );
         }
+        if let Some(aux_power) = update.aux_power {
+            builder = builder.field("aux_power", aux_power);
+        }
+        // Times are stored as UNIX timestamps in seconds
+        if let Some(start) = update.pv_window_start {
+            builder = builder.field("pv_window_start", start.timestamp());
+        }
+        if let Some(end) = update.pv_window_end {
+            builder = builder.field("pv_window_end", end.timestamp());
+        }
+        let telemetry = [
+            ("battery_power", update.battery_power),
+            ("battery_voltage", update.battery_voltage),
+            ("battery_current", update.battery_current),
+            ("battery_temperature", update.battery_temperature),
+            ("cell_temperature_min", update.cell_temperature_min),
+            ("cell_temperature_max", update.cell_temperature_max),
+            ("load_power", update.load_power),
+            ("backup_runtime", update.backup_runtime),
+            ("time_to_sunrise", update.time_to_sunrise),
+            ("daylight_remaining", update.daylight_remaining),
+            ("manual_soc", update.manual_soc),
+            ("clock_skew", update.clock_skew),
+            ("wear_cost", update.wear_cost),
+            ("grid_cost", update.grid_cost),
+            ("prediction_bias", update.prediction_bias),
+            ("prediction_error", update.prediction_error),
+            ("load_scale", update.load_scale),
+            ("pv_scale", update.pv_scale),
+        ];
+        for (name, value) in telemetry {
+            if let Some(value) = value {
+                builder = builder.field(name, value);
+            }
+        }
+        if let Some(grid_connected) = update.grid_connected {
+            builder = builder.field("grid_connected", grid_connected);
+        }
+        if let Some(esp_age) = update.esp_age {
+            builder = builder.field("esp_age", esp_age);
+        }
+        if let Some(esp_latency) = update.esp_latency {
+            builder = builder.field("esp_latency", esp_latency);
+        }
+        if let Some(quota) = update.esp_quota_remaining {
+            builder = builder.field("esp_quota_remaining", quota);
+        }
+        if let Some(writes) = update.inverter_writes {
+            builder = builder.field("inverter_writes", writes as i64);
+        }
+        if let Some(used) = update.write_budget_used {
+            builder = builder.field("write_budget_used", used as i64);
+        }
+        if let Some(faults) = &update.active_faults {
+            builder = builder.field("fault_count", faults.len() as i64);
+        }
         let point = builder.build().unwrap();
         let strm = futures::stream::once(async { point });
         self.client
@@ -88,7 +170,8 @@ impl Monitor for Influxdb2Monitor {
     }
 
     async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>> {
-        let mut builder = DataPoint::builder("socit-coil")
+        let mut builder = self
+            .builder("socit-coil")
             .timestamp(update.time.timestamp())
             .field("active", update.active)
             .field("target", update.target);
@@ -102,4 +185,45 @@ impl Monitor for Influxdb2Monitor {
             .await?;
         Ok(())
     }
+
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>> {
+        let mut points = Vec::new();
+        for string in update.strings.iter() {
+            let mut builder = self
+                .builder("socit-pv")
+                .timestamp(update.time.timestamp())
+                .tag("mppt", string.mppt.to_string())
+                .field("predicted", string.predicted)
+                .field("measured", string.measured);
+            if let Some(ratio) = string.ratio {
+                builder = builder.field("ratio", ratio);
+            }
+            points.push(builder.build().unwrap());
+        }
+        let strm = futures::stream::iter(points);
+        self.client
+            .write_with_precision(&self.bucket, strm, TimestampPrecision::Seconds)
+            .await?;
+        Ok(())
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        let point = self
+            .builder("socit-health")
+            .timestamp(update.time.timestamp())
+            .field("inverter_reachable", update.inverter_reachable)
+            .field("inverter_successes", update.inverter_successes as i64)
+            .field("inverter_failures", update.inverter_failures as i64)
+            .field(
+                "inverter_consecutive_failures",
+                update.inverter_consecutive_failures as i64,
+            )
+            .build()
+            .unwrap();
+        let strm = futures::stream::once(async { point });
+        self.client
+            .write_with_precision(&self.bucket, strm, TimestampPrecision::Seconds)
+            .await?;
+        Ok(())
+    }
 }
@@ -15,13 +15,40 @@
  */
 
 use async_trait::async_trait;
+use chrono::{NaiveDateTime, NaiveTime};
+use log::info;
+use std::collections::HashMap;
+
+use crate::config::WorkMode;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Written values did not read back correctly (for example, because the
+/// inverter ignored the write)
+#[derive(Debug)]
+pub struct VerifyError {
+    pub what: &'static str,
+    pub attempts: u32,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} did not read back as written after {} attempts",
+            self.what, self.attempts
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
 pub struct Info {
     pub capacity: f64,     // Wh
     pub charge_power: f64, // W
+    /// Whether the AUX/GEN port is configured as a smart load output
+    pub smart_load: bool,
 }
 
 pub struct CoilInfo {
@@ -33,28 +60,218 @@ pub struct CoilInfo {
     pub coil_active: bool,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CurrentLimits {
+    /// Maximum battery charge current (A)
+    pub charge: f64,
+    /// Maximum battery discharge current (A)
+    pub discharge: f64,
+}
+
+/// Readings that are only used for monitoring
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Telemetry {
+    /// Battery power (W) - positive for discharging
+    pub battery_power: Option<f64>,
+    /// Battery voltage (V)
+    pub battery_voltage: Option<f64>,
+    /// Battery current (A) - positive for discharging
+    pub battery_current: Option<f64>,
+    /// Battery temperature (°C)
+    pub battery_temperature: Option<f64>,
+    /// Whether the grid is present
+    pub grid_connected: Option<bool>,
+    /// Power (W) consumed by the loads
+    pub load_power: Option<f64>,
+    /// Descriptions of the faults currently reported by the inverter
+    pub faults: Option<Vec<String>>,
+}
+
+/// A time-of-use program, in effect from its start time until the next program's
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    /// Start time (in the inverter's time zone)
+    pub time: NaiveTime,
+    /// Maximum battery power (W)
+    pub power: u16,
+    /// Minimum SoC (%)
+    pub soc: u16,
+    /// Whether the battery may be charged from the grid to reach the SoC
+    pub grid_charge: bool,
+}
+
+/// Outcomes of communication with the inverter, for monitoring
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub successes: u64,
+    pub failures: u64,
+    /// Failures since the last success
+    pub consecutive_failures: u64,
+}
+
 #[async_trait]
 pub trait Inverter: Send {
     async fn get_info(&mut self) -> Result<Info>;
     async fn get_soc(&mut self) -> Result<f64>;
     async fn set_min_soc(&mut self, target: f64, fallback: f64) -> Result<()>;
     async fn get_coil(&mut self) -> Result<Option<CoilInfo>>;
+    /// Power (W) drawn by the non-essential loads, if the inverter measures
+    /// it separately
+    async fn get_non_essential_power(&mut self) -> Result<Option<f64>>;
     async fn set_trickle(&mut self, trickle: f64) -> Result<()>;
+    /// Power (W) produced by each MPPT string, if supported
+    async fn get_pv(&mut self) -> Result<Option<Vec<f64>>>;
+    async fn get_current_limits(&mut self) -> Result<CurrentLimits>;
+    async fn set_current_limits(&mut self, limits: &CurrentLimits) -> Result<()>;
+    /// Power (W) supplied through the AUX/GEN port, if supported
+    async fn get_aux_power(&mut self) -> Result<Option<f64>>;
+    /// Additional readings for monitoring, if supported
+    async fn get_telemetry(&mut self) -> Result<Option<Telemetry>>;
+    /// System work mode, if recognised
+    async fn get_work_mode(&mut self) -> Result<Option<WorkMode>>;
+    async fn set_work_mode(&mut self, mode: WorkMode) -> Result<()>;
+    /// Number of register writes since startup, if counted
+    fn write_count(&self) -> Option<u64>;
+    /// Outcomes of communication since startup, if tracked
+    fn io_stats(&self) -> Option<IoStats>;
+    /// Descriptions of the writes suppressed since the previous call, if in dry-run mode
+    fn take_intended_writes(&mut self) -> Option<Vec<String>>;
+    /// Register writes counted against the daily write budget, if limited
+    fn budget_used(&self) -> Option<u64>;
+    /// Restore the programs that were in effect at startup
+    async fn restore_programs(&mut self) -> Result<()>;
+    /// Time-of-use programs, if supported
+    async fn get_programs(&mut self) -> Result<Option<Vec<Program>>>;
+    /// Replace all the time-of-use programs
+    async fn set_programs(&mut self, programs: &[Program]) -> Result<()>;
+    /// Inverter clock (local time), if supported
+    async fn get_clock(&mut self) -> Result<Option<NaiveDateTime>>;
+    async fn set_clock(&mut self, time: NaiveDateTime) -> Result<()>;
 }
 
-/// Wrap another inverter class to turn set methods into nops
-pub struct DryrunInverter<T: Inverter> {
-    base: T,
+/// Forward to a borrowed inverter (so that it can be wrapped temporarily)
+#[async_trait]
+impl<T: Inverter + ?Sized> Inverter for &mut T {
+    async fn get_info(&mut self) -> Result<Info> {
+        (**self).get_info().await
+    }
+
+    async fn get_soc(&mut self) -> Result<f64> {
+        (**self).get_soc().await
+    }
+
+    async fn set_min_soc(&mut self, target: f64, fallback: f64) -> Result<()> {
+        (**self).set_min_soc(target, fallback).await
+    }
+
+    async fn get_coil(&mut self) -> Result<Option<CoilInfo>> {
+        (**self).get_coil().await
+    }
+
+    async fn get_non_essential_power(&mut self) -> Result<Option<f64>> {
+        (**self).get_non_essential_power().await
+    }
+
+    async fn set_trickle(&mut self, trickle: f64) -> Result<()> {
+        (**self).set_trickle(trickle).await
+    }
+
+    async fn get_pv(&mut self) -> Result<Option<Vec<f64>>> {
+        (**self).get_pv().await
+    }
+
+    async fn get_current_limits(&mut self) -> Result<CurrentLimits> {
+        (**self).get_current_limits().await
+    }
+
+    async fn set_current_limits(&mut self, limits: &CurrentLimits) -> Result<()> {
+        (**self).set_current_limits(limits).await
+    }
+
+    async fn get_aux_power(&mut self) -> Result<Option<f64>> {
+        (**self).get_aux_power().await
+    }
+
+    async fn get_telemetry(&mut self) -> Result<Option<Telemetry>> {
+        (**self).get_telemetry().await
+    }
+
+    async fn get_work_mode(&mut self) -> Result<Option<WorkMode>> {
+        (**self).get_work_mode().await
+    }
+
+    async fn set_work_mode(&mut self, mode: WorkMode) -> Result<()> {
+        (**self).set_work_mode(mode).await
+    }
+
+    fn write_count(&self) -> Option<u64> {
+        (**self).write_count()
+    }
+
+    fn io_stats(&self) -> Option<IoStats> {
+        (**self).io_stats()
+    }
+
+    fn take_intended_writes(&mut self) -> Option<Vec<String>> {
+        (**self).take_intended_writes()
+    }
+
+    fn budget_used(&self) -> Option<u64> {
+        (**self).budget_used()
+    }
+
+    async fn restore_programs(&mut self) -> Result<()> {
+        (**self).restore_programs().await
+    }
+
+    async fn get_programs(&mut self) -> Result<Option<Vec<Program>>> {
+        (**self).get_programs().await
+    }
+
+    async fn set_programs(&mut self, programs: &[Program]) -> Result<()> {
+        (**self).set_programs(programs).await
+    }
+
+    async fn get_clock(&mut self) -> Result<Option<NaiveDateTime>> {
+        (**self).get_clock().await
+    }
+
+    async fn set_clock(&mut self, time: NaiveDateTime) -> Result<()> {
+        (**self).set_clock(time).await
+    }
 }
 
-impl<T: Inverter> DryrunInverter<T> {
-    pub fn new(base: T) -> Self {
-        Self { base }
+/// Wrap another inverter to turn set methods into nops, logging what would
+/// have been written instead
+pub struct DryrunInverter<'a> {
+    base: Box<dyn Inverter + 'a>,
+    /// Most recent suppressed value of each setting, so that only changes are logged
+    last: HashMap<&'static str, String>,
+    /// Suppressed writes not yet collected by [Inverter::take_intended_writes]
+    intended: Vec<String>,
+}
+
+impl<'a> DryrunInverter<'a> {
+    pub fn new(base: Box<dyn Inverter + 'a>) -> Self {
+        Self {
+            base,
+            last: HashMap::new(),
+            intended: Vec::new(),
+        }
+    }
+
+    /// Record a write that was suppressed
+    fn intend(&mut self, setting: &'static str, description: String) {
+        if self.last.get(setting) != Some(&description) {
+            info!("Dry run: not setting {description}");
+            self.intended.push(description.clone());
+            self.last.insert(setting, description);
+        }
     }
 }
 
 #[async_trait]
-impl<T: Inverter> Inverter for DryrunInverter<T> {
+impl Inverter for DryrunInverter<'_> {
     async fn get_info(&mut self) -> Result<Info> {
         self.base.get_info().await
     }
@@ -63,7 +280,11 @@ impl<T: Inverter> Inverter for DryrunInverter<T> {
         self.base.get_soc().await
     }
 
-    async fn set_min_soc(&mut self, _target: f64, _fallback: f64) -> Result<()> {
+    async fn set_min_soc(&mut self, target: f64, fallback: f64) -> Result<()> {
+        self.intend(
+            "min_soc",
+            format!("minimum SoC to {target:.1}% (fallback {fallback:.1}%)"),
+        );
         Ok(())
     }
 
@@ -71,7 +292,103 @@ impl<T: Inverter> Inverter for DryrunInverter<T> {
         self.base.get_coil().await
     }
 
-    async fn set_trickle(&mut self, _trickle: f64) -> Result<()> {
+    async fn get_non_essential_power(&mut self) -> Result<Option<f64>> {
+        self.base.get_non_essential_power().await
+    }
+
+    async fn set_trickle(&mut self, trickle: f64) -> Result<()> {
+        self.intend("trickle", format!("trickle to {trickle:.0} W"));
+        Ok(())
+    }
+
+    async fn get_pv(&mut self) -> Result<Option<Vec<f64>>> {
+        self.base.get_pv().await
+    }
+
+    async fn get_current_limits(&mut self) -> Result<CurrentLimits> {
+        self.base.get_current_limits().await
+    }
+
+    async fn set_current_limits(&mut self, limits: &CurrentLimits) -> Result<()> {
+        self.intend(
+            "current_limits",
+            format!(
+                "battery current limits to {} A (charge), {} A (discharge)",
+                limits.charge, limits.discharge
+            ),
+        );
+        Ok(())
+    }
+
+    async fn get_aux_power(&mut self) -> Result<Option<f64>> {
+        self.base.get_aux_power().await
+    }
+
+    async fn get_telemetry(&mut self) -> Result<Option<Telemetry>> {
+        self.base.get_telemetry().await
+    }
+
+    async fn get_work_mode(&mut self) -> Result<Option<WorkMode>> {
+        self.base.get_work_mode().await
+    }
+
+    async fn set_work_mode(&mut self, mode: WorkMode) -> Result<()> {
+        self.intend("work_mode", format!("work mode to {mode:?}"));
+        Ok(())
+    }
+
+    fn write_count(&self) -> Option<u64> {
+        self.base.write_count()
+    }
+
+    fn io_stats(&self) -> Option<IoStats> {
+        self.base.io_stats()
+    }
+
+    fn take_intended_writes(&mut self) -> Option<Vec<String>> {
+        Some(std::mem::take(&mut self.intended))
+    }
+
+    fn budget_used(&self) -> Option<u64> {
+        self.base.budget_used()
+    }
+
+    async fn restore_programs(&mut self) -> Result<()> {
+        self.intend("programs", "the programs found at startup".to_string());
+        Ok(())
+    }
+
+    async fn get_programs(&mut self) -> Result<Option<Vec<Program>>> {
+        self.base.get_programs().await
+    }
+
+    async fn set_programs(&mut self, programs: &[Program]) -> Result<()> {
+        let programs: Vec<String> = programs
+            .iter()
+            .map(|program| {
+                format!(
+                    "{}: {}% ({} W{})",
+                    program.time,
+                    program.soc,
+                    program.power,
+                    if program.grid_charge {
+                        ", grid charge"
+                    } else {
+                        ""
+                    }
+                )
+            })
+            .collect();
+        self.intend("programs", format!("programs to {}", programs.join(", ")));
+        Ok(())
+    }
+
+    async fn get_clock(&mut self) -> Result<Option<NaiveDateTime>> {
+        self.base.get_clock().await
+    }
+
+    async fn set_clock(&mut self, time: NaiveDateTime) -> Result<()> {
+        self.intend("clock", format!("clock to {time}"));
         Ok(())
     }
 }
@@ -86,12 +403,14 @@ mod test {
         pub fallback_soc: f64,
         pub soc: f64,
         pub trickle: f64,
+        pub limits: CurrentLimits,
+        pub work_mode: WorkMode,
         pub inject_error: Option<Error>, // Error returned on next call (one-shot)
     }
 
     impl TestInverter {
         fn check_inject_error(&mut self) -> Result<()> {
-            self.inject_error.take().map_or(Ok(()), |err| Err(err))
+            self.inject_error.take().map_or(Ok(()), Err)
         }
     }
 
@@ -102,6 +421,7 @@ mod test {
             Ok(Info {
                 capacity: 5000.0,
                 charge_power: 2000.0,
+                smart_load: false,
             })
         }
 
@@ -126,10 +446,154 @@ mod test {
             }))
         }
 
+        async fn get_non_essential_power(&mut self) -> Result<Option<f64>> {
+            self.check_inject_error()?;
+            Ok(Some(250.0))
+        }
+
         async fn set_trickle(&mut self, trickle: f64) -> Result<()> {
             self.check_inject_error()?;
             self.trickle = trickle;
             Ok(())
         }
+
+        async fn get_pv(&mut self) -> Result<Option<Vec<f64>>> {
+            self.check_inject_error()?;
+            Ok(Some(vec![1000.0, 500.0]))
+        }
+
+        async fn get_current_limits(&mut self) -> Result<CurrentLimits> {
+            self.check_inject_error()?;
+            Ok(self.limits)
+        }
+
+        async fn set_current_limits(&mut self, limits: &CurrentLimits) -> Result<()> {
+            self.check_inject_error()?;
+            self.limits = *limits;
+            Ok(())
+        }
+
+        async fn get_aux_power(&mut self) -> Result<Option<f64>> {
+            self.check_inject_error()?;
+            Ok(None)
+        }
+
+        async fn get_telemetry(&mut self) -> Result<Option<Telemetry>> {
+            self.check_inject_error()?;
+            Ok(None)
+        }
+
+        async fn get_work_mode(&mut self) -> Result<Option<WorkMode>> {
+            self.check_inject_error()?;
+            Ok(Some(self.work_mode))
+        }
+
+        async fn set_work_mode(&mut self, mode: WorkMode) -> Result<()> {
+            self.check_inject_error()?;
+            self.work_mode = mode;
+            Ok(())
+        }
+
+        fn write_count(&self) -> Option<u64> {
+            None
+        }
+
+        fn io_stats(&self) -> Option<IoStats> {
+            None
+        }
+
+        fn take_intended_writes(&mut self) -> Option<Vec<String>> {
+            None
+        }
+
+        fn budget_used(&self) -> Option<u64> {
+            None
+        }
+
+        async fn restore_programs(&mut self) -> Result<()> {
+            self.check_inject_error()?;
+            Ok(())
+        }
+
+        async fn get_programs(&mut self) -> Result<Option<Vec<Program>>> {
+            self.check_inject_error()?;
+            Ok(None)
+        }
+
+        async fn set_programs(&mut self, _programs: &[Program]) -> Result<()> {
+            self.check_inject_error()?;
+            Ok(())
+        }
+
+        async fn get_clock(&mut self) -> Result<Option<NaiveDateTime>> {
+            self.check_inject_error()?;
+            Ok(None)
+        }
+
+        async fn set_clock(&mut self, _time: NaiveDateTime) -> Result<()> {
+            self.check_inject_error()?;
+            Ok(())
+        }
+    }
+
+    impl Default for TestInverter {
+        fn default() -> Self {
+            Self {
+                target_soc: 0.0,
+                fallback_soc: 0.0,
+                soc: 50.0,
+                trickle: 0.0,
+                limits: CurrentLimits {
+                    charge: 100.0,
+                    discharge: 100.0,
+                },
+                work_mode: WorkMode::SellingFirst,
+                inject_error: None,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dryrun() {
+        let mut base = TestInverter::default();
+        let mut inverter = DryrunInverter::new(Box::new(&mut base));
+        assert_eq!(inverter.get_soc().await.unwrap(), 50.0);
+        inverter.set_min_soc(30.0, 40.0).await.unwrap();
+        inverter.set_trickle(20.0).await.unwrap();
+        inverter.set_trickle(20.0).await.unwrap();
+        let limits = CurrentLimits {
+            charge: 10.0,
+            discharge: 10.0,
+        };
+        inverter.set_current_limits(&limits).await.unwrap();
+        inverter
+            .set_work_mode(WorkMode::LimitedToHome)
+            .await
+            .unwrap();
+        assert_eq!(
+            inverter.take_intended_writes().unwrap(),
+            vec![
+                "minimum SoC to 30.0% (fallback 40.0%)",
+                "trickle to 20 W",
+                "battery current limits to 10 A (charge), 10 A (discharge)",
+                "work mode to LimitedToHome",
+            ]
+        );
+        // Repeated writes of the same value are only reported once
+        inverter.set_trickle(20.0).await.unwrap();
+        assert_eq!(
+            inverter.take_intended_writes().unwrap(),
+            Vec::<String>::new()
+        );
+        drop(inverter);
+        assert_eq!(base.limits.discharge, 100.0);
+        assert_eq!(base.work_mode, WorkMode::SellingFirst);
+        assert_eq!(base.target_soc, 0.0);
+        assert_eq!(base.fallback_soc, 0.0);
+        assert_eq!(base.trickle, 0.0);
+        base.inject_error = Some("injected".into());
+        let mut inverter = DryrunInverter::new(Box::new(&mut base));
+        assert!(inverter.get_coil().await.is_err());
+        assert!(inverter.get_coil().await.unwrap().is_some());
     }
 }
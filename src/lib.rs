@@ -16,11 +16,68 @@
 
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "daemon")]
+pub mod accuracy;
+#[cfg(feature = "daemon")]
+pub mod alert;
+#[cfg(feature = "daemon")]
+pub mod bms;
 pub mod config;
+#[cfg(feature = "daemon")]
 pub mod control;
 pub mod esp_api;
+#[cfg(feature = "daemon")]
+pub mod ev_charger;
+#[cfg(any(test, feature = "test-support"))]
+pub mod fake_sunsynk;
+pub mod ffi;
+#[cfg(feature = "daemon")]
+pub mod file_monitor;
+#[cfg(feature = "daemon")]
+pub mod forecast_solar;
+pub mod holidays;
+#[cfg(feature = "daemon")]
+pub mod http;
+#[cfg(feature = "daemon")]
+pub mod influxdb1;
+#[cfg(feature = "daemon")]
 pub mod influxdb2;
+#[cfg(feature = "daemon")]
 pub mod inverter;
+pub mod load_profile;
+#[cfg(feature = "daemon")]
+pub mod logging;
+#[cfg(feature = "daemon")]
+pub mod manual;
+#[cfg(feature = "daemon")]
+pub mod modbus;
+#[cfg(feature = "daemon")]
 pub mod monitoring;
+#[cfg(feature = "daemon")]
+pub mod mqtt;
+#[cfg(feature = "daemon")]
+pub mod open_meteo;
+#[cfg(feature = "daemon")]
+pub mod parquet;
+#[cfg(feature = "daemon")]
+pub mod persist;
+pub mod planner;
+#[cfg(feature = "daemon")]
+pub mod postgres;
+pub mod pv;
+#[cfg(feature = "daemon")]
+pub mod relay;
+#[cfg(feature = "daemon")]
+pub mod site;
+#[cfg(feature = "daemon")]
+pub mod status;
 pub mod sun;
+#[cfg(feature = "daemon")]
 pub mod sunsynk;
+#[cfg(feature = "daemon")]
+pub mod systemd;
+pub mod timezone;
+#[cfg(feature = "daemon")]
+pub mod tls;
+#[cfg(feature = "daemon")]
+pub mod write_budget;
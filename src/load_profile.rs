@@ -0,0 +1,180 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Learning the household load from inverter readings
+//!
+//! Samples are grouped by hour of day, separately for weekdays and weekends
+//! (including holidays).
+//! Each slot holds a running mean, which becomes an exponential moving
+//! average once enough samples have been seen so that the profile adapts to
+//! changing habits.
+
+use chrono::{NaiveDateTime, Timelike};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::config::LoadProfileConfig;
+use crate::holidays::Holidays;
+use crate::planner::LoadProfile;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Slot {
+    mean: f64,
+    samples: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LoadLearner {
+    weekday: Vec<Slot>,
+    weekend: Vec<Slot>,
+}
+
+impl Default for LoadLearner {
+    fn default() -> Self {
+        Self {
+            weekday: vec![Slot::default(); 24],
+            weekend: vec![Slot::default(); 24],
+        }
+    }
+}
+
+impl LoadLearner {
+    /// Load from a file, starting afresh if it does not exist or is invalid
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<Self>(&contents) {
+                Ok(learner) if learner.weekday.len() == 24 && learner.weekend.len() == 24 => {
+                    info!("Loaded load profile from {}", path.display());
+                    learner
+                }
+                Ok(_) => {
+                    warn!("Ignoring malformed load profile {}", path.display());
+                    Self::default()
+                }
+                Err(err) => {
+                    warn!("Ignoring invalid load profile {}: {err}", path.display());
+                    Self::default()
+                }
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(err) => {
+                warn!("Could not read load profile {}: {err}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        // Write to a temporary file and rename so that the update is atomic
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string(self)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    fn slot_mut(&mut self, time: NaiveDateTime, holidays: &Holidays) -> &mut Slot {
+        let slots = if holidays.is_weekend(time.date()) {
+            &mut self.weekend
+        } else {
+            &mut self.weekday
+        };
+        &mut slots[time.hour() as usize]
+    }
+
+    /// Add a sample of the load power (W) at a local time
+    pub fn add_sample(
+        &mut self,
+        time: NaiveDateTime,
+        power: f64,
+        max_samples: u64,
+        holidays: &Holidays,
+    ) {
+        let slot = self.slot_mut(time, holidays);
+        slot.samples = slot.samples.saturating_add(1);
+        let weight = slot.samples.min(max_samples.max(1)) as f64;
+        slot.mean += (power - slot.mean) / weight;
+    }
+
+    /// Get the profile for the planner, omitting slots with too few samples
+    pub fn profile(&self, config: &LoadProfileConfig) -> LoadProfile {
+        let usable = |slots: &[Slot]| {
+            slots
+                .iter()
+                .map(|slot| (slot.samples >= config.min_samples).then_some(slot.mean))
+                .collect()
+        };
+        LoadProfile {
+            weekday: usable(&self.weekday),
+            weekend: usable(&self.weekend),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn config() -> LoadProfileConfig {
+        LoadProfileConfig {
+            path: None,
+            min_samples: 2,
+            max_samples: 4,
+        }
+    }
+
+    #[test]
+    fn test_learn() {
+        let mut learner = LoadLearner::default();
+        // 2024-06-01 is a Saturday
+        let saturday = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(14, 30, 0)
+            .unwrap();
+        let monday = saturday + chrono::Duration::days(2);
+        let holidays = Holidays::default();
+        learner.add_sample(saturday, 100.0, 4, &holidays);
+        learner.add_sample(saturday, 300.0, 4, &holidays);
+        learner.add_sample(monday, 500.0, 4, &holidays);
+        let profile = learner.profile(&config());
+        assert_eq!(profile.weekend[14], Some(200.0));
+        // Too few samples
+        assert_eq!(profile.weekday[14], None);
+        assert_eq!(profile.weekend[15], None);
+
+        // Once max_samples is reached, new samples have a fixed weight
+        for _ in 0..10 {
+            learner.add_sample(saturday, 1000.0, 4, &holidays);
+        }
+        let mean = learner.profile(&config()).weekend[14].unwrap();
+        assert!(mean > 900.0 && mean < 1000.0, "{mean}");
+    }
+
+    #[test]
+    fn test_learn_holiday() {
+        let mut learner = LoadLearner::default();
+        let monday = NaiveDate::from_ymd_opt(2024, 6, 3)
+            .unwrap()
+            .and_hms_opt(9, 0, 0)
+            .unwrap();
+        let holidays = Holidays::new([monday.date()]);
+        learner.add_sample(monday, 400.0, 4, &holidays);
+        learner.add_sample(monday, 400.0, 4, &holidays);
+        let profile = learner.profile(&config());
+        assert_eq!(profile.weekend[9], Some(400.0));
+        assert_eq!(profile.weekday[9], None);
+    }
+}
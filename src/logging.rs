@@ -0,0 +1,298 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Logging setup, with an optional structured (JSON) format
+//!
+//! In the JSON format, each record is written as a single line containing a
+//! JSON object with the time, level, target and message, together with any
+//! key-value pairs attached to the record.
+//!
+//! Records can also be written to a file (see [RotatingFile]) instead of
+//! standard error.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::kv::{Error, Key, Value, VisitSource, VisitValue};
+use log::Record;
+use serde::Deserialize;
+use serde_json::{Map, Number};
+
+use crate::config::LoggingConfig;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable text (the env_logger default)
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {s:?} (expected text or json)")),
+        }
+    }
+}
+
+/// Converts a key-value value to JSON, preserving numbers and booleans
+struct JsonValue(serde_json::Value);
+
+impl<'v> VisitValue<'v> for &mut JsonValue {
+    fn visit_any(&mut self, value: Value) -> Result<(), Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), Error> {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), Error> {
+        // JSON has no representation for NaN or infinities
+        self.0 = Number::from_f64(value).map_or(serde_json::Value::Null, Into::into);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
+
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let mut json = JsonValue(serde_json::Value::Null);
+        value.visit(&mut json)?;
+        self.0.insert(key.to_string(), json.0);
+        Ok(())
+    }
+}
+
+/// Build the JSON object for a log record
+fn json_record(record: &Record, time: DateTime<Utc>) -> serde_json::Value {
+    let mut fields = Map::new();
+    // Failing to convert a field should not lose the rest of the record
+    let _ = record.key_values().visit(&mut Fields(&mut fields));
+    // Standard fields take precedence over key-value pairs with the same name
+    fields.insert(
+        "time".to_string(),
+        time.to_rfc3339_opts(SecondsFormat::Millis, true).into(),
+    );
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+    fields.into()
+}
+
+/// A log file that is rotated when it would grow too large or gets too old.
+///
+/// Rotated files are named by appending `.1`, `.2` and so on to the path,
+/// with `.1` being the most recent. Rotation only happens between writes, so
+/// a record is never split across files (the logger writes each record with
+/// a single call).
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    file: File,
+    size: u64,
+    created: SystemTime,
+}
+
+impl RotatingFile {
+    pub fn new(config: &LoggingConfig) -> io::Result<Self> {
+        let (file, size, created) = Self::open(&config.file)?;
+        Ok(Self {
+            path: config.file.clone(),
+            max_size: config.max_size,
+            max_age: config.max_age,
+            keep: config.keep,
+            file,
+            size,
+            created,
+        })
+    }
+
+    fn open(path: &PathBuf) -> io::Result<(File, u64, SystemTime)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let created = metadata.created().unwrap_or_else(|_| SystemTime::now());
+        Ok((file, metadata.len(), created))
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn needs_rotation(&self, len: usize, now: SystemTime) -> bool {
+        let too_old = self.max_age.is_some_and(|max_age| {
+            now.duration_since(self.created)
+                .is_ok_and(|age| age >= max_age)
+        });
+        // An empty file is never rotated, even if a single record is too large
+        self.size > 0 && (self.size + len as u64 > self.max_size || too_old)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                match std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        (self.file, self.size, _) = Self::open(&self.path)?;
+        // The creation time of a recreated file may be reported as that of
+        // the file it replaced, so track it ourselves.
+        self.created = SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len(), SystemTime::now()) {
+            if let Err(err) = self.rotate() {
+                // Keep logging to the current file rather than losing records
+                eprintln!("Failed to rotate {}: {err}", self.path.display());
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Initialise the logger. The filter is taken from `RUST_LOG` as usual.
+pub fn init(format: LogFormat, config: Option<&LoggingConfig>) -> io::Result<()> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            serde_json::to_writer(&mut *buf, &json_record(record, Utc::now()))?;
+            writeln!(buf)
+        });
+    }
+    if let Some(config) = config {
+        builder.target(env_logger::Target::Pipe(Box::new(RotatingFile::new(
+            config,
+        )?)));
+    }
+    builder.init();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use log::Level;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_record() {
+        let time = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let kvs: &[(&str, Value)] = &[
+            ("controller", Value::from("SoC")),
+            ("soc", Value::from(42.5)),
+            ("writes", Value::from(3u64)),
+            ("message", Value::from("ignored")),
+        ];
+        let record = |args| {
+            let record = Record::builder()
+                .args(args)
+                .level(Level::Error)
+                .target("socit::control")
+                .key_values(&kvs)
+                .build();
+            json_record(&record, time)
+        };
+        assert_eq!(
+            record(format_args!("Failed to update {}", "SoC")),
+            json!({
+                "time": "2024-06-01T12:00:00.000Z",
+                "level": "ERROR",
+                "target": "socit::control",
+                "message": "Failed to update SoC",
+                "controller": "SoC",
+                "soc": 42.5,
+                "writes": 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("socit-logging-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = LoggingConfig {
+            file: dir.join("socit.log"),
+            max_size: 10,
+            max_age: None,
+            keep: 2,
+        };
+        let mut file = RotatingFile::new(&config).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("socit.log"), "fourth\n");
+        assert_eq!(read("socit.log.1"), "third\n");
+        assert_eq!(read("socit.log.2"), "second\n");
+        assert!(!dir.join("socit.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,25 +14,90 @@
  * with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use clap::Parser;
-use log::info;
-use std::path::PathBuf;
+use chrono::{Days, NaiveTime, Utc};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tokio_util::sync::CancellationToken;
 
+use socit::alert::Alerter;
+use socit::bms::Bms;
 use socit::config::Config;
 use socit::control;
-use socit::esp_api::API;
+use socit::esp_api::{AreaResponse, API};
+use socit::file_monitor::FileMonitor;
+use socit::forecast_solar::ForecastSolar;
+use socit::http;
+use socit::influxdb1::Influxdb1Monitor;
 use socit::influxdb2::Influxdb2Monitor;
 use socit::inverter::{DryrunInverter, Inverter};
-use socit::monitoring::{Monitor, NullMonitor};
+use socit::logging::{self, LogFormat};
+use socit::modbus::Buses;
+use socit::monitoring::{BufferedMonitor, CycleLog, CycleLogMonitor, Monitor, MultiMonitor};
+use socit::mqtt::MqttMonitor;
+use socit::open_meteo::OpenMeteo;
+use socit::planner::{clip_power, local_time, utc_time};
+use socit::postgres::PostgresMonitor;
+use socit::pv;
+use socit::site::{self, Site};
+use socit::status::{self, StatusBoard, StatusMonitor};
 use socit::sunsynk::SunsynkInverter;
+use socit::systemd::Supervisor;
+use socit::write_budget::BudgetedInverter;
 
 #[derive(Parser)]
 #[clap(author, version)]
 struct Args {
     #[clap()]
     config_file: PathBuf,
+    /// Reload the configuration when the file changes
+    #[clap(long)]
+    watch: bool,
+    /// Fetch the load-shedding schedule, update the SoC once and exit
+    #[clap(long, conflicts_with = "watch")]
+    once: bool,
+    /// Log format (text or json)
+    #[clap(long, default_value = "text")]
+    log_format: LogFormat,
+    /// Only use the named site (required for subcommands if there are several)
+    #[clap(long)]
+    site: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the predicted clear-sky PV production for today and tomorrow
+    Sun {
+        /// Time between predictions (minutes)
+        #[clap(long, default_value_t = 60)]
+        step: u32,
+    },
+    /// Print the status of the running daemon
+    Status,
+    /// Print the targets that would be computed, without using the inverter
+    Simulate {
+        /// Usable battery capacity (Wh) [default: capacity_wh from the configuration]
+        #[clap(long)]
+        capacity: Option<f64>,
+        /// Saved EskomSePush area response (JSON) to use instead of fetching it
+        #[clap(long)]
+        response: Option<PathBuf>,
+    },
+    /// Print the upcoming load-shedding events and schedule
+    Schedule {
+        /// Saved EskomSePush area response (JSON) to use instead of fetching it
+        #[clap(long)]
+        response: Option<PathBuf>,
+    },
+    /// Write the programs for a minimum SoC once and exit
+    SetSoc {
+        /// Minimum SoC (%)
+        soc: f64,
+    },
 }
 
 #[cfg(unix)]
@@ -52,60 +117,627 @@ async fn wait_shutdown() -> std::io::Result<()> {
     tokio::signal::ctrl_c().await
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-    let args = Args::parse();
-    let config: Config = toml::from_str(&std::fs::read_to_string(args.config_file)?)?;
-    let esp_timeout = chrono::Duration::from_std(config.esp.timeout)?;
+#[cfg(unix)]
+async fn wait_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::hangup()) {
+        Ok(mut sighup) => {
+            sighup.recv().await;
+        }
+        Err(err) => {
+            warn!("Cannot listen for SIGHUP: {err}");
+            std::future::pending::<()>().await;
+        }
+    }
+}
 
-    let mut inverter = SunsynkInverter::new(&config.inverter.device, config.inverter.id);
-    if let Ok(programs) = inverter.get_programs().await {
-        for (i, program) in programs.iter().enumerate() {
-            info!("Program {}: {}: {}", i, program.time, program.soc);
+#[cfg(not(unix))]
+async fn wait_hangup() {
+    std::future::pending::<()>().await;
+}
+
+fn load_config(path: &Path, name: Option<&str>) -> Result<Vec<Site>, Box<dyn std::error::Error>> {
+    site::select_sites(site::parse_sites(&std::fs::read_to_string(path)?)?, name)
+}
+
+/// Get the configuration of the only (selected) site
+fn single_site(sites: Vec<Site>) -> Result<Config, Box<dyn std::error::Error>> {
+    if sites.len() > 1 {
+        return Err("the configuration has several sites; choose one with --site".into());
+    }
+    Ok(sites.into_iter().next().unwrap().config)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Wait until the modification time of a file changes and then stays the
+/// same for a poll interval (so that a partially-written file is not loaded).
+async fn wait_modified(path: &Path, last: Option<SystemTime>) -> Option<SystemTime> {
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+    let mut current = last;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let modified = modified_time(path);
+        if modified == current && current != last {
+            return current;
+        }
+        current = modified;
+    }
+}
+
+/// Wait for a request to reload the configuration, returning the new one.
+///
+/// Invalid configurations are reported and ignored.
+async fn wait_reload(path: &Path, name: Option<&str>, watch: bool) -> Vec<Site> {
+    let mut modified = modified_time(path);
+    loop {
+        tokio::select! {
+            _ = wait_hangup() => {},
+            new_modified = wait_modified(path, modified), if watch => {
+                modified = new_modified;
+            }
+        }
+        match load_config(path, name) {
+            Ok(sites) => {
+                info!("Reloading configuration from {}", path.display());
+                return sites;
+            }
+            Err(err) => {
+                error!("Not reloading invalid configuration: {err}");
+            }
+        }
+    }
+}
+
+/// Print the predicted production of each set of panels, so that the
+/// orientation settings can be checked.
+fn print_sun(config: &Config, step: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let panels = &config.inverter.panels;
+    let timezone = config.inverter.timezone.as_ref();
+    let step = chrono::Duration::minutes(step.max(1).into());
+    let today = local_time(timezone, Utc::now()).date();
+    for date in [today, today + Days::new(1)] {
+        let start = utc_time(timezone, date.and_time(NaiveTime::MIN));
+        let end = utc_time(timezone, (date + Days::new(1)).and_time(NaiveTime::MIN));
+        let (Some(start), Some(end)) = (start, end) else {
+            continue;
+        };
+        let curves: Vec<_> = panels
+            .iter()
+            .map(|panel| pv::forecast(std::slice::from_ref(panel), start..end, step))
+            .collect();
+        let total: Vec<_> = pv::forecast(panels, start..end, step)
+            .into_iter()
+            .map(|(time, power)| (time, clip_power(power, config.inverter.max_pv_power)))
+            .collect();
+        let mut header = format!("{date} ");
+        for i in 0..panels.len() {
+            header += &format!(" {:>8}", format!("Panels {}", i + 1));
+        }
+        println!("{header}  Total W");
+        for (i, (time, power)) in total.iter().enumerate() {
+            if *power <= 0.0 {
+                continue;
+            }
+            let mut line = format!("{:>10} ", local_time(timezone, *time).format("%H:%M"));
+            for curve in curves.iter() {
+                line += &format!(" {:8.0}", curve[i].1);
+            }
+            line += &format!(" {power:8.0}");
+            println!("{line}");
+        }
+        println!("  Total: {:.2} kWh", pv::energy(&total, step) * 1e-3);
+    }
+    Ok(())
+}
+
+/// Print the status reported by the running daemon
+async fn print_status(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(status_config) = &config.status else {
+        return Err("no [status] section in the configuration".into());
+    };
+    let status = status::query(&status_config.socket).await?;
+    let timezone = config.inverter.timezone.as_ref();
+    let format = |time| local_time(timezone, time).format("%Y-%m-%d %H:%M:%S");
+    match &status.soc {
+        Some(soc) => {
+            println!(
+                "SoC:              {:.1}% at {}",
+                soc.current_soc,
+                format(soc.time)
+            );
+            println!(
+                "Target SoC:       {:.1}% to {:.1}% (alarm {:.1}%)",
+                soc.target_soc_low, soc.target_soc_high, soc.alarm_soc
+            );
+            let battery: Vec<String> = [
+                soc.battery_power.map(|x| format!("{x:.0} W")),
+                soc.battery_voltage.map(|x| format!("{x:.2} V")),
+                soc.battery_current.map(|x| format!("{x:.1} A")),
+                soc.battery_temperature.map(|x| format!("{x:.1} °C")),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            if !battery.is_empty() {
+                println!("Battery:          {}", battery.join(", "));
+            }
+        }
+        None => println!("SoC:              unknown"),
+    }
+    match &status.next_event {
+        Some(event) if event.start <= Utc::now() => {
+            println!(
+                "Load-shedding:    {} until {}",
+                event.note,
+                format(event.end)
+            )
+        }
+        Some(event) => println!(
+            "Load-shedding:    {} from {} to {}",
+            event.note,
+            format(event.start),
+            format(event.end)
+        ),
+        None => println!("Load-shedding:    none scheduled"),
+    }
+    let optional = |time: Option<_>| time.map_or("never".to_string(), |t| format(t).to_string());
+    println!("Schedule fetched: {}", optional(status.esp_refresh));
+    println!("Inverter written: {}", optional(status.last_write));
+    if let Some(health) = &status.health {
+        println!(
+            "Inverter:         {} ({} failed transactions, {} consecutive)",
+            if health.inverter_reachable {
+                "reachable"
+            } else {
+                "unreachable"
+            },
+            health.inverter_failures,
+            health.inverter_consecutive_failures
+        );
+    }
+    Ok(())
+}
+
+/// Write the programs for a minimum SoC, with the fallback SoC outside the
+/// current window (as the daemon would), honouring `dry_run`.
+async fn set_soc(config: &Config, soc: f64) -> Result<(), Box<dyn std::error::Error>> {
+    if !(0.0..=100.0).contains(&soc) {
+        return Err(format!("SoC {soc} is not between 0 and 100").into());
+    }
+    let mut inverter = wrap_inverter(config, SunsynkInverter::new(&config.inverter));
+    inverter
+        .set_min_soc(soc, config.inverter.fallback_soc_at(Utc::now()))
+        .await
+        .map_err(|err| -> Box<dyn std::error::Error> { err })?;
+    println!("Set minimum SoC to {soc}%");
+    Ok(())
+}
+
+/// Load a saved EskomSePush area response, or fetch it if no path is given.
+/// Returns `None` if there is no path and no `[esp]` section.
+async fn load_response(
+    config: &Config,
+    path: Option<&Path>,
+) -> Result<Option<AreaResponse>, Box<dyn std::error::Error>> {
+    Ok(match (path, &config.esp) {
+        (Some(path), _) => Some(serde_json::from_str(&std::fs::read_to_string(path)?)?),
+        (None, Some(esp)) => Some(API::new(esp.key.clone())?.area(&esp.area).await?),
+        (None, None) => None,
+    })
+}
+
+/// Print the upcoming load-shedding events and the schedule for each stage
+async fn print_schedule(
+    config: &Config,
+    response: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(response) = load_response(config, response).await? else {
+        return Err("no [esp] section in the configuration".into());
+    };
+    let timezone = config.inverter.timezone.as_ref();
+    let format = |time| local_time(timezone, time).format("%a %Y-%m-%d %H:%M");
+    println!("{} ({})", response.info.name, response.info.region);
+    println!();
+    println!("Events:");
+    if response.events.is_empty() {
+        println!("  none");
+    }
+    for event in response.events.iter() {
+        println!(
+            "  {} to {}  {}",
+            format(event.start),
+            local_time(timezone, event.end).format("%H:%M"),
+            event.note
+        );
+    }
+    println!();
+    println!("Schedule ({}):", response.schedule.source);
+    for day in response.schedule.days.iter() {
+        println!("  {} ({})", day.date, day.name);
+        for (i, slots) in day.stages.iter().enumerate() {
+            let slots = if slots.is_empty() {
+                "none".to_string()
+            } else {
+                slots.join(", ")
+            };
+            println!("    Stage {}: {slots}", i + 1);
+        }
+    }
+    Ok(())
+}
+
+/// Print the targets and projected SoC for the current load-shedding
+/// schedule (or a saved one).
+async fn print_simulation(
+    config: &Config,
+    capacity: Option<f64>,
+    response: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(capacity) = capacity.or(config.inverter.capacity_wh) else {
+        return Err("the battery capacity must be given with --capacity or capacity_wh".into());
+    };
+    let response = load_response(config, response).await?;
+    let now = Utc::now();
+    let timezone = config.inverter.timezone.as_ref();
+    let Some(plan) = control::plan_offline(config, response, Vec::new(), capacity, now) else {
+        return Err("no load-shedding information".into());
+    };
+    let targets = &plan.targets;
+    let projection = &plan.projection;
+    println!(
+        "Target SoC: {:.1}% to {:.1}%",
+        targets.target_soc_low, targets.target_soc_high
+    );
+    println!("Alarm SoC: {:.1}%", targets.alarm_soc);
+    println!(
+        "Worst case: {}",
+        local_time(timezone, projection.worst_time).format("%Y-%m-%d %H:%M")
+    );
+    println!("Projected SoC starting from {:.1}%:", projection.target_soc);
+    let mut next = now;
+    for &(time, soc) in projection.soc.iter() {
+        if time >= next {
+            let local = local_time(timezone, time).format("%Y-%m-%d %H:%M");
+            println!("  {local} {soc:5.1}%");
+            next = time + chrono::Duration::hours(1);
         }
     }
+    Ok(())
+}
+
+/// Create the configured monitoring backends
+async fn create_monitors(
+    config: &Config,
+) -> Result<Vec<Box<dyn Monitor>>, Box<dyn std::error::Error>> {
+    let mut monitors: Vec<Box<dyn Monitor>> = Vec::new();
+    let mut add_monitor = |name: &str, monitor: Box<dyn Monitor>| {
+        monitors.push(Box::new(BufferedMonitor::new(
+            name,
+            monitor,
+            &config.monitoring,
+        )));
+    };
+    if let Some(conf) = &config.influxdb1 {
+        add_monitor("influxdb1", Box::new(Influxdb1Monitor::new(conf).await?));
+    }
+    if let Some(conf) = &config.influxdb2 {
+        add_monitor("influxdb2", Box::new(Influxdb2Monitor::new(conf).await));
+    }
+    if let Some(conf) = &config.mqtt {
+        add_monitor("mqtt", Box::new(MqttMonitor::new(conf)?));
+    }
+    if let Some(conf) = &config.postgres {
+        add_monitor("postgres", Box::new(PostgresMonitor::new(conf)?));
+    }
+    if let Some(conf) = &config.file {
+        add_monitor("file", Box::new(FileMonitor::new(conf)?));
+    }
+    Ok(monitors)
+}
+
+/// Fetch everything once, update the SoC and return. Fails if the
+/// load-shedding schedule could not be fetched, rather than planning
+/// without it.
+async fn run_once(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let esp = control::EspStatus::default();
+    let esp_timeout = match &config.esp {
+        Some(esp_config) => {
+            let api = API::new(esp_config.key.clone())?;
+            if !control::refresh_esp(&api, &esp_config.area, &esp).await {
+                return Err("failed to fetch the load-shedding schedule".into());
+            }
+            chrono::Duration::from_std(esp_config.timeout)?
+        }
+        None => {
+            info!("No [esp] section: optimising for self-consumption only");
+            chrono::Duration::zero()
+        }
+    };
+    let forecasts = Mutex::new(Vec::new());
+    let panels = &config.inverter.panels;
+    control::refresh_forecasts(
+        &ForecastSolar::new(&config.forecast_solar)?,
+        panels,
+        &forecasts,
+    )
+    .await;
+    control::refresh_temperatures(&OpenMeteo::new(&config.open_meteo)?, panels, &forecasts).await;
 
-    let mut inverter: Box<dyn Inverter> = if config.inverter.dry_run {
-        Box::new(DryrunInverter::new(inverter))
-    } else {
-        Box::new(inverter)
+    let (inverter, bms) = open_devices(config, &mut Buses::default());
+    let mut inverter = wrap_inverter(config, inverter);
+    let mut monitor = MultiMonitor::new(create_monitors(config).await?);
+    let alerter = Alerter::new(config.alerts.as_ref())?;
+    let cycle_log = CycleLog::new(config.monitoring.cycle_log_size);
+    let ctx = control::Context {
+        config,
+        esp: &esp,
+        esp_timeout,
+        alerter: &alerter,
+        log: &cycle_log,
+        forecasts: &forecasts,
+        bms: bms.as_ref(),
     };
+    control::control_once(inverter.as_mut(), &mut monitor, &ctx)
+        .await
+        .map_err(|err| -> Box<dyn std::error::Error> { err })
+}
+
+/// Apply the write budget and dry-run mode (if configured) to the inverter
+fn wrap_inverter(config: &Config, inverter: SunsynkInverter) -> Box<dyn Inverter> {
+    let mut inverter: Box<dyn Inverter> = Box::new(inverter);
+    if let Some(budget) = &config.write_budget {
+        inverter = Box::new(BudgetedInverter::new(inverter, budget));
+    }
+    if config.inverter.dry_run {
+        inverter = Box::new(DryrunInverter::new(inverter));
+    }
+    inverter
+}
+
+/// Create the inverter and the BMS (if configured), sharing a Modbus
+/// connection with any other devices already opened on the same bus.
+fn open_devices(config: &Config, buses: &mut Buses) -> (SunsynkInverter, Option<Bms>) {
+    let inverter = SunsynkInverter::with_bus(&config.inverter, buses.open(&config.inverter.device));
+    let bms = config
+        .bms
+        .as_ref()
+        .map(|bms| Bms::new(bms, buses.open(&bms.device)));
+    (inverter, bms)
+}
+
+/// Run until cancelled, reporting progress to `supervisor` as loop `index`
+async fn run(
+    config: Config,
+    (inverter, bms): (SunsynkInverter, Option<Bms>),
+    supervisor: Arc<Supervisor>,
+    index: usize,
+    token: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Shared with the HTTP server
+    let config = Arc::new(config);
+    let esp_timeout = match &config.esp {
+        Some(esp) => chrono::Duration::from_std(esp.timeout)?,
+        None => {
+            info!("No [esp] section: optimising for self-consumption only");
+            chrono::Duration::zero()
+        }
+    };
+    let mut inverter = wrap_inverter(&config, inverter);
+    // This also keeps the programs for restoring them on shutdown
+    if let Ok(Some(programs)) = inverter.get_programs().await {
+        for (i, program) in programs.iter().enumerate() {
+            info!("Program {}: {}: {}", i, program.time, program.soc);
+        }
+    }
 
-    let token = CancellationToken::new();
     let esp_token = token.clone();
     let control_token = token.clone();
-    let state = Arc::new(Mutex::new(None));
-    let state2 = state.clone();
+    let esp = Arc::new(control::EspStatus::default());
+    let esp2 = esp.clone();
+    let esp3 = esp.clone();
+    let esp4 = esp.clone();
     /* TODO: see if there is a nice way to avoid cloning (std::mem::take
      * requires making config mutable).
      */
-    let api = API::new(config.esp.key.clone())?;
-    let area = config.esp.area.clone();
-    let esp_handle = tokio::spawn(async move {
-        control::poll_esp(&api, &area, config.esp.interval, &state, esp_token).await;
-    });
-    let mut monitor: Box<dyn Monitor> = match &config.influxdb2 {
-        Some(conf) => Box::new(Influxdb2Monitor::new(conf).await),
-        None => Box::new(NullMonitor {}),
+    let esp_handle = match &config.esp {
+        Some(esp_config) => {
+            let api = API::new(esp_config.key.clone())?;
+            let area = esp_config.area.clone();
+            let interval = esp_config.interval;
+            Some(tokio::spawn(async move {
+                control::poll_esp(&api, &area, interval, &esp, esp_token).await;
+            }))
+        }
+        None => None,
     };
+    let forecast_api = ForecastSolar::new(&config.forecast_solar)?;
+    let forecasts = Arc::new(Mutex::new(Vec::new()));
+    let forecasts2 = forecasts.clone();
+    let forecasts3 = forecasts.clone();
+    let forecast_token = token.clone();
+    let panels = config.inverter.panels.clone();
+    let forecast_interval = config.forecast_solar.interval;
+    let forecast_handle = tokio::spawn(async move {
+        control::poll_forecasts(
+            &forecast_api,
+            &panels,
+            forecast_interval,
+            &forecasts,
+            forecast_token,
+        )
+        .await;
+    });
+    let temperature_api = OpenMeteo::new(&config.open_meteo)?;
+    let temperature_forecasts = forecasts2.clone();
+    let temperature_token = token.clone();
+    let panels = config.inverter.panels.clone();
+    let temperature_interval = config.open_meteo.interval;
+    let temperature_handle = tokio::spawn(async move {
+        control::poll_temperatures(
+            &temperature_api,
+            &panels,
+            temperature_interval,
+            &temperature_forecasts,
+            temperature_token,
+        )
+        .await;
+    });
+    let mut monitors = create_monitors(&config).await?;
+    let cycle_log = Arc::new(CycleLog::new(config.monitoring.cycle_log_size));
+    monitors.push(Box::new(CycleLogMonitor::new(cycle_log.clone())));
+    let status_board = Arc::new(StatusBoard::default());
+    monitors.push(Box::new(StatusMonitor::new(status_board.clone())));
+    let status_board2 = status_board.clone();
+    let status_handle = config.status.as_ref().map(|status| {
+        let path = status.socket.clone();
+        let status_token = token.clone();
+        tokio::spawn(async move {
+            status::serve(&path, &status_board, &esp3, status_token).await;
+        })
+    });
+    let http_handle = config.http.as_ref().map(|http| {
+        let listen = http.listen.clone();
+        let config = config.clone();
+        let http_token = token.clone();
+        tokio::spawn(async move {
+            let api = http::Api {
+                config: &config,
+                board: &status_board2,
+                esp: &esp4,
+                forecasts: &forecasts3,
+            };
+            http::serve(&listen, api, http_token).await;
+        })
+    });
+    let mut monitor = MultiMonitor::new(monitors);
+    let alerter = Alerter::new(config.alerts.as_ref())?;
     let control_handle = tokio::spawn(async move {
         // Give poll_esp some time to load the first set of information
         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        let ctx = control::Context {
+            config: &config,
+            esp: &esp2,
+            esp_timeout,
+            alerter: &alerter,
+            log: &cycle_log,
+            forecasts: &forecasts2,
+            bms: bms.as_ref(),
+        };
         control::control_inverter(
             inverter.as_mut(),
-            &config,
-            &mut *monitor,
-            &state2,
-            esp_timeout,
+            &mut monitor,
+            &ctx,
+            &supervisor,
+            index,
             control_token,
         )
         .await;
     });
 
-    wait_shutdown().await?;
-    token.cancel();
-    esp_handle.await?;
+    if let Some(esp_handle) = esp_handle {
+        esp_handle.await?;
+    }
+    forecast_handle.await?;
+    temperature_handle.await?;
     control_handle.await?;
+    if let Some(status_handle) = status_handle {
+        status_handle.await?;
+    }
+    if let Some(http_handle) = http_handle {
+        http_handle.await?;
+    }
     Ok(())
 }
+
+/// Run the control loops for all the sites concurrently.
+///
+/// Each Modbus device is opened once, so that sites with devices on the
+/// same bus share the connection. Systemd is only told that the service is
+/// ready, and the watchdog only petted, when that is true of every site.
+async fn run_sites(
+    sites: Vec<Site>,
+    token: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buses = Buses::default();
+    let devices: Vec<_> = sites
+        .iter()
+        .map(|site| open_devices(&site.config, &mut buses))
+        .collect();
+    let supervisor = Arc::new(Supervisor::new(sites.len()));
+    let running = futures::future::try_join_all(sites.into_iter().zip(devices).enumerate().map(
+        |(index, (site, devices))| {
+            if let Some(name) = &site.name {
+                info!("Starting site {name}");
+            }
+            run(
+                site.config,
+                devices,
+                supervisor.clone(),
+                index,
+                token.clone(),
+            )
+        },
+    ));
+    // Stops the watchdog if the sites stop (including on startup failure)
+    let watchdog_token = token.child_token();
+    let (result, ()) = tokio::join!(
+        async {
+            let result = running.await;
+            watchdog_token.cancel();
+            result
+        },
+        supervisor.run_watchdog(watchdog_token.clone())
+    );
+    result?;
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let site_name = args.site.as_deref();
+    let mut sites = load_config(&args.config_file, site_name)?;
+    // Changes to the logging configuration only take effect on restart. It
+    // is shared by all the sites, since [[site]] tables may not contain it.
+    logging::init(args.log_format, sites[0].config.logging.as_ref())?;
+    if let Some(command) = &args.command {
+        let config = single_site(sites)?;
+        return match command {
+            Command::Sun { step } => print_sun(&config, *step),
+            Command::Status => print_status(&config).await,
+            Command::Simulate { capacity, response } => {
+                print_simulation(&config, *capacity, response.as_deref()).await
+            }
+            Command::SetSoc { soc } => set_soc(&config, *soc).await,
+            Command::Schedule { response } => print_schedule(&config, response.as_deref()).await,
+        };
+    }
+    if args.once {
+        return run_once(&single_site(sites)?).await;
+    }
+    loop {
+        let token = CancellationToken::new();
+        let running = run_sites(sites, token.clone());
+        tokio::pin!(running);
+        tokio::select! {
+            result = &mut running => {
+                // Only happens if startup fails
+                return result;
+            }
+            result = wait_shutdown() => {
+                result?;
+                token.cancel();
+                running.await?;
+                return Ok(());
+            }
+            new_sites = wait_reload(&args.config_file, site_name, args.watch) => {
+                token.cancel();
+                running.await?;
+                sites = new_sites;
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Manual overrides of the automatic targets
+//!
+//! The override is read from a file at the start of every SoC cycle, so it
+//! can be changed (e.g. with `echo hold > /run/socit/override`) without
+//! restarting. A missing or empty file means automatic control.
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Override {
+    /// Use the computed targets
+    #[default]
+    Auto,
+    /// Keep the battery at its current SoC
+    Hold,
+    /// Charge to (and hold at) the given SoC
+    Charge(f64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOverrideError(String);
+
+impl fmt::Display for ParseOverrideError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid override {:?} (expected auto, hold, charge or charge <SoC>)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseOverrideError {}
+
+impl FromStr for Override {
+    type Err = ParseOverrideError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseOverrideError(s.to_string());
+        let mut words = s.split_whitespace();
+        let result = match words.next() {
+            None | Some("auto") => Override::Auto,
+            Some("hold") => Override::Hold,
+            Some("charge") => match words.next() {
+                None => Override::Charge(100.0),
+                Some(soc) => {
+                    let soc: f64 = soc.trim_end_matches('%').parse().map_err(|_| err())?;
+                    if !(0.0..=100.0).contains(&soc) {
+                        return Err(err());
+                    }
+                    Override::Charge(soc)
+                }
+            },
+            Some(_) => return Err(err()),
+        };
+        if words.next().is_some() {
+            return Err(err());
+        }
+        Ok(result)
+    }
+}
+
+impl fmt::Display for Override {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Override::Auto => write!(f, "auto"),
+            Override::Hold => write!(f, "hold"),
+            Override::Charge(soc) => write!(f, "charge {soc}"),
+        }
+    }
+}
+
+impl Override {
+    /// Read the override from a file (automatic if the file does not exist)
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Ok(contents.parse()?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Override::Auto),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Minimum SoC to set instead of the automatic target (None if automatic)
+    pub fn target(&self, current_soc: f64) -> Option<f64> {
+        match self {
+            Override::Auto => None,
+            Override::Hold => Some(current_soc),
+            Override::Charge(soc) => Some(*soc),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("".parse(), Ok(Override::Auto));
+        assert_eq!("auto\n".parse(), Ok(Override::Auto));
+        assert_eq!("hold".parse(), Ok(Override::Hold));
+        assert_eq!("charge".parse(), Ok(Override::Charge(100.0)));
+        assert_eq!(" charge 80% ".parse(), Ok(Override::Charge(80.0)));
+        assert!("charge 120".parse::<Override>().is_err());
+        assert!("hold 50".parse::<Override>().is_err());
+        assert!("discharge".parse::<Override>().is_err());
+    }
+}
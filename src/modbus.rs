@@ -0,0 +1,101 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Modbus connections that can be shared by several devices on one bus
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::Instant;
+use tokio_modbus::client::Context;
+use tokio_modbus::slave::{Slave, SlaveContext};
+
+/// State of a bus, accessed through [Bus::lock]
+pub struct Connection {
+    pub ctx: Context,
+    /// Time at which the previous request on the bus completed
+    pub last_request: Option<Instant>,
+}
+
+/// A Modbus connection shared by devices with different slave IDs.
+///
+/// Cloning gives another handle to the same connection. Each transaction
+/// should be done while holding the lock returned by [Bus::lock], so that
+/// requests from different devices are not interleaved.
+#[derive(Clone)]
+pub struct Bus {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Bus {
+    /// Create a bus for a device, which is either a socket address (for
+    /// Modbus TCP) or a serial device file (for Modbus RTU). The connection
+    /// is only made when the first request is issued.
+    pub fn open(device: &str) -> Self {
+        let slave = Slave::broadcast();
+        let ctx = match device.parse() {
+            Ok(socket_addr) => modbus_robust::new_tcp_slave(socket_addr, slave),
+            Err(_) => {
+                // Not an address. Try it as a device file for serial
+                modbus_robust::new_rtu_slave(device, 9600, slave)
+            }
+        };
+        Self {
+            connection: Arc::new(Mutex::new(Connection {
+                ctx,
+                last_request: None,
+            })),
+        }
+    }
+
+    /// Get exclusive access to the bus, addressing requests to `slave`
+    pub async fn lock(&self, slave: Slave) -> MutexGuard<'_, Connection> {
+        let mut connection = self.connection.lock().await;
+        connection.ctx.set_slave(slave);
+        connection
+    }
+}
+
+/// Buses opened so far, so that each device is only opened once
+#[derive(Default)]
+pub struct Buses {
+    buses: HashMap<String, Bus>,
+}
+
+impl Buses {
+    /// Get the bus for a device, opening it if necessary
+    pub fn open(&mut self, device: &str) -> Bus {
+        self.buses
+            .entry(device.to_owned())
+            .or_insert_with(|| Bus::open(device))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buses_shared() {
+        let mut buses = Buses::default();
+        let a = buses.open("127.0.0.1:502");
+        let b = buses.open("127.0.0.1:502");
+        let c = buses.open("127.0.0.1:503");
+        assert!(Arc::ptr_eq(&a.connection, &b.connection));
+        assert!(!Arc::ptr_eq(&a.connection, &c.connection));
+    }
+}
@@ -16,21 +16,110 @@
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::error::Error;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-#[derive(Clone, PartialEq, Debug)]
+use crate::config::MonitoringConfig;
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct SocUpdate {
     pub time: DateTime<Utc>,
     pub target_soc_low: f64,
     pub target_soc_high: f64,
     pub alarm_soc: f64,
     pub current_soc: f64,
+    #[serde(default)]
+    pub capacity: f64, // Battery capacity in Wh
+    #[serde(default)]
+    pub target_energy_low: f64, // Stored energy at target_soc_low, in Wh
+    #[serde(default)]
+    pub target_energy_high: f64, // Stored energy at target_soc_high, in Wh
+    #[serde(default)]
+    pub alarm_energy: f64, // Stored energy at alarm_soc, in Wh
+    #[serde(default)]
+    pub current_energy: f64, // In Wh
+    #[serde(default)]
+    pub energy_deficit: f64, // Energy needed to reach target_soc_low, in Wh
+    #[serde(default)]
+    pub backup_runtime: Option<f64>, // Hours until min_soc at the current load
     pub predicted_pv: f64, // In watts
+    #[serde(default)]
+    pub pv_window_start: Option<DateTime<Utc>>, // Today's predicted PV window
+    #[serde(default)]
+    pub pv_window_end: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub time_to_sunrise: Option<f64>, // Seconds until the next sunrise
+    #[serde(default)]
+    pub daylight_remaining: Option<f64>, // Seconds until sunset, or zero at night
     pub is_loadshedding: bool,
     pub next_change: Option<DateTime<Utc>>,
+    pub smart_load: bool,
+    pub aux_power: Option<f64>, // In watts
+    #[serde(default)]
+    pub battery_power: Option<f64>, // In watts, positive for discharging
+    #[serde(default)]
+    pub battery_voltage: Option<f64>, // In volts
+    #[serde(default)]
+    pub battery_current: Option<f64>, // In amps, positive for discharging
+    #[serde(default)]
+    pub battery_temperature: Option<f64>, // In °C
+    #[serde(default)]
+    pub grid_connected: Option<bool>,
+    #[serde(default)]
+    pub load_power: Option<f64>, // In watts
+    #[serde(default)]
+    pub soc_filtered: u64, // Implausible SoC readings rejected since startup
+    #[serde(default)]
+    pub esp_successes: u64, // Successful polls since startup
+    #[serde(default)]
+    pub esp_failures: u64, // Failed polls since startup
+    #[serde(default)]
+    pub esp_age: Option<f64>, // Seconds since the last successful poll
+    #[serde(default)]
+    pub esp_latency: Option<f64>, // Seconds taken by the last poll
+    #[serde(default)]
+    pub esp_quota_remaining: Option<i64>, // API calls left in the current period
+    #[serde(default)]
+    pub wear_cost: Option<f64>, // Estimated battery wear cost so far today
+    #[serde(default)]
+    pub grid_cost: Option<f64>, // Estimated grid import cost so far today
+    #[serde(default)]
+    pub emergency: bool, // Whether the SoC has fallen below alarm_soc and not yet recovered
+    #[serde(default)]
+    pub unscheduled_outage: bool, // Whether the grid is down outside the load-shedding schedule
+    #[serde(default)]
+    pub manual_soc: Option<f64>, // Minimum SoC forced by a manual override
+    #[serde(default)]
+    pub inverter_writes: Option<u64>, // Register writes since startup
+    #[serde(default)]
+    pub write_budget_used: Option<u64>, // Register writes in the last 24 hours, if limited
+    #[serde(default)]
+    pub clock_skew: Option<f64>, // Seconds by which the inverter clock is ahead of system time
+    #[serde(default)]
+    pub active_faults: Option<Vec<String>>, // Faults reported by the inverter
+    #[serde(default)]
+    pub cell_temperature_min: Option<f64>, // In °C, from the BMS
+    #[serde(default)]
+    pub cell_temperature_max: Option<f64>, // In °C, from the BMS
+    #[serde(default)]
+    pub dry_run_writes: Option<Vec<String>>, // Writes suppressed since the previous update
+    #[serde(default)]
+    pub prediction_bias: Option<f64>, // Mean actual minus predicted battery power, in watts
+    #[serde(default)]
+    pub prediction_error: Option<f64>, // Mean absolute prediction error, in watts
+    #[serde(default)]
+    pub load_scale: Option<f64>, // Estimated ratio of actual to predicted load
+    #[serde(default)]
+    pub pv_scale: Option<f64>, // Estimated ratio of actual to predicted PV power
 }
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct CoilUpdate {
     pub time: DateTime<Utc>,
     pub active: bool,
@@ -38,10 +127,37 @@ pub struct CoilUpdate {
     pub setting: Option<f64>, // In watts
 }
 
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PvString {
+    pub mppt: usize,        // Numbered from 1
+    pub predicted: f64,     // In watts
+    pub measured: f64,      // In watts
+    pub ratio: Option<f64>, // measured / predicted, if predicted is significant
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PvUpdate {
+    pub time: DateTime<Utc>,
+    pub strings: Vec<PvString>,
+}
+
+/// Health of the connection to the inverter, sent every SoC cycle (even if
+/// the cycle failed).
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct HealthUpdate {
+    pub time: DateTime<Utc>,
+    pub inverter_reachable: bool,
+    pub inverter_successes: u64,
+    pub inverter_failures: u64,
+    pub inverter_consecutive_failures: u64,
+}
+
 #[async_trait]
 pub trait Monitor: Send {
     async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>>;
     async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>>;
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>>;
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>>;
 }
 
 pub struct NullMonitor;
@@ -55,4 +171,407 @@ impl Monitor for NullMonitor {
     async fn coil_update(&mut self, _: CoilUpdate) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
+
+    async fn pv_update(&mut self, _: PvUpdate) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn health_update(&mut self, _: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// Forward updates to several monitors.
+///
+/// All monitors are updated even if some fail, and the errors are combined.
+pub struct MultiMonitor {
+    monitors: Vec<Box<dyn Monitor>>,
+}
+
+impl MultiMonitor {
+    pub fn new(monitors: Vec<Box<dyn Monitor>>) -> Self {
+        Self { monitors }
+    }
+}
+
+fn combine_errors(errors: Vec<String>) -> Result<(), Box<dyn Error>> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; ").into())
+    }
+}
+
+#[async_trait]
+impl Monitor for MultiMonitor {
+    async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
+        let mut errors = Vec::new();
+        for monitor in self.monitors.iter_mut() {
+            if let Err(err) = monitor.soc_update(update.clone()).await {
+                errors.push(err.to_string());
+            }
+        }
+        combine_errors(errors)
+    }
+
+    async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>> {
+        let mut errors = Vec::new();
+        for monitor in self.monitors.iter_mut() {
+            if let Err(err) = monitor.coil_update(update.clone()).await {
+                errors.push(err.to_string());
+            }
+        }
+        combine_errors(errors)
+    }
+
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>> {
+        let mut errors = Vec::new();
+        for monitor in self.monitors.iter_mut() {
+            if let Err(err) = monitor.pv_update(update.clone()).await {
+                errors.push(err.to_string());
+            }
+        }
+        combine_errors(errors)
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        let mut errors = Vec::new();
+        for monitor in self.monitors.iter_mut() {
+            if let Err(err) = monitor.health_update(update.clone()).await {
+                errors.push(err.to_string());
+            }
+        }
+        combine_errors(errors)
+    }
+}
+
+/// Any of the updates that can be sent to a monitor
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Update {
+    Soc(Box<SocUpdate>),
+    Coil(CoilUpdate),
+    Pv(PvUpdate),
+    Health(HealthUpdate),
+}
+
+impl Update {
+    async fn send(&self, monitor: &mut dyn Monitor) -> Result<(), Box<dyn Error>> {
+        match self {
+            Update::Soc(update) => monitor.soc_update((**update).clone()).await,
+            Update::Coil(update) => monitor.coil_update(update.clone()).await,
+            Update::Pv(update) => monitor.pv_update(update.clone()).await,
+            Update::Health(update) => monitor.health_update(update.clone()).await,
+        }
+    }
+}
+
+/// Something that happened in a control cycle
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CycleEvent {
+    Update(Update),
+    Error { controller: String, message: String },
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct CycleRecord {
+    pub time: DateTime<Utc>,
+    pub event: CycleEvent,
+}
+
+/// In-memory log of the most recent control cycles.
+///
+/// Updates are added by [CycleLogMonitor] and errors by the control loop.
+pub struct CycleLog {
+    capacity: usize,
+    records: Mutex<VecDeque<CycleRecord>>,
+}
+
+impl CycleLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, event: CycleEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(CycleRecord {
+            time: Utc::now(),
+            event,
+        });
+    }
+
+    /// Get the current contents, oldest first
+    pub fn records(&self) -> Vec<CycleRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Record all updates in a [CycleLog]
+pub struct CycleLogMonitor {
+    log: Arc<CycleLog>,
+}
+
+impl CycleLogMonitor {
+    pub fn new(log: Arc<CycleLog>) -> Self {
+        Self { log }
+    }
+}
+
+#[async_trait]
+impl Monitor for CycleLogMonitor {
+    async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
+        self.log
+            .push(CycleEvent::Update(Update::Soc(Box::new(update))));
+        Ok(())
+    }
+
+    async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>> {
+        self.log.push(CycleEvent::Update(Update::Coil(update)));
+        Ok(())
+    }
+
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>> {
+        self.log.push(CycleEvent::Update(Update::Pv(update)));
+        Ok(())
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        self.log.push(CycleEvent::Update(Update::Health(update)));
+        Ok(())
+    }
+}
+
+/// Wrap another monitor to queue updates that fail and retry them later.
+///
+/// Retries use exponential backoff. If a spool file is given, the queue is
+/// saved to it while it is non-empty so that it survives a restart. To limit
+/// wear on SD cards, the file is rewritten at most every
+/// [SAVE_INTERVAL](Self::SAVE_INTERVAL), and when the monitor is dropped.
+pub struct BufferedMonitor {
+    base: Box<dyn Monitor>,
+    queue: VecDeque<Update>,
+    capacity: usize,
+    spool: Option<PathBuf>,
+    /// Whether the queue has changed since the spool file was saved
+    dirty: bool,
+    last_save: Option<Instant>,
+    backoff: Duration,
+    max_backoff: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl BufferedMonitor {
+    const MIN_BACKOFF: Duration = Duration::from_secs(10);
+    const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+    pub fn new(name: &str, base: Box<dyn Monitor>, config: &MonitoringConfig) -> Self {
+        let spool = config
+            .spool_directory
+            .as_ref()
+            .map(|dir| dir.join(format!("{name}.jsonl")));
+        let mut monitor = Self {
+            base,
+            queue: VecDeque::new(),
+            capacity: config.buffer_size,
+            spool,
+            dirty: false,
+            last_save: None,
+            backoff: Self::MIN_BACKOFF,
+            max_backoff: config.max_backoff,
+            next_attempt: None,
+        };
+        if let Err(err) = monitor.load_spool() {
+            warn!("Failed to load monitoring spool file: {err}");
+        }
+        monitor
+    }
+
+    fn load_spool(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(path) = &self.spool else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        if !path.exists() {
+            return Ok(());
+        }
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        for line in file.lines() {
+            self.queue.push_back(serde_json::from_str(&line?)?);
+        }
+        while self.queue.len() > self.capacity {
+            self.queue.pop_front();
+        }
+        info!(
+            "Loaded {} queued monitoring updates from {}",
+            self.queue.len(),
+            path.to_string_lossy()
+        );
+        Ok(())
+    }
+
+    fn save_spool(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(path) = &self.spool else {
+            return Ok(());
+        };
+        self.dirty = false;
+        if self.queue.is_empty() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        self.last_save = Some(Instant::now());
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for update in self.queue.iter() {
+            serde_json::to_writer(&mut file, update)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Save the spool file if it is out of date and was not saved recently.
+    ///
+    /// Emptying the queue is saved immediately, so that a restart does not
+    /// send the updates again.
+    fn save_spool_periodic(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.dirty
+            && (self.queue.is_empty()
+                || self
+                    .last_save
+                    .is_none_or(|t| t.elapsed() >= Self::SAVE_INTERVAL))
+        {
+            self.save_spool()
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn push(&mut self, update: Update) -> Result<(), Box<dyn Error>> {
+        if self.queue.len() >= self.capacity {
+            warn!("Monitoring queue is full, discarding oldest update");
+            self.queue.pop_front();
+        }
+        self.queue.push_back(update);
+        self.dirty = true;
+        self.flush().await
+    }
+
+    /// Send queued updates, unless waiting for the backoff to expire
+    async fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.next_attempt.is_some_and(|t| Instant::now() < t) {
+            return self.save_spool_periodic();
+        }
+        while let Some(update) = self.queue.front() {
+            if let Err(err) = update.send(self.base.as_mut()).await {
+                self.next_attempt = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(self.max_backoff);
+                let msg = format!("{err} ({} updates queued)", self.queue.len());
+                self.save_spool_periodic()?;
+                return Err(msg.into());
+            }
+            self.queue.pop_front();
+        }
+        self.next_attempt = None;
+        self.backoff = Self::MIN_BACKOFF;
+        self.save_spool_periodic()
+    }
+}
+
+impl Drop for BufferedMonitor {
+    fn drop(&mut self) {
+        if self.dirty {
+            if let Err(err) = self.save_spool() {
+                warn!("Failed to save monitoring spool file: {err}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Monitor for BufferedMonitor {
+    async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
+        self.push(Update::Soc(Box::new(update))).await
+    }
+
+    async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>> {
+        self.push(Update::Coil(update)).await
+    }
+
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>> {
+        self.push(Update::Pv(update)).await
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        self.push(Update::Health(update)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct FailingMonitor;
+
+    #[async_trait]
+    impl Monitor for FailingMonitor {
+        async fn soc_update(&mut self, _: SocUpdate) -> Result<(), Box<dyn Error>> {
+            Err("unavailable".into())
+        }
+
+        async fn coil_update(&mut self, _: CoilUpdate) -> Result<(), Box<dyn Error>> {
+            Err("unavailable".into())
+        }
+
+        async fn pv_update(&mut self, _: PvUpdate) -> Result<(), Box<dyn Error>> {
+            Err("unavailable".into())
+        }
+
+        async fn health_update(&mut self, _: HealthUpdate) -> Result<(), Box<dyn Error>> {
+            Err("unavailable".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spool() {
+        let dir = std::env::temp_dir().join(format!("socit-spool-{}", std::process::id()));
+        let config = MonitoringConfig {
+            spool_directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let path = dir.join("test.jsonl");
+        let lines = || std::fs::read_to_string(&path).unwrap().lines().count();
+        let update = |target| CoilUpdate {
+            time: Utc::now(),
+            active: true,
+            target,
+            setting: None,
+        };
+
+        let mut monitor = BufferedMonitor::new("test", Box::new(FailingMonitor), &config);
+        assert!(monitor.coil_update(update(0.0)).await.is_err());
+        // These are queued without retrying, until the backoff expires
+        monitor.coil_update(update(1.0)).await.unwrap();
+        monitor.coil_update(update(2.0)).await.unwrap();
+        // Only the first failure is saved straight away
+        assert_eq!(lines(), 1);
+        drop(monitor);
+        assert_eq!(lines(), 3);
+
+        let monitor = BufferedMonitor::new("test", Box::new(FailingMonitor), &config);
+        assert_eq!(monitor.queue.len(), 3);
+        drop(monitor);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,794 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Publish monitoring data to an MQTT broker
+//!
+//! Messages are published with QoS 0. Each time the connection is
+//! established, [Home Assistant discovery](https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery)
+//! messages are also published so that the sensors appear in Home Assistant
+//! automatically.
+
+use async_trait::async_trait;
+use log::{info, warn};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS, TlsConfiguration, Transport,
+};
+use serde_json::json;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::MqttConfig;
+use crate::monitoring::{CoilUpdate, HealthUpdate, Monitor, PvUpdate, SocUpdate};
+use crate::tls;
+
+/// Time to wait before reconnecting after the connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// Number of messages that can be waiting to be sent, beyond the announcements
+const QUEUE_SIZE: usize = 256;
+
+/// A sensor to advertise to Home Assistant
+struct Sensor {
+    /// Suffix of the state topic, and object ID for Home Assistant
+    name: &'static str,
+    /// Human-readable name
+    title: &'static str,
+    /// Home Assistant component type ("sensor" or "binary_sensor")
+    component: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+}
+
+const SENSORS: &[Sensor] = &[
+    Sensor {
+        name: "target_soc_low",
+        title: "Target SoC (low)",
+        component: "sensor",
+        device_class: Some("battery"),
+        unit: Some("%"),
+    },
+    Sensor {
+        name: "target_soc_high",
+        title: "Target SoC (high)",
+        component: "sensor",
+        device_class: Some("battery"),
+        unit: Some("%"),
+    },
+    Sensor {
+        name: "alarm_soc",
+        title: "Alarm SoC",
+        component: "sensor",
+        device_class: Some("battery"),
+        unit: Some("%"),
+    },
+    Sensor {
+        name: "current_soc",
+        title: "Current SoC",
+        component: "sensor",
+        device_class: Some("battery"),
+        unit: Some("%"),
+    },
+    Sensor {
+        name: "capacity",
+        title: "Battery capacity",
+        component: "sensor",
+        device_class: Some("energy_storage"),
+        unit: Some("Wh"),
+    },
+    Sensor {
+        name: "target_energy_low",
+        title: "Target energy (low)",
+        component: "sensor",
+        device_class: Some("energy_storage"),
+        unit: Some("Wh"),
+    },
+    Sensor {
+        name: "target_energy_high",
+        title: "Target energy (high)",
+        component: "sensor",
+        device_class: Some("energy_storage"),
+        unit: Some("Wh"),
+    },
+    Sensor {
+        name: "alarm_energy",
+        title: "Alarm energy",
+        component: "sensor",
+        device_class: Some("energy_storage"),
+        unit: Some("Wh"),
+    },
+    Sensor {
+        name: "current_energy",
+        title: "Current energy",
+        component: "sensor",
+        device_class: Some("energy_storage"),
+        unit: Some("Wh"),
+    },
+    Sensor {
+        name: "energy_deficit",
+        title: "Energy deficit",
+        component: "sensor",
+        device_class: Some("energy_storage"),
+        unit: Some("Wh"),
+    },
+    Sensor {
+        name: "backup_runtime",
+        title: "Backup runtime",
+        component: "sensor",
+        device_class: Some("duration"),
+        unit: Some("h"),
+    },
+    Sensor {
+        name: "time_to_sunrise",
+        title: "Time to sunrise",
+        component: "sensor",
+        device_class: Some("duration"),
+        unit: Some("s"),
+    },
+    Sensor {
+        name: "daylight_remaining",
+        title: "Daylight remaining",
+        component: "sensor",
+        device_class: Some("duration"),
+        unit: Some("s"),
+    },
+    Sensor {
+        name: "manual_soc",
+        title: "Manual override SoC",
+        component: "sensor",
+        device_class: Some("battery"),
+        unit: Some("%"),
+    },
+    Sensor {
+        name: "predicted_pv",
+        title: "Predicted PV",
+        component: "sensor",
+        device_class: Some("power"),
+        unit: Some("W"),
+    },
+    Sensor {
+        name: "pv_window_start",
+        title: "PV window start",
+        component: "sensor",
+        device_class: Some("timestamp"),
+        unit: None,
+    },
+    Sensor {
+        name: "pv_window_end",
+        title: "PV window end",
+        component: "sensor",
+        device_class: Some("timestamp"),
+        unit: None,
+    },
+    Sensor {
+        name: "is_loadshedding",
+        title: "Load-shedding",
+        component: "binary_sensor",
+        device_class: Some("problem"),
+        unit: None,
+    },
+    Sensor {
+        name: "emergency",
+        title: "Low SoC emergency",
+        component: "binary_sensor",
+        device_class: Some("problem"),
+        unit: None,
+    },
+    Sensor {
+        name: "unscheduled_outage",
+        title: "Unscheduled grid outage",
+        component: "binary_sensor",
+        device_class: Some("problem"),
+        unit: None,
+    },
+    Sensor {
+        name: "next_change",
+        title: "Next load-shedding change",
+        component: "sensor",
+        device_class: Some("timestamp"),
+        unit: None,
+    },
+    Sensor {
+        name: "smart_load",
+        title: "Smart load output",
+        component: "binary_sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "aux_power",
+        title: "AUX/GEN port power",
+        component: "sensor",
+        device_class: Some("power"),
+        unit: Some("W"),
+    },
+    Sensor {
+        name: "battery_power",
+        title: "Battery power",
+        component: "sensor",
+        device_class: Some("power"),
+        unit: Some("W"),
+    },
+    Sensor {
+        name: "battery_voltage",
+        title: "Battery voltage",
+        component: "sensor",
+        device_class: Some("voltage"),
+        unit: Some("V"),
+    },
+    Sensor {
+        name: "battery_current",
+        title: "Battery current",
+        component: "sensor",
+        device_class: Some("current"),
+        unit: Some("A"),
+    },
+    Sensor {
+        name: "battery_temperature",
+        title: "Battery temperature",
+        component: "sensor",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+    },
+    Sensor {
+        name: "cell_temperature_min",
+        title: "Minimum cell temperature",
+        component: "sensor",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+    },
+    Sensor {
+        name: "cell_temperature_max",
+        title: "Maximum cell temperature",
+        component: "sensor",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+    },
+    Sensor {
+        name: "grid_connected",
+        title: "Grid connected",
+        component: "binary_sensor",
+        device_class: Some("power"),
+        unit: None,
+    },
+    Sensor {
+        name: "load_power",
+        title: "Load power",
+        component: "sensor",
+        device_class: Some("power"),
+        unit: Some("W"),
+    },
+    Sensor {
+        name: "soc_filtered",
+        title: "Implausible SoC readings",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "esp_successes",
+        title: "EskomSePush successful polls",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "esp_failures",
+        title: "EskomSePush failed polls",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "wear_cost",
+        title: "Battery wear cost today",
+        component: "sensor",
+        device_class: Some("monetary"),
+        unit: None,
+    },
+    Sensor {
+        name: "grid_cost",
+        title: "Grid cost today",
+        component: "sensor",
+        device_class: Some("monetary"),
+        unit: None,
+    },
+    Sensor {
+        name: "prediction_bias",
+        title: "Battery power prediction bias",
+        component: "sensor",
+        device_class: Some("power"),
+        unit: Some("W"),
+    },
+    Sensor {
+        name: "prediction_error",
+        title: "Battery power prediction error",
+        component: "sensor",
+        device_class: Some("power"),
+        unit: Some("W"),
+    },
+    Sensor {
+        name: "load_scale",
+        title: "Load prediction scale",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "pv_scale",
+        title: "PV prediction scale",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "clock_skew",
+        title: "Inverter clock skew",
+        component: "sensor",
+        device_class: Some("duration"),
+        unit: Some("s"),
+    },
+    Sensor {
+        name: "inverter_writes",
+        title: "Inverter register writes",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "write_budget_used",
+        title: "Inverter writes in the last day",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "active_faults",
+        title: "Inverter faults",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "inverter_reachable",
+        title: "Inverter reachable",
+        component: "binary_sensor",
+        device_class: Some("connectivity"),
+        unit: None,
+    },
+    Sensor {
+        name: "inverter_failures",
+        title: "Inverter communication failures",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "inverter_consecutive_failures",
+        title: "Inverter consecutive communication failures",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "esp_age",
+        title: "EskomSePush data age",
+        component: "sensor",
+        device_class: Some("duration"),
+        unit: Some("s"),
+    },
+    Sensor {
+        name: "esp_latency",
+        title: "EskomSePush latency",
+        component: "sensor",
+        device_class: Some("duration"),
+        unit: Some("s"),
+    },
+    Sensor {
+        name: "esp_quota_remaining",
+        title: "EskomSePush quota remaining",
+        component: "sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "coil_active",
+        title: "CT coil active",
+        component: "binary_sensor",
+        device_class: None,
+        unit: None,
+    },
+    Sensor {
+        name: "coil_target",
+        title: "Trickle target",
+        component: "sensor",
+        device_class: Some("power"),
+        unit: Some("W"),
+    },
+    Sensor {
+        name: "coil_setting",
+        title: "Trickle setting",
+        component: "sensor",
+        device_class: Some("power"),
+        unit: Some("W"),
+    },
+];
+
+fn availability_topic(config: &MqttConfig) -> String {
+    format!("{}/status", config.topic_prefix)
+}
+
+/// Messages (topic and payload) to publish as retained each time the
+/// connection is established: the availability, and the Home Assistant
+/// discovery messages if enabled.
+fn announcements(config: &MqttConfig) -> Vec<(String, String)> {
+    let mut messages = vec![(availability_topic(config), "online".to_string())];
+    if config.discovery_prefix.is_empty() {
+        return messages;
+    }
+    let node_id = &config.client_id;
+    for sensor in SENSORS {
+        let mut payload = json!({
+            "name": sensor.title,
+            "unique_id": format!("{node_id}_{}", sensor.name),
+            "state_topic": format!("{}/{}", config.topic_prefix, sensor.name),
+            "availability_topic": availability_topic(config),
+            "device": {
+                "identifiers": [node_id],
+                "name": "socit",
+                "sw_version": env!("CARGO_PKG_VERSION"),
+            },
+        });
+        if let Some(device_class) = sensor.device_class {
+            payload["device_class"] = json!(device_class);
+        }
+        if let Some(unit) = sensor.unit {
+            payload["unit_of_measurement"] = json!(unit);
+        }
+        let topic = format!(
+            "{}/{}/{node_id}/{}/config",
+            config.discovery_prefix, sensor.component, sensor.name
+        );
+        messages.push((topic, payload.to_string()));
+    }
+    messages
+}
+
+/// Drive the connection, publishing the announcements each time it is
+/// (re)established. The event loop reconnects by itself when polled again
+/// after an error.
+async fn run_event_loop(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    announcements: Vec<(String, String)>,
+    connected: Arc<AtomicBool>,
+    reconnect_delay: Duration,
+) {
+    let (host, port) = eventloop.mqtt_options.broker_address();
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Successfully connected to MQTT broker at {host}:{port}");
+                for (topic, payload) in announcements.iter() {
+                    if let Err(err) =
+                        client.try_publish(topic, QoS::AtMostOnce, true, payload.as_bytes())
+                    {
+                        warn!("Failed to publish {topic} to MQTT broker: {err}");
+                    }
+                }
+                connected.store(true, Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                if connected.swap(false, Ordering::Relaxed) {
+                    warn!("Lost connection to MQTT broker: {err}");
+                }
+                tokio::time::sleep(reconnect_delay).await;
+            }
+        }
+    }
+}
+
+pub struct MqttMonitor {
+    client: AsyncClient,
+    host: String,
+    port: u16,
+    topic_prefix: String,
+    /// Whether the event loop is currently connected to the broker
+    connected: Arc<AtomicBool>,
+}
+
+impl MqttMonitor {
+    pub fn new(config: &MqttConfig) -> Result<Self, Box<dyn Error>> {
+        Self::with_reconnect_delay(config, RECONNECT_DELAY)
+    }
+
+    fn with_reconnect_delay(
+        config: &MqttConfig,
+        reconnect_delay: Duration,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        // Retained will to mark us offline
+        options.set_last_will(LastWill::new(
+            availability_topic(config),
+            "offline",
+            QoS::AtMostOnce,
+            true,
+        ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+        }
+        if config.tls {
+            let tls_config = tls::client_config(config.ca_file.as_deref())?;
+            options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(
+                Arc::new(tls_config),
+            )));
+        }
+        let announcements = announcements(config);
+        let (client, eventloop) = AsyncClient::new(options, announcements.len() + QUEUE_SIZE);
+        let connected = Arc::new(AtomicBool::new(false));
+        tokio::spawn(run_event_loop(
+            eventloop,
+            client.clone(),
+            announcements,
+            connected.clone(),
+            reconnect_delay,
+        ));
+        Ok(Self {
+            client,
+            host: config.host.clone(),
+            port: config.port,
+            topic_prefix: config.topic_prefix.clone(),
+            connected,
+        })
+    }
+
+    /// Publish a set of (topic suffix, value) pairs.
+    ///
+    /// This fails if the broker is not connected, so that the caller can
+    /// queue the values and try again later.
+    async fn publish(
+        &mut self,
+        values: &[(impl AsRef<str> + Sync, String)],
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(format!(
+                "Not connected to MQTT broker at {}:{}",
+                self.host, self.port
+            )
+            .into());
+        }
+        for (name, value) in values {
+            let topic = format!("{}/{}", self.topic_prefix, name.as_ref());
+            self.client
+                .try_publish(topic, QoS::AtMostOnce, false, value.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn on_off(value: bool) -> String {
+    if value { "ON" } else { "OFF" }.to_string()
+}
+
+#[async_trait]
+impl Monitor for MqttMonitor {
+    async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
+        let mut values = vec![
+            ("target_soc_low", update.target_soc_low.to_string()),
+            ("target_soc_high", update.target_soc_high.to_string()),
+            ("alarm_soc", update.alarm_soc.to_string()),
+            ("current_soc", update.current_soc.to_string()),
+            ("capacity", update.capacity.to_string()),
+            ("target_energy_low", update.target_energy_low.to_string()),
+            ("target_energy_high", update.target_energy_high.to_string()),
+            ("alarm_energy", update.alarm_energy.to_string()),
+            ("current_energy", update.current_energy.to_string()),
+            ("energy_deficit", update.energy_deficit.to_string()),
+            ("predicted_pv", update.predicted_pv.to_string()),
+            ("is_loadshedding", on_off(update.is_loadshedding)),
+            ("emergency", on_off(update.emergency)),
+            ("unscheduled_outage", on_off(update.unscheduled_outage)),
+            (
+                "next_change",
+                update
+                    .next_change
+                    .map_or("None".to_string(), |t| t.to_rfc3339()),
+            ),
+            ("smart_load", on_off(update.smart_load)),
+            ("soc_filtered", update.soc_filtered.to_string()),
+            ("esp_successes", update.esp_successes.to_string()),
+            ("esp_failures", update.esp_failures.to_string()),
+        ];
+        if let Some(aux_power) = update.aux_power {
+            values.push(("aux_power", aux_power.to_string()));
+        }
+        if let Some(start) = update.pv_window_start {
+            values.push(("pv_window_start", start.to_rfc3339()));
+        }
+        if let Some(end) = update.pv_window_end {
+            values.push(("pv_window_end", end.to_rfc3339()));
+        }
+        let telemetry = [
+            ("battery_power", update.battery_power),
+            ("battery_voltage", update.battery_voltage),
+            ("battery_current", update.battery_current),
+            ("battery_temperature", update.battery_temperature),
+            ("cell_temperature_min", update.cell_temperature_min),
+            ("cell_temperature_max", update.cell_temperature_max),
+            ("load_power", update.load_power),
+            ("backup_runtime", update.backup_runtime),
+            ("time_to_sunrise", update.time_to_sunrise),
+            ("daylight_remaining", update.daylight_remaining),
+            ("manual_soc", update.manual_soc),
+            ("clock_skew", update.clock_skew),
+            ("wear_cost", update.wear_cost),
+            ("grid_cost", update.grid_cost),
+            ("prediction_bias", update.prediction_bias),
+            ("prediction_error", update.prediction_error),
+            ("load_scale", update.load_scale),
+            ("pv_scale", update.pv_scale),
+        ];
+        for (name, value) in telemetry {
+            if let Some(value) = value {
+                values.push((name, value.to_string()));
+            }
+        }
+        if let Some(grid_connected) = update.grid_connected {
+            values.push(("grid_connected", on_off(grid_connected)));
+        }
+        if let Some(esp_age) = update.esp_age {
+            values.push(("esp_age", esp_age.to_string()));
+        }
+        if let Some(esp_latency) = update.esp_latency {
+            values.push(("esp_latency", esp_latency.to_string()));
+        }
+        if let Some(quota) = update.esp_quota_remaining {
+            values.push(("esp_quota_remaining", quota.to_string()));
+        }
+        if let Some(writes) = update.inverter_writes {
+            values.push(("inverter_writes", writes.to_string()));
+        }
+        if let Some(used) = update.write_budget_used {
+            values.push(("write_budget_used", used.to_string()));
+        }
+        if let Some(faults) = &update.active_faults {
+            let faults = if faults.is_empty() {
+                "None".to_string()
+            } else {
+                faults.join(", ")
+            };
+            values.push(("active_faults", faults));
+        }
+        self.publish(&values).await
+    }
+
+    async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>> {
+        let mut values = vec![
+            ("coil_active", on_off(update.active)),
+            ("coil_target", update.target.to_string()),
+        ];
+        if let Some(setting) = update.setting {
+            values.push(("coil_setting", setting.to_string()));
+        }
+        self.publish(&values).await
+    }
+
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>> {
+        let mut values = Vec::new();
+        for string in update.strings.iter() {
+            let prefix = format!("pv{}", string.mppt);
+            values.push((format!("{prefix}_predicted"), string.predicted.to_string()));
+            values.push((format!("{prefix}_measured"), string.measured.to_string()));
+            if let Some(ratio) = string.ratio {
+                values.push((format!("{prefix}_ratio"), ratio.to_string()));
+            }
+        }
+        self.publish(&values).await
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        let values = vec![
+            ("inverter_reachable", on_off(update.inverter_reachable)),
+            ("inverter_successes", update.inverter_successes.to_string()),
+            ("inverter_failures", update.inverter_failures.to_string()),
+            (
+                "inverter_consecutive_failures",
+                update.inverter_consecutive_failures.to_string(),
+            ),
+        ];
+        self.publish(&values).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Read one packet, returning the first byte and the body
+    async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let header = stream.read_u8().await.unwrap();
+        let mut len = 0;
+        for shift in (0..).step_by(7) {
+            let byte = stream.read_u8().await.unwrap();
+            len |= usize::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.unwrap();
+        (header, body)
+    }
+
+    /// Accept a connection and read the retained topics that are published
+    async fn accept(listener: &TcpListener, count: usize) -> (TcpStream, Vec<String>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (header, _) = read_packet(&mut stream).await;
+        assert_eq!(header, 0x10); // CONNECT
+        stream.write_all(&[0x20, 2, 0, 0]).await.unwrap(); // CONNACK
+        let mut topics = Vec::new();
+        while topics.len() < count {
+            let (header, body) = read_packet(&mut stream).await;
+            if header == 0x31 {
+                let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                topics.push(String::from_utf8(body[2..2 + len].to_vec()).unwrap());
+            }
+        }
+        (stream, topics)
+    }
+
+    fn update() -> CoilUpdate {
+        CoilUpdate {
+            time: Utc::now(),
+            active: true,
+            target: 100.0,
+            setting: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: MqttConfig = toml::from_str(&format!(
+            "host = \"127.0.0.1\"\nport = {}",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let count = announcements(&config).len();
+        let mut monitor =
+            MqttMonitor::with_reconnect_delay(&config, Duration::from_millis(50)).unwrap();
+        assert!(monitor.coil_update(update()).await.is_err());
+
+        let discovery = "homeassistant/sensor/socit/current_soc/config".to_string();
+        for _ in 0..2 {
+            let (stream, topics) = accept(&listener, count).await;
+            assert_eq!(topics[0], "socit/status");
+            assert!(topics.contains(&discovery));
+            while monitor.coil_update(update()).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            // Disconnect, and check that updates fail until reconnected
+            drop(stream);
+            while monitor.coil_update(update()).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Client for the [Open-Meteo](https://open-meteo.com) weather forecast API
+
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::config::OpenMeteoConfig;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HourlyResponse {
+    /// Time of each sample (UNIX time)
+    pub time: Vec<i64>,
+    /// Air temperature 2m above the ground (°C)
+    pub temperature_2m: Vec<Option<f64>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ForecastResponse {
+    pub hourly: HourlyResponse,
+}
+
+impl ForecastResponse {
+    /// Ambient temperature (°C) at points in time, skipping missing values
+    pub fn temperatures(&self) -> Vec<(DateTime<Utc>, f64)> {
+        self.hourly
+            .time
+            .iter()
+            .zip(self.hourly.temperature_2m.iter())
+            .filter_map(|(time, temperature)| {
+                Some((DateTime::from_timestamp(*time, 0)?, (*temperature)?))
+            })
+            .collect()
+    }
+}
+
+pub struct OpenMeteo {
+    client: Client,
+    url: String,
+}
+
+impl OpenMeteo {
+    pub fn new(config: &OpenMeteoConfig) -> reqwest::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            url: config.url.clone(),
+        })
+    }
+
+    pub async fn forecast(
+        &self,
+        latitude: f64,
+        longitude: f64,
+    ) -> reqwest::Result<ForecastResponse> {
+        self.client
+            .get(&self.url)
+            .query(&[
+                ("latitude", latitude.to_string()),
+                ("longitude", longitude.to_string()),
+                ("hourly", "temperature_2m".to_string()),
+                ("timeformat", "unixtime".to_string()),
+                ("forecast_days", "3".to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_temperatures() {
+        let json = r#"{
+            "latitude": -34.0,
+            "longitude": 18.5,
+            "hourly_units": {"time": "unixtime", "temperature_2m": "°C"},
+            "hourly": {
+                "time": [1717221600, 1717225200, 1717228800],
+                "temperature_2m": [12.5, null, 15.0]
+            }
+        }"#;
+        let response: ForecastResponse = serde_json::from_str(json).unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 6, 0, 0).unwrap();
+        assert_eq!(
+            response.temperatures(),
+            vec![(start, 12.5), (start + chrono::Duration::hours(2), 15.0)]
+        );
+    }
+}
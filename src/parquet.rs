@@ -0,0 +1,435 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Minimal writer for Parquet files
+//!
+//! Only what is needed to archive monitoring data is supported: a flat
+//! schema of optional columns in a single row group, with PLAIN encoding and
+//! no compression. Column types are inferred from JSON values.
+
+use chrono::DateTime;
+use serde_json::{Map, Value};
+use std::io::Write;
+
+const MAGIC: &[u8] = b"PAR1";
+
+// Thrift compact protocol types
+const T_I32: u8 = 5;
+const T_I64: u8 = 6;
+const T_BINARY: u8 = 8;
+const T_LIST: u8 = 9;
+const T_STRUCT: u8 = 12;
+
+// Parquet enums
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const PAGE_DATA: i32 = 0;
+const REPETITION_OPTIONAL: i32 = 1;
+const CONVERTED_UTF8: i32 = 0;
+const CONVERTED_TIMESTAMP_MICROS: i32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ColumnType {
+    Boolean,
+    Int64,
+    Double,
+    /// RFC 3339 strings, stored as microseconds since the epoch
+    Timestamp,
+    String,
+}
+
+impl ColumnType {
+    fn physical(self) -> i32 {
+        match self {
+            ColumnType::Boolean => 0,
+            ColumnType::Int64 | ColumnType::Timestamp => 2,
+            ColumnType::Double => 5,
+            ColumnType::String => 6,
+        }
+    }
+
+    fn converted(self) -> Option<i32> {
+        match self {
+            ColumnType::Timestamp => Some(CONVERTED_TIMESTAMP_MICROS),
+            ColumnType::String => Some(CONVERTED_UTF8),
+            _ => None,
+        }
+    }
+
+    /// Choose a type that can represent all the (non-null) values
+    fn infer<'a>(values: impl Iterator<Item = &'a Value>) -> Self {
+        let mut kind = None;
+        for value in values {
+            let this = match value {
+                Value::Null => continue,
+                Value::Bool(_) => ColumnType::Boolean,
+                Value::Number(n) if n.is_f64() => ColumnType::Double,
+                Value::Number(_) => ColumnType::Int64,
+                Value::String(s) if DateTime::parse_from_rfc3339(s).is_ok() => {
+                    ColumnType::Timestamp
+                }
+                _ => ColumnType::String,
+            };
+            kind = Some(match (kind, this) {
+                (None, _) => this,
+                (Some(a), b) if a == b => a,
+                (Some(ColumnType::Int64), ColumnType::Double)
+                | (Some(ColumnType::Double), ColumnType::Int64) => ColumnType::Double,
+                _ => ColumnType::String,
+            });
+        }
+        kind.unwrap_or(ColumnType::String)
+    }
+}
+
+/// Encoder for the Thrift compact protocol, used for Parquet metadata
+struct Thrift {
+    buf: Vec<u8>,
+    /// Last field ID written in each nested struct
+    last_field: Vec<i16>,
+}
+
+impl Thrift {
+    fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            last_field: vec![0],
+        }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn zigzag(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.last_field.last_mut().unwrap();
+        let delta = id - *last;
+        *last = id;
+        if (1..=15).contains(&delta) {
+            self.buf.push(((delta as u8) << 4) | kind);
+        } else {
+            self.buf.push(kind);
+            self.zigzag(id.into());
+        }
+    }
+
+    fn i32_field(&mut self, id: i16, value: i32) {
+        self.field(id, T_I32);
+        self.zigzag(value.into());
+    }
+
+    fn i64_field(&mut self, id: i16, value: i64) {
+        self.field(id, T_I64);
+        self.zigzag(value);
+    }
+
+    fn binary_field(&mut self, id: i16, value: &[u8]) {
+        self.field(id, T_BINARY);
+        self.bytes(value);
+    }
+
+    fn list_field(&mut self, id: i16, kind: u8, len: usize) {
+        self.field(id, T_LIST);
+        if len < 15 {
+            self.buf.push(((len as u8) << 4) | kind);
+        } else {
+            self.buf.push(0xf0 | kind);
+            self.varint(len as u64);
+        }
+    }
+
+    /// Start a struct, either as a field or (if `id` is None) a list element
+    fn begin(&mut self, id: Option<i16>) {
+        if let Some(id) = id {
+            self.field(id, T_STRUCT);
+        }
+        self.last_field.push(0);
+    }
+
+    fn end(&mut self) {
+        self.buf.push(0);
+        self.last_field.pop();
+    }
+
+    /// Finish the top-level struct
+    fn finish(mut self) -> Vec<u8> {
+        self.buf.push(0);
+        self.buf
+    }
+}
+
+/// Encode the values of one column as a data page
+fn encode_page(kind: ColumnType, values: &[&Value]) -> Vec<u8> {
+    // Definition levels, as bit-packed runs with a bit width of 1
+    let mut levels = Vec::new();
+    let groups = values.len().div_ceil(8);
+    let mut header = Thrift::new();
+    header.varint(((groups as u64) << 1) | 1);
+    levels.extend_from_slice(&header.buf);
+    let mut bits = vec![0u8; groups];
+    let mut data = Vec::new();
+    let mut bools = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        let encoded = match kind {
+            ColumnType::Boolean => value.as_bool().map(|x| bools.push(x)),
+            ColumnType::Int64 => value.as_i64().map(|x| data.extend(x.to_le_bytes())),
+            ColumnType::Double => value.as_f64().map(|x| data.extend(x.to_le_bytes())),
+            ColumnType::Timestamp => value
+                .as_str()
+                .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+                .map(|t| data.extend(t.timestamp_micros().to_le_bytes())),
+            ColumnType::String => match value {
+                Value::Null => None,
+                Value::String(s) => Some(s.clone()),
+                other => Some(other.to_string()),
+            }
+            .map(|s| {
+                data.extend((s.len() as u32).to_le_bytes());
+                data.extend(s.as_bytes());
+            }),
+        };
+        if encoded.is_some() {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+    levels.extend(bits);
+    if kind == ColumnType::Boolean {
+        let mut packed = vec![0u8; bools.len().div_ceil(8)];
+        for (i, value) in bools.iter().enumerate() {
+            if *value {
+                packed[i / 8] |= 1 << (i % 8);
+            }
+        }
+        data = packed;
+    }
+    let mut page = Vec::with_capacity(4 + levels.len() + data.len());
+    page.extend((levels.len() as u32).to_le_bytes());
+    page.extend(levels);
+    page.extend(data);
+    page
+}
+
+/// Write rows (JSON objects) as a Parquet file.
+///
+/// The columns are the union of the keys of all the rows, and missing
+/// values are null.
+pub fn write(mut writer: impl Write, rows: &[Map<String, Value>]) -> std::io::Result<()> {
+    let mut names: Vec<&str> = Vec::new();
+    for row in rows {
+        for key in row.keys() {
+            if !names.contains(&key.as_str()) {
+                names.push(key);
+            }
+        }
+    }
+
+    let mut out = MAGIC.to_vec();
+    let mut schema = Vec::new();
+    let mut chunks = Vec::new();
+    for name in names.iter() {
+        let values: Vec<&Value> = rows
+            .iter()
+            .map(|row| row.get(*name).unwrap_or(&Value::Null))
+            .collect();
+        let kind = ColumnType::infer(values.iter().copied());
+        let page = encode_page(kind, &values);
+
+        let mut header = Thrift::new();
+        header.i32_field(1, PAGE_DATA);
+        header.i32_field(2, page.len() as i32);
+        header.i32_field(3, page.len() as i32);
+        header.begin(Some(5));
+        header.i32_field(1, rows.len() as i32);
+        header.i32_field(2, ENCODING_PLAIN);
+        header.i32_field(3, ENCODING_RLE);
+        header.i32_field(4, ENCODING_RLE);
+        header.end();
+        let header = header.finish();
+
+        let offset = out.len() as i64;
+        let size = (header.len() + page.len()) as i64;
+        out.extend(header);
+        out.extend(page);
+        schema.push((*name, kind));
+        chunks.push((*name, kind, offset, size));
+    }
+
+    let mut meta = Thrift::new();
+    meta.i32_field(1, 1);
+    meta.list_field(2, T_STRUCT, schema.len() + 1);
+    meta.begin(None);
+    meta.binary_field(4, b"schema");
+    meta.i32_field(5, schema.len() as i32);
+    meta.end();
+    for (name, kind) in schema.iter() {
+        meta.begin(None);
+        meta.i32_field(1, kind.physical());
+        meta.i32_field(3, REPETITION_OPTIONAL);
+        meta.binary_field(4, name.as_bytes());
+        if let Some(converted) = kind.converted() {
+            meta.i32_field(6, converted);
+        }
+        meta.end();
+    }
+    meta.i64_field(3, rows.len() as i64);
+    meta.list_field(4, T_STRUCT, 1);
+    meta.begin(None);
+    meta.list_field(1, T_STRUCT, chunks.len());
+    for (name, kind, offset, size) in chunks.iter() {
+        meta.begin(None);
+        meta.i64_field(2, *offset);
+        meta.begin(Some(3));
+        meta.i32_field(1, kind.physical());
+        meta.list_field(2, T_I32, 2);
+        meta.zigzag(ENCODING_PLAIN.into());
+        meta.zigzag(ENCODING_RLE.into());
+        meta.list_field(3, T_BINARY, 1);
+        meta.bytes(name.as_bytes());
+        meta.i32_field(4, 0); // Uncompressed
+        meta.i64_field(5, rows.len() as i64);
+        meta.i64_field(6, *size);
+        meta.i64_field(7, *size);
+        meta.i64_field(9, *offset);
+        meta.end();
+        meta.end();
+    }
+    let total: i64 = chunks.iter().map(|(_, _, _, size)| size).sum();
+    meta.i64_field(2, total);
+    meta.i64_field(3, rows.len() as i64);
+    meta.end();
+    meta.binary_field(6, b"socit");
+    let meta = meta.finish();
+
+    out.extend(&meta);
+    out.extend((meta.len() as u32).to_le_bytes());
+    out.extend(MAGIC);
+    writer.write_all(&out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_infer() {
+        let infer = |values: &[Value]| ColumnType::infer(values.iter());
+        assert_eq!(infer(&[json!(null), json!(true)]), ColumnType::Boolean);
+        assert_eq!(infer(&[json!(1), json!(2)]), ColumnType::Int64);
+        assert_eq!(infer(&[json!(1), json!(2.5)]), ColumnType::Double);
+        assert_eq!(
+            infer(&[json!("2024-06-01T12:00:00Z")]),
+            ColumnType::Timestamp
+        );
+        assert_eq!(infer(&[json!("x"), json!(1)]), ColumnType::String);
+        assert_eq!(infer(&[json!(null)]), ColumnType::String);
+    }
+
+    #[test]
+    fn test_page() {
+        let values = [json!(1.5), json!(null), json!(2.0)];
+        let refs: Vec<&Value> = values.iter().collect();
+        let page = encode_page(ColumnType::Double, &refs);
+        let mut expected = vec![2, 0, 0, 0, 0x03, 0b101];
+        expected.extend(1.5f64.to_le_bytes());
+        expected.extend(2.0f64.to_le_bytes());
+        assert_eq!(page, expected);
+    }
+
+    /// Check that the reference implementation can read the files back
+    #[test]
+    fn test_read_back() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use parquet::record::Field;
+
+        let rows = [
+            json!({"time": "2024-06-01T12:00:00Z", "soc": 50.5, "count": 3, "active": true}),
+            json!({"time": "2024-06-01T12:01:00Z", "soc": null, "note": "low"}),
+        ];
+        let rows: Vec<_> = rows
+            .into_iter()
+            .map(|row| row.as_object().unwrap().clone())
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("socit-parquet-{}.parquet", std::process::id()));
+        write(std::fs::File::create(&path).unwrap(), &rows).unwrap();
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let read: Vec<Vec<(String, Field)>> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                row.unwrap()
+                    .get_column_iter()
+                    .map(|(name, field)| (name.clone(), field.clone()))
+                    .collect()
+            })
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        let time =
+            |s| Field::TimestampMicros(DateTime::parse_from_rfc3339(s).unwrap().timestamp_micros());
+        let columns = ["active", "count", "soc", "time", "note"].map(String::from);
+        let expected = [
+            [
+                Field::Bool(true),
+                Field::Long(3),
+                Field::Double(50.5),
+                time("2024-06-01T12:00:00Z"),
+                Field::Null,
+            ],
+            [
+                Field::Null,
+                Field::Null,
+                Field::Null,
+                time("2024-06-01T12:01:00Z"),
+                Field::Str("low".to_string()),
+            ],
+        ];
+        let expected: Vec<Vec<_>> = expected
+            .into_iter()
+            .map(|row| columns.iter().cloned().zip(row).collect())
+            .collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn test_thrift() {
+        let mut thrift = Thrift::new();
+        thrift.i32_field(1, -1);
+        thrift.i64_field(17, 300);
+        thrift.begin(Some(18));
+        thrift.binary_field(1, b"ab");
+        thrift.end();
+        assert_eq!(
+            thrift.finish(),
+            vec![0x15, 0x01, 0x06, 0x22, 0xd8, 0x04, 0x1c, 0x18, 0x02, b'a', b'b', 0, 0]
+        );
+    }
+}
@@ -0,0 +1,136 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Persisting controller state across restarts
+//!
+//! Each controller saves its state to a JSON file in the state directory,
+//! together with the time at which it was saved so that stale state can be
+//! ignored on startup.
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use crate::config::StateConfig;
+
+#[derive(Serialize, Deserialize)]
+struct Saved<T> {
+    time: DateTime<Utc>,
+    state: T,
+}
+
+/// Directory holding the saved state of the controllers
+pub struct StateStore {
+    directory: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(config: &StateConfig) -> Self {
+        if let Err(err) = std::fs::create_dir_all(&config.directory) {
+            warn!(
+                "Failed to create state directory {}: {err}",
+                config.directory.display()
+            );
+        }
+        Self {
+            directory: config.directory.clone(),
+        }
+    }
+
+    /// Path of the file for the state called `name`
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.directory.join(format!("{name}.json"))
+    }
+
+    /// Load the state saved under `name`, with the time it was saved.
+    ///
+    /// Missing or invalid state is treated as absent.
+    pub fn load<T: DeserializeOwned>(&self, name: &str) -> Option<(DateTime<Utc>, T)> {
+        let path = self.path(name);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<Saved<T>>(&contents) {
+                Ok(saved) => {
+                    info!("Loaded {name} state from {}", path.display());
+                    Some((saved.time, saved.state))
+                }
+                Err(err) => {
+                    warn!("Ignoring invalid {name} state {}: {err}", path.display());
+                    None
+                }
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                warn!("Could not read {name} state {}: {err}", path.display());
+                None
+            }
+        }
+    }
+
+    fn try_save<T: Serialize>(&self, name: &str, saved: &Saved<T>) -> std::io::Result<()> {
+        let path = self.path(name);
+        // Write to a temporary file and rename so that the update is atomic
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string(saved)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Save the state under `name`, logging (but otherwise ignoring) failures
+    pub fn save<T: Serialize>(&self, name: &str, time: DateTime<Utc>, state: T) {
+        if let Err(err) = self.try_save(name, &Saved { time, state }) {
+            warn!(
+                "Failed to save {name} state to {}: {err}",
+                self.directory.display()
+            );
+        }
+    }
+
+    /// Remove the state saved under `name`, if any
+    pub fn remove(&self, name: &str) {
+        match std::fs::remove_file(self.path(name)) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => warn!("Failed to remove {name} state: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use std::time::Duration;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("socit-state-{}", std::process::id()));
+        let store = StateStore::new(&StateConfig {
+            directory: dir.clone(),
+            max_age: Duration::from_secs(600),
+        });
+        let time = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        assert_eq!(store.load::<Vec<f64>>("test"), None);
+        store.save("test", time, vec![1.0, 2.5]);
+        assert_eq!(store.load("test"), Some((time, vec![1.0, 2.5])));
+        // State of the wrong type is ignored
+        assert_eq!(store.load::<String>("test"), None);
+        store.remove("test");
+        assert_eq!(store.load::<Vec<f64>>("test"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1564 @@
+/* Copyright 2023-2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Computation of target states of charge
+//!
+//! This is independent of the inverter and of the source of load-shedding
+//! information, so that it can be reused by other schedulers. All the
+//! inputs and outputs can be serialised with serde.
+//!
+//! [Simulator] bundles the description of a site so that targets can be
+//! computed for different schedules and times:
+//!
+//! ```
+//! use chrono::{Duration, Utc};
+//! use socit::esp_api::Event;
+//! use socit::planner::{Battery, LoadModel, Simulation, Simulator};
+//!
+//! let simulator = Simulator {
+//!     panels: Vec::new(),
+//!     forecasts: Vec::new(),
+//!     battery: Battery {
+//!         capacity: 5000.0,
+//!         min_soc: 20.0,
+//!         charge_power: Some(2000.0),
+//!         cheap_windows: Vec::new(),
+//!         weekend_cheap_windows: None,
+//!         charge_efficiency: 1.0,
+//!         discharge_efficiency: 1.0,
+//!         soc_floors: Vec::new(),
+//!     },
+//!     load: LoadModel {
+//!         min_discharge_power: 100.0,
+//!         max_discharge_power: 500.0,
+//!         smart_load: Vec::new(),
+//!         timezone: None,
+//!         profile: None,
+//!         self_consumption: 0.0,
+//!         holidays: Default::default(),
+//!         scale: 1.0,
+//!     },
+//!     simulation: Simulation::default(),
+//! };
+//! let now = Utc::now();
+//! let events = [Event {
+//!     start: now + Duration::hours(4),
+//!     end: now + Duration::hours(6),
+//!     note: "Stage 2".to_string(),
+//! }];
+//! let plan = simulator.plan(&events, now);
+//! assert!(plan.targets.target_soc_low > 20.0);
+//! ```
+
+use chrono::{
+    DateTime, Datelike, Duration, DurationRound, Local, NaiveDateTime, NaiveTime, TimeZone,
+    Timelike, Utc,
+};
+use radians::Deg64;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{PanelConfig, SmartLoadConfig, SocFloorConfig};
+use crate::esp_api::Event;
+use crate::holidays::Holidays;
+use crate::sun::solar_fraction;
+use crate::timezone::Timezone;
+
+/// Properties of the battery and the ability to charge it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Battery {
+    /// Usable capacity (Wh)
+    pub capacity: f64,
+    /// State of charge (%) below which the battery should never fall
+    pub min_soc: f64,
+    /// Maximum rate at which the battery can charge from the grid (W)
+    #[serde(default)]
+    pub charge_power: Option<f64>,
+    /// Times of day (in the load model's time zone) when grid power is
+    /// cheap. If given, reaching the target SoC is deferred to these windows
+    /// where possible.
+    #[serde(default)]
+    pub cheap_windows: Vec<Window>,
+    /// Cheap windows on weekends and holidays, if different from `cheap_windows`
+    #[serde(default)]
+    pub weekend_cheap_windows: Option<Vec<Window>>,
+    /// Fraction of the energy put into the battery that is stored
+    #[serde(default = "efficiency_default")]
+    pub charge_efficiency: f64,
+    /// Fraction of the energy taken from the battery that reaches the load
+    #[serde(default = "efficiency_default")]
+    pub discharge_efficiency: f64,
+    /// Times of day (in the load model's time zone) with a higher minimum SoC
+    #[serde(default)]
+    pub soc_floors: Vec<SocFloorConfig>,
+}
+
+fn efficiency_default() -> f64 {
+    1.0
+}
+
+/// A time-of-day window, which may wrap past midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Window {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Window {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        in_window(self.start, self.end, time)
+    }
+}
+
+/// Model of household consumption
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LoadModel {
+    /// Load (W) assumed when optimistic
+    pub min_discharge_power: f64,
+    /// Load (W) assumed during load-shedding when pessimistic
+    pub max_discharge_power: f64,
+    /// Windows during which a smart load takes power away from charging
+    #[serde(default)]
+    pub smart_load: Vec<SmartLoadConfig>,
+    /// Time zone for time-of-day settings (system local time if not given)
+    #[serde(default)]
+    pub timezone: Option<Timezone>,
+    /// Expected load by time of day, replacing `min_discharge_power` where known
+    #[serde(default)]
+    pub profile: Option<LoadProfile>,
+    /// Power (W) used by the inverter itself, in addition to the load
+    #[serde(default)]
+    pub self_consumption: f64,
+    /// Days (in the time zone above) that follow the weekend profile and
+    /// cheap windows
+    #[serde(default)]
+    pub holidays: Holidays,
+    /// Factor applied to the expected load from the profile or
+    /// `min_discharge_power`
+    #[serde(default = "load_scale_default")]
+    pub scale: f64,
+}
+
+fn load_scale_default() -> f64 {
+    1.0
+}
+
+/// Average load (W) for each hour of the day, if known
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LoadProfile {
+    /// Indexed by hour (0-23) for Monday to Friday
+    pub weekday: Vec<Option<f64>>,
+    /// Indexed by hour (0-23) for Saturday, Sunday and holidays
+    pub weekend: Vec<Option<f64>>,
+}
+
+impl LoadProfile {
+    /// Expected load (W) at a local time, if known
+    pub fn power(&self, time: NaiveDateTime, holidays: &Holidays) -> Option<f64> {
+        let hours = if holidays.is_weekend(time.date()) {
+            &self.weekend
+        } else {
+            &self.weekday
+        };
+        hours.get(time.hour() as usize).copied().flatten()
+    }
+
+    /// Fill in unknown hours from another profile
+    pub fn or(&self, fallback: &LoadProfile) -> LoadProfile {
+        let merge = |a: &[Option<f64>], b: &[Option<f64>]| {
+            (0..a.len().max(b.len()))
+                .map(|i| a.get(i).copied().flatten().or(b.get(i).copied().flatten()))
+                .collect()
+        };
+        LoadProfile {
+            weekday: merge(&self.weekday, &fallback.weekday),
+            weekend: merge(&self.weekend, &fallback.weekend),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Targets {
+    /// Below this SoC, charge the battery from the grid
+    pub target_soc_low: f64,
+    /// Above this SoC, no grid power is needed
+    pub target_soc_high: f64,
+    /// Below this SoC there is a risk of falling below the minimum
+    pub alarm_soc: f64,
+}
+
+/// Number of (non-integer) hours in a duration
+pub fn duration_hours(duration: Duration) -> f64 {
+    (duration.num_milliseconds() as f64) / 3600000.0
+}
+
+/// Predicted power (W) from a set of panels, assuming clear skies
+pub fn panels_power<'a>(
+    panels: impl IntoIterator<Item = &'a PanelConfig>,
+    time: DateTime<Utc>,
+) -> f64 {
+    let mut power = 0.0;
+    for panels in panels {
+        let raw: f64 = panels
+            .all_faces()
+            .map(|face| {
+                face.power
+                    * solar_fraction(
+                        Deg64::new(panels.latitude),
+                        Deg64::new(panels.longitude),
+                        Deg64::new(90.0 - face.tilt),
+                        Deg64::new(face.azimuth),
+                        &panels.horizon,
+                        panels.diffuse,
+                        &time,
+                    )
+            })
+            .sum();
+        power += clip_power(raw * calibration(panels, time), panels.max_power);
+    }
+    power
+}
+
+/// Factor applied to the predicted output of a set of panels, to calibrate
+/// against observed production.
+pub fn calibration(panels: &PanelConfig, time: DateTime<Utc>) -> f64 {
+    let monthly = panels
+        .monthly
+        .map_or(1.0, |monthly| monthly[time.month0() as usize]);
+    panels.derate * monthly
+}
+
+/// Apply [`calibration`] to an external forecast for a set of panels.
+pub fn calibrate_forecast(panels: &PanelConfig, forecast: PvForecast) -> PvForecast {
+    PvForecast {
+        points: forecast
+            .points
+            .into_iter()
+            .map(|(time, power)| (time, power * calibration(panels, time)))
+            .collect(),
+    }
+}
+
+/// Limit `power` to `max_power`, if given.
+pub fn clip_power(power: f64, max_power: Option<f64>) -> f64 {
+    max_power.map_or(power, |max_power| power.min(max_power))
+}
+
+/// Fraction of the output remaining after derating for cell temperature,
+/// given the ambient temperature (°C) and the fraction of peak irradiance.
+pub fn temperature_factor(panels: &PanelConfig, ambient: f64, irradiance: f64) -> f64 {
+    let Some(coefficient) = panels.temperature_coefficient else {
+        return 1.0;
+    };
+    // The cell heats up in proportion to irradiance. NOCT is the cell
+    // temperature at 800 W/m² and 20°C ambient.
+    let cell = ambient + (panels.noct - 20.0) * irradiance * (1000.0 / 800.0);
+    (1.0 + coefficient * 0.01 * (cell - 25.0)).max(0.0)
+}
+
+/// Clear-sky forecast for a set of panels, derated for cell temperature
+/// given forecasts of the ambient temperature (°C), sorted by time.
+pub fn derated_forecast(panels: &PanelConfig, ambient: &[(DateTime<Utc>, f64)]) -> PvForecast {
+    let step = Duration::minutes(15);
+    let power = |time, temperature| {
+        let power = panels_power([panels], time);
+        let rated = panels.rated_power() * calibration(panels, time);
+        let irradiance = if rated > 0.0 { power / rated } else { 0.0 };
+        power * temperature_factor(panels, temperature, irradiance)
+    };
+    let mut points = Vec::new();
+    for pair in ambient.windows(2) {
+        let ((t0, a0), (t1, a1)) = (pair[0], pair[1]);
+        let mut t = t0;
+        while t < t1 {
+            let frac = duration_hours(t - t0) / duration_hours(t1 - t0);
+            points.push((t, power(t, a0 + (a1 - a0) * frac)));
+            t += step;
+        }
+    }
+    if let Some(&(t, temperature)) = ambient.last() {
+        points.push((t, power(t, temperature)));
+    }
+    PvForecast::new(points)
+}
+
+/// Externally-sourced forecast of the power (W) from a set of panels
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PvForecast {
+    /// Forecast power at points in time, sorted by time
+    pub points: Vec<(DateTime<Utc>, f64)>,
+}
+
+impl PvForecast {
+    pub fn new(mut points: Vec<(DateTime<Utc>, f64)>) -> Self {
+        points.sort_by_key(|(time, _)| *time);
+        Self { points }
+    }
+
+    /// Forecast power at a given time, interpolating linearly between
+    /// points. Returns `None` outside the range of the forecast.
+    pub fn power(&self, time: DateTime<Utc>) -> Option<f64> {
+        let pos = self.points.partition_point(|(t, _)| *t <= time);
+        if pos == 0 {
+            return None;
+        }
+        let (t0, p0) = self.points[pos - 1];
+        if t0 == time {
+            return Some(p0);
+        }
+        let (t1, p1) = *self.points.get(pos)?;
+        let frac = duration_hours(time - t0) / duration_hours(t1 - t0);
+        Some(p0 + (p1 - p0) * frac)
+    }
+
+    /// Sum of several forecasts, at the times of any of them that are
+    /// covered by all of them.
+    pub fn sum(forecasts: &[PvForecast]) -> Self {
+        let mut times: Vec<_> = forecasts
+            .iter()
+            .flat_map(|forecast| forecast.points.iter().map(|(time, _)| *time))
+            .collect();
+        times.sort();
+        times.dedup();
+        let points = times
+            .into_iter()
+            .filter_map(|time| {
+                let powers: Option<Vec<f64>> = forecasts
+                    .iter()
+                    .map(|forecast| forecast.power(time))
+                    .collect();
+                Some((time, powers?.iter().sum()))
+            })
+            .collect();
+        Self { points }
+    }
+}
+
+/// Predicted power (W) from sets of panels, using the forecast for each
+/// set where available (`forecasts` is indexed like `panels`) and assuming
+/// clear skies otherwise.
+pub fn forecast_power(
+    panels: &[PanelConfig],
+    forecasts: &[Option<PvForecast>],
+    time: DateTime<Utc>,
+) -> f64 {
+    panels
+        .iter()
+        .enumerate()
+        .map(|(i, panel)| {
+            forecasts
+                .get(i)
+                .and_then(|forecast| forecast.as_ref())
+                .and_then(|forecast| forecast.power(time))
+                .map(|power| clip_power(power, panel.max_power))
+                .unwrap_or_else(|| panels_power([panel], time))
+        })
+        .sum()
+}
+
+/// First and last times on the local day containing `now` at which the
+/// predicted power from the panels reaches `threshold` (W).
+pub fn pv_window(
+    panels: &[PanelConfig],
+    timezone: Option<&Timezone>,
+    now: DateTime<Utc>,
+    threshold: f64,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let step = Duration::minutes(5);
+    let today = local_time(timezone, now).date();
+    // Scan a wide enough range to cover the local day for any offset
+    let mut t = (now - Duration::days(1)).duration_trunc(step).ok()?;
+    let mut window = None;
+    while t < now + Duration::days(1) {
+        if local_time(timezone, t).date() == today && panels_power(panels, t) >= threshold {
+            window = match window {
+                None => Some((t, t)),
+                Some((start, _)) => Some((start, t)),
+            };
+        }
+        t += step;
+    }
+    window
+}
+
+/// Convert a local time in the given time zone (or the system zone) to UTC
+pub fn utc_time(timezone: Option<&Timezone>, time: NaiveDateTime) -> Option<DateTime<Utc>> {
+    match timezone {
+        Some(timezone) => Some(timezone.to_utc(time)),
+        None => Local
+            .from_local_datetime(&time)
+            .earliest()
+            .map(|t| t.with_timezone(&Utc)),
+    }
+}
+
+/// Convert a time to local time in the given time zone (or the system zone)
+pub fn local_time(timezone: Option<&Timezone>, time: DateTime<Utc>) -> NaiveDateTime {
+    match timezone {
+        Some(timezone) => timezone.to_local(time),
+        None => time.with_timezone(&Local).naive_local(),
+    }
+}
+
+/// Whether a time of day falls in a window (which may wrap past midnight)
+pub fn in_window(start: NaiveTime, end: NaiveTime, time: NaiveTime) -> bool {
+    if start <= end {
+        start <= time && time < end
+    } else {
+        time >= start || time < end
+    }
+}
+
+/// Power (W) expected to be drawn by the smart load at a given time
+fn smart_load_power(load: &LoadModel, time: DateTime<Utc>) -> f64 {
+    let local = local_time(load.timezone.as_ref(), time).time();
+    load.smart_load
+        .iter()
+        .filter(|window| in_window(window.start, window.end, local))
+        .map(|window| window.power)
+        .sum()
+}
+
+/// Load (W) expected at a given time when optimistic
+fn base_load_power(load: &LoadModel, time: DateTime<Utc>) -> f64 {
+    load.profile
+        .as_ref()
+        .and_then(|profile| profile.power(local_time(load.timezone.as_ref(), time), &load.holidays))
+        .unwrap_or(load.min_discharge_power)
+        * load.scale
+}
+
+/// Total load (W) expected at a given time when optimistic, including the
+/// smart load and the inverter's own consumption
+pub fn expected_load(load: &LoadModel, time: DateTime<Utc>) -> f64 {
+    base_load_power(load, time) + smart_load_power(load, time) + load.self_consumption
+}
+
+/// PV power (W) expected at a given time in excess of the load
+pub fn pv_surplus(pv: f64, load: &LoadModel, time: DateTime<Utc>) -> f64 {
+    pv - expected_load(load, time)
+}
+
+/// What to simulate when no load-shedding and not enough solar
+enum SimMode {
+    /// Power drains from battery
+    Drain,
+    /// Battery level held steady
+    Hold,
+    /// Charge battery as fast as possible
+    Charge,
+}
+
+/// Parameters controlling the simulation
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Simulation {
+    /// How far ahead to look for load-shedding (s)
+    pub horizon: i64,
+    /// Time step (s)
+    pub step: i64,
+    /// Load-shedding which starts more than this far ahead (s) is "far
+    /// future". This is also the horizon for the upper target, which
+    /// assumes no grid power at all.
+    #[serde(default = "far_future_default")]
+    pub far_future: i64,
+    /// Weight (0 to 1) applied to the energy needed for far-future
+    /// load-shedding, which is more likely to change
+    #[serde(default = "far_future_weight_default")]
+    pub far_future_weight: f64,
+    /// Factor applied to the predicted PV power (less than 1 to be
+    /// pessimistic)
+    #[serde(default = "pv_scale_default")]
+    pub pv_scale: f64,
+    /// Limit on the total PV power (W) accepted by the inverter
+    #[serde(default)]
+    pub max_pv_power: Option<f64>,
+    /// If given, run an ensemble of simulations with perturbed inputs
+    #[serde(default)]
+    pub ensemble: Option<Ensemble>,
+}
+
+/// Ensemble of simulations with randomly perturbed event boundaries and PV
+/// output, to account for uncertainty in the schedule and weather
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ensemble {
+    /// Number of simulations
+    pub members: usize,
+    /// Percentile (0-100) of the targets across the ensemble to use
+    pub percentile: f64,
+    /// Maximum time (s) by which an event may start early
+    pub early: i64,
+    /// Maximum time (s) by which an event may be extended
+    pub extend: i64,
+    /// Maximum fractional reduction in PV output
+    pub pv_spread: f64,
+}
+
+fn pv_scale_default() -> f64 {
+    1.0
+}
+
+fn far_future_default() -> i64 {
+    86400
+}
+
+fn far_future_weight_default() -> f64 {
+    1.0
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self {
+            horizon: 2 * 86400,
+            step: 60,
+            far_future: far_future_default(),
+            far_future_weight: far_future_weight_default(),
+            pv_scale: pv_scale_default(),
+            max_pv_power: None,
+            ensemble: None,
+        }
+    }
+}
+
+/// All the inputs to a simulation
+#[derive(Clone, Copy)]
+struct Scenario<'a> {
+    events: &'a [Event],
+    /// Predicted PV power (W) in the middle of each step, before scaling
+    pv: &'a [f64],
+    battery: &'a Battery,
+    load: &'a LoadModel,
+    simulation: &'a Simulation,
+    now: DateTime<Utc>,
+}
+
+/// Run a simulation, returning the target SoC and the time at which the
+/// battery is closest to the minimum. If `trace` is given, the battery level
+/// (Wh, relative to the start) after each step is appended to it.
+fn target_soc_helper(
+    scenario: Scenario<'_>,
+    mode: SimMode,
+    mut trace: Option<&mut Vec<(DateTime<Utc>, f64)>>,
+) -> (f64, DateTime<Utc>) {
+    let Scenario {
+        events,
+        pv,
+        battery,
+        load,
+        simulation,
+        now,
+    } = scenario;
+    let step = Duration::seconds(simulation.step.max(1));
+    let step_h = duration_hours(step);
+    let depth = battery.capacity - battery.min_soc * 0.01 * battery.capacity;
+
+    let mut base_wh = 0.0;
+    let mut worst = 0.0_f64;
+    let mut floor = -depth;
+    let mut worst_time = now;
+    /* Project battery level forward over the horizon, using optimistic
+     * assumptions about solar PV and consumption. Whenever the
+     * current point falls into load-shedding, check that there will
+     * be enough to get to the end with pessimistic assumptions.
+     */
+    let horizon = match mode {
+        SimMode::Drain => simulation.horizon.min(simulation.far_future),
+        _ => simulation.horizon,
+    };
+    let goal = now + Duration::seconds(horizon);
+    let far_future = now + Duration::seconds(simulation.far_future);
+    let mut t = now;
+    let mut observe = |wh, t| {
+        if wh < worst {
+            worst = wh;
+            worst_time = t;
+        }
+    };
+    let is_cheap = |t| {
+        let local = local_time(load.timezone.as_ref(), t);
+        let windows = match &battery.weekend_cheap_windows {
+            Some(windows) if load.holidays.is_weekend(local.date()) => windows,
+            _ => &battery.cheap_windows,
+        };
+        windows.iter().any(|window| window.contains(local.time()))
+    };
+    /* Charging in a cheap window is only assumed once we are past the
+     * current one, so that the target isn't deferred indefinitely while in
+     * a cheap window.
+     */
+    let mut past_current_cheap = !is_cheap(now);
+    let mut index = 0;
+    if let Some(trace) = trace.as_mut() {
+        trace.push((now, 0.0));
+    }
+    while t < goal {
+        let mut have_grid = true;
+        for event in events.iter() {
+            if t >= event.start && t < event.end {
+                have_grid = false;
+                let weight = if event.start >= far_future {
+                    simulation.far_future_weight
+                } else {
+                    1.0
+                };
+                let drain = (load.max_discharge_power + load.self_consumption)
+                    / battery.discharge_efficiency;
+                let end_wh = base_wh - weight * drain * duration_hours(event.end - t);
+                observe(end_wh.max(floor), t);
+            }
+        }
+        // While the smart load is on, it takes power that would otherwise
+        // charge the battery.
+        let aux = smart_load_power(load, t + step / 2);
+        let charge_power = battery.charge_power.map(|x| (x - aux).max(0.0));
+        let pv = clip_power(
+            pv.get(index).copied().unwrap_or(0.0) * simulation.pv_scale,
+            simulation.max_pv_power,
+        );
+        let mut power = (pv - aux).max(0.0);
+        if let Some(charge_power) = charge_power {
+            power = power.min(charge_power);
+        }
+        power -= base_load_power(load, t + step / 2) + load.self_consumption;
+        let cheap = is_cheap(t + step / 2);
+        past_current_cheap |= !cheap;
+        if have_grid {
+            power = match mode {
+                SimMode::Drain => power,
+                SimMode::Hold if cheap && past_current_cheap => {
+                    charge_power.map_or(power.max(0.0), |x| x.max(power))
+                }
+                SimMode::Hold => power.max(0.0),
+                SimMode::Charge => charge_power.unwrap_or(power),
+            };
+        }
+        // Account for losses going into or out of the battery
+        let stored = if power >= 0.0 {
+            power * battery.charge_efficiency
+        } else {
+            power / battery.discharge_efficiency
+        };
+        base_wh += stored * step_h;
+        t += step;
+        index += 1;
+
+        floor = floor.max(base_wh - depth);
+        observe(base_wh.max(floor), t);
+        if let Some(trace) = trace.as_mut() {
+            trace.push((t, base_wh.max(floor)));
+        }
+        // Enforce time-of-day floors by requiring extra energy above min_soc
+        let local = local_time(load.timezone.as_ref(), t).time();
+        for reserve in battery.soc_floors.iter() {
+            if reserve.soc > battery.min_soc && in_window(reserve.start, reserve.end, local) {
+                let extra_wh = (reserve.soc - battery.min_soc) * 0.01 * battery.capacity;
+                observe(base_wh.max(floor) - extra_wh, t);
+            }
+        }
+    }
+
+    let extra = -worst / battery.capacity * 100.0;
+    let target = battery.min_soc + extra;
+    let target = target.clamp(0.0, 100.0);
+    (target, worst_time)
+}
+
+/// Clean up a list of load-shedding events so that it is sorted and
+/// non-overlapping. Events that are empty or already over are dropped, and
+/// overlapping or adjacent events are merged.
+pub fn normalize_events(events: &[Event], now: DateTime<Utc>) -> Vec<Event> {
+    let mut sorted: Vec<&Event> = events
+        .iter()
+        .filter(|event| event.end > event.start && event.end > now)
+        .collect();
+    sorted.sort_by_key(|event| (event.start, event.end));
+    let mut out: Vec<Event> = Vec::with_capacity(sorted.len());
+    for event in sorted {
+        match out.last_mut() {
+            Some(last) if event.start <= last.end => {
+                last.end = last.end.max(event.end);
+                if !last.note.split(" / ").any(|note| note == event.note) {
+                    last.note = format!("{} / {}", last.note, event.note);
+                }
+            }
+            _ => out.push(event.clone()),
+        }
+    }
+    out
+}
+
+/// Hours for which the battery could supply a constant drain (W) before
+/// reaching the minimum SoC, or `None` if nothing is being drawn.
+pub fn backup_runtime(battery: &Battery, soc: f64, drain: f64) -> Option<f64> {
+    if drain <= 0.0 {
+        return None;
+    }
+    let usable = (soc - battery.min_soc).max(0.0) * 0.01 * battery.capacity;
+    Some(usable * battery.discharge_efficiency / drain)
+}
+
+/// Compute target states of charge given the upcoming load-shedding events.
+pub fn compute_targets(
+    events: &[Event],
+    panels: &[PanelConfig],
+    battery: &Battery,
+    load: &LoadModel,
+    now: DateTime<Utc>,
+) -> Targets {
+    compute_targets_with_forecasts(
+        events,
+        panels,
+        &[],
+        battery,
+        load,
+        &Simulation::default(),
+        now,
+    )
+}
+
+/// Compute target states of charge, using PV forecasts (indexed like
+/// `panels`) where available instead of assuming clear skies, and with
+/// control over the simulation parameters.
+pub fn compute_targets_with_forecasts(
+    events: &[Event],
+    panels: &[PanelConfig],
+    forecasts: &[Option<PvForecast>],
+    battery: &Battery,
+    load: &LoadModel,
+    simulation: &Simulation,
+    now: DateTime<Utc>,
+) -> Targets {
+    let events = normalize_events(events, now);
+    // Computing the position of the sun is relatively expensive, so do it
+    // once for all the simulations.
+    let pv = step_pv(panels, forecasts, simulation, now);
+    let scenario = Scenario {
+        events: &events,
+        pv: &pv,
+        battery,
+        load,
+        simulation,
+        now,
+    };
+    match &simulation.ensemble {
+        None => scenario_targets(scenario),
+        Some(ensemble) => ensemble_targets(scenario, ensemble),
+    }
+}
+
+/// Predicted PV power in the middle of each simulation step
+fn step_pv(
+    panels: &[PanelConfig],
+    forecasts: &[Option<PvForecast>],
+    simulation: &Simulation,
+    now: DateTime<Utc>,
+) -> Vec<f64> {
+    let step = Duration::seconds(simulation.step.max(1));
+    let steps = (simulation.horizon.max(0) + step.num_seconds() - 1) / step.num_seconds();
+    (0..steps as i32)
+        .map(|i| forecast_power(panels, forecasts, now + step * i + step / 2))
+        .collect()
+}
+
+/// Projected battery level for the lower target, for diagnostics
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Projection {
+    /// Lower target SoC, before any ensemble or margin is applied
+    pub target_soc: f64,
+    /// Time at which the battery is projected to be closest to the minimum
+    pub worst_time: DateTime<Utc>,
+    /// Projected SoC (%) at each step, starting from `target_soc`
+    pub soc: Vec<(DateTime<Utc>, f64)>,
+}
+
+/// Simulate the battery starting from the lower target SoC, with the same
+/// inputs as [compute_targets_with_forecasts] (but no ensemble).
+pub fn project(
+    events: &[Event],
+    panels: &[PanelConfig],
+    forecasts: &[Option<PvForecast>],
+    battery: &Battery,
+    load: &LoadModel,
+    simulation: &Simulation,
+    now: DateTime<Utc>,
+) -> Projection {
+    let events = normalize_events(events, now);
+    let pv = step_pv(panels, forecasts, simulation, now);
+    let scenario = Scenario {
+        events: &events,
+        pv: &pv,
+        battery,
+        load,
+        simulation,
+        now,
+    };
+    let mut trace = Vec::new();
+    let (target_soc, worst_time) = target_soc_helper(scenario, SimMode::Hold, Some(&mut trace));
+    let soc = trace
+        .into_iter()
+        .map(|(t, wh)| {
+            (
+                t,
+                (target_soc + wh / battery.capacity * 100.0).clamp(0.0, 100.0),
+            )
+        })
+        .collect();
+    Projection {
+        target_soc,
+        worst_time,
+        soc,
+    }
+}
+
+/// Description of a site, for computing targets for load-shedding events
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Simulator {
+    #[serde(default)]
+    pub panels: Vec<PanelConfig>,
+    /// PV forecasts, indexed like `panels` (clear-sky predictions are used
+    /// for any that are missing)
+    #[serde(default)]
+    pub forecasts: Vec<Option<PvForecast>>,
+    pub battery: Battery,
+    pub load: LoadModel,
+    #[serde(default)]
+    pub simulation: Simulation,
+}
+
+/// Targets together with the projected battery level
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub targets: Targets,
+    pub projection: Projection,
+}
+
+impl Simulator {
+    /// Compute target states of charge for the load-shedding events
+    pub fn targets(&self, events: &[Event], now: DateTime<Utc>) -> Targets {
+        compute_targets_with_forecasts(
+            events,
+            &self.panels,
+            &self.forecasts,
+            &self.battery,
+            &self.load,
+            &self.simulation,
+            now,
+        )
+    }
+
+    /// Simulate the battery starting from the lower target SoC
+    pub fn project(&self, events: &[Event], now: DateTime<Utc>) -> Projection {
+        project(
+            events,
+            &self.panels,
+            &self.forecasts,
+            &self.battery,
+            &self.load,
+            &self.simulation,
+            now,
+        )
+    }
+
+    /// Compute the targets and the projection
+    pub fn plan(&self, events: &[Event], now: DateTime<Utc>) -> Plan {
+        Plan {
+            targets: self.targets(events, now),
+            projection: self.project(events, now),
+        }
+    }
+}
+
+fn scenario_targets(scenario: Scenario<'_>) -> Targets {
+    let helper = |mode| target_soc_helper(scenario, mode, None).0;
+    Targets {
+        target_soc_low: helper(SimMode::Hold),
+        target_soc_high: helper(SimMode::Drain),
+        alarm_soc: helper(SimMode::Charge),
+    }
+}
+
+/// Minimal deterministic PRNG (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Uniform in [0, 1)
+    fn uniform(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform duration between zero and `max` seconds
+    fn seconds(&mut self, max: i64) -> Duration {
+        Duration::seconds((self.uniform() * max.max(0) as f64) as i64)
+    }
+}
+
+/// Value at a percentile (0-100), using the nearest-rank method
+fn percentile(mut values: Vec<f64>, p: f64) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let rank = (p / 100.0 * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+fn ensemble_targets(scenario: Scenario<'_>, ensemble: &Ensemble) -> Targets {
+    // Seed from the time, so that results are reproducible
+    let mut rng = Rng(scenario.now.timestamp() as u64 | 1);
+    let mut low = Vec::new();
+    let mut high = Vec::new();
+    let mut alarm = Vec::new();
+    for _ in 0..ensemble.members.max(1) {
+        let events: Vec<Event> = scenario
+            .events
+            .iter()
+            .map(|event| Event {
+                start: event.start - rng.seconds(ensemble.early),
+                end: event.end + rng.seconds(ensemble.extend),
+                note: event.note.clone(),
+            })
+            .collect();
+        let events = normalize_events(&events, scenario.now);
+        let simulation = Simulation {
+            pv_scale: scenario.simulation.pv_scale
+                * (1.0 - ensemble.pv_spread * rng.uniform()).max(0.0),
+            ensemble: None,
+            ..scenario.simulation.clone()
+        };
+        let targets = scenario_targets(Scenario {
+            events: &events,
+            simulation: &simulation,
+            ..scenario
+        });
+        low.push(targets.target_soc_low);
+        high.push(targets.target_soc_high);
+        alarm.push(targets.alarm_soc);
+    }
+    Targets {
+        target_soc_low: percentile(low, ensemble.percentile),
+        target_soc_high: percentile(high, ensemble.percentile),
+        alarm_soc: percentile(alarm, ensemble.percentile),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{ForecastSource, PanelFaceConfig};
+    use chrono::{NaiveDate, TimeZone};
+
+    fn battery() -> Battery {
+        Battery {
+            capacity: 5000.0,
+            min_soc: 20.0,
+            charge_power: Some(2000.0),
+            cheap_windows: Vec::new(),
+            weekend_cheap_windows: None,
+            charge_efficiency: 1.0,
+            discharge_efficiency: 1.0,
+            soc_floors: Vec::new(),
+        }
+    }
+
+    fn panels() -> PanelConfig {
+        PanelConfig {
+            latitude: -33.9,
+            longitude: 18.4,
+            tilt: 0.0,
+            azimuth: 0.0,
+            power: 1000.0,
+            mppt: None,
+            forecast: ForecastSource::ClearSky,
+            horizon: Vec::new(),
+            diffuse: 0.0,
+            temperature_coefficient: None,
+            noct: 45.0,
+            max_power: None,
+            derate: 1.0,
+            monthly: None,
+            faces: Vec::new(),
+        }
+    }
+
+    fn load() -> LoadModel {
+        LoadModel {
+            min_discharge_power: 100.0,
+            max_discharge_power: 500.0,
+            smart_load: Vec::new(),
+            timezone: None,
+            profile: None,
+            self_consumption: 0.0,
+            holidays: Holidays::default(),
+            scale: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_compute_targets() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let events = [Event {
+            start: now + Duration::hours(4),
+            end: now + Duration::hours(6),
+            note: "Stage 2".to_string(),
+        }];
+        let targets = compute_targets(&events, &[], &battery(), &load(), now);
+        // Need 2 hours at 500 W = 1000 Wh = 20% above the minimum
+        assert!((targets.target_soc_low - 40.0).abs() < 1e-6, "{targets:?}");
+        // There is time to charge before the event
+        assert_eq!(targets.alarm_soc, 20.0);
+        // Without grid, need 24 hours at 100 W = 2400 Wh = 48%, which is
+        // more than the 4 hours at 100 W + 2 hours at 500 W needed to get
+        // through load-shedding.
+        assert!((targets.target_soc_high - 68.0).abs() < 1e-6, "{targets:?}");
+    }
+
+    #[test]
+    fn test_normalize_events() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let hours = |h| now + Duration::hours(h);
+        let event = |start, end, note: &str| Event {
+            start: hours(start),
+            end: hours(end),
+            note: note.to_string(),
+        };
+        let events = [
+            event(6, 8, "Stage 4"),
+            event(-4, -2, "Stage 2"), // in the past
+            event(2, 4, "Stage 2"),
+            event(3, 5, "Stage 2"),   // overlaps previous
+            event(2, 4, "Stage 2"),   // duplicate
+            event(8, 9, "Stage 6"),   // adjacent to first
+            event(10, 10, "Stage 6"), // empty
+            event(12, 11, "Stage 6"), // inverted
+        ];
+        let normalized = normalize_events(&events, now);
+        let summary: Vec<_> = normalized
+            .iter()
+            .map(|e| (e.start, e.end, e.note.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (hours(2), hours(5), "Stage 2"),
+                (hours(6), hours(9), "Stage 4 / Stage 6"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simulation() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let events = [Event {
+            start: now + Duration::hours(30),
+            end: now + Duration::hours(32),
+            note: "Stage 2".to_string(),
+        }];
+        let targets = compute_targets(&events, &[], &battery(), &load(), now);
+        assert!((targets.target_soc_low - 40.0).abs() < 1e-6, "{targets:?}");
+        // The upper target only looks one day ahead
+        assert!((targets.target_soc_high - 68.0).abs() < 1e-6, "{targets:?}");
+
+        let mut simulation = Simulation {
+            horizon: 24 * 3600,
+            step: 300,
+            ..Default::default()
+        };
+        let compute = |simulation: &Simulation| {
+            compute_targets_with_forecasts(&events, &[], &[], &battery(), &load(), simulation, now)
+        };
+        assert_eq!(compute(&simulation).target_soc_low, 20.0);
+        simulation.horizon = 48 * 3600;
+        simulation.far_future_weight = 0.5;
+        let targets = compute(&simulation);
+        assert!((targets.target_soc_low - 30.0).abs() < 1e-6, "{targets:?}");
+    }
+
+    #[test]
+    fn test_project() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let events = [Event {
+            start: now + Duration::hours(4),
+            end: now + Duration::hours(6),
+            note: "Stage 2".to_string(),
+        }];
+        let simulation = Simulation::default();
+        let targets = compute_targets(&events, &[], &battery(), &load(), now);
+        let projection = project(&events, &[], &[], &battery(), &load(), &simulation, now);
+        assert_eq!(projection.target_soc, targets.target_soc_low);
+        assert_eq!(projection.worst_time, now + Duration::hours(4));
+        assert_eq!(projection.soc[0], (now, targets.target_soc_low));
+        assert_eq!(projection.soc.len(), 2 * 24 * 60 + 1);
+        // The battery holds steady until load-shedding and then drains
+        let at = |hours: i64| projection.soc[(hours * 60) as usize].1;
+        assert_eq!(at(4), targets.target_soc_low);
+        assert!(at(6) < at(4));
+    }
+
+    #[test]
+    fn test_simulator() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let events = [Event {
+            start: now + Duration::hours(4),
+            end: now + Duration::hours(6),
+            note: "Stage 2".to_string(),
+        }];
+        let simulator = Simulator {
+            panels: Vec::new(),
+            forecasts: Vec::new(),
+            battery: battery(),
+            load: load(),
+            simulation: Simulation::default(),
+        };
+        let plan = simulator.plan(&events, now);
+        assert_eq!(
+            plan.targets,
+            compute_targets(&events, &[], &battery(), &load(), now)
+        );
+        assert_eq!(plan.projection.target_soc, plan.targets.target_soc_low);
+    }
+
+    #[test]
+    fn test_backup_runtime() {
+        let mut battery = battery();
+        assert_eq!(backup_runtime(&battery, 80.0, 500.0), Some(6.0));
+        assert_eq!(backup_runtime(&battery, 10.0, 500.0), Some(0.0));
+        assert_eq!(backup_runtime(&battery, 80.0, 0.0), None);
+        battery.discharge_efficiency = 0.5;
+        assert_eq!(backup_runtime(&battery, 80.0, 500.0), Some(3.0));
+    }
+
+    #[test]
+    fn test_losses() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let events = [Event {
+            start: now + Duration::hours(4),
+            end: now + Duration::hours(6),
+            note: "Stage 2".to_string(),
+        }];
+        let mut battery = battery();
+        battery.discharge_efficiency = 0.8;
+        let mut load = load();
+        load.self_consumption = 100.0;
+        let targets = compute_targets(&events, &[], &battery, &load, now);
+        // Need 2 hours at 600 W = 1200 Wh, which takes 1500 Wh from the
+        // battery = 30% above the minimum
+        assert!((targets.target_soc_low - 50.0).abs() < 1e-6, "{targets:?}");
+    }
+
+    #[test]
+    fn test_ensemble() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let events = [Event {
+            start: now + Duration::hours(4),
+            end: now + Duration::hours(6),
+            note: "Stage 2".to_string(),
+        }];
+        let mut simulation = Simulation::default();
+        let compute = |simulation: &Simulation| {
+            compute_targets_with_forecasts(&events, &[], &[], &battery(), &load(), simulation, now)
+        };
+        let base = compute(&simulation);
+        // Without perturbations, every member matches
+        let mut ensemble = Ensemble {
+            members: 10,
+            percentile: 90.0,
+            early: 0,
+            extend: 0,
+            pv_spread: 0.0,
+        };
+        simulation.ensemble = Some(ensemble.clone());
+        assert_eq!(compute(&simulation), base);
+        // Extending events by up to an hour adds up to 10%
+        ensemble.extend = 3600;
+        simulation.ensemble = Some(ensemble);
+        let targets = compute(&simulation);
+        assert!(targets.target_soc_low > base.target_soc_low, "{targets:?}");
+        assert!(targets.target_soc_low <= 50.0 + 1e-6, "{targets:?}");
+    }
+
+    #[test]
+    fn test_soc_floor() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let mut battery = battery();
+        battery.soc_floors = vec![SocFloorConfig {
+            start: NaiveTime::from_hms_opt(16, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(20, 0, 0).unwrap(),
+            soc: 60.0,
+        }];
+        let mut load = load();
+        load.timezone = Some("UTC".parse().unwrap());
+        let targets = compute_targets(&[], &[], &battery, &load, now);
+        // With grid available the battery is held, so it must start at 60%
+        assert!((targets.target_soc_low - 60.0).abs() < 1e-6, "{targets:?}");
+        // Draining at 100 W until 20:00 uses 8 hours = 800 Wh = 16% (less
+        // a step, since the floor no longer applies at 20:00)
+        assert!((targets.target_soc_high - 76.0).abs() < 0.1, "{targets:?}");
+    }
+
+    #[test]
+    fn test_no_events() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let targets = compute_targets(&[], &[], &battery(), &load(), now);
+        assert_eq!(targets.target_soc_low, 20.0);
+        assert_eq!(targets.alarm_soc, 20.0);
+    }
+
+    #[test]
+    fn test_profile() {
+        // A Sunday
+        let now = Utc.with_ymd_and_hms(2024, 6, 2, 12, 0, 0).unwrap();
+        let mut load = load();
+        load.timezone = Some("UTC".parse().unwrap());
+        // 200 W all weekend, and the default of 100 W on Monday
+        load.profile = Some(LoadProfile {
+            weekday: vec![None; 24],
+            weekend: vec![Some(200.0); 24],
+        });
+        let targets = compute_targets(&[], &[], &battery(), &load, now);
+        // 12 hours at 200 W + 12 hours at 100 W = 3600 Wh = 72%
+        assert!((targets.target_soc_high - 92.0).abs() < 1e-6, "{targets:?}");
+
+        // A holiday on Monday uses the weekend profile
+        let profile = load.profile.as_ref().unwrap();
+        let monday = NaiveDate::from_ymd_opt(2024, 6, 3)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        assert_eq!(profile.power(monday, &Holidays::default()), None);
+        let holidays = Holidays::new([monday.date()]);
+        assert_eq!(profile.power(monday, &holidays), Some(200.0));
+    }
+
+    #[test]
+    fn test_profile_or() {
+        let learned = LoadProfile {
+            weekday: vec![Some(1.0), None],
+            weekend: vec![],
+        };
+        let configured = LoadProfile {
+            weekday: vec![Some(2.0), Some(3.0)],
+            weekend: vec![Some(4.0)],
+        };
+        assert_eq!(
+            learned.or(&configured),
+            LoadProfile {
+                weekday: vec![Some(1.0), Some(3.0)],
+                weekend: vec![Some(4.0)],
+            }
+        );
+    }
+
+    #[test]
+    fn test_cheap_windows() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let events = [Event {
+            start: now + Duration::hours(8),
+            end: now + Duration::hours(10),
+            note: "Stage 2".to_string(),
+        }];
+        let mut battery = battery();
+        battery.cheap_windows = vec![Window {
+            start: NaiveTime::from_hms_opt(16, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+        }];
+        let mut load = load();
+        load.timezone = Some("UTC".parse().unwrap());
+        // The battery can be charged in the cheap window before the event
+        let targets = compute_targets(&events, &[], &battery, &load, now);
+        assert_eq!(targets.target_soc_low, 20.0);
+        // Once in the cheap window, charge to the full requirement
+        let now = now + Duration::hours(5);
+        let targets = compute_targets(&events, &[], &battery, &load, now);
+        assert!((targets.target_soc_low - 40.0).abs() < 1e-6, "{targets:?}");
+        // 2024-06-01 is a Saturday, so the weekend windows apply if given
+        battery.weekend_cheap_windows = Some(Vec::new());
+        let now = now - Duration::hours(5);
+        let targets = compute_targets(&events, &[], &battery, &load, now);
+        assert!(targets.target_soc_low > 20.0, "{targets:?}");
+    }
+
+    #[test]
+    fn test_forecast() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let forecast = PvForecast::new(vec![(now + Duration::hours(1), 300.0), (now, 100.0)]);
+        assert_eq!(forecast.power(now - Duration::minutes(1)), None);
+        assert_eq!(forecast.power(now), Some(100.0));
+        assert_eq!(forecast.power(now + Duration::minutes(30)), Some(200.0));
+        assert_eq!(forecast.power(now + Duration::hours(1)), Some(300.0));
+        assert_eq!(forecast.power(now + Duration::hours(2)), None);
+
+        let panels = [panels()];
+        let clear = panels_power(&panels, now);
+        assert_eq!(forecast_power(&panels, &[], now), clear);
+        assert_eq!(
+            forecast_power(&panels, &[Some(forecast.clone())], now),
+            100.0
+        );
+        let later = now + Duration::hours(3);
+        assert_eq!(
+            forecast_power(&panels, &[Some(forecast)], later),
+            panels_power(&panels, later)
+        );
+    }
+
+    #[test]
+    fn test_temperature_factor() {
+        let mut panels = panels();
+        assert_eq!(temperature_factor(&panels, 35.0, 1.0), 1.0);
+        panels.temperature_coefficient = Some(-0.4);
+        // Cell at 25°C
+        assert!((temperature_factor(&panels, 25.0, 0.0) - 1.0).abs() < 1e-9);
+        // Cell at 35 + 31.25 = 66.25°C
+        assert!((temperature_factor(&panels, 35.0, 1.0) - 0.835).abs() < 1e-9);
+
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 10, 0, 0).unwrap();
+        let ambient = [(now, 20.0), (now + Duration::hours(1), 24.0)];
+        let forecast = derated_forecast(&panels, &ambient);
+        assert_eq!(forecast.points.len(), 5);
+        let t = now + Duration::minutes(30);
+        let clear = panels_power([&panels], t);
+        let expected = clear * temperature_factor(&panels, 22.0, clear / panels.power);
+        assert!((forecast.power(t).unwrap() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_max_power() {
+        let mut panels = panels();
+        let now = Utc.with_ymd_and_hms(2024, 12, 21, 10, 0, 0).unwrap();
+        let clear = panels_power([&panels], now);
+        assert!(clear > 500.0);
+        panels.max_power = Some(500.0);
+        assert_eq!(panels_power([&panels], now), 500.0);
+        let forecast = PvForecast {
+            points: vec![(now, 800.0)],
+        };
+        assert_eq!(forecast_power(&[panels], &[Some(forecast)], now), 500.0);
+        assert_eq!(clip_power(800.0, None), 800.0);
+    }
+
+    #[test]
+    fn test_calibration() {
+        let mut panels = panels();
+        let now = Utc.with_ymd_and_hms(2024, 3, 21, 10, 0, 0).unwrap();
+        let clear = panels_power([&panels], now);
+        panels.derate = 0.9;
+        let mut monthly = [1.0; 12];
+        monthly[2] = 0.5;
+        panels.monthly = Some(monthly);
+        assert!((calibration(&panels, now) - 0.45).abs() < 1e-9);
+        assert!((panels_power([&panels], now) - 0.45 * clear).abs() < 1e-9);
+        let forecast = calibrate_forecast(
+            &panels,
+            PvForecast {
+                points: vec![(now, 1000.0)],
+            },
+        );
+        assert!((forecast.points[0].1 - 450.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_faces() {
+        let face = |azimuth| PanelConfig {
+            tilt: 30.0,
+            azimuth,
+            power: 2000.0,
+            ..panels()
+        };
+        let east = face(90.0);
+        let west = face(270.0);
+        let mut array = east.clone();
+        array.faces.push(PanelFaceConfig {
+            tilt: 30.0,
+            azimuth: 270.0,
+            power: 2000.0,
+        });
+        assert_eq!(array.rated_power(), 4000.0);
+        let now = Utc.with_ymd_and_hms(2024, 12, 21, 10, 0, 0).unwrap();
+        let separate = panels_power([&east, &west], now);
+        assert!((panels_power([&array], now) - separate).abs() < 1e-9);
+        array.max_power = Some(1000.0);
+        assert_eq!(panels_power([&array], now), 1000.0);
+    }
+
+    #[test]
+    fn test_forecast_sum() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 6, 0, 0).unwrap();
+        let hour = Duration::hours(1);
+        let a = PvForecast::new(vec![(start, 100.0), (start + hour * 2, 300.0)]);
+        let b = PvForecast::new(vec![(start + hour, 50.0), (start + hour * 3, 50.0)]);
+        assert_eq!(
+            PvForecast::sum(&[a, b]).points,
+            vec![(start + hour, 250.0), (start + hour * 2, 350.0)]
+        );
+    }
+
+    #[test]
+    fn test_pv_window() {
+        let panels = [panels()];
+        let tz = "UTC+2".parse().unwrap();
+        // Late in the evening local time, but still the same day
+        let now = Utc.with_ymd_and_hms(2024, 6, 21, 21, 0, 0).unwrap();
+        let (start, end) = pv_window(&panels, Some(&tz), now, 100.0).unwrap();
+        let start = tz.to_local(start);
+        let end = tz.to_local(end);
+        assert_eq!(start.date(), end.date());
+        assert_eq!(start.date(), tz.to_local(now).date());
+        // Roughly symmetric about solar noon (about 12:45 local time)
+        assert!(start.hour() >= 7 && start.hour() <= 9, "{start}");
+        assert!(end.hour() >= 16 && end.hour() <= 18, "{end}");
+        assert_eq!(pv_window(&panels, Some(&tz), now, 2000.0), None);
+    }
+
+    #[test]
+    fn test_serde() {
+        let json = r#"{"min_discharge_power": 100, "max_discharge_power": 500}"#;
+        let parsed: LoadModel = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed, load());
+    }
+
+    impl Rng {
+        /// Uniform integer in [lo, hi)
+        fn range(&mut self, lo: i64, hi: i64) -> i64 {
+            lo + (self.next() % (hi - lo) as u64) as i64
+        }
+    }
+
+    fn event(start: DateTime<Utc>, end: DateTime<Utc>) -> Event {
+        Event {
+            start,
+            end,
+            note: "Stage 6".to_string(),
+        }
+    }
+
+    /// Generate an adversarial load-shedding schedule of the sort that
+    /// EskomSePush occasionally returns.
+    fn stress_schedule(rng: &mut Rng, now: DateTime<Utc>) -> Vec<Event> {
+        let minutes = |m| Duration::minutes(m);
+        let mut events = Vec::new();
+        match rng.range(0, 5) {
+            0 => {
+                // Back-to-back stage 6 slots
+                let mut start = now + minutes(rng.range(-120, 600));
+                for _ in 0..rng.range(1, 8) {
+                    let end = start + minutes(30 * rng.range(4, 9));
+                    events.push(event(start, end));
+                    start = end;
+                }
+            }
+            1 => {
+                // Events spanning (local) midnight, possibly on several days
+                let midnight = now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+                for day in 0..rng.range(1, 3) {
+                    let centre = midnight + Duration::days(day + 1);
+                    events.push(event(
+                        centre - minutes(rng.range(0, 300)),
+                        centre + minutes(rng.range(1, 300)),
+                    ));
+                }
+            }
+            2 => {
+                // Overlapping and duplicated events
+                for _ in 0..rng.range(1, 5) {
+                    let start = now + minutes(rng.range(-60, 1440));
+                    let e = event(start, start + minutes(rng.range(30, 300)));
+                    if rng.range(0, 2) == 0 {
+                        events.push(e.clone());
+                    }
+                    events.push(e);
+                }
+            }
+            3 => {
+                // Zero-length and inverted events
+                for _ in 0..rng.range(1, 5) {
+                    let start = now + minutes(rng.range(-60, 1440));
+                    events.push(event(start, start - minutes(rng.range(0, 2) * 30)));
+                }
+            }
+            _ => {
+                // Arbitrary mix, including events far in the past or future
+                for _ in 0..rng.range(0, 10) {
+                    let start = now + minutes(rng.range(-3000, 3000));
+                    events.push(event(start, start + minutes(rng.range(-60, 600))));
+                }
+            }
+        }
+        events
+    }
+
+    fn check_bounds(targets: &Targets, battery: &Battery) {
+        for value in [
+            targets.target_soc_low,
+            targets.target_soc_high,
+            targets.alarm_soc,
+        ] {
+            assert!(value.is_finite(), "{targets:?}");
+            assert!((battery.min_soc..=100.0).contains(&value), "{targets:?}");
+        }
+        // Being able to charge never requires more, and holding never
+        // requires more than draining.
+        assert!(
+            targets.alarm_soc <= targets.target_soc_low + 1e-9,
+            "{targets:?}"
+        );
+        assert!(
+            targets.target_soc_low <= targets.target_soc_high + 1e-9,
+            "{targets:?}"
+        );
+    }
+
+    #[test]
+    fn test_stress() {
+        let panels = [PanelConfig {
+            tilt: 30.0,
+            power: 3000.0,
+            ..panels()
+        }];
+        let mut rng = Rng(0x5eed);
+        let base = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        for _ in 0..100 {
+            let now = base + Duration::minutes(rng.range(0, 365 * 1440));
+            let panels = &panels[..rng.range(0, 2) as usize];
+            let mut battery = battery();
+            battery.capacity = rng.range(1000, 20000) as f64;
+            let mut load = load();
+            load.max_discharge_power = rng.range(100, 5000) as f64;
+            let events = stress_schedule(&mut rng, now);
+            let targets = compute_targets(&events, panels, &battery, &load, now);
+            check_bounds(&targets, &battery);
+
+            // Adding load-shedding must never reduce the targets
+            let mut worse = events.clone();
+            worse.extend(stress_schedule(&mut rng, now));
+            let worse_targets = compute_targets(&worse, panels, &battery, &load, now);
+            check_bounds(&worse_targets, &battery);
+            assert!(
+                worse_targets.target_soc_low >= targets.target_soc_low - 1e-9,
+                "{events:?} {worse:?}"
+            );
+            assert!(
+                worse_targets.target_soc_high >= targets.target_soc_high - 1e-9,
+                "{events:?} {worse:?}"
+            );
+            assert!(
+                worse_targets.alarm_soc >= targets.alarm_soc - 1e-9,
+                "{events:?} {worse:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,412 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Record monitoring data in PostgreSQL (optionally with TimescaleDB)
+
+use async_trait::async_trait;
+use log::{info, warn};
+use std::error::Error;
+use std::future::Future;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Client;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::config::{PostgresConfig, PostgresSslMode};
+use crate::monitoring::{CoilUpdate, HealthUpdate, Monitor, PvUpdate, SocUpdate};
+use crate::tls;
+
+type PgResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Value bound to a statement parameter
+type Value = Box<dyn ToSql + Send + Sync>;
+
+/// Quote an SQL identifier
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Statement to insert `rows` rows into `table`, with parameters for the values
+fn insert_sql(table: &str, columns: &[&str], rows: usize) -> String {
+    let columns_sql: Vec<_> = columns.iter().map(|name| quote_ident(name)).collect();
+    let values: Vec<_> = (0..rows)
+        .map(|row| {
+            let params: Vec<_> = (1..=columns.len())
+                .map(|i| format!("${}", row * columns.len() + i))
+                .collect();
+            format!("({})", params.join(", "))
+        })
+        .collect();
+    format!(
+        "INSERT INTO {} ({}) VALUES {}",
+        quote_ident(table),
+        columns_sql.join(", "),
+        values.join(", ")
+    )
+}
+
+/// Convert a counter to the type of a bigint column
+fn bigint(x: u64) -> i64 {
+    x.try_into().unwrap_or(i64::MAX)
+}
+
+const DOUBLE: &str = "double precision";
+
+/// Columns (other than time) of each table
+const TABLES: &[(&str, &[(&str, &str)])] = &[
+    (
+        "soc",
+        &[
+            ("target_soc_low", DOUBLE),
+            ("target_soc_high", DOUBLE),
+            ("alarm_soc", DOUBLE),
+            ("current_soc", DOUBLE),
+            ("capacity", DOUBLE),
+            ("target_energy_low", DOUBLE),
+            ("target_energy_high", DOUBLE),
+            ("alarm_energy", DOUBLE),
+            ("current_energy", DOUBLE),
+            ("energy_deficit", DOUBLE),
+            ("backup_runtime", DOUBLE),
+            ("time_to_sunrise", DOUBLE),
+            ("daylight_remaining", DOUBLE),
+            ("manual_soc", DOUBLE),
+            ("clock_skew", DOUBLE),
+            ("predicted_pv", DOUBLE),
+            ("pv_window_start", "timestamptz"),
+            ("pv_window_end", "timestamptz"),
+            ("is_loadshedding", "boolean"),
+            ("emergency", "boolean"),
+            ("unscheduled_outage", "boolean"),
+            ("next_change", "timestamptz"),
+            ("smart_load", "boolean"),
+            ("aux_power", DOUBLE),
+            ("battery_power", DOUBLE),
+            ("battery_voltage", DOUBLE),
+            ("battery_current", DOUBLE),
+            ("battery_temperature", DOUBLE),
+            ("cell_temperature_min", DOUBLE),
+            ("cell_temperature_max", DOUBLE),
+            ("grid_connected", "boolean"),
+            ("load_power", DOUBLE),
+            ("soc_filtered", "bigint"),
+            ("esp_successes", "bigint"),
+            ("esp_failures", "bigint"),
+            ("esp_age", DOUBLE),
+            ("esp_latency", DOUBLE),
+            ("esp_quota_remaining", "bigint"),
+            ("inverter_writes", "bigint"),
+            ("write_budget_used", "bigint"),
+            ("active_faults", "text"),
+            ("wear_cost", DOUBLE),
+            ("grid_cost", DOUBLE),
+            ("prediction_bias", DOUBLE),
+            ("prediction_error", DOUBLE),
+            ("load_scale", DOUBLE),
+            ("pv_scale", DOUBLE),
+        ],
+    ),
+    (
+        "coil",
+        &[
+            ("active", "boolean"),
+            ("target", DOUBLE),
+            ("setting", DOUBLE),
+        ],
+    ),
+    (
+        "pv",
+        &[
+            ("mppt", "integer"),
+            ("predicted", DOUBLE),
+            ("measured", DOUBLE),
+            ("ratio", DOUBLE),
+        ],
+    ),
+    (
+        "health",
+        &[
+            ("inverter_reachable", "boolean"),
+            ("inverter_successes", "bigint"),
+            ("inverter_failures", "bigint"),
+            ("inverter_consecutive_failures", "bigint"),
+        ],
+    ),
+];
+
+pub struct PostgresMonitor {
+    config: PostgresConfig,
+    tls: MakeRustlsConnect,
+    client: Option<Client>,
+}
+
+impl PostgresMonitor {
+    pub fn new(config: &PostgresConfig) -> Result<Self, Box<dyn Error>> {
+        let prefix = &config.table_prefix;
+        if prefix.is_empty()
+            || !prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("Invalid PostgreSQL table prefix {prefix:?}").into());
+        }
+        Ok(Self {
+            config: config.clone(),
+            tls: MakeRustlsConnect::new(tls::client_config(config.ca_file.as_deref())?),
+            client: None,
+        })
+    }
+
+    fn table(&self, table: &str) -> String {
+        format!("{}_{table}", self.config.table_prefix)
+    }
+
+    /// Run `future`, failing if it takes longer than the configured timeout
+    async fn timeout<T>(&self, future: impl Future<Output = PgResult<T>>) -> PgResult<T> {
+        tokio::time::timeout(self.config.timeout, future)
+            .await
+            .map_err(|_| "PostgreSQL request timed out")?
+    }
+
+    /// Connect and create the tables (or add any missing columns)
+    async fn connect(&self) -> PgResult<Client> {
+        let mut pg = tokio_postgres::Config::new();
+        pg.host(&self.config.host)
+            .port(self.config.port)
+            .user(&self.config.user)
+            .dbname(&self.config.database)
+            .connect_timeout(self.config.timeout)
+            .ssl_mode(match self.config.ssl_mode {
+                PostgresSslMode::Disable => SslMode::Disable,
+                PostgresSslMode::Prefer => SslMode::Prefer,
+                PostgresSslMode::Require => SslMode::Require,
+            });
+        if let Some(password) = &self.config.password {
+            pg.password(password);
+        }
+        let (client, connection) = pg.connect(self.tls.clone()).await?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                warn!("PostgreSQL connection failed: {err}");
+            }
+        });
+        for (table, columns) in TABLES {
+            let table = quote_ident(&self.table(table));
+            // Add columns one at a time so that tables created by older
+            // versions gain any new columns.
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE IF NOT EXISTS {table} (time timestamptz NOT NULL)"
+                ))
+                .await?;
+            for (name, sql_type) in columns.iter() {
+                client
+                    .batch_execute(&format!(
+                        "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {} {sql_type}",
+                        quote_ident(name)
+                    ))
+                    .await?;
+            }
+            if self.config.timescaledb {
+                client
+                    .execute(
+                        "SELECT create_hypertable(CAST($1::text AS regclass), 'time', \
+                         if_not_exists => TRUE)",
+                        &[&table],
+                    )
+                    .await?;
+            }
+        }
+        Ok(client)
+    }
+
+    async fn insert(
+        &mut self,
+        table: &str,
+        rows: Vec<Vec<(&str, Value)>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(first) = rows.first() else {
+            return Ok(());
+        };
+        let columns: Vec<_> = first.iter().map(|(name, _)| *name).collect();
+        let sql = insert_sql(&self.table(table), &columns, rows.len());
+        let values: Vec<_> = rows.into_iter().flatten().map(|(_, value)| value).collect();
+        let params: Vec<_> = values
+            .iter()
+            .map(|value| value.as_ref() as &(dyn ToSql + Sync))
+            .collect();
+        if self.client.is_none() {
+            let client = self
+                .timeout(self.connect())
+                .await
+                .map_err(|err| err as Box<dyn Error>)?;
+            info!(
+                "Successfully connected to PostgreSQL server at {}:{}",
+                self.config.host, self.config.port
+            );
+            self.client = Some(client);
+        }
+        let client = self.client.as_ref().unwrap();
+        let result = self
+            .timeout(async { Ok(client.execute(&sql, &params).await?) })
+            .await;
+        if let Err(err) = result {
+            // The connection may be broken, so start afresh next time
+            warn!("PostgreSQL query failed: {err}");
+            self.client = None;
+            return Err(err as Box<dyn Error>);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Monitor for PostgresMonitor {
+    async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
+        let row: Vec<(&str, Value)> = vec![
+            ("time", Box::new(update.time)),
+            ("target_soc_low", Box::new(update.target_soc_low)),
+            ("target_soc_high", Box::new(update.target_soc_high)),
+            ("alarm_soc", Box::new(update.alarm_soc)),
+            ("current_soc", Box::new(update.current_soc)),
+            ("capacity", Box::new(update.capacity)),
+            ("target_energy_low", Box::new(update.target_energy_low)),
+            ("target_energy_high", Box::new(update.target_energy_high)),
+            ("alarm_energy", Box::new(update.alarm_energy)),
+            ("current_energy", Box::new(update.current_energy)),
+            ("energy_deficit", Box::new(update.energy_deficit)),
+            ("backup_runtime", Box::new(update.backup_runtime)),
+            ("time_to_sunrise", Box::new(update.time_to_sunrise)),
+            ("daylight_remaining", Box::new(update.daylight_remaining)),
+            ("manual_soc", Box::new(update.manual_soc)),
+            ("clock_skew", Box::new(update.clock_skew)),
+            (
+                "inverter_writes",
+                Box::new(update.inverter_writes.map(bigint)),
+            ),
+            (
+                "write_budget_used",
+                Box::new(update.write_budget_used.map(bigint)),
+            ),
+            (
+                "active_faults",
+                Box::new(update.active_faults.map(|faults| faults.join(", "))),
+            ),
+            ("predicted_pv", Box::new(update.predicted_pv)),
+            ("pv_window_start", Box::new(update.pv_window_start)),
+            ("pv_window_end", Box::new(update.pv_window_end)),
+            ("is_loadshedding", Box::new(update.is_loadshedding)),
+            ("emergency", Box::new(update.emergency)),
+            ("unscheduled_outage", Box::new(update.unscheduled_outage)),
+            ("next_change", Box::new(update.next_change)),
+            ("smart_load", Box::new(update.smart_load)),
+            ("aux_power", Box::new(update.aux_power)),
+            ("battery_power", Box::new(update.battery_power)),
+            ("battery_voltage", Box::new(update.battery_voltage)),
+            ("battery_current", Box::new(update.battery_current)),
+            ("battery_temperature", Box::new(update.battery_temperature)),
+            (
+                "cell_temperature_min",
+                Box::new(update.cell_temperature_min),
+            ),
+            (
+                "cell_temperature_max",
+                Box::new(update.cell_temperature_max),
+            ),
+            ("grid_connected", Box::new(update.grid_connected)),
+            ("load_power", Box::new(update.load_power)),
+            ("soc_filtered", Box::new(bigint(update.soc_filtered))),
+            ("esp_successes", Box::new(bigint(update.esp_successes))),
+            ("esp_failures", Box::new(bigint(update.esp_failures))),
+            ("esp_age", Box::new(update.esp_age)),
+            ("esp_latency", Box::new(update.esp_latency)),
+            ("esp_quota_remaining", Box::new(update.esp_quota_remaining)),
+            ("wear_cost", Box::new(update.wear_cost)),
+            ("grid_cost", Box::new(update.grid_cost)),
+            ("prediction_bias", Box::new(update.prediction_bias)),
+            ("prediction_error", Box::new(update.prediction_error)),
+            ("load_scale", Box::new(update.load_scale)),
+            ("pv_scale", Box::new(update.pv_scale)),
+        ];
+        self.insert("soc", vec![row]).await
+    }
+
+    async fn coil_update(&mut self, update: CoilUpdate) -> Result<(), Box<dyn Error>> {
+        let row: Vec<(&str, Value)> = vec![
+            ("time", Box::new(update.time)),
+            ("active", Box::new(update.active)),
+            ("target", Box::new(update.target)),
+            ("setting", Box::new(update.setting)),
+        ];
+        self.insert("coil", vec![row]).await
+    }
+
+    async fn pv_update(&mut self, update: PvUpdate) -> Result<(), Box<dyn Error>> {
+        let rows: Vec<_> = update
+            .strings
+            .iter()
+            .map(|string| -> Vec<(&str, Value)> {
+                vec![
+                    ("time", Box::new(update.time)),
+                    ("mppt", Box::new(string.mppt as i32)),
+                    ("predicted", Box::new(string.predicted)),
+                    ("measured", Box::new(string.measured)),
+                    ("ratio", Box::new(string.ratio)),
+                ]
+            })
+            .collect();
+        self.insert("pv", rows).await
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        let row: Vec<(&str, Value)> = vec![
+            ("time", Box::new(update.time)),
+            ("inverter_reachable", Box::new(update.inverter_reachable)),
+            (
+                "inverter_successes",
+                Box::new(bigint(update.inverter_successes)),
+            ),
+            (
+                "inverter_failures",
+                Box::new(bigint(update.inverter_failures)),
+            ),
+            (
+                "inverter_consecutive_failures",
+                Box::new(bigint(update.inverter_consecutive_failures)),
+            ),
+        ];
+        self.insert("health", vec![row]).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quote_ident() {
+        assert_eq!(quote_ident("socit_soc"), "\"socit_soc\"");
+        assert_eq!(quote_ident("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    fn test_insert_sql() {
+        assert_eq!(
+            insert_sql("socit_pv", &["time", "mppt"], 2),
+            "INSERT INTO \"socit_pv\" (\"time\", \"mppt\") VALUES ($1, $2), ($3, $4)"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Prediction of the power produced by solar panels
+//!
+//! The predictions use a clear-sky model based on the position of the sun
+//! (see [`crate::sun`]), adjusted for the horizon, calibration and clipping
+//! settings in each [`PanelConfig`]. They are the same predictions that the
+//! planner uses when no external forecast is available.
+
+use chrono::{DateTime, Duration, Utc};
+use std::ops::Range;
+
+use crate::config::PanelConfig;
+use crate::planner::duration_hours;
+pub use crate::planner::{calibration, clip_power, panels_power, PvForecast};
+
+/// Predicted total power (W) from `panels` at intervals of `step` through
+/// `range`, including the start but not the end.
+pub fn forecast(
+    panels: &[PanelConfig],
+    range: Range<DateTime<Utc>>,
+    step: Duration,
+) -> Vec<(DateTime<Utc>, f64)> {
+    let mut points = Vec::new();
+    if step <= Duration::zero() {
+        return points;
+    }
+    let mut time = range.start;
+    while time < range.end {
+        points.push((time, panels_power(panels, time)));
+        time += step;
+    }
+    points
+}
+
+/// Energy (Wh) of a forecast with equally-spaced points `step` apart,
+/// treating each point as the average power until the next.
+pub fn energy(points: &[(DateTime<Utc>, f64)], step: Duration) -> f64 {
+    points.iter().map(|(_, power)| power).sum::<f64>() * duration_hours(step)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ForecastSource;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_forecast() {
+        let panels = [PanelConfig {
+            latitude: -33.9,
+            longitude: 18.4,
+            tilt: 0.0,
+            azimuth: 0.0,
+            power: 1000.0,
+            mppt: None,
+            forecast: ForecastSource::ClearSky,
+            horizon: Vec::new(),
+            diffuse: 0.0,
+            temperature_coefficient: None,
+            noct: 45.0,
+            max_power: None,
+            derate: 1.0,
+            monthly: None,
+            faces: Vec::new(),
+        }];
+        let start = Utc.with_ymd_and_hms(2024, 12, 21, 0, 0, 0).unwrap();
+        let step = Duration::hours(1);
+        let points = forecast(&panels, start..start + Duration::days(1), step);
+        assert_eq!(points.len(), 24);
+        assert_eq!(points[0], (start, 0.0));
+        assert_eq!(points[10].1, panels_power(&panels, start + step * 10));
+        let total = energy(&points, step);
+        assert!(total > 3000.0 && total < 10000.0);
+    }
+}
@@ -0,0 +1,88 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Switching of smart relays over HTTP
+
+use reqwest::Client;
+use std::time::Duration;
+
+use crate::config::RelayConfig;
+
+pub struct Relay {
+    client: Client,
+    config: RelayConfig,
+}
+
+impl Relay {
+    pub fn new(config: &RelayConfig) -> reqwest::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            config: config.clone(),
+        })
+    }
+
+    /// URL which switches the relay on or off
+    fn url(&self, on: bool) -> String {
+        match &self.config {
+            RelayConfig::Shelly { url, channel } => {
+                let turn = if on { "on" } else { "off" };
+                format!("{}/relay/{channel}?turn={turn}", url.trim_end_matches('/'))
+            }
+            RelayConfig::Tasmota { url, channel } => {
+                let state = if on { "On" } else { "Off" };
+                format!(
+                    "{}/cm?cmnd=Power{channel}%20{state}",
+                    url.trim_end_matches('/')
+                )
+            }
+        }
+    }
+
+    pub async fn set(&self, on: bool) -> reqwest::Result<()> {
+        self.client
+            .get(self.url(on))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let shelly = Relay::new(&RelayConfig::Shelly {
+            url: "http://192.168.1.50/".to_string(),
+            channel: 0,
+        })
+        .unwrap();
+        assert_eq!(shelly.url(true), "http://192.168.1.50/relay/0?turn=on");
+        let tasmota = Relay::new(&RelayConfig::Tasmota {
+            url: "http://tasmota.local".to_string(),
+            channel: 2,
+        })
+        .unwrap();
+        assert_eq!(
+            tasmota.url(false),
+            "http://tasmota.local/cm?cmnd=Power2%20Off"
+        );
+    }
+}
@@ -0,0 +1,217 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Several independent sites in one configuration file
+//!
+//! Each `[[site]]` table has a name and may contain any of the top-level
+//! sections except `[logging]`, which applies to the whole process. The
+//! configuration for a site is the top-level configuration with the site's
+//! sections merged into it: tables are merged key by key, and other values
+//! (including arrays such as the panels) are replaced. Without any
+//! `[[site]]` tables, the top-level configuration describes the only site.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use toml::{Table, Value};
+
+use crate::config::Config;
+
+/// Configuration for one site
+pub struct Site {
+    /// Name of the site (`None` if there are no `[[site]]` tables)
+    pub name: Option<String>,
+    pub config: Config,
+}
+
+/// Merge `overlay` into `base`, recursing into tables
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Resources that can only be used by one site: the status socket, the HTTP
+/// listen address, and the state and spool directories
+fn exclusive_resources(config: &Config) -> Vec<(&'static str, String)> {
+    let mut resources = Vec::new();
+    if let Some(status) = &config.status {
+        resources.push(("status socket", status.socket.display().to_string()));
+    }
+    if let Some(http) = &config.http {
+        resources.push(("HTTP listen address", http.listen.clone()));
+    }
+    if let Some(state) = &config.state {
+        resources.push(("state directory", state.directory.display().to_string()));
+    }
+    if let Some(spool) = &config.monitoring.spool_directory {
+        resources.push(("spool directory", spool.display().to_string()));
+    }
+    resources
+}
+
+/// Parse a configuration file into its sites
+pub fn parse_sites(text: &str) -> Result<Vec<Site>, Box<dyn Error>> {
+    let mut table: Table = toml::from_str(text)?;
+    let sites = match table.remove("site") {
+        None => {
+            return Ok(vec![Site {
+                name: None,
+                config: table.try_into()?,
+            }]);
+        }
+        Some(Value::Array(sites)) if !sites.is_empty() => sites,
+        Some(_) => return Err("site must be a list of tables ([[site]])".into()),
+    };
+    let mut names = HashSet::new();
+    let mut result = Vec::with_capacity(sites.len());
+    for site in sites {
+        let Value::Table(mut site) = site else {
+            return Err("site must be a list of tables ([[site]])".into());
+        };
+        let name = match site.remove("name") {
+            Some(Value::String(name)) => name,
+            _ => return Err("every [[site]] needs a name".into()),
+        };
+        // Logging is set up once for the whole process
+        if site.contains_key("logging") {
+            return Err(format!("site {name:?}: [logging] cannot be given per site").into());
+        }
+        if !names.insert(name.clone()) {
+            return Err(format!("site {name:?} is given more than once").into());
+        }
+        let mut config = table.clone();
+        merge(&mut config, site);
+        let config = config
+            .try_into()
+            .map_err(|err| format!("site {name:?}: {err}"))?;
+        result.push(Site {
+            name: Some(name),
+            config,
+        });
+    }
+    let mut owners = HashMap::new();
+    for site in result.iter() {
+        let name = site.name.as_deref().unwrap();
+        for resource in exclusive_resources(&site.config) {
+            if let Some(other) = owners.insert(resource.clone(), name) {
+                let (kind, value) = resource;
+                return Err(
+                    format!("sites {other:?} and {name:?} both use the {kind} {value}").into(),
+                );
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Pick out the site called `name`, or all the sites if `name` is `None`
+pub fn select_sites(sites: Vec<Site>, name: Option<&str>) -> Result<Vec<Site>, Box<dyn Error>> {
+    let Some(name) = name else {
+        return Ok(sites);
+    };
+    let selected: Vec<Site> = sites
+        .into_iter()
+        .filter(|site| site.name.as_deref() == Some(name))
+        .collect();
+    if selected.is_empty() {
+        return Err(format!("no site called {name:?} in the configuration").into());
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [inverter]
+        device = "/dev/ttyUSB0"
+        min_soc = 20
+        fallback_soc = 50
+        min_discharge_power = 500
+        max_discharge_power = 5000
+        capacity_wh = 10000.0
+
+        [[site]]
+        name = "home"
+
+        [[site]]
+        name = "office"
+        [site.inverter]
+        device = "/dev/ttyUSB1"
+    "#;
+
+    #[test]
+    fn test_parse_sites() {
+        let sites = parse_sites(CONFIG).unwrap();
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites[0].name.as_deref(), Some("home"));
+        assert_eq!(sites[0].config.inverter.device, "/dev/ttyUSB0");
+        assert_eq!(sites[1].name.as_deref(), Some("office"));
+        assert_eq!(sites[1].config.inverter.device, "/dev/ttyUSB1");
+        // Keys that the site doesn't give are inherited
+        assert_eq!(sites[1].config.inverter.capacity_wh, Some(10000.0));
+
+        let office = select_sites(sites, Some("office")).unwrap();
+        assert_eq!(office.len(), 1);
+        assert_eq!(office[0].name.as_deref(), Some("office"));
+    }
+
+    #[test]
+    fn test_single_site() {
+        let sites = parse_sites(
+            r#"
+            [inverter]
+            device = "/dev/ttyUSB0"
+            min_soc = 20
+            fallback_soc = 50
+            min_discharge_power = 500
+            max_discharge_power = 5000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].name, None);
+        assert!(select_sites(sites, Some("home")).is_err());
+    }
+
+    #[test]
+    fn test_bad_sites() {
+        assert!(parse_sites("[[site]]\n[site.inverter]\ndevice = \"x\"").is_err());
+        assert!(parse_sites("[[site]]\nname = \"a\"\n[[site]]\nname = \"a\"").is_err());
+        assert!(parse_sites("[[site]]\nname = \"a\"\n[site.logging]").is_err());
+    }
+
+    #[test]
+    fn test_shared_resources() {
+        // The status socket is inherited by both sites
+        let config = format!("{CONFIG}\n[site.status]\nsocket = \"/run/socit.sock\"");
+        assert!(parse_sites(&config).is_ok());
+        let config = format!("[status]\nsocket = \"/run/socit.sock\"\n{CONFIG}");
+        assert!(parse_sites(&config).is_err());
+        let config = format!("[http]\n{CONFIG}");
+        assert!(parse_sites(&config).is_err());
+        let config = format!("[state]\ndirectory = \"/var/lib/socit\"\n{CONFIG}");
+        assert!(parse_sites(&config).is_err());
+        let config = format!("[monitoring]\nspool_directory = \"/var/spool/socit\"\n{CONFIG}");
+        assert!(parse_sites(&config).is_err());
+    }
+}
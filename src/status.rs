@@ -0,0 +1,215 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Status of the daemon, served as JSON over a Unix domain socket
+//!
+//! Each connection receives a single JSON document, after which the socket
+//! is closed.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use crate::control::EspStatus;
+use crate::esp_api::Event;
+use crate::monitoring::{CoilUpdate, HealthUpdate, Monitor, PvUpdate, SocUpdate};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Status {
+    /// Latest SoC update
+    pub soc: Option<SocUpdate>,
+    /// Time of the latest successful load-shedding poll
+    pub esp_refresh: Option<DateTime<Utc>>,
+    /// Current or next load-shedding event
+    pub next_event: Option<Event>,
+    /// Time of the latest SoC update at which the inverter was written to
+    pub last_write: Option<DateTime<Utc>>,
+    /// Latest health of the connection to the inverter
+    #[serde(default)]
+    pub health: Option<HealthUpdate>,
+}
+
+/// Status shared between [StatusMonitor] and the server
+#[derive(Default)]
+pub struct StatusBoard {
+    status: Mutex<Status>,
+}
+
+impl StatusBoard {
+    /// Current status, with the load-shedding information taken from `esp`
+    pub fn status(&self, esp: &EspStatus, now: DateTime<Utc>) -> Status {
+        let mut status = self.status.lock().unwrap().clone();
+        if let Some(state) = esp.state.lock().unwrap().as_ref() {
+            status.esp_refresh = Some(state.time);
+            status.next_event = state
+                .response
+                .events
+                .iter()
+                .filter(|event| event.end > now)
+                .min_by_key(|event| event.start)
+                .cloned();
+        }
+        status
+    }
+}
+
+/// Record updates in a [StatusBoard]
+pub struct StatusMonitor {
+    board: Arc<StatusBoard>,
+    writes: Option<u64>,
+}
+
+impl StatusMonitor {
+    pub fn new(board: Arc<StatusBoard>) -> Self {
+        Self {
+            board,
+            writes: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Monitor for StatusMonitor {
+    async fn soc_update(&mut self, update: SocUpdate) -> Result<(), Box<dyn Error>> {
+        let mut status = self.board.status.lock().unwrap();
+        if update.inverter_writes.is_some() && update.inverter_writes != self.writes {
+            if self.writes.is_some() {
+                status.last_write = Some(update.time);
+            }
+            self.writes = update.inverter_writes;
+        }
+        status.soc = Some(update);
+        Ok(())
+    }
+
+    async fn coil_update(&mut self, _: CoilUpdate) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn pv_update(&mut self, _: PvUpdate) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn health_update(&mut self, update: HealthUpdate) -> Result<(), Box<dyn Error>> {
+        self.board.status.lock().unwrap().health = Some(update);
+        Ok(())
+    }
+}
+
+/// Serve the status on a Unix domain socket until cancelled
+#[cfg(unix)]
+pub async fn serve(
+    path: &std::path::Path,
+    board: &StatusBoard,
+    esp: &EspStatus,
+    token: tokio_util::sync::CancellationToken,
+) {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixListener;
+
+    // Remove a socket left behind by a previous run
+    let _ = std::fs::remove_file(path);
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("Cannot listen on {}: {err}", path.display());
+            return;
+        }
+    };
+    info!("Serving status on {}", path.display());
+    loop {
+        let mut stream = tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Failed to accept status connection: {err}");
+                    continue;
+                }
+            },
+            _ = token.cancelled() => { break; }
+        };
+        let json = match serde_json::to_vec(&board.status(esp, Utc::now())) {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("Failed to encode status: {err}");
+                continue;
+            }
+        };
+        if let Err(err) = stream.write_all(&json).await {
+            warn!("Failed to send status: {err}");
+        }
+    }
+    let _ = std::fs::remove_file(path);
+}
+
+#[cfg(not(unix))]
+pub async fn serve(
+    _path: &std::path::Path,
+    _board: &StatusBoard,
+    _esp: &EspStatus,
+    _token: tokio_util::sync::CancellationToken,
+) {
+    warn!("The status socket is only supported on Unix");
+}
+
+/// Fetch the status from a running daemon
+#[cfg(unix)]
+pub async fn query(path: &std::path::Path) -> Result<Status, Box<dyn Error>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    let mut json = Vec::new();
+    stream.read_to_end(&mut json).await?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+#[cfg(not(unix))]
+pub async fn query(_path: &std::path::Path) -> Result<Status, Box<dyn Error>> {
+    Err("the status socket is only supported on Unix".into())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn test_serve() {
+        let path = std::env::temp_dir().join(format!("socit-status-{}.sock", std::process::id()));
+        let board = StatusBoard::default();
+        board.status.lock().unwrap().last_write = Some(Utc::now());
+        let esp = EspStatus::default();
+        let token = CancellationToken::new();
+        let server = serve(&path, &board, &esp, token.clone());
+        let client = async {
+            // Wait for the server to bind
+            while tokio::net::UnixStream::connect(&path).await.is_err() {
+                tokio::task::yield_now().await;
+            }
+            let status = query(&path).await.unwrap();
+            token.cancel();
+            status
+        };
+        let ((), status) = tokio::join!(server, client);
+        assert!(status.soc.is_none());
+        assert!(status.next_event.is_none());
+        assert_eq!(status.last_write, board.status.lock().unwrap().last_write);
+        assert!(!path.exists());
+    }
+}
@@ -29,13 +29,16 @@
 //! Nevertheless it agrees with high-precision astronomy libraries to
 //! better than a degree.
 //!
+//! The intensity of sunlight is reduced according to the air mass it passes
+//! through, so that output near sunrise and sunset is not overestimated.
+//!
 //! The orbital parameters and the equations for applying them are taken
 //! from <https://ssd.jpl.nasa.gov/planets/approx_pos.html>, table 2a.
 
 // Lots of variables from external equations don't have snake case
 #![allow(non_snake_case)]
 
-use chrono::{DateTime, TimeZone};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use radians::{Angle, Deg64, Rad64, Unit, Wrap64};
 use std::f64::consts::PI;
 use std::ops::{Index, IndexMut, Mul, Neg};
@@ -207,12 +210,143 @@ where
     Matrix([l_x.0, l_y.0, l_z.0]) * r_tirs.normalized() // ignores TIRS -> ITRS corrections
 }
 
+/// Elevation (degrees) of the sun above the horizon at a location
+pub fn sun_elevation<Tz, U1, U2>(
+    lat: Angle<f64, U1>,
+    lon: Angle<f64, U2>,
+    time: &DateTime<Tz>,
+) -> f64
+where
+    Tz: TimeZone,
+    U1: Unit<f64>,
+    U2: Unit<f64>,
+{
+    sun_direction(lat, lon, time)[2].asin().to_degrees()
+}
+
+/// Elevation (degrees) of the centre of the sun at sunrise and sunset,
+/// allowing for refraction and the radius of the sun.
+const SUNRISE_ELEVATION: f64 = -0.833;
+
+/// Find the first time after `time` (within two days) at which the sun
+/// rises (if `rising`) or sets. Returns `None` if there is no such time,
+/// which can happen near the poles.
+fn next_crossing<U1, U2>(
+    lat: Angle<f64, U1>,
+    lon: Angle<f64, U2>,
+    time: DateTime<Utc>,
+    rising: bool,
+) -> Option<DateTime<Utc>>
+where
+    U1: Unit<f64>,
+    U2: Unit<f64>,
+{
+    let step = Duration::minutes(10);
+    let up = |t: &DateTime<Utc>| sun_elevation(lat, lon, t) > SUNRISE_ELEVATION;
+    let mut t0 = time;
+    let mut up0 = up(&t0);
+    while t0 < time + Duration::days(2) {
+        let t1 = t0 + step;
+        let up1 = up(&t1);
+        if up0 != up1 && up1 == rising {
+            // Bisect to within a second
+            let (mut lo, mut hi) = (t0, t1);
+            while hi - lo > Duration::seconds(1) {
+                let mid = lo + (hi - lo) / 2;
+                if up(&mid) == rising {
+                    hi = mid;
+                } else {
+                    lo = mid;
+                }
+            }
+            return Some(hi);
+        }
+        (t0, up0) = (t1, up1);
+    }
+    None
+}
+
+/// Next sunrise after `time`
+pub fn next_sunrise<U1, U2>(
+    lat: Angle<f64, U1>,
+    lon: Angle<f64, U2>,
+    time: DateTime<Utc>,
+) -> Option<DateTime<Utc>>
+where
+    U1: Unit<f64>,
+    U2: Unit<f64>,
+{
+    next_crossing(lat, lon, time, true)
+}
+
+/// Next sunset after `time`
+pub fn next_sunset<U1, U2>(
+    lat: Angle<f64, U1>,
+    lon: Angle<f64, U2>,
+    time: DateTime<Utc>,
+) -> Option<DateTime<Utc>>
+where
+    U1: Unit<f64>,
+    U2: Unit<f64>,
+{
+    next_crossing(lat, lon, time, false)
+}
+
+/// Elevation (degrees) of the local horizon at an azimuth (degrees).
+///
+/// The profile consists of (azimuth, elevation) pairs in degrees, in any
+/// order. Between points the elevation is interpolated linearly, wrapping
+/// around through north. An empty profile is a flat horizon.
+pub fn horizon_elevation(profile: &[[f64; 2]], azimuth: f64) -> f64 {
+    // Nearest points on either side, as (angular distance, elevation)
+    let mut below: Option<(f64, f64)> = None;
+    let mut above: Option<(f64, f64)> = None;
+    for &[az, el] in profile {
+        let d_below = (azimuth - az).rem_euclid(360.0);
+        let d_above = (az - azimuth).rem_euclid(360.0);
+        if below.is_none_or(|(d, _)| d_below < d) {
+            below = Some((d_below, el));
+        }
+        if above.is_none_or(|(d, _)| d_above < d) {
+            above = Some((d_above, el));
+        }
+    }
+    match (below, above) {
+        (Some((d_below, el_below)), Some((d_above, el_above))) => {
+            if d_below + d_above == 0.0 {
+                el_below
+            } else {
+                el_below + (el_above - el_below) * d_below / (d_below + d_above)
+            }
+        }
+        _ => 0.0,
+    }
+}
+
+/// Relative air mass for the sun at a given elevation (degrees), using the
+/// formula of Kasten and Young (1989).
+pub fn air_mass(elevation: f64) -> f64 {
+    let zenith = 90.0 - elevation.max(0.0);
+    1.0 / (zenith.to_radians().cos() + 0.50572 * (96.07995 - zenith).powf(-1.6364))
+}
+
+/// Direct sunlight intensity relative to that with the sun at the zenith,
+/// using the empirical formula of Meinel and Meinel (1976).
+pub fn air_mass_attenuation(elevation: f64) -> f64 {
+    0.7_f64.powf(air_mass(elevation).powf(0.678) - 1.0)
+}
+
 /// Compute fraction of peak energy for a solar panel with given elevation and azimuth
+///
+/// When the sun is behind the local horizon (see [horizon_elevation]), only
+/// `diffuse` times the unshaded fraction is produced.
 pub fn solar_fraction<Tz, U1, U2, U3, U4>(
     lat: Angle<f64, U1>,
     lon: Angle<f64, U2>,
     elevation: Angle<f64, U3>,
     azimuth: Angle<f64, U4>,
+    horizon: &[[f64; 2]],
+    diffuse: f64,
     time: &DateTime<Tz>,
 ) -> f64
 where
@@ -229,5 +363,63 @@ where
     let (s_el, c_el) = elevation.sin_cos();
     let (s_az, c_az) = azimuth.sin_cos();
     let panel_dir = Vector([c_el * s_az, c_el * c_az, s_el]);
-    dot(&sun_dir, &panel_dir).max(0.0)
+    let sun_elevation = sun_dir[2].asin().to_degrees();
+    let fraction = dot(&sun_dir, &panel_dir).max(0.0) * air_mass_attenuation(sun_elevation);
+    if !horizon.is_empty() {
+        let sun_azimuth = sun_dir[0].atan2(sun_dir[1]).to_degrees();
+        if sun_elevation < horizon_elevation(horizon, sun_azimuth) {
+            return fraction * diffuse;
+        }
+    }
+    fraction
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_air_mass() {
+        assert!((air_mass(90.0) - 1.0).abs() < 1e-3);
+        assert!((air_mass(30.0) - 2.0).abs() < 1e-2);
+        // Finite at the horizon
+        assert!((air_mass(0.0) - 38.0).abs() < 0.1);
+        assert!((air_mass_attenuation(90.0) - 1.0).abs() < 1e-3);
+        assert!(air_mass_attenuation(10.0) < 0.7);
+    }
+
+    #[test]
+    fn test_sunrise_sunset() {
+        let lat = Deg64::new(-33.9);
+        let lon = Deg64::new(18.4);
+        // Cape Town on 2024-06-21: sunrise 07:51 and sunset 17:44 SAST
+        let midnight = Utc.with_ymd_and_hms(2024, 6, 20, 22, 0, 0).unwrap();
+        let sunrise = next_sunrise(lat, lon, midnight).unwrap();
+        let expected = Utc.with_ymd_and_hms(2024, 6, 21, 5, 51, 0).unwrap();
+        assert!((sunrise - expected).num_seconds().abs() < 120);
+        let sunset = next_sunset(lat, lon, midnight).unwrap();
+        let expected = Utc.with_ymd_and_hms(2024, 6, 21, 15, 44, 0).unwrap();
+        assert!((sunset - expected).num_seconds().abs() < 120);
+        // After sunrise, the next one is the following day
+        let next = next_sunrise(lat, lon, sunrise + Duration::minutes(1)).unwrap();
+        assert!((next - sunrise - Duration::days(1)).num_seconds().abs() < 120);
+        assert!(sun_elevation(lat, lon, &(sunrise + Duration::hours(4))) > 20.0);
+        // No sunrise during the polar night
+        assert_eq!(
+            next_sunrise(Deg64::new(80.0), lon, midnight + Duration::days(180)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_horizon_elevation() {
+        assert_eq!(horizon_elevation(&[], 90.0), 0.0);
+        let profile = [[90.0, 20.0], [180.0, 10.0], [300.0, 5.0]];
+        assert_eq!(horizon_elevation(&profile, 90.0), 20.0);
+        assert_eq!(horizon_elevation(&profile, 135.0), 15.0);
+        // Wraps around through north
+        assert_eq!(horizon_elevation(&profile, 0.0), 11.0);
+        assert_eq!(horizon_elevation(&profile, -60.0), 5.0);
+        assert_eq!(horizon_elevation(&[[45.0, 8.0]], 200.0), 8.0);
+    }
 }
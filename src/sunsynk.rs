@@ -16,36 +16,311 @@
 
 use async_trait::async_trait;
 use chrono::naive::{NaiveDate, NaiveDateTime, NaiveTime};
-use chrono::{Duration, DurationRound, Timelike};
-use log::info;
-use std::io::ErrorKind;
-use tokio_modbus::client::Context;
-use tokio_modbus::prelude::{Reader, Writer};
+use chrono::{Datelike, Duration, DurationRound, Timelike, Utc};
+use log::{info, warn};
+use std::io::{self, ErrorKind};
+use tokio::time::Instant;
+use tokio_modbus::prelude::{Client, Reader, Writer};
 use tokio_modbus::slave::Slave;
 
-use super::inverter::{CoilInfo, Info, Inverter, Result};
+use super::config::{InverterConfig, RegisterLayout, Rounding, WorkMode};
+use super::inverter::{
+    CoilInfo, CurrentLimits, Info, Inverter, IoStats, Program, Result, Telemetry, VerifyError,
+};
+use super::modbus::Bus;
+use super::timezone::Timezone;
 
 const NUM_PROGRAMS: usize = 6;
+const REG_DEVICE_TYPE: u16 = 0;
+const REG_PROTOCOL_VERSION: u16 = 2;
+const REG_SERIAL: u16 = 3;
+/// Number of registers holding the serial number (two ASCII characters each)
+const SERIAL_LENGTH: u16 = 5;
+/// Values of [REG_DEVICE_TYPE] for three-phase hybrid inverters (low- and high-voltage battery)
+const THREE_PHASE_DEVICE_TYPES: [u16; 2] = [5, 6];
 const REG_CLOCK: u16 = 22;
 const REG_BATTERY_CAPACITY_AH: u16 = 204;
 const REG_BATTERY_RESTART_VOLTAGE: u16 = 221;
 const REG_GRID_CHARGE_CURRENT: u16 = 230;
 const REG_SOC: u16 = 184;
 const REG_PROGRAM_TIME: u16 = 250;
+const REG_PROGRAM_POWER: u16 = 256;
 const REG_PROGRAM_SOC: u16 = 268;
+const REG_PROGRAM_CHARGE: u16 = 274;
+/// Number of registers from the first program time to the last program charge flags
+const PROGRAM_SPAN: u16 = REG_PROGRAM_CHARGE + NUM_PROGRAMS as u16 - REG_PROGRAM_TIME;
+/// Number of times to write the programs before giving up on verifying them
+const VERIFY_ATTEMPTS: u32 = 4;
+/// Bit in [REG_PROGRAM_CHARGE] that enables charging from the grid
+const PROGRAM_GRID_CHARGE: u16 = 1;
 const REG_TRICKLE: u16 = 206;
 const REG_COIL_POWER: u16 = 172;
 const REG_INVERTER_POWER: u16 = 167;
 const REG_SYSTEM_MODE: u16 = 244;
+// Only meaningful on firmware that reports power to non-essential loads
+const REG_NON_ESSENTIAL_POWER: u16 = 170;
+const REG_PV_POWER: u16 = 186;
+const REG_BATTERY_MAX_CHARGE_CURRENT: u16 = 210;
+const REG_AUX_POWER: u16 = 166;
+const REG_AUX_MODE: u16 = 235;
+const REG_LOAD_POWER: u16 = 178;
+const REG_BATTERY_TEMPERATURE: u16 = 182;
+const REG_BATTERY_VOLTAGE: u16 = 183;
+const REG_BATTERY_POWER: u16 = 190;
+const REG_BATTERY_CURRENT: u16 = 191;
+const REG_GRID_CONNECTED: u16 = 194;
+/// First of the fault registers, in which bit `i` (counting across the
+/// registers) is set if fault F`i+1` is active
+const REG_FAULTS: u16 = 103;
+const NUM_FAULT_REGISTERS: u16 = 4;
+/// Descriptions of the more common fault codes
+const FAULT_DESCRIPTIONS: &[(u16, &str)] = &[
+    (1, "DC inversed"),
+    (7, "DC/DC soft start failure"),
+    (13, "Working mode changed"),
+    (15, "AC overcurrent (software)"),
+    (16, "AC leakage current"),
+    (18, "AC overcurrent (hardware)"),
+    (20, "DC overcurrent (hardware)"),
+    (22, "Remote emergency stop"),
+    (23, "AC leakage current transient"),
+    (24, "DC insulation impedance"),
+    (26, "DC busbar unbalanced"),
+    (29, "Parallel CAN bus fault"),
+    (35, "No AC grid"),
+    (41, "Parallel system stop"),
+    (42, "AC line low voltage"),
+    (46, "Backup battery fault"),
+    (47, "AC over frequency"),
+    (48, "AC under frequency"),
+    (56, "DC busbar voltage too low"),
+    (58, "BMS communication fault"),
+    (63, "Arc fault"),
+    (64, "Heatsink temperature too high"),
+];
+/// Values of [REG_SYSTEM_MODE], indexed by value
+const WORK_MODES: [WorkMode; 3] = [
+    WorkMode::SellingFirst,
+    WorkMode::LimitedToLoad,
+    WorkMode::LimitedToHome,
+];
+/// Addresses of the registers that depend on the register layout. The
+/// constants above give the single-phase layout. Only the identification
+/// registers ([REG_DEVICE_TYPE] to [REG_SERIAL]) are shared by all layouts.
+///
+/// Registers whose address is not known for a layout are `None`, and the
+/// features that need them are unavailable with that layout.
+#[derive(Debug, PartialEq, Eq)]
+struct Registers {
+    soc: u16,
+    /// First program time; the other program registers follow as in [PROGRAM_SPAN]
+    program_time: u16,
+    system_mode: u16,
+    coil_power: u16,
+    inverter_power: u16,
+    load_power: u16,
+    pv_power: u16,
+    /// The battery registers, which are read as one block from temperature to current
+    battery_temperature: u16,
+    battery_voltage: u16,
+    battery_power: u16,
+    battery_current: u16,
+    clock: Option<u16>,
+    battery_capacity_ah: Option<u16>,
+    battery_restart_voltage: Option<u16>,
+    grid_charge_current: Option<u16>,
+    aux_mode: Option<u16>,
+    aux_power: Option<u16>,
+    non_essential_power: Option<u16>,
+    trickle: Option<u16>,
+    /// Followed by the max discharge current
+    battery_max_charge_current: Option<u16>,
+    grid_connected: Option<u16>,
+    faults: Option<u16>,
+}
+
+const SINGLE_PHASE_REGISTERS: Registers = Registers {
+    soc: REG_SOC,
+    program_time: REG_PROGRAM_TIME,
+    system_mode: REG_SYSTEM_MODE,
+    coil_power: REG_COIL_POWER,
+    inverter_power: REG_INVERTER_POWER,
+    load_power: REG_LOAD_POWER,
+    pv_power: REG_PV_POWER,
+    battery_temperature: REG_BATTERY_TEMPERATURE,
+    battery_voltage: REG_BATTERY_VOLTAGE,
+    battery_power: REG_BATTERY_POWER,
+    battery_current: REG_BATTERY_CURRENT,
+    clock: Some(REG_CLOCK),
+    battery_capacity_ah: Some(REG_BATTERY_CAPACITY_AH),
+    battery_restart_voltage: Some(REG_BATTERY_RESTART_VOLTAGE),
+    grid_charge_current: Some(REG_GRID_CHARGE_CURRENT),
+    aux_mode: Some(REG_AUX_MODE),
+    aux_power: Some(REG_AUX_POWER),
+    non_essential_power: Some(REG_NON_ESSENTIAL_POWER),
+    trickle: Some(REG_TRICKLE),
+    battery_max_charge_current: Some(REG_BATTERY_MAX_CHARGE_CURRENT),
+    grid_connected: Some(REG_GRID_CONNECTED),
+    faults: Some(REG_FAULTS),
+};
+
+const THREE_PHASE_REGISTERS: Registers = Registers {
+    soc: 588,
+    program_time: 148,
+    system_mode: 142,
+    coil_power: 619,
+    inverter_power: 636,
+    load_power: 653,
+    pv_power: 672,
+    battery_temperature: 586,
+    battery_voltage: 587,
+    battery_power: 590,
+    battery_current: 591,
+    clock: None,
+    battery_capacity_ah: None,
+    battery_restart_voltage: None,
+    grid_charge_current: None,
+    aux_mode: None,
+    aux_power: None,
+    non_essential_power: None,
+    trickle: None,
+    battery_max_charge_current: None,
+    grid_connected: None,
+    faults: None,
+};
+
+impl RegisterLayout {
+    fn registers(self) -> &'static Registers {
+        match self {
+            RegisterLayout::SinglePhase => &SINGLE_PHASE_REGISTERS,
+            RegisterLayout::ThreePhase => &THREE_PHASE_REGISTERS,
+        }
+    }
+}
+
+/// Error for a feature that needs a register that is not known for the
+/// register layout in use
+fn unsupported(what: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    format!("{what} is not supported with the three-phase register layout").into()
+}
+
+/// Choose the register layout for a value of [REG_DEVICE_TYPE]
+fn layout_for_device(device_type: u16) -> RegisterLayout {
+    if THREE_PHASE_DEVICE_TYPES.contains(&device_type) {
+        RegisterLayout::ThreePhase
+    } else {
+        RegisterLayout::SinglePhase
+    }
+}
+
+/// Decode the serial number registers, which hold two ASCII characters each
+fn decode_serial(regs: &[u16]) -> String {
+    regs.iter()
+        .flat_map(|reg| reg.to_be_bytes())
+        .filter(|c| c.is_ascii_graphic())
+        .map(char::from)
+        .collect()
+}
+
+/// Value of [REG_AUX_MODE] when the port is a smart load output
+const AUX_MODE_SMART_LOAD: u16 = 1;
+const NUM_PV_STRINGS: u16 = 2;
 
 pub struct SunsynkInverter {
-    ctx: Context,
+    bus: Bus,
+    slave: Slave,
+    /// Time to wait for the response to each request
+    timeout: std::time::Duration,
+    /// Number of times to retry a failed request
+    retries: u32,
+    /// Minimum time between requests on the bus
+    request_delay: std::time::Duration,
+    /// Battery capacity (Wh) to report instead of the one derived from the registers
+    capacity_wh: Option<f64>,
+    /// Grid charge power (W) to report instead of the one derived from the registers
+    charge_power: Option<f64>,
+    /// Values derived from the registers when last logged, for comparison with the overrides
+    derived: Option<(f64, f64)>,
+    /// Time zone of the inverter's programs. If not specified, the inverter's clock is used.
+    timezone: Option<Timezone>,
+    target_rounding: Rounding,
+    fallback_rounding: Rounding,
+    /// Write all program registers in a single transaction
+    batch_writes: bool,
+    /// Read the programs back after writing them
+    verify_writes: bool,
+    grid_charge: Option<bool>,
+    program_power: Option<u16>,
+    /// Whether the trickle register is signed
+    signed_trickle: bool,
+    /// Register layout, once configured or detected
+    registers: Option<&'static Registers>,
+    /// Minimum change in the target (%) for which the programs are rewritten
+    deadband: f64,
+    /// Most recent programs written by [Inverter::set_min_soc]
+    last_write: Option<LastWrite>,
+    /// Number of register writes since startup
+    writes: u64,
+    io: IoStats,
+    /// Programs first read from the inverter (normally at startup), for
+    /// [Inverter::restore_programs]
+    snapshot: Option<[RawProgram; NUM_PROGRAMS]>,
+}
+
+/// A single Modbus transaction
+enum Request<'a> {
+    Read(u16, u16),
+    Write(u16, &'a [u16]),
+}
+
+/// Summary of programs written by [SunsynkInverter::set_min_soc]
+struct LastWrite {
+    target: u16,
+    fallback: u16,
+    /// Local time after which the programs need to be moved
+    valid_until: NaiveDateTime,
 }
 
+/// Program as stored in the registers
 #[derive(Clone, Copy, Default, Eq, PartialEq)]
-pub struct Program {
-    pub time: NaiveTime,
-    pub soc: u16, // %
+struct RawProgram {
+    time: NaiveTime,
+    power: u16,  // W
+    soc: u16,    // %
+    charge: u16, // Flags (see [PROGRAM_GRID_CHARGE])
+}
+
+impl From<&RawProgram> for Program {
+    fn from(raw: &RawProgram) -> Self {
+        Self {
+            time: raw.time,
+            power: raw.power,
+            soc: raw.soc,
+            grid_charge: raw.charge & PROGRAM_GRID_CHARGE != 0,
+        }
+    }
+}
+
+/// Convert programs to their register values, keeping the charge flags other
+/// than grid charging from `old`.
+fn encode_raw_programs(
+    programs: &[Program; NUM_PROGRAMS],
+    old: &[RawProgram; NUM_PROGRAMS],
+) -> [RawProgram; NUM_PROGRAMS] {
+    let mut raw = [RawProgram::default(); NUM_PROGRAMS];
+    for ((raw, program), old) in raw.iter_mut().zip(programs.iter()).zip(old.iter()) {
+        *raw = RawProgram {
+            time: program.time,
+            power: program.power,
+            soc: program.soc,
+            charge: if program.grid_charge {
+                old.charge | PROGRAM_GRID_CHARGE
+            } else {
+                old.charge & !PROGRAM_GRID_CHARGE
+            },
+        };
+    }
+    raw
 }
 
 /// Decode time from a modbus register.
@@ -58,6 +333,27 @@ fn decode_time(raw: u16) -> Option<NaiveTime> {
     NaiveTime::from_hms_opt(h.into(), m.into(), 0)
 }
 
+/// Decode the clock from its three registers
+fn decode_clock(data: &[u16]) -> Option<NaiveDateTime> {
+    let year = 2000 + (data[0] >> 8) as i32;
+    let month = (data[0] & 0xff) as u32;
+    let day = (data[1] >> 8) as u32;
+    let hour = (data[1] & 0xff) as u32;
+    let minute = (data[2] >> 8) as u32;
+    let second = (data[2] & 0xff) as u32;
+    NaiveDate::from_ymd_opt(year, month, day).and_then(|x| x.and_hms_opt(hour, minute, second))
+}
+
+/// Encode the clock to store in its three registers
+fn encode_clock(time: NaiveDateTime) -> [u16; 3] {
+    let year = (time.year() - 2000).clamp(0, 255) as u16;
+    [
+        (year << 8) | time.month() as u16,
+        ((time.day() as u16) << 8) | time.hour() as u16,
+        ((time.minute() as u16) << 8) | time.second() as u16,
+    ]
+}
+
 /// Encode time to store in a modbus register.
 ///
 /// The seconds part of the time is ignored.
@@ -66,22 +362,90 @@ fn encode_time(time: NaiveTime) -> u16 {
 }
 
 /// Convert state of charge to u16 and clamp
-fn round_soc(soc: f64) -> u16 {
-    if soc < 0.0 {
+fn round_soc(soc: f64, rounding: Rounding) -> u16 {
+    if soc <= 0.0 {
         0
     } else if soc >= 100.0 {
         100
     } else {
-        // .round() seems to be broken on Raspberry Pi
-        (soc + 0.5) as u16
+        // .round() seems to be broken on Raspberry Pi, so avoid the
+        // floating-point rounding functions and rely only on truncation.
+        match rounding {
+            Rounding::Nearest => (soc + 0.5) as u16,
+            Rounding::Down => soc as u16,
+            Rounding::Up => {
+                let truncated = soc as u16;
+                if (truncated as f64) < soc {
+                    truncated + 1
+                } else {
+                    truncated
+                }
+            }
+        }
     }
 }
 
+/// Update the registers spanning all the programs (starting from `old`) with
+/// the program times and SoCs, and optionally the power and charge fields.
+fn encode_programs(old: &[u16], programs: &[RawProgram], power: bool, charge: bool) -> Vec<u16> {
+    let mut block = old.to_vec();
+    let power_offset = (REG_PROGRAM_POWER - REG_PROGRAM_TIME) as usize;
+    let soc_offset = (REG_PROGRAM_SOC - REG_PROGRAM_TIME) as usize;
+    let charge_offset = (REG_PROGRAM_CHARGE - REG_PROGRAM_TIME) as usize;
+    for (i, program) in programs.iter().enumerate() {
+        block[i] = encode_time(program.time);
+        block[soc_offset + i] = program.soc;
+        if power {
+            block[power_offset + i] = program.power;
+        }
+        if charge {
+            block[charge_offset + i] = program.charge;
+        }
+    }
+    block
+}
+
+/// Runs of consecutive registers that differ between `old` and `new`, as
+/// offsets and the new values.
+fn changed_spans<'a>(old: &[u16], new: &'a [u16]) -> Vec<(usize, &'a [u16])> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for i in 0..=new.len() {
+        let changed = i < new.len() && old[i] != new[i];
+        match (start, changed) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                spans.push((s, &new[s..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    spans
+}
+
+/// Whether the registers spanning all the programs hold `programs`. The power
+/// and charge fields are only checked if requested, since they are not always
+/// written.
+fn programs_written(programs: &[RawProgram], block: &[u16], power: bool, charge: bool) -> bool {
+    let power_offset = (REG_PROGRAM_POWER - REG_PROGRAM_TIME) as usize;
+    let soc_offset = (REG_PROGRAM_SOC - REG_PROGRAM_TIME) as usize;
+    let charge_offset = (REG_PROGRAM_CHARGE - REG_PROGRAM_TIME) as usize;
+    programs.iter().enumerate().all(|(i, program)| {
+        block[i] == encode_time(program.time)
+            && block[soc_offset + i] == program.soc
+            && (!power || block[power_offset + i] == program.power)
+            && (!charge || block[charge_offset + i] == program.charge)
+    })
+}
+
 /// Construct programs to load
-fn make_programs(target: f64, fallback: f64, now_local: NaiveDateTime) -> [Program; NUM_PROGRAMS] {
-    let target = round_soc(target);
-    let fallback = round_soc(fallback);
-    let mut programs = [Program::default(); NUM_PROGRAMS];
+fn make_programs(
+    target: u16,
+    fallback: u16,
+    now_local: NaiveDateTime,
+) -> [RawProgram; NUM_PROGRAMS] {
+    let mut programs = [RawProgram::default(); NUM_PROGRAMS];
     // The inverter truncates program times to the nearest 5 minutes.
     // Set target in a 20-minute window around the current time.
     let step = Duration::seconds(300);
@@ -107,20 +471,100 @@ fn make_programs(target: f64, fallback: f64, now_local: NaiveDateTime) -> [Progr
     programs
 }
 
+/// Time until which the programs from [make_programs] still cover the
+/// current time with at least 5 minutes to spare
+fn program_valid_until(now_local: NaiveDateTime) -> NaiveDateTime {
+    let step = Duration::seconds(300);
+    (now_local + step * 2).duration_round(step).unwrap() - step
+}
+
+/// Apply the configured charge settings, keeping the existing values of
+/// settings that are not configured.
+fn apply_charge_settings(
+    programs: &mut [RawProgram],
+    old: &[RawProgram],
+    grid_charge: Option<bool>,
+    power: Option<u16>,
+) {
+    for (program, old) in programs.iter_mut().zip(old.iter()) {
+        program.power = power.unwrap_or(old.power);
+        program.charge = match grid_charge {
+            Some(true) => old.charge | PROGRAM_GRID_CHARGE,
+            Some(false) => old.charge & !PROGRAM_GRID_CHARGE,
+            None => old.charge,
+        };
+    }
+}
+
 impl SunsynkInverter {
-    fn connect(device: &str, modbus_id: u8) -> Context {
-        let slave = Slave(modbus_id);
-        match device.parse() {
-            Ok(socket_addr) => modbus_robust::new_tcp_slave(socket_addr, slave),
-            Err(_) => {
-                // Not an address. Try it as a device file for serial
-                modbus_robust::new_rtu_slave(device, 9600, slave)
+    /// Update [SunsynkInverter::io] with the outcome of a transaction
+    fn record<T>(&mut self, result: Result<T>) -> Result<T> {
+        if result.is_ok() {
+            self.io.successes += 1;
+            self.io.consecutive_failures = 0;
+        } else {
+            self.io.failures += 1;
+            self.io.consecutive_failures += 1;
+        }
+        result
+    }
+
+    /// Issue a request, applying the configured delay, timeout and retries.
+    ///
+    /// Only transport failures and timeouts are retried: an exception
+    /// response from the inverter is returned immediately.
+    async fn transact(&mut self, request: Request<'_>) -> Result<Vec<u16>> {
+        let mut attempt = 0;
+        loop {
+            let outcome = {
+                let mut connection = self.bus.lock(self.slave).await;
+                if let Some(last) = connection.last_request {
+                    tokio::time::sleep_until(last + self.request_delay).await;
+                }
+                let ctx = &mut connection.ctx;
+                let call = async {
+                    match request {
+                        Request::Read(addr, cnt) => ctx.read_holding_registers(addr, cnt).await,
+                        Request::Write(addr, words) => ctx
+                            .write_multiple_registers(addr, words)
+                            .await
+                            .map(|response| response.map(|()| Vec::new())),
+                    }
+                };
+                let outcome = tokio::time::timeout(self.timeout, call).await;
+                if outcome.is_err() {
+                    // A late response could be mistaken for the answer to the
+                    // next request, so drop the connection and start afresh.
+                    let _ = connection.ctx.disconnect().await;
+                }
+                connection.last_request = Some(Instant::now());
+                outcome
+            };
+            let err: Box<dyn std::error::Error + Send + Sync> = match outcome {
+                Ok(Ok(response)) => return self.record(response.map_err(|err| err.into())),
+                Ok(Err(err)) => err.into(),
+                Err(_) => io::Error::new(ErrorKind::TimedOut, "Modbus request timed out").into(),
+            };
+            if attempt >= self.retries {
+                return self.record(Err(err));
             }
+            attempt += 1;
+            warn!(
+                "Modbus request failed ({err}), retrying ({attempt}/{})",
+                self.retries
+            );
         }
     }
 
     async fn read(&mut self, addr: u16, cnt: u16) -> Result<Vec<u16>> {
-        Ok(self.ctx.read_holding_registers(addr, cnt).await??)
+        self.transact(Request::Read(addr, cnt)).await
+    }
+
+    /// Write registers unconditionally
+    async fn write_registers(&mut self, addr: u16, words: &[u16]) -> Result<()> {
+        self.writes += 1;
+        self.transact(Request::Write(addr, words)).await?;
+        Ok(())
     }
 
     async fn read_one(&mut self, addr: u16) -> Result<u16> {
@@ -134,101 +578,283 @@ impl SunsynkInverter {
          */
         let old = self.read(addr, words.len() as u16).await?;
         if words != old {
-            self.ctx.write_multiple_registers(addr, words).await??;
+            self.write_registers(addr, words).await?;
         }
         Ok(())
     }
 
-    pub fn new(device: &str, modbus_id: u8) -> Self {
+    /// Create an inverter with its own connection to `config.device`
+    pub fn new(config: &InverterConfig) -> Self {
+        Self::with_bus(config, Bus::open(&config.device))
+    }
+
+    /// Create an inverter on a bus that may be shared with other devices
+    pub fn with_bus(config: &InverterConfig, bus: Bus) -> Self {
         Self {
-            ctx: Self::connect(device, modbus_id),
+            bus,
+            slave: Slave(config.id),
+            timeout: config.timeout,
+            retries: config.retries,
+            request_delay: config.request_delay,
+            capacity_wh: config.capacity_wh,
+            charge_power: config.charge_power,
+            derived: None,
+            timezone: config.timezone.clone(),
+            target_rounding: config.target_rounding,
+            fallback_rounding: config.fallback_rounding,
+            batch_writes: config.batch_writes,
+            verify_writes: config.verify_writes,
+            grid_charge: config.grid_charge,
+            program_power: config
+                .program_power
+                .map(|power| power.clamp(0.0, u16::MAX as f64) as u16),
+            signed_trickle: config.signed_trickle,
+            registers: config.register_layout.map(RegisterLayout::registers),
+            deadband: config.deadband,
+            last_write: None,
+            writes: 0,
+            io: IoStats::default(),
+            snapshot: None,
+        }
+    }
+
+    /// Fill in program fields from the registers spanning all the programs
+    fn decode_programs(block: &[u16]) -> [RawProgram; NUM_PROGRAMS] {
+        let mut programs = [RawProgram::default(); NUM_PROGRAMS];
+        let power_offset = (REG_PROGRAM_POWER - REG_PROGRAM_TIME) as usize;
+        let soc_offset = (REG_PROGRAM_SOC - REG_PROGRAM_TIME) as usize;
+        let charge_offset = (REG_PROGRAM_CHARGE - REG_PROGRAM_TIME) as usize;
+        for (i, program) in programs.iter_mut().enumerate() {
+            program.time = decode_time(block[i]).unwrap_or_default();
+            program.power = block[power_offset + i];
+            program.soc = block[soc_offset + i];
+            program.charge = block[charge_offset + i];
         }
+        programs
     }
 
-    async fn get_program_field(
+    /// Get the register layout, detecting it from the device type if it
+    /// was not configured.
+    async fn registers(&mut self) -> Result<&'static Registers> {
+        if let Some(registers) = self.registers {
+            return Ok(registers);
+        }
+        let ident = self
+            .read(REG_DEVICE_TYPE, REG_SERIAL + SERIAL_LENGTH)
+            .await?;
+        let device_type = ident[REG_DEVICE_TYPE as usize];
+        let layout = layout_for_device(device_type);
+        info!(
+            "Inverter {} has device type {device_type} and protocol version {:#06x}: \
+             using the {layout} register layout",
+            decode_serial(&ident[REG_SERIAL as usize..]),
+            ident[REG_PROTOCOL_VERSION as usize],
+        );
+        let registers = layout.registers();
+        self.registers = Some(registers);
+        Ok(registers)
+    }
+
+    async fn read_programs(&mut self) -> Result<[RawProgram; NUM_PROGRAMS]> {
+        let registers = self.registers().await?;
+        let block = self.read(registers.program_time, PROGRAM_SPAN).await?;
+        let programs = Self::decode_programs(&block);
+        self.snapshot.get_or_insert(programs);
+        Ok(programs)
+    }
+
+    /// Write the programs in a single transaction.
+    ///
+    /// The registers between the program times and SoCs are rewritten with
+    /// their current values, as are the power and charge fields unless they
+    /// are requested.
+    async fn set_programs_batch(
         &mut self,
-        programs: &mut [Program],
-        start: u16,
-        apply: impl Fn(&mut Program, u16),
+        programs: &[RawProgram; NUM_PROGRAMS],
+        power: bool,
+        charge: bool,
     ) -> Result<()> {
-        let values = self.read(start, NUM_PROGRAMS as u16).await?;
-        for (program, value) in programs.iter_mut().zip(values) {
-            apply(program, value);
+        let registers = self.registers().await?;
+        let old = self.read(registers.program_time, PROGRAM_SPAN).await?;
+        let block = encode_programs(&old, programs, power, charge);
+        if block != old {
+            self.write_registers(registers.program_time, &block).await?;
         }
         Ok(())
     }
 
-    async fn set_program_field(
+    /// Write the programs, reading all the program registers in one request
+    /// and then writing only the runs of registers that changed. The power
+    /// and charge fields are only written if requested.
+    async fn set_programs_spans(
         &mut self,
-        programs: &[Program],
-        start: u16,
-        get: impl Fn(&Program) -> u16,
+        programs: &[RawProgram; NUM_PROGRAMS],
+        power: bool,
+        charge: bool,
     ) -> Result<()> {
-        let mut values = [0u16; NUM_PROGRAMS];
-        for (program, value) in programs.iter().zip(values.iter_mut()) {
-            *value = get(program);
+        let registers = self.registers().await?;
+        let old = self.read(registers.program_time, PROGRAM_SPAN).await?;
+        let block = encode_programs(&old, programs, power, charge);
+        for (offset, words) in changed_spans(&old, &block) {
+            self.write_registers(registers.program_time + offset as u16, words)
+                .await?;
         }
-        self.write(start, &values).await
+        Ok(())
     }
 
-    pub async fn get_programs(&mut self) -> Result<[Program; NUM_PROGRAMS]> {
-        let mut programs = [Program::default(); NUM_PROGRAMS];
-        self.get_program_field(&mut programs, REG_PROGRAM_TIME, |program, x| {
-            program.time = decode_time(x).unwrap_or_default();
-        })
-        .await?;
-        self.get_program_field(&mut programs, REG_PROGRAM_SOC, |program, x| {
-            program.soc = x;
-        })
-        .await?;
-        Ok(programs)
+    /// Write the programs, and verify them if enabled.
+    ///
+    /// Some firmware ignores writes (for example, while a menu is open on the
+    /// keypad), so if they don't read back correctly, they are rewritten with
+    /// increasing delays.
+    async fn store_programs(
+        &mut self,
+        programs: &[RawProgram; NUM_PROGRAMS],
+        power: bool,
+        charge: bool,
+    ) -> Result<()> {
+        let mut delay = std::time::Duration::from_secs(1);
+        for attempt in 1..=VERIFY_ATTEMPTS {
+            self.write_programs(programs, power, charge).await?;
+            if !self.verify_writes {
+                return Ok(());
+            }
+            let registers = self.registers().await?;
+            let block = self.read(registers.program_time, PROGRAM_SPAN).await?;
+            if programs_written(programs, &block, power, charge) {
+                return Ok(());
+            }
+            if attempt < VERIFY_ATTEMPTS {
+                warn!("Programs did not read back as written; retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+        Err(VerifyError {
+            what: "programs",
+            attempts: VERIFY_ATTEMPTS,
+        }
+        .into())
     }
 
-    pub async fn set_programs(&mut self, programs: &[Program; NUM_PROGRAMS]) -> Result<()> {
-        self.set_program_field(programs, REG_PROGRAM_TIME, |program| {
-            encode_time(program.time)
-        })
-        .await?;
-        self.set_program_field(programs, REG_PROGRAM_SOC, |program| program.soc)
-            .await?;
-        Ok(())
+    async fn write_programs(
+        &mut self,
+        programs: &[RawProgram; NUM_PROGRAMS],
+        power: bool,
+        charge: bool,
+    ) -> Result<()> {
+        if self.batch_writes {
+            self.set_programs_batch(programs, power, charge).await
+        } else {
+            self.set_programs_spans(programs, power, charge).await
+        }
+    }
+
+    /// Write programs with the configured power and grid charge settings
+    async fn store_configured_programs(
+        &mut self,
+        programs: &[RawProgram; NUM_PROGRAMS],
+    ) -> Result<()> {
+        let power = self.program_power.is_some();
+        let charge = self.grid_charge.is_some();
+        self.store_programs(programs, power, charge).await
     }
 
-    pub async fn get_clock(&mut self) -> Result<NaiveDateTime> {
-        let data = self.read(REG_CLOCK, 3).await?;
-        let year = 2000 + (data[0] >> 8) as i32;
-        let month = (data[0] & 0xff) as u32;
-        let day = (data[1] >> 8) as u32;
-        let hour = (data[1] & 0xff) as u32;
-        let minute = (data[2] >> 8) as u32;
-        let second = (data[2] & 0xff) as u32;
-        Ok(NaiveDate::from_ymd_opt(year, month, day)
-            .and_then(|x| x.and_hms_opt(hour, minute, second))
-            .ok_or_else(|| std::io::Error::from(ErrorKind::InvalidData))?)
+    async fn read_clock(&mut self) -> Result<NaiveDateTime> {
+        let registers = self.registers().await?;
+        let clock = registers
+            .clock
+            .ok_or_else(|| unsupported("reading the inverter clock (set `timezone` instead)"))?;
+        let data = self.read(clock, 3).await?;
+        Ok(decode_clock(&data).ok_or_else(|| std::io::Error::from(ErrorKind::InvalidData))?)
+    }
+
+    /// Current time in the time zone of the programs
+    async fn get_local_time(&mut self) -> Result<NaiveDateTime> {
+        match &self.timezone {
+            Some(timezone) => Ok(timezone.to_local(Utc::now())),
+            None => self.read_clock().await,
+        }
     }
 }
 
 #[async_trait]
 impl Inverter for SunsynkInverter {
     async fn get_info(&mut self) -> Result<Info> {
-        let capacity_ah = self.read_one(REG_BATTERY_CAPACITY_AH).await? as f64;
+        let registers = self.registers().await?;
+        let (Some(capacity_reg), Some(voltage_reg), Some(charge_current_reg)) = (
+            registers.battery_capacity_ah,
+            registers.battery_restart_voltage,
+            registers.grid_charge_current,
+        ) else {
+            // The battery settings can't be read, so they must be configured
+            return Ok(Info {
+                capacity: self.capacity_wh.ok_or_else(|| {
+                    unsupported("deriving the battery capacity (set `capacity_wh`)")
+                })?,
+                charge_power: self
+                    .charge_power
+                    .ok_or_else(|| unsupported("deriving the charge power (set `charge_power`)"))?,
+                smart_load: false,
+            });
+        };
+        let capacity_ah = self.read_one(capacity_reg).await? as f64;
         // There are many voltages (low, restart, equalisation, float... this one seems
         // as good as any.
-        let voltage = self.read_one(REG_BATTERY_RESTART_VOLTAGE).await? as f64 * 0.01;
-        let charge_current = self.read_one(REG_GRID_CHARGE_CURRENT).await? as f64;
+        let voltage = self.read_one(voltage_reg).await? as f64 * 0.01;
+        let charge_current = self.read_one(charge_current_reg).await? as f64;
+        let aux_mode = match registers.aux_mode {
+            Some(reg) => Some(self.read_one(reg).await?),
+            None => None,
+        };
+        let capacity = capacity_ah * voltage;
+        let charge_power = charge_current * voltage;
+        if (self.capacity_wh.is_some() || self.charge_power.is_some())
+            && self.derived != Some((capacity, charge_power))
+        {
+            info!(
+                "Inverter settings give a capacity of {capacity:.0} Wh and charge power of \
+                 {charge_power:.0} W (configured: {}, {})",
+                self.capacity_wh
+                    .map_or("none".to_string(), |x| format!("{x:.0} Wh")),
+                self.charge_power
+                    .map_or("none".to_string(), |x| format!("{x:.0} W")),
+            );
+            self.derived = Some((capacity, charge_power));
+        }
         Ok(Info {
-            capacity: capacity_ah * voltage,
-            charge_power: charge_current * voltage,
+            capacity: self.capacity_wh.unwrap_or(capacity),
+            charge_power: self.charge_power.unwrap_or(charge_power),
+            smart_load: aux_mode == Some(AUX_MODE_SMART_LOAD),
         })
     }
 
     async fn get_soc(&mut self) -> Result<f64> {
-        Ok(self.read_one(REG_SOC).await? as f64)
+        let registers = self.registers().await?;
+        Ok(self.read_one(registers.soc).await? as f64)
     }
 
     async fn set_min_soc(&mut self, target: f64, fallback: f64) -> Result<()> {
-        let dt = self.get_clock().await?;
-        let programs = make_programs(target, fallback, dt);
+        let dt = self.get_local_time().await?;
+        let target = round_soc(target, self.target_rounding);
+        let fallback = round_soc(fallback, self.fallback_rounding);
+        if let Some(last) = &self.last_write {
+            if dt < last.valid_until
+                && fallback == last.fallback
+                && (target as f64 - last.target as f64).abs() < self.deadband
+            {
+                info!(
+                    "Keeping programs with minimum SoC {} (within deadband of {})",
+                    last.target, target
+                );
+                return Ok(());
+            }
+        }
+        let mut programs = make_programs(target, fallback, dt);
+        if self.grid_charge.is_some() || self.program_power.is_some() {
+            let old = self.read_programs().await?;
+            apply_charge_settings(&mut programs, &old, self.grid_charge, self.program_power);
+        }
         for (i, program) in programs.iter().enumerate() {
             info!(
                 "Setting program {} to {}: {}",
@@ -237,13 +863,21 @@ impl Inverter for SunsynkInverter {
                 program.soc
             );
         }
-        self.set_programs(&programs).await
+        self.last_write = None;
+        self.store_configured_programs(&programs).await?;
+        self.last_write = Some(LastWrite {
+            target,
+            fallback,
+            valid_until: program_valid_until(dt),
+        });
+        Ok(())
     }
 
     async fn get_coil(&mut self) -> Result<Option<CoilInfo>> {
-        let coil = self.read_one(REG_COIL_POWER).await? as i16 as f64;
-        let inverter = self.read_one(REG_INVERTER_POWER).await? as i16 as f64;
-        let mode = self.read_one(REG_SYSTEM_MODE).await?;
+        let registers = self.registers().await?;
+        let coil = self.read_one(registers.coil_power).await? as i16 as f64;
+        let inverter = self.read_one(registers.inverter_power).await? as i16 as f64;
+        let mode = self.read_one(registers.system_mode).await?;
         Ok(Some(CoilInfo {
             coil,
             inverter,
@@ -251,9 +885,396 @@ impl Inverter for SunsynkInverter {
         }))
     }
 
+    async fn get_non_essential_power(&mut self) -> Result<Option<f64>> {
+        match self.registers().await?.non_essential_power {
+            Some(reg) => Ok(Some(self.read_one(reg).await? as i16 as f64)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_work_mode(&mut self) -> Result<Option<WorkMode>> {
+        let registers = self.registers().await?;
+        let mode = self.read_one(registers.system_mode).await?;
+        Ok(WORK_MODES.get(mode as usize).copied())
+    }
+
+    async fn set_work_mode(&mut self, mode: WorkMode) -> Result<()> {
+        let value = WORK_MODES.iter().position(|&x| x == mode).unwrap() as u16;
+        let registers = self.registers().await?;
+        self.write(registers.system_mode, &[value]).await
+    }
+
+    fn write_count(&self) -> Option<u64> {
+        Some(self.writes)
+    }
+
+    fn io_stats(&self) -> Option<IoStats> {
+        Some(self.io)
+    }
+
+    fn take_intended_writes(&mut self) -> Option<Vec<String>> {
+        None
+    }
+
+    fn budget_used(&self) -> Option<u64> {
+        None
+    }
+
+    async fn get_clock(&mut self) -> Result<Option<NaiveDateTime>> {
+        if self.registers().await?.clock.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(self.read_clock().await?))
+    }
+
+    async fn set_clock(&mut self, time: NaiveDateTime) -> Result<()> {
+        let registers = self.registers().await?;
+        let clock = registers
+            .clock
+            .ok_or_else(|| unsupported("setting the inverter clock"))?;
+        self.write(clock, &encode_clock(time)).await
+    }
+
+    async fn restore_programs(&mut self) -> Result<()> {
+        let programs = self
+            .snapshot
+            .ok_or("the programs could not be read at startup")?;
+        self.last_write = None;
+        self.store_configured_programs(&programs).await
+    }
+
+    async fn get_programs(&mut self) -> Result<Option<Vec<Program>>> {
+        let programs = self.read_programs().await?;
+        Ok(Some(programs.iter().map(Program::from).collect()))
+    }
+
+    async fn set_programs(&mut self, programs: &[Program]) -> Result<()> {
+        let programs: &[Program; NUM_PROGRAMS] = programs.try_into().map_err(|_| {
+            format!(
+                "expected {NUM_PROGRAMS} programs but got {}",
+                programs.len()
+            )
+        })?;
+        let old = self.read_programs().await?;
+        let raw = encode_raw_programs(programs, &old);
+        for (i, program) in raw.iter().enumerate() {
+            info!(
+                "Setting program {} to {}: {} ({} W)",
+                i + 1,
+                program.time,
+                program.soc,
+                program.power
+            );
+        }
+        self.last_write = None;
+        self.store_programs(&raw, true, true).await
+    }
+
     async fn set_trickle(&mut self, trickle: f64) -> Result<()> {
-        let trickle = (trickle / 10.0).round() * 10.0; // UI only supports multiples of 10W
-        let trickle = trickle.clamp(0.0, 32760.0).round() as u16;
-        self.write(REG_TRICKLE, &[trickle, 0]).await
+        let registers = self.registers().await?;
+        let reg = registers
+            .trickle
+            .ok_or_else(|| unsupported("setting the trickle"))?;
+        let trickle = encode_trickle(trickle, self.signed_trickle);
+        self.write(reg, &[trickle, 0]).await
+    }
+
+    async fn get_pv(&mut self) -> Result<Option<Vec<f64>>> {
+        let registers = self.registers().await?;
+        let values = self.read(registers.pv_power, NUM_PV_STRINGS).await?;
+        Ok(Some(values.into_iter().map(|x| x as f64).collect()))
+    }
+
+    async fn get_current_limits(&mut self) -> Result<CurrentLimits> {
+        let registers = self.registers().await?;
+        let reg = registers
+            .battery_max_charge_current
+            .ok_or_else(|| unsupported("reading the battery current limits"))?;
+        // Max discharge current immediately follows max charge current
+        let values = self.read(reg, 2).await?;
+        Ok(CurrentLimits {
+            charge: values[0] as f64,
+            discharge: values[1] as f64,
+        })
+    }
+
+    async fn set_current_limits(&mut self, limits: &CurrentLimits) -> Result<()> {
+        let registers = self.registers().await?;
+        let reg = registers
+            .battery_max_charge_current
+            .ok_or_else(|| unsupported("setting the battery current limits"))?;
+        let charge = limits.charge.clamp(0.0, 65535.0).round() as u16;
+        let discharge = limits.discharge.clamp(0.0, 65535.0).round() as u16;
+        info!("Setting battery current limits to {charge} A (charge), {discharge} A (discharge)");
+        self.write(reg, &[charge, discharge]).await
+    }
+
+    async fn get_aux_power(&mut self) -> Result<Option<f64>> {
+        match self.registers().await?.aux_power {
+            Some(reg) => Ok(Some(self.read_one(reg).await? as i16 as f64)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_telemetry(&mut self) -> Result<Option<Telemetry>> {
+        let registers = self.registers().await?;
+        // Read all the battery registers at once
+        let battery = self
+            .read(
+                registers.battery_temperature,
+                registers.battery_current - registers.battery_temperature + 1,
+            )
+            .await?;
+        let reg = |addr: u16| battery[(addr - registers.battery_temperature) as usize];
+        let load_power = self.read_one(registers.load_power).await? as i16 as f64;
+        let grid_connected = match registers.grid_connected {
+            Some(reg) => Some(self.read_one(reg).await? == 1),
+            None => None,
+        };
+        let faults = match registers.faults {
+            Some(reg) => Some(decode_faults(&self.read(reg, NUM_FAULT_REGISTERS).await?)),
+            None => None,
+        };
+        Ok(Some(Telemetry {
+            battery_power: Some(reg(registers.battery_power) as i16 as f64),
+            battery_voltage: Some(reg(registers.battery_voltage) as f64 * 0.01),
+            battery_current: Some(reg(registers.battery_current) as i16 as f64 * 0.01),
+            // Stored in units of 0.1°C with an offset of 100°C
+            battery_temperature: Some((reg(registers.battery_temperature) as f64 - 1000.0) * 0.1),
+            grid_connected,
+            load_power: Some(load_power),
+            faults,
+        }))
+    }
+}
+
+/// Describe the faults that are flagged in the fault registers
+fn decode_faults(regs: &[u16]) -> Vec<String> {
+    let mut faults = Vec::new();
+    for (i, reg) in regs.iter().enumerate() {
+        for bit in 0..16 {
+            if reg & (1 << bit) != 0 {
+                let code = i as u16 * 16 + bit + 1;
+                match FAULT_DESCRIPTIONS.iter().find(|(c, _)| *c == code) {
+                    Some((_, description)) => faults.push(format!("F{code:02}: {description}")),
+                    None => faults.push(format!("F{code:02}")),
+                }
+            }
+        }
+    }
+    faults
+}
+
+/// Convert a trickle setting (W) to the register value
+fn encode_trickle(trickle: f64, signed: bool) -> u16 {
+    let trickle = (trickle / 10.0).round() * 10.0; // UI only supports multiples of 10W
+    let min = if signed { -32760.0 } else { 0.0 };
+    trickle.clamp(min, 32760.0) as i16 as u16
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_encode_trickle() {
+        assert_eq!(encode_trickle(24.0, false), 20);
+        assert_eq!(encode_trickle(-30.0, false), 0);
+        assert_eq!(encode_trickle(-30.0, true), (-30i16) as u16);
+        assert_eq!(encode_trickle(1e6, true), 32760);
+    }
+
+    #[test]
+    fn test_round_soc() {
+        assert_eq!(round_soc(-3.0, Rounding::Up), 0);
+        assert_eq!(round_soc(100.5, Rounding::Down), 100);
+        assert_eq!(round_soc(42.5, Rounding::Nearest), 43);
+        assert_eq!(round_soc(42.49, Rounding::Nearest), 42);
+        assert_eq!(round_soc(42.01, Rounding::Up), 43);
+        assert_eq!(round_soc(42.0, Rounding::Up), 42);
+        assert_eq!(round_soc(42.99, Rounding::Down), 42);
+        assert_eq!(round_soc(99.5, Rounding::Up), 100);
+        assert_eq!(round_soc(0.2, Rounding::Up), 1);
+    }
+
+    #[test]
+    fn test_make_programs() {
+        let now = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 2, 0)
+            .unwrap();
+        let programs = make_programs(40, 50, now);
+        let times: Vec<_> = programs.iter().map(|p| p.time).collect();
+        assert_eq!(
+            times,
+            [
+                time(11, 50),
+                time(12, 10),
+                time(12, 15),
+                time(12, 20),
+                time(12, 25),
+                time(12, 30)
+            ]
+        );
+        assert_eq!(programs[0].soc, 40);
+        assert!(programs[1..].iter().all(|p| p.soc == 50));
+    }
+
+    #[test]
+    fn test_clock() {
+        let time = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 2, 37)
+            .unwrap();
+        let data = encode_clock(time);
+        assert_eq!(data, [(24 << 8) | 6, (1 << 8) | 12, (2 << 8) | 37]);
+        assert_eq!(decode_clock(&data), Some(time));
+        assert_eq!(decode_clock(&[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_program_valid_until() {
+        let now = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 2, 0)
+            .unwrap();
+        let programs = make_programs(40, 50, now);
+        let valid_until = program_valid_until(now);
+        assert_eq!(valid_until.time() + Duration::minutes(5), programs[1].time);
+    }
+
+    #[test]
+    fn test_encode_raw_programs() {
+        let old = [RawProgram {
+            charge: 2,
+            ..Default::default()
+        }; NUM_PROGRAMS];
+        let mut programs = [Program::default(); NUM_PROGRAMS];
+        programs[0] = Program {
+            time: time(5, 30),
+            power: 3000,
+            soc: 60,
+            grid_charge: true,
+        };
+        let raw = encode_raw_programs(&programs, &old);
+        assert!(
+            raw[0]
+                == RawProgram {
+                    time: time(5, 30),
+                    power: 3000,
+                    soc: 60,
+                    charge: 3,
+                }
+        );
+        assert!(raw[1..].iter().all(|p| p.charge == 2));
+        assert_eq!(Program::from(&raw[0]), programs[0]);
+        assert_eq!(Program::from(&raw[1]), programs[1]);
+    }
+
+    #[test]
+    fn test_register_layout() {
+        assert_eq!(layout_for_device(3), RegisterLayout::SinglePhase);
+        assert_eq!(layout_for_device(5), RegisterLayout::ThreePhase);
+        assert_eq!(layout_for_device(6), RegisterLayout::ThreePhase);
+        assert_eq!(
+            decode_serial(&[0x3232, 0x3031, 0x3233, 0x3435, 0x3637]),
+            "2201234567"
+        );
+        assert_eq!(decode_serial(&[0x4142, 0x0000]), "AB");
+    }
+
+    #[test]
+    fn test_decode_faults() {
+        assert!(decode_faults(&[0, 0, 0, 0]).is_empty());
+        assert_eq!(
+            decode_faults(&[0x1001, 0, 0x0004, 0x8000]),
+            vec![
+                "F01: DC inversed",
+                "F13: Working mode changed",
+                "F35: No AC grid",
+                "F64: Heatsink temperature too high",
+            ]
+        );
+        assert_eq!(decode_faults(&[0x0002, 0, 0, 0]), vec!["F02"]);
+    }
+
+    #[test]
+    fn test_changed_spans() {
+        let old = [1, 2, 3, 4, 5, 6];
+        assert!(changed_spans(&old, &old).is_empty());
+        let new = [1, 9, 9, 4, 5, 7];
+        assert_eq!(
+            changed_spans(&old, &new),
+            vec![(1, &[9, 9][..]), (5, &[7][..])]
+        );
+        let new = [0, 0, 0, 0, 0, 0];
+        assert_eq!(changed_spans(&old, &new), vec![(0, &new[..])]);
+    }
+
+    #[test]
+    fn test_programs_written() {
+        let now = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 2, 0)
+            .unwrap();
+        let programs = make_programs(40, 20, now);
+        let power_offset = (REG_PROGRAM_POWER - REG_PROGRAM_TIME) as usize;
+        let soc_offset = (REG_PROGRAM_SOC - REG_PROGRAM_TIME) as usize;
+        let mut block = vec![0u16; PROGRAM_SPAN as usize];
+        for (i, program) in programs.iter().enumerate() {
+            block[i] = encode_time(program.time);
+            block[soc_offset + i] = program.soc;
+        }
+        assert!(programs_written(&programs, &block, false, false));
+        block[power_offset] = 1234;
+        assert!(programs_written(&programs, &block, false, false));
+        assert!(!programs_written(&programs, &block, true, false));
+        block[soc_offset + 2] += 1;
+        assert!(!programs_written(&programs, &block, false, false));
+    }
+
+    #[test]
+    fn test_apply_charge_settings() {
+        let old = [RawProgram {
+            power: 2000,
+            charge: 2,
+            ..Default::default()
+        }; NUM_PROGRAMS];
+        let mut programs = [RawProgram::default(); NUM_PROGRAMS];
+        apply_charge_settings(&mut programs, &old, None, None);
+        assert!(programs.iter().all(|p| p.power == 2000 && p.charge == 2));
+        apply_charge_settings(&mut programs, &old, Some(true), Some(5000));
+        assert!(programs.iter().all(|p| p.power == 5000 && p.charge == 3));
+        let old = programs;
+        apply_charge_settings(&mut programs, &old, Some(false), None);
+        assert!(programs.iter().all(|p| p.power == 5000 && p.charge == 2));
+    }
+
+    #[test]
+    fn test_make_programs_midnight() {
+        // Window crosses midnight in local time, but not in UTC
+        let timezone: Timezone = "+02:00".parse().unwrap();
+        let now = Utc.with_ymd_and_hms(2017, 9, 2, 22, 3, 0).unwrap();
+        let programs = make_programs(40, 50, timezone.to_local(now));
+        let times: Vec<_> = programs.iter().map(|p| p.time).collect();
+        assert_eq!(
+            times,
+            [
+                time(0, 15),
+                time(0, 20),
+                time(0, 25),
+                time(0, 30),
+                time(0, 35),
+                time(23, 55)
+            ]
+        );
+        // The last program wraps around to cover the current time
+        assert_eq!(programs[NUM_PROGRAMS - 1].soc, 40);
+        assert!(programs[..NUM_PROGRAMS - 1].iter().all(|p| p.soc == 50));
     }
 }
@@ -0,0 +1,233 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Readiness and watchdog notifications to systemd
+//!
+//! This implements the `sd_notify` protocol directly: if systemd started the
+//! service with `Type=notify` (and optionally `WatchdogSec=`), it passes the
+//! path of a datagram socket in `NOTIFY_SOCKET`, to which state changes such
+//! as `READY=1` are sent. When not running under systemd, notifications are
+//! silently skipped.
+
+use log::warn;
+use std::ffi::OsStr;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+/// Send a state change to the socket at `path`
+#[cfg(unix)]
+fn notify_socket(path: &OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify_socket(_path: &OsStr, _state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// Send a state change (such as `READY=1`) to systemd, if it is listening
+pub fn notify(state: &str) -> io::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_socket(&path, state),
+        None => Ok(()),
+    }
+}
+
+/// Interval at which to pet the watchdog, given the watchdog environment
+/// variables. This is half the timeout, as recommended by systemd.
+fn watchdog_interval_from(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    // If WATCHDOG_PID is set, the watchdog is meant for that process only
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec) / 2)
+}
+
+/// Interval at which to send `WATCHDOG=1`, if the systemd watchdog is enabled
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// Tracks the control loops of all the sites, so that systemd is only told
+/// the service is ready (or petted by the watchdog) when all of them are.
+///
+/// Each loop reports that it is responsive at least every
+/// [alive_interval](Self::alive_interval), and the watchdog is only petted
+/// if every loop has done so since the last time. A loop that hangs (for
+/// example, on a dead Modbus connection) thus stops the petting, and systemd
+/// restarts the service.
+pub struct Supervisor {
+    alive: Vec<AtomicBool>,
+    ready: Vec<AtomicBool>,
+    notified_ready: AtomicBool,
+    watchdog: Option<Duration>,
+}
+
+impl Supervisor {
+    pub fn new(loops: usize) -> Self {
+        Self::with_watchdog(loops, watchdog_interval())
+    }
+
+    fn with_watchdog(loops: usize, watchdog: Option<Duration>) -> Self {
+        Self {
+            alive: (0..loops).map(|_| AtomicBool::new(false)).collect(),
+            ready: (0..loops).map(|_| AtomicBool::new(false)).collect(),
+            notified_ready: AtomicBool::new(false),
+            watchdog,
+        }
+    }
+
+    /// How often each loop must call [Supervisor::set_alive], if the
+    /// watchdog is enabled
+    pub fn alive_interval(&self) -> Option<Duration> {
+        // Twice as often as petting, so that a tick is not missed due to jitter
+        self.watchdog.map(|period| period / 2)
+    }
+
+    /// Record that loop `index` is responsive
+    pub fn set_alive(&self, index: usize) {
+        self.alive[index].store(true, Ordering::Relaxed);
+    }
+
+    /// Record that loop `index` has completed an update, and tell systemd
+    /// the service is ready once every loop has
+    pub fn set_ready(&self, index: usize) {
+        if self.mark_ready(index) {
+            if let Err(err) = notify("READY=1") {
+                warn!("Failed to notify systemd of readiness: {err}");
+            }
+        }
+    }
+
+    /// Returns true the first time that all the loops are ready
+    fn mark_ready(&self, index: usize) -> bool {
+        self.ready[index].store(true, Ordering::Relaxed);
+        self.ready.iter().all(|ready| ready.load(Ordering::Relaxed))
+            && !self.notified_ready.swap(true, Ordering::Relaxed)
+    }
+
+    /// Returns true (and starts a new period) if all the loops have been
+    /// responsive since the previous call
+    fn check_alive(&self) -> bool {
+        if !self.alive.iter().all(|alive| alive.load(Ordering::Relaxed)) {
+            return false;
+        }
+        for alive in self.alive.iter() {
+            alive.store(false, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Pet the watchdog (if enabled) until cancelled
+    pub async fn run_watchdog(&self, token: CancellationToken) {
+        let Some(period) = self.watchdog else {
+            return;
+        };
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick is immediate, before the loops have had a chance to run
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if !self.check_alive() {
+                        warn!("A control loop is not responding, not petting the watchdog");
+                    } else if let Err(err) = notify("WATCHDOG=1") {
+                        warn!("Failed to notify the systemd watchdog: {err}");
+                    }
+                }
+                _ = token.cancelled() => { break; }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_interval() {
+        let interval = |usec, pid| watchdog_interval_from(usec, pid, 1234);
+        assert_eq!(
+            interval(Some("30000000"), None),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            interval(Some("30000000"), Some("1234")),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(interval(Some("30000000"), Some("999")), None);
+        assert_eq!(interval(Some("0"), None), None);
+        assert_eq!(interval(Some("soon"), None), None);
+        assert_eq!(interval(None, None), None);
+    }
+
+    #[test]
+    fn test_supervisor() {
+        let supervisor = Supervisor::with_watchdog(2, Some(Duration::from_secs(10)));
+        assert_eq!(supervisor.alive_interval(), Some(Duration::from_secs(5)));
+        assert!(!supervisor.mark_ready(0));
+        assert!(supervisor.mark_ready(1));
+        assert!(!supervisor.mark_ready(0));
+
+        // One loop hanging stops the petting
+        supervisor.set_alive(0);
+        assert!(!supervisor.check_alive());
+        supervisor.set_alive(1);
+        assert!(supervisor.check_alive());
+        supervisor.set_alive(1);
+        assert!(!supervisor.check_alive());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify_socket() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("socit-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Time zone in which the inverter's programs are expressed
+//!
+//! This is either a fixed offset from UTC (so that local time never jumps,
+//! as it can around daylight-saving transitions), or a zone from the IANA
+//! time zone database (e.g. `Africa/Johannesburg`).
+
+use chrono::{DateTime, Duration, FixedOffset, LocalResult, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timezone {
+    kind: Kind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Fixed(FixedOffset),
+    Zone(Tz),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTimezoneError(String);
+
+impl fmt::Display for ParseTimezoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid time zone {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseTimezoneError {}
+
+/// Convert a local time in `zone` to UTC (see [Timezone::to_utc])
+fn zone_to_utc<Z: TimeZone>(zone: &Z, time: NaiveDateTime) -> DateTime<Utc> {
+    match zone.from_local_datetime(&time) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => t.with_timezone(&Utc),
+        LocalResult::None => {
+            // Transitions are assumed to be more than a day apart
+            let before = zone
+                .offset_from_utc_datetime(&(time - Duration::days(1)))
+                .fix();
+            (time - before).and_utc()
+        }
+    }
+}
+
+impl Timezone {
+    pub fn from_offset(offset: FixedOffset) -> Self {
+        Self {
+            kind: Kind::Fixed(offset),
+        }
+    }
+
+    pub fn from_zone(zone: Tz) -> Self {
+        Self {
+            kind: Kind::Zone(zone),
+        }
+    }
+
+    /// Convert a UTC time to local time in this zone
+    pub fn to_local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        match &self.kind {
+            Kind::Fixed(offset) => time.with_timezone(offset).naive_local(),
+            Kind::Zone(zone) => time.with_timezone(zone).naive_local(),
+        }
+    }
+
+    /// Convert a local time in this zone to UTC.
+    ///
+    /// If the local time occurs twice, the earlier is returned. If it does not
+    /// occur (because it is skipped by a transition), the offset from before
+    /// the transition is used.
+    pub fn to_utc(&self, time: NaiveDateTime) -> DateTime<Utc> {
+        match &self.kind {
+            Kind::Fixed(offset) => zone_to_utc(offset, time),
+            Kind::Zone(zone) => zone_to_utc(zone, time),
+        }
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = ParseTimezoneError;
+
+    /// Parse offsets like `UTC`, `UTC+2`, `+02:00` or `-0130`, or zone names
+    /// like `Africa/Johannesburg`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseTimezoneError(s.to_string());
+        let rest = s.trim();
+        // Neither offsets nor zone names contain anything else, and this
+        // keeps the slicing below on character boundaries
+        if !rest.is_ascii() {
+            return Err(err());
+        }
+        let rest = rest.strip_prefix("UTC").unwrap_or(rest);
+        if rest.is_empty() {
+            return Ok(Self::from_offset(FixedOffset::east_opt(0).unwrap()));
+        }
+        let (sign, rest) = match rest.as_bytes()[0] {
+            b'+' => (1, &rest[1..]),
+            b'-' => (-1, &rest[1..]),
+            _ => {
+                return s
+                    .trim()
+                    .parse::<Tz>()
+                    .map(Self::from_zone)
+                    .map_err(|_| err())
+            }
+        };
+        let (hours, minutes) = match rest.split_once(':') {
+            Some((h, m)) => (h, m),
+            None if rest.len() > 2 => rest.split_at(rest.len() - 2),
+            None => (rest, "0"),
+        };
+        let hours: i32 = hours.parse().map_err(|_| err())?;
+        let minutes: i32 = minutes.parse().map_err(|_| err())?;
+        if !(0..60).contains(&minutes) {
+            return Err(err());
+        }
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(Self::from_offset)
+            .ok_or_else(err)
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            Kind::Fixed(offset) => write!(f, "{offset}"),
+            Kind::Zone(zone) => f.write_str(zone.name()),
+        }
+    }
+}
+
+impl Serialize for Timezone {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timezone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn offset(seconds: i32) -> Timezone {
+        Timezone::from_offset(FixedOffset::east_opt(seconds).unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("UTC".parse(), Ok(offset(0)));
+        assert_eq!("UTC+2".parse(), Ok(offset(7200)));
+        assert_eq!("+02:00".parse(), Ok(offset(7200)));
+        assert_eq!("-0130".parse(), Ok(offset(-5400)));
+        assert_eq!(" +1 ".parse(), Ok(offset(3600)));
+        assert!("Nowhere/Atlantis".parse::<Timezone>().is_err());
+        assert!("../etc/passwd".parse::<Timezone>().is_err());
+        assert!("+02:75".parse::<Timezone>().is_err());
+        assert!("+25".parse::<Timezone>().is_err());
+        assert!("+1é".parse::<Timezone>().is_err());
+        assert!("UTC+€0".parse::<Timezone>().is_err());
+        assert_eq!(
+            "Africa/Johannesburg".parse(),
+            Ok(Timezone::from_zone(chrono_tz::Africa::Johannesburg))
+        );
+    }
+
+    #[test]
+    fn test_no_dst_jump() {
+        // Namibia switched from winter time (UTC+1) to UTC+2 at 02:00 local
+        // time on 2017-09-03. A fixed offset must not jump.
+        let tz = offset(7200);
+        let before = Utc.with_ymd_and_hms(2017, 9, 2, 23, 55, 0).unwrap();
+        let after = Utc.with_ymd_and_hms(2017, 9, 3, 0, 5, 0).unwrap();
+        assert_eq!(
+            tz.to_local(after) - tz.to_local(before),
+            chrono::Duration::minutes(10)
+        );
+        assert_eq!(
+            tz.to_local(before),
+            NaiveDateTime::parse_from_str("2017-09-03 01:55:00", "%Y-%m-%d %H:%M:%S").unwrap()
+        );
+        assert_eq!(tz.to_utc(tz.to_local(before)), before);
+    }
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, mo, d)
+            .unwrap()
+            .and_hms_opt(h, mi, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_windhoek() {
+        // Namibia moved from winter time (UTC+1) to UTC+2 at 02:00 local
+        // time on 2017-09-03, and has stayed there since
+        let tz: Timezone = "Africa/Windhoek".parse().unwrap();
+        assert_eq!(tz.to_string(), "Africa/Windhoek");
+        let before = Utc.with_ymd_and_hms(2017, 9, 3, 0, 55, 0).unwrap();
+        let after = Utc.with_ymd_and_hms(2017, 9, 3, 1, 5, 0).unwrap();
+        assert_eq!(tz.to_local(before), local(2017, 9, 3, 1, 55));
+        assert_eq!(tz.to_local(after), local(2017, 9, 3, 3, 5));
+        assert_eq!(tz.to_utc(local(2017, 9, 3, 3, 5)), after);
+        let later = Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap();
+        assert_eq!(tz.to_local(later), local(2024, 7, 1, 14, 0));
+    }
+
+    #[test]
+    fn test_zone() {
+        let tz: Timezone = "Europe/Berlin".parse().unwrap();
+        let summer = Utc.with_ymd_and_hms(2024, 10, 1, 12, 0, 0).unwrap();
+        assert_eq!(tz.to_local(summer), local(2024, 10, 1, 14, 0));
+        assert_eq!(tz.to_utc(local(2024, 10, 1, 14, 0)), summer);
+        // 02:30 occurs twice on 27 October: use the earlier one
+        assert_eq!(
+            tz.to_utc(local(2024, 10, 27, 2, 30)),
+            Utc.with_ymd_and_hms(2024, 10, 27, 0, 30, 0).unwrap()
+        );
+        // 02:30 does not occur on 31 March
+        assert_eq!(
+            tz.to_utc(local(2024, 3, 31, 2, 30)),
+            Utc.with_ymd_and_hms(2024, 3, 31, 1, 30, 0).unwrap()
+        );
+    }
+}
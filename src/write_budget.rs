@@ -0,0 +1,281 @@
+/* Copyright 2025 Bruce Merry
+ *
+ * This program is free software: you can redistribute it and/or modify it
+ * under the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+ * more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Limits on the rate at which settings are written to the inverter
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use log::warn;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::config::{WorkMode, WriteBudgetConfig};
+use crate::inverter::{
+    CoilInfo, CurrentLimits, Info, Inverter, IoStats, Program, Result, Telemetry,
+};
+
+const HOUR: Duration = Duration::from_secs(3600);
+const DAY: Duration = Duration::from_secs(86400);
+
+/// A write was not made because it would exceed the write budget
+#[derive(Debug)]
+pub struct WriteDeferred {
+    pub what: &'static str,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for WriteDeferred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deferred writing the {} ({})", self.what, self.reason)
+    }
+}
+
+impl std::error::Error for WriteDeferred {}
+
+/// Register writes made over the last day
+struct WriteBudget {
+    max_per_hour: Option<u64>,
+    max_per_day: Option<u64>,
+    /// Times and numbers of writes, oldest first
+    writes: VecDeque<(Instant, u64)>,
+}
+
+impl WriteBudget {
+    fn new(config: &WriteBudgetConfig) -> Self {
+        Self {
+            max_per_hour: config.max_writes_per_hour,
+            max_per_day: config.max_writes_per_day,
+            writes: VecDeque::new(),
+        }
+    }
+
+    /// Number of writes made in the `period` before `now`
+    fn used(&self, now: Instant, period: Duration) -> u64 {
+        self.writes
+            .iter()
+            .filter(|(time, _)| now.saturating_duration_since(*time) < period)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Check whether a write may be made. The hourly limit applies to all
+    /// writes, while the daily budget only defers writes that aren't urgent.
+    fn check(&mut self, now: Instant, urgent: bool) -> std::result::Result<(), &'static str> {
+        while self
+            .writes
+            .front()
+            .is_some_and(|(time, _)| now.saturating_duration_since(*time) >= DAY)
+        {
+            self.writes.pop_front();
+        }
+        if self
+            .max_per_hour
+            .is_some_and(|max| self.used(now, HOUR) >= max)
+        {
+            return Err("hourly write limit reached");
+        }
+        if !urgent
+            && self
+                .max_per_day
+                .is_some_and(|max| self.used(now, DAY) >= max)
+        {
+            return Err("daily write budget used up");
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, now: Instant, count: u64) {
+        if count > 0 {
+            self.writes.push_back((now, count));
+        }
+    }
+}
+
+/// Wrap another inverter to limit how often it is written to, across all
+/// the controllers that use it.
+pub struct BudgetedInverter<'a> {
+    base: Box<dyn Inverter + 'a>,
+    budget: WriteBudget,
+}
+
+impl<'a> BudgetedInverter<'a> {
+    pub fn new(base: Box<dyn Inverter + 'a>, config: &WriteBudgetConfig) -> Self {
+        Self {
+            base,
+            budget: WriteBudget::new(config),
+        }
+    }
+
+    /// Check the budget before a write, returning the write count to compare against afterwards
+    fn before(&mut self, what: &'static str, urgent: bool) -> Result<Option<u64>> {
+        if let Err(reason) = self.budget.check(Instant::now(), urgent) {
+            warn!("Not writing the {what}: {reason}");
+            return Err(WriteDeferred { what, reason }.into());
+        }
+        Ok(self.base.write_count())
+    }
+
+    /// Charge the writes made since [BudgetedInverter::before] to the budget
+    fn after<T>(&mut self, start: Option<u64>, result: Result<T>) -> Result<T> {
+        // Backends that don't count writes are charged one per call
+        let count = match (start, self.base.write_count()) {
+            (Some(start), Some(end)) => end.saturating_sub(start),
+            _ => 1,
+        };
+        self.budget.record(Instant::now(), count);
+        result
+    }
+}
+
+#[async_trait]
+impl Inverter for BudgetedInverter<'_> {
+    async fn get_info(&mut self) -> Result<Info> {
+        self.base.get_info().await
+    }
+
+    async fn get_soc(&mut self) -> Result<f64> {
+        self.base.get_soc().await
+    }
+
+    async fn set_min_soc(&mut self, target: f64, fallback: f64) -> Result<()> {
+        let start = self.before("SoC programs", true)?;
+        let result = self.base.set_min_soc(target, fallback).await;
+        self.after(start, result)
+    }
+
+    async fn get_coil(&mut self) -> Result<Option<CoilInfo>> {
+        self.base.get_coil().await
+    }
+
+    async fn get_non_essential_power(&mut self) -> Result<Option<f64>> {
+        self.base.get_non_essential_power().await
+    }
+
+    async fn set_trickle(&mut self, trickle: f64) -> Result<()> {
+        let start = self.before("trickle", false)?;
+        let result = self.base.set_trickle(trickle).await;
+        self.after(start, result)
+    }
+
+    async fn get_pv(&mut self) -> Result<Option<Vec<f64>>> {
+        self.base.get_pv().await
+    }
+
+    async fn get_current_limits(&mut self) -> Result<CurrentLimits> {
+        self.base.get_current_limits().await
+    }
+
+    async fn set_current_limits(&mut self, limits: &CurrentLimits) -> Result<()> {
+        let start = self.before("current limits", false)?;
+        let result = self.base.set_current_limits(limits).await;
+        self.after(start, result)
+    }
+
+    async fn get_aux_power(&mut self) -> Result<Option<f64>> {
+        self.base.get_aux_power().await
+    }
+
+    async fn get_telemetry(&mut self) -> Result<Option<Telemetry>> {
+        self.base.get_telemetry().await
+    }
+
+    async fn get_work_mode(&mut self) -> Result<Option<WorkMode>> {
+        self.base.get_work_mode().await
+    }
+
+    async fn set_work_mode(&mut self, mode: WorkMode) -> Result<()> {
+        let start = self.before("work mode", false)?;
+        let result = self.base.set_work_mode(mode).await;
+        self.after(start, result)
+    }
+
+    fn write_count(&self) -> Option<u64> {
+        self.base.write_count()
+    }
+
+    fn io_stats(&self) -> Option<IoStats> {
+        self.base.io_stats()
+    }
+
+    fn take_intended_writes(&mut self) -> Option<Vec<String>> {
+        self.base.take_intended_writes()
+    }
+
+    fn budget_used(&self) -> Option<u64> {
+        Some(self.budget.used(Instant::now(), DAY))
+    }
+
+    async fn restore_programs(&mut self) -> Result<()> {
+        let start = self.before("programs", true)?;
+        let result = self.base.restore_programs().await;
+        self.after(start, result)
+    }
+
+    async fn get_programs(&mut self) -> Result<Option<Vec<Program>>> {
+        self.base.get_programs().await
+    }
+
+    async fn set_programs(&mut self, programs: &[Program]) -> Result<()> {
+        let start = self.before("programs", true)?;
+        let result = self.base.set_programs(programs).await;
+        self.after(start, result)
+    }
+
+    async fn get_clock(&mut self) -> Result<Option<NaiveDateTime>> {
+        self.base.get_clock().await
+    }
+
+    async fn set_clock(&mut self, time: NaiveDateTime) -> Result<()> {
+        let start = self.before("clock", false)?;
+        let result = self.base.set_clock(time).await;
+        self.after(start, result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let mut budget = WriteBudget::new(&WriteBudgetConfig {
+            max_writes_per_hour: Some(3),
+            max_writes_per_day: Some(4),
+        });
+        let start = Instant::now();
+        assert!(budget.check(start, false).is_ok());
+        budget.record(start, 2);
+        budget.record(start + Duration::from_secs(60), 1);
+        // Hour limit applies to urgent writes too
+        assert!(budget
+            .check(start + Duration::from_secs(120), true)
+            .is_err());
+        let later = start + Duration::from_secs(3600);
+        assert!(budget.check(later, false).is_ok());
+        budget.record(later, 1);
+        // Day budget only defers writes that aren't urgent
+        assert_eq!(
+            budget.check(later, false),
+            Err("daily write budget used up")
+        );
+        assert!(budget.check(later, true).is_ok());
+        assert_eq!(budget.used(later, DAY), 4);
+        let next_day = start + DAY + Duration::from_secs(60);
+        assert!(budget.check(next_day, false).is_ok());
+        assert_eq!(budget.used(next_day, DAY), 1);
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright 2025 Bruce Merry
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Access the socit planner compiled to WebAssembly. Build it with
+//
+//   cargo build --release --lib --no-default-features --target wasm32-unknown-unknown
+//
+// and serve target/wasm32-unknown-unknown/release/socit.wasm alongside this
+// file. Usage:
+//
+//   const planner = await Planner.load("socit.wasm");
+//   const targets = planner.computeTargets({
+//     events: [{start: "2024-06-01T16:00:00Z", end: "2024-06-01T18:00:00Z", note: "Stage 2"}],
+//     panels: [],
+//     battery: {capacity: 5000, min_soc: 20, charge_power: 2000},
+//     load: {min_discharge_power: 100, max_discharge_power: 500},
+//     now: new Date().toISOString(),
+//   });
+
+export class Planner {
+  constructor(instance) {
+    this.exports = instance.exports;
+  }
+
+  static async load(url) {
+    const { instance } = await WebAssembly.instantiateStreaming(fetch(url), {});
+    return new Planner(instance);
+  }
+
+  // Copy a JS string into module memory. The result must be freed.
+  #toWasm(s) {
+    const bytes = new TextEncoder().encode(s);
+    const ptr = this.exports.socit_alloc_string(bytes.length);
+    new Uint8Array(this.exports.memory.buffer, ptr, bytes.length).set(bytes);
+    return ptr;
+  }
+
+  // Read a NUL-terminated string from module memory and free it.
+  #fromWasm(ptr) {
+    const memory = new Uint8Array(this.exports.memory.buffer);
+    let end = ptr;
+    while (memory[end] !== 0) {
+      end++;
+    }
+    const s = new TextDecoder().decode(memory.subarray(ptr, end));
+    this.exports.socit_free_string(ptr);
+    return s;
+  }
+
+  computeTargets(request) {
+    // JSON.stringify escapes control characters, so there are no NUL bytes
+    const input = this.#toWasm(JSON.stringify(request));
+    try {
+      const response = JSON.parse(this.#fromWasm(this.exports.socit_compute_targets(input)));
+      if ("error" in response) {
+        throw new Error(response.error);
+      }
+      return response;
+    } finally {
+      this.exports.socit_free_string(input);
+    }
+  }
+
+  panelsPower(panels, time) {
+    const input = this.#toWasm(JSON.stringify(panels));
+    try {
+      return this.exports.socit_panels_power(input, time.getTime() / 1000);
+    } finally {
+      this.exports.socit_free_string(input);
+    }
+  }
+}